@@ -1,28 +1,32 @@
 #![feature(test)]
 
-use bevy::{
-    pbr::StandardMaterial,
-    prelude::{Assets, Image},
-};
+use std::sync::Arc;
+
+use bevy::prelude::{Assets, Image};
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use quell::{
+    brush::BrushMaterial,
+    cache::TextureCache,
     data::{GameId, LoadedTextures, VpkState},
     map::GameMap,
-    material::load_materials,
+    material::{load_materials, load_materials2},
 };
 
+/// The game directory and map to benchmark with can be overridden with `QUELL_BENCH_GAME` and
+/// `QUELL_BENCH_MAP`, so that the same numbers can be taken against an installed copy of the game.
 fn bench_load_materials(c: &mut Criterion) {
     let game_id = GameId::Tf2;
-    let root_path = "./ex/tf/";
-    let vpk = VpkState::new(root_path, game_id).expect("Failed to load vpk state");
-    let map_path = "./ex/ctf_2fort.bsp";
+    let root_path = std::env::var("QUELL_BENCH_GAME").unwrap_or_else(|_| "./ex/tf/".to_string());
+    let vpk = VpkState::new(&root_path, game_id).expect("Failed to load vpk state");
+    let map_path =
+        std::env::var("QUELL_BENCH_MAP").unwrap_or_else(|_| "./ex/ctf_2fort.bsp".to_string());
     // let map_path = "ex/tf/tf/maps/test.bsp";
-    let map = GameMap::from_path(map_path).expect("Failed to load game map");
+    let map = GameMap::from_path(&map_path).expect("Failed to load game map");
 
     c.bench_function("load-materials1", |b| {
         b.iter(|| {
             let mut images: Assets<Image> = Assets::default();
-            let mut materials: Assets<StandardMaterial> = Assets::default();
+            let mut materials: Assets<BrushMaterial> = Assets::default();
             let mut loaded_textures = LoadedTextures::default();
 
             let res = load_materials(
@@ -42,6 +46,49 @@ fn bench_load_materials(c: &mut Criterion) {
             );
         });
     });
+
+    c.bench_function("load-materials2", |b| {
+        b.iter(|| {
+            let mut images: Assets<Image> = Assets::default();
+            let mut materials: Assets<BrushMaterial> = Assets::default();
+            let mut loaded_textures = LoadedTextures::default();
+
+            let res = load_materials2(
+                &vpk,
+                &mut loaded_textures,
+                &mut images,
+                &mut materials,
+                &map,
+            );
+
+            black_box(res).unwrap();
+        });
+    });
+
+    // The first iteration fills the cache, the rest read from it
+    let cache = Arc::new(
+        TextureCache::new("./cache/bench-textures", u64::MAX).expect("Failed to open cache"),
+    );
+    c.bench_function("load-materials-cached", |b| {
+        b.iter(|| {
+            let mut images: Assets<Image> = Assets::default();
+            let mut materials: Assets<BrushMaterial> = Assets::default();
+            let mut loaded_textures = LoadedTextures {
+                cache: Some(cache.clone()),
+                ..Default::default()
+            };
+
+            let res = load_materials(
+                &vpk,
+                &mut loaded_textures,
+                &mut images,
+                &mut materials,
+                &map,
+            );
+
+            black_box(res).unwrap();
+        });
+    });
 }
 
 criterion_group!(benches, bench_load_materials);
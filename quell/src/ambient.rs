@@ -0,0 +1,373 @@
+//! The ambient lighting of the map, which is how the game lights models rather than with
+//! lightmaps.
+//! vrad samples the light arriving from each of the six axis directions at points inside every
+//! leaf, which are compressed into the leaf ambient lumps. A model is lit by the samples in the
+//! leaf at its center, picking between the six colors by its normal.
+
+use std::ops::Range;
+
+use bevy::prelude::{Component, Vec3};
+
+use crate::{
+    lightmap::decode_luxel,
+    lump::{read_records, LumpReader, LumpType, Lumps},
+    vis::{Bounds, Vis},
+};
+
+/// Size of a `dleafambientindex_t`
+const AMBIENT_INDEX_SIZE: usize = 4;
+/// Size of a `dleafambientlighting_t`
+const AMBIENT_SAMPLE_SIZE: usize = 28;
+/// Size of a `dleaf_t` in version 0 of the leaf lump, see [`Vis`]
+const LEAF_V0_SIZE: usize = 56;
+/// Offset of the ambient lighting in a version 0 `dleaf_t`
+const LEAF_V0_AMBIENT_OFFSET: usize = 30;
+/// The darkest color that [`AmbientCube::quantize`] keeps, as a power of two, anything darker is
+/// black
+const QUANTIZE_MIN_EXPONENT: f32 = -8.0;
+/// How many steps [`AmbientCube::quantize`] divides each doubling of brightness into
+const QUANTIZE_STEPS_PER_STOP: f32 = 16.0;
+
+/// The directions that the colors of an [`AmbientCube`] are lit from, in bevy coordinates
+pub const AMBIENT_CUBE_DIRECTIONS: [Vec3; 6] = [
+    Vec3::X,
+    Vec3::NEG_X,
+    Vec3::Y,
+    Vec3::NEG_Y,
+    Vec3::Z,
+    Vec3::NEG_Z,
+];
+
+/// The light arriving at a point from each of the six axis directions.
+/// Also a component of the models lit by it.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Component)]
+pub struct AmbientCube {
+    /// Linear colors from the directions in [`AMBIENT_CUBE_DIRECTIONS`]
+    pub colors: [[f32; 3]; 6],
+}
+impl AmbientCube {
+    /// Convert the colors from +X, -X, +Y, -Y, +Z and -Z in source coordinates.
+    pub fn from_source([px, nx, py, ny, pz, nz]: [[f32; 3]; 6]) -> AmbientCube {
+        // Source's X is bevy's -Z, source's Y is bevy's -X and source's Z is bevy's Y
+        AmbientCube {
+            colors: [ny, py, pz, nz, nx, px],
+        }
+    }
+
+    fn read(r: &mut LumpReader<'_>) -> eyre::Result<AmbientCube> {
+        let mut colors = [[0.0; 3]; 6];
+        for color in &mut colors {
+            *color = decode_luxel(&r.bytes::<4>()?);
+        }
+        Ok(AmbientCube::from_source(colors))
+    }
+
+    /// The light on a surface facing `normal` (in bevy coordinates), which is the colors of the
+    /// directions it faces weighted by the square of the normal, like the game's shaders do.
+    pub fn light(&self, normal: Vec3) -> [f32; 3] {
+        let n = normal.normalize_or_zero();
+        let pick = |v: f32, axis: usize| {
+            let color = self.colors[axis * 2 + usize::from(v < 0.0)];
+            color.map(|c| c * v * v)
+        };
+        let [x, y, z] = [pick(n.x, 0), pick(n.y, 1), pick(n.z, 2)];
+        [0, 1, 2].map(|i| x[i] + y[i] + z[i])
+    }
+
+    /// Round the colors on a log scale, so that cubes which look alike can share a key.
+    /// Each step is about 4% brighter than the last, and colors from `2^-8` up to about `245`
+    /// fit, brighter colors are clamped to the brightest step.
+    pub fn quantize(&self) -> [[u8; 3]; 6] {
+        self.colors.map(|color| {
+            color.map(|c| {
+                let step = (c.log2() - QUANTIZE_MIN_EXPONENT) * QUANTIZE_STEPS_PER_STOP;
+                // Black is `-inf`, which clamps to 0
+                if step.is_nan() {
+                    0
+                } else {
+                    step.round().clamp(0.0, 255.0) as u8
+                }
+            })
+        })
+    }
+
+    /// The cube that every cube with this [`AmbientCube::quantize`] key is drawn with.
+    pub fn from_quantized(key: [[u8; 3]; 6]) -> AmbientCube {
+        AmbientCube {
+            colors: key.map(|color| {
+                color.map(|step| match step {
+                    0 => 0.0,
+                    _ => (f32::from(step) / QUANTIZE_STEPS_PER_STOP + QUANTIZE_MIN_EXPONENT).exp2(),
+                })
+            }),
+        }
+    }
+}
+
+/// One of the ambient samples of a leaf
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AmbientSample {
+    /// In source coordinates
+    pub position: [f32; 3],
+    pub cube: AmbientCube,
+}
+
+/// The ambient samples of each leaf of the map
+#[derive(Debug, Clone, Default)]
+pub struct LeafAmbient {
+    /// The range of each leaf's samples in `samples`
+    leaves: Vec<Range<usize>>,
+    samples: Vec<AmbientSample>,
+}
+impl LeafAmbient {
+    /// Read the ambient lighting, preferring the HDR lighting like the lightmaps do.
+    pub fn from_bsp(data: &[u8], vis: &Vis) -> eyre::Result<LeafAmbient> {
+        let lumps = Lumps::new(data)?;
+        let lighting_hdr = lumps.get(LumpType::LeafAmbientLightingHdr)?;
+        let (index, lighting) = if lighting_hdr.is_empty() {
+            (
+                lumps.get(LumpType::LeafAmbientIndex)?,
+                lumps.get(LumpType::LeafAmbientLighting)?,
+            )
+        } else {
+            (lumps.get(LumpType::LeafAmbientIndexHdr)?, lighting_hdr)
+        };
+
+        if lighting.is_empty() && lumps.lump_version(LumpType::Leafs) == 0 {
+            // Older maps have a single cube stored in each leaf
+            return parse_leaf_v0(&lumps.get(LumpType::Leafs)?);
+        }
+
+        parse_leaf_ambient(&index, &lighting, |leaf| vis.leaf_bounds(leaf))
+    }
+
+    pub fn samples(&self) -> &[AmbientSample] {
+        &self.samples
+    }
+
+    pub fn leaf_samples(&self, leaf: usize) -> &[AmbientSample] {
+        self.leaves
+            .get(leaf)
+            .and_then(|range| self.samples.get(range.clone()))
+            .unwrap_or(&[])
+    }
+
+    /// The ambient lighting at the point, in source coordinates.
+    /// `None` if the leaf the point is in has no samples, ex: when it is inside a wall.
+    pub fn sample(&self, vis: &Vis, point: [f32; 3]) -> Option<AmbientCube> {
+        self.sample_leaf(vis.leaf_at(point)?, point)
+    }
+
+    /// The ambient lighting at the point in the leaf, blending its samples by their inverse
+    /// squared distance to the point like the game does.
+    pub fn sample_leaf(&self, leaf: usize, point: [f32; 3]) -> Option<AmbientCube> {
+        let samples = self.leaf_samples(leaf);
+        if samples.is_empty() {
+            return None;
+        }
+
+        let mut total = 0.0;
+        let mut colors = [[0.0; 3]; 6];
+        for sample in samples {
+            let distance_squared = (0..3)
+                .map(|i| (sample.position[i] - point[i]).powi(2))
+                .sum::<f32>();
+            let weight = 1.0 / (distance_squared + 1.0);
+            total += weight;
+            for (color, sample_color) in colors.iter_mut().zip(sample.cube.colors) {
+                for (c, s) in color.iter_mut().zip(sample_color) {
+                    *c += s * weight;
+                }
+            }
+        }
+
+        Some(AmbientCube {
+            colors: colors.map(|color| color.map(|c| c / total)),
+        })
+    }
+}
+
+/// Parse the leaf ambient index and lighting lumps.
+/// The position of each sample is stored as a fraction of the bounds of its leaf.
+fn parse_leaf_ambient(
+    index: &[u8],
+    lighting: &[u8],
+    leaf_bounds: impl Fn(usize) -> Option<Bounds>,
+) -> eyre::Result<LeafAmbient> {
+    let leaves = read_records(index, AMBIENT_INDEX_SIZE, |r| {
+        let count = r.u16()? as usize;
+        let first = r.u16()? as usize;
+        Ok(first..first + count)
+    })?;
+    let samples = read_records(lighting, AMBIENT_SAMPLE_SIZE, |r| {
+        let cube = AmbientCube::read(r)?;
+        let fraction = r.bytes::<3>()?.map(|v| f32::from(v) / 255.0);
+        Ok((cube, fraction))
+    })?;
+
+    let mut positioned = samples
+        .iter()
+        .map(|(cube, _)| AmbientSample {
+            position: [0.0; 3],
+            cube: *cube,
+        })
+        .collect::<Vec<_>>();
+    for (leaf, range) in leaves.iter().enumerate() {
+        let bounds = leaf_bounds(leaf).unwrap_or_default();
+        let leaf_samples = positioned
+            .get_mut(range.clone())
+            .ok_or_else(|| eyre::eyre!("Leaf {leaf} has invalid ambient samples {range:?}"))?;
+        for (sample, (_, fraction)) in leaf_samples.iter_mut().zip(&samples[range.clone()]) {
+            sample.position =
+                [0, 1, 2].map(|i| bounds.mins[i] + (bounds.maxs[i] - bounds.mins[i]) * fraction[i]);
+        }
+    }
+
+    Ok(LeafAmbient {
+        leaves,
+        samples: positioned,
+    })
+}
+
+/// Parse the ambient cubes stored in version 0 of the leaf lump, which are at the center of the
+/// leaf.
+fn parse_leaf_v0(leaves: &[u8]) -> eyre::Result<LeafAmbient> {
+    let samples = read_records(leaves, LEAF_V0_SIZE, |r| {
+        // Contents, cluster and area
+        r.skip(8);
+        let mut bounds = [0.0; 6];
+        for v in &mut bounds {
+            *v = f32::from(r.i16()?);
+        }
+        r.skip(LEAF_V0_AMBIENT_OFFSET - 20);
+        Ok(AmbientSample {
+            position: [0, 1, 2].map(|i| (bounds[i] + bounds[i + 3]) * 0.5),
+            cube: AmbientCube::read(r)?,
+        })
+    })?;
+
+    Ok(LeafAmbient {
+        leaves: (0..samples.len()).map(|i| i..i + 1).collect(),
+        samples,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cube where each direction has a different brightness, `1.0` for the first
+    fn write_cube(data: &mut Vec<u8>, scale: u8) {
+        for i in 0..6u8 {
+            data.extend([255 - i * 2 * scale, 0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn test_ambient_cube() {
+        let cube = AmbientCube::from_source([
+            [1.0, 0.0, 0.0],
+            [2.0, 0.0, 0.0],
+            [3.0, 0.0, 0.0],
+            [4.0, 0.0, 0.0],
+            [5.0, 0.0, 0.0],
+            [6.0, 0.0, 0.0],
+        ]);
+        // Up is source's +Z, forward (-Z) is source's +X and right (+X) is source's -Y
+        assert_eq!(cube.light(Vec3::Y), [5.0, 0.0, 0.0]);
+        assert_eq!(cube.light(Vec3::NEG_Z), [1.0, 0.0, 0.0]);
+        assert_eq!(cube.light(Vec3::X), [4.0, 0.0, 0.0]);
+        // Weighted by the squared normal, so this is half of each
+        let light = cube.light(Vec3::new(0.0, -1.0, 1.0));
+        assert!((light[0] - (6.0 + 2.0) * 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_quantize() {
+        let key = |c: f32| {
+            AmbientCube {
+                colors: [[c; 3]; 6],
+            }
+            .quantize()
+        };
+        let quantized = |c: f32| AmbientCube::from_quantized(key(c)).colors[0][0];
+        assert_eq!(quantized(0.0), 0.0);
+        assert_eq!(quantized(0.001), 0.0);
+        // Within half a step, on a log scale
+        for c in [0.01, 0.5, 1.0, 3.0, 4.5, 17.0, 100.0] {
+            let ratio = quantized(c) / c;
+            assert!((ratio.log2() * QUANTIZE_STEPS_PER_STOP).abs() <= 0.5, "{c}");
+        }
+        // Bright HDR colors don't all share one step
+        assert_ne!(key(4.5), key(9.0));
+        assert_ne!(key(9.0), key(40.0));
+        assert_eq!(key(1000.0), [[255; 3]; 6]);
+    }
+
+    #[test]
+    fn test_leaf_ambient() {
+        let mut index = Vec::new();
+        // The first leaf (solid) has no samples, the second has two
+        for (count, first) in [(0u16, 0u16), (2, 0)] {
+            index.extend(count.to_le_bytes());
+            index.extend(first.to_le_bytes());
+        }
+        let mut lighting = Vec::new();
+        for (scale, position) in [(0, [0, 0, 0]), (1, [255, 255, 255])] {
+            write_cube(&mut lighting, scale);
+            lighting.extend(position);
+            lighting.push(0);
+        }
+
+        let bounds = Bounds {
+            mins: [0.0; 3],
+            maxs: [100.0, 200.0, 50.0],
+        };
+        let ambient =
+            parse_leaf_ambient(&index, &lighting, |leaf| (leaf == 1).then_some(bounds)).unwrap();
+        assert!(ambient.leaf_samples(0).is_empty());
+        assert_eq!(ambient.sample_leaf(0, [0.0; 3]), None);
+        assert_eq!(ambient.sample_leaf(5, [0.0; 3]), None);
+
+        let samples = ambient.leaf_samples(1);
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].position, [0.0; 3]);
+        assert_eq!(samples[1].position, [100.0, 200.0, 50.0]);
+        // Source's +X is bevy's -Z, the last color
+        assert_eq!(samples[0].cube.colors[5], [1.0, 0.0, 0.0]);
+        assert_eq!(samples[1].cube.colors[5], [1.0, 0.0, 0.0]);
+        // Source's +Z is bevy's +Y, the fifth color of the source cube
+        assert_eq!(samples[1].cube.colors[2], [247.0 * (1.0 / 255.0), 0.0, 0.0]);
+
+        // Right on a sample, the other barely counts
+        let cube = ambient.sample_leaf(1, [0.0; 3]).unwrap();
+        assert!((cube.colors[2][0] - 1.0).abs() < 1e-4);
+        // Halfway between is the average
+        let cube = ambient.sample_leaf(1, [50.0, 100.0, 25.0]).unwrap();
+        let expected = (1.0 + 247.0 / 255.0) / 2.0;
+        assert!((cube.colors[2][0] - expected).abs() < 1e-5);
+
+        // Samples past the end of the lighting lump
+        let mut bad_index = index.clone();
+        bad_index.extend([4, 0, 1, 0]);
+        assert!(parse_leaf_ambient(&bad_index, &lighting, |_| Some(bounds)).is_err());
+    }
+
+    #[test]
+    fn test_leaf_v0() {
+        let mut leaf = Vec::new();
+        // Contents, cluster, area
+        leaf.extend([0; 8]);
+        for v in [-64i16, 0, 0, 64, 32, 128] {
+            leaf.extend(v.to_le_bytes());
+        }
+        leaf.resize(LEAF_V0_AMBIENT_OFFSET, 0);
+        write_cube(&mut leaf, 0);
+        leaf.resize(LEAF_V0_SIZE, 0);
+
+        let ambient = parse_leaf_v0(&leaf).unwrap();
+        assert_eq!(ambient.samples().len(), 1);
+        assert_eq!(ambient.leaf_samples(0)[0].position, [0.0, 16.0, 64.0]);
+        assert_eq!(ambient.leaf_samples(0)[0].cube.colors, [[1.0, 0.0, 0.0]; 6]);
+    }
+}
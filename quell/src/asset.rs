@@ -0,0 +1,367 @@
+//! Integration of the game's materials with Bevy's asset system.
+//! [`SourceAssetReader`] serves files out of the vpks and the map's pakfile, so that
+//! `asset_server.load("materials/foo.vmt")` works, and [`VmtLoader`]/[`VtfLoader`] turn them into
+//! materials and images.
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use bevy::{
+    asset::{
+        io::{AssetReader, AssetReaderError, AssetSource, PathStream, Reader, VecReader},
+        AssetLoader, AsyncReadExt, LoadContext,
+    },
+    log::warn,
+    prelude::{Handle, Image, Resource},
+    utils::BoxedFuture,
+};
+use serde::{Deserialize, Serialize};
+use vmt::{ShaderName, VMT};
+
+use crate::{
+    brush::BrushMaterial,
+    cache::{CachedImage, TextureRole},
+    data::{image_from_cached, VpkState},
+    map::GameMap,
+    material::{make_material, MaterialImages, MaterialParams},
+};
+
+/// How deep a chain of vmt `include`s can be before we give up, to avoid include cycles.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// A file found in the [`AssetMounts`]
+enum MountedFile {
+    /// A loose file on disk, which is read like any other file
+    Loose(PathBuf),
+    /// The contents of a file in a vpk or the map's pakfile
+    Data(Vec<u8>),
+}
+
+#[derive(Default)]
+struct Mounts {
+    vpk: Option<VpkState>,
+    map: Option<GameMap>,
+}
+
+/// The vpks and map that assets are read from.
+/// This is shared with the [`SourceAssetReader`], since the reader has to be created before the
+/// vpks are loaded.
+#[derive(Clone, Default, Resource)]
+pub struct AssetMounts(Arc<RwLock<Mounts>>);
+impl AssetMounts {
+    pub fn set_vpk(&self, vpk: VpkState) {
+        self.0.write().unwrap().vpk = Some(vpk);
+    }
+
+    pub fn set_map(&self, map: GameMap) {
+        self.0.write().unwrap().map = Some(map);
+    }
+
+    pub fn clear_map(&self) {
+        self.0.write().unwrap().map = None;
+    }
+
+    /// Whether the path is one that we would serve from the mounts, rather than the fallback.
+    fn handles(path: &Path) -> bool {
+        let Some(path) = path.to_str() else {
+            return false;
+        };
+        let path = path.to_lowercase();
+        path.starts_with("materials/") && (path.ends_with(".vmt") || path.ends_with(".vtf"))
+    }
+
+    /// Find the file at the path (ex: `materials/concrete/wall.vmt`) in the mounts, reading it if
+    /// it is in an archive.  
+    /// This follows the same precedence as the rest of the loading, vpks first and then the map.
+    /// Reading the archives blocks, so this should not be called on the async executor.
+    fn read(&self, path: &Path) -> Result<MountedFile, AssetReaderError> {
+        let not_found = || AssetReaderError::NotFound(path.to_path_buf());
+        if !AssetMounts::handles(path) {
+            return Err(not_found());
+        }
+
+        let name = path.to_str().ok_or_else(not_found)?.to_lowercase();
+        let mounts = self.0.read().unwrap();
+        // Until the vpks are loaded there's nothing to serve
+        let vpk = mounts.vpk.as_ref().ok_or_else(not_found)?;
+
+        let vpk_err = |err: vpk::Error| {
+            AssetReaderError::Io(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                err.to_string(),
+            ))
+        };
+
+        // Loose files take precedence, like in `data`
+        if let Some(path) = vpk.find_loose(&name) {
+            return Ok(MountedFile::Loose(path));
+        }
+
+        let data = if name.ends_with(".vmt") {
+            match vpk.find_vmt(&name) {
+                Some((entry, _)) => Some(entry.get().map_err(vpk_err)?.into_owned()),
+                None => mounts
+                    .map
+                    .as_ref()
+                    .and_then(|map| map.find_vmt(&name))
+                    .map(|(data, _)| data),
+            }
+        } else {
+            match vpk.find_texture(&name) {
+                Some((entry, _)) => Some(entry.get().map_err(vpk_err)?.into_owned()),
+                None => mounts
+                    .map
+                    .as_ref()
+                    .and_then(|map| map.get_texture_data(&name)),
+            }
+        };
+
+        data.map(MountedFile::Data).ok_or_else(not_found)
+    }
+}
+
+/// Reads materials out of the [`AssetMounts`], and everything else from the fallback (typically
+/// the `assets/` folder).
+pub struct SourceAssetReader {
+    mounts: AssetMounts,
+    fallback: Box<dyn AssetReader>,
+}
+impl SourceAssetReader {
+    pub fn new(mounts: AssetMounts, fallback: Box<dyn AssetReader>) -> SourceAssetReader {
+        SourceAssetReader { mounts, fallback }
+    }
+}
+impl AssetReader for SourceAssetReader {
+    fn read<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxedFuture<'a, Result<Box<Reader<'a>>, AssetReaderError>> {
+        Box::pin(async move {
+            if AssetMounts::handles(path) {
+                // Reading out of the archives blocks, so it is done on a thread meant for that
+                let mounts = self.mounts.clone();
+                let owned_path = path.to_path_buf();
+                let file = blocking::unblock(move || mounts.read(&owned_path)).await?;
+
+                let reader: Box<Reader<'a>> = match file {
+                    MountedFile::Loose(path) => Box::new(
+                        async_fs::File::open(path)
+                            .await
+                            .map_err(AssetReaderError::Io)?,
+                    ),
+                    MountedFile::Data(data) => Box::new(VecReader::new(data)),
+                };
+                Ok(reader)
+            } else {
+                self.fallback.read(path).await
+            }
+        })
+    }
+
+    fn read_meta<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxedFuture<'a, Result<Box<Reader<'a>>, AssetReaderError>> {
+        Box::pin(async move {
+            if AssetMounts::handles(path) {
+                // There are never meta files for the game's files, so the defaults are used
+                Err(AssetReaderError::NotFound(path.to_path_buf()))
+            } else {
+                self.fallback.read_meta(path).await
+            }
+        })
+    }
+
+    fn read_directory<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxedFuture<'a, Result<Box<PathStream>, AssetReaderError>> {
+        self.fallback.read_directory(path)
+    }
+
+    fn is_directory<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxedFuture<'a, Result<bool, AssetReaderError>> {
+        self.fallback.is_directory(path)
+    }
+}
+
+/// Create the asset source which reads from the mounts, falling back to the `assets` folder.
+/// This should be registered as the default source, before the `AssetPlugin` is added.
+pub fn source_asset_source(mounts: AssetMounts) -> bevy::asset::io::AssetSourceBuilder {
+    let mut fallback = AssetSource::get_default_reader("assets".to_string());
+    AssetSource::build()
+        .with_reader(move || Box::new(SourceAssetReader::new(mounts.clone(), fallback())))
+}
+
+/// The asset path of a material, ex: `concrete/wall` -> `materials/concrete/wall.vmt`
+pub fn material_path(name: &str) -> String {
+    let name = name.replace('\\', "/").to_lowercase();
+    let name = name.strip_prefix("materials/").unwrap_or(&name);
+    let name = name.strip_suffix(".vmt").unwrap_or(name);
+    format!("materials/{name}.vmt")
+}
+
+/// The asset path of a texture, ex: `concrete/wall` -> `materials/concrete/wall.vtf`
+pub fn texture_path(name: &str) -> String {
+    let name = name.replace('\\', "/").to_lowercase();
+    let name = name.strip_prefix("materials/").unwrap_or(&name);
+    let name = name.strip_suffix(".vtf").unwrap_or(name);
+    format!("materials/{name}.vtf")
+}
+
+/// Loads `.vmt` files as a [`BrushMaterial`].
+/// Included vmts are loader dependencies, so changing them reloads the material, and the base
+/// texture is loaded as a dependent asset.
+#[derive(Default)]
+pub struct VmtLoader;
+impl AssetLoader for VmtLoader {
+    type Asset = BrushMaterial;
+    type Settings = ();
+    type Error = eyre::Report;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<BrushMaterial, eyre::Report>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+
+            // Reading is async but resolving isn't, so we read the whole include chain first
+            let mut chain = vec![bytes];
+            loop {
+                let vmt = VMT::from_bytes(chain.last().unwrap())
+                    .map_err(|err| eyre::eyre!("Failed to parse vmt: {err:?}"))?;
+                let Some(include) = vmt.include.as_deref().map(material_path) else {
+                    break;
+                };
+
+                if chain.len() > MAX_INCLUDE_DEPTH {
+                    eyre::bail!("Too many nested includes in {:?}", load_context.path());
+                }
+
+                chain.push(load_context.read_asset_bytes(include).await?);
+            }
+
+            // Merge from the innermost include outwards
+            let mut vmt = VMT::from_bytes(chain.last().unwrap())
+                .map_err(|err| eyre::eyre!("Failed to parse vmt: {err:?}"))?;
+            for bytes in chain.iter().rev().skip(1) {
+                let outer = VMT::from_bytes(bytes)
+                    .map_err(|err| eyre::eyre!("Failed to parse vmt: {err:?}"))?;
+                vmt = outer
+                    .resolve(|_| Ok::<_, ()>(vmt))
+                    .map_err(|err| eyre::eyre!("Failed to resolve vmt: {err:?}"))?;
+            }
+
+            for (key, val) in vmt.invalid.iter() {
+                warn!(
+                    "Ignoring invalid {} {val:?} in {:?}",
+                    String::from_utf8_lossy(key),
+                    load_context.path()
+                );
+            }
+
+            let base_texture = match &vmt.shader_name {
+                // Water is drawn with its normal map, but usually has a texture for hammer
+                ShaderName::Water => vmt
+                    .base_texture
+                    .as_deref()
+                    .or_else(|| vmt.other.get(b"%tooltexture")),
+                _ => vmt.base_texture.as_deref(),
+            };
+            let Some(base_texture) = base_texture else {
+                eyre::bail!(
+                    "Could not find base texture in vmt {:?}",
+                    load_context.path()
+                );
+            };
+
+            let params = MaterialParams::from_vmt(&vmt);
+            // Textures which aren't colors have to be loaded as linear rather than sRGB
+            let mut load = |name: &str, role: TextureRole| {
+                load_context.load_with_settings(texture_path(name), move |s: &mut VtfSettings| {
+                    s.role = role;
+                })
+            };
+            let base_texture = load(base_texture, TextureRole::Base);
+            let images = MaterialImages {
+                base_texture,
+                normal_map: params
+                    .normal_map
+                    .as_deref()
+                    .map(|name| load(name, TextureRole::NormalMap)),
+                base_texture2: params
+                    .base_texture2
+                    .as_deref()
+                    .map(|name| load(name, TextureRole::Base)),
+                blend_modulate: params
+                    .blend_modulate
+                    .as_deref()
+                    .map(|name| load(name, TextureRole::BlendModulate)),
+                // Nor are they in a map with a sky or cubemaps to reflect
+                reflection: None,
+                envmap: None,
+                envmap_mask: None,
+            };
+
+            // These aren't tied to a map, so they use the default white image as their lightmap
+            let lightmap = Handle::default();
+
+            Ok(make_material(images, lightmap, &params))
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["vmt"]
+    }
+}
+
+/// Settings of the [`VtfLoader`]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct VtfSettings {
+    /// What the texture is used for, which decides the format it is loaded as.  
+    /// Note that Bevy keeps a single asset per path, so the first load of a vtf decides its role.
+    pub role: TextureRole,
+}
+
+/// Loads `.vtf` files as an [`Image`], using the highest resolution mip.
+#[derive(Default)]
+pub struct VtfLoader;
+impl AssetLoader for VtfLoader {
+    type Asset = Image;
+    type Settings = VtfSettings;
+    type Error = eyre::Report;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        settings: &'a VtfSettings,
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<Image, eyre::Report>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+
+            let tex = vtf::from_bytes(&bytes)?;
+            let image = tex.highres_image.decode(0)?.into_rgba8();
+            let (width, height) = image.dimensions();
+
+            Ok(image_from_cached(CachedImage {
+                width,
+                height,
+                format: settings.role.format(),
+                data: image.into_raw(),
+            }))
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["vtf"]
+    }
+}
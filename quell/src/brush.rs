@@ -0,0 +1,327 @@
+//! The material used for brush faces (the world geometry of the map).
+//! Like the game's `LightmappedGeneric`, brushes are lit entirely by their baked lightmap, which
+//! is in the map's lightmap atlas and indexed by the mesh's `UV_1`.
+//! Faces with bumped lightmaps blend between their three directional lightmaps by the normal
+//! map, which is the game's radiosity normal mapping.
+//! `WorldVertexTransition` materials blend to a second base texture by the mesh's vertex alpha.
+//! `Water` materials aren't lit, instead they refract what is behind them (the view's transmission
+//! texture) and reflect the sky through their scrolling normal map.
+//! Materials with an `$envmap` add the reflection of their cubemap on top of their lit color.
+use bevy::{
+    app::{App, Plugin},
+    asset::{embedded_asset, Asset, Handle},
+    math::{Vec2, Vec4},
+    pbr::{AlphaMode, Material, MaterialPipeline, MaterialPipelineKey, MaterialPlugin},
+    prelude::{Color, Image, Mesh},
+    reflect::TypePath,
+    render::{
+        mesh::{MeshVertexAttribute, MeshVertexBufferLayout},
+        render_asset::RenderAssets,
+        render_resource::{
+            AsBindGroup, AsBindGroupShaderType, DepthBiasState, Face, RenderPipelineDescriptor,
+            ShaderRef, ShaderType, SpecializedMeshPipelineError, VertexFormat,
+        },
+    },
+};
+
+use crate::{
+    material::{EnvMapParams, WaterParams},
+    mesh::SCALE,
+};
+
+const BRUSH_SHADER: &str = "embedded://quell/brush.wgsl";
+
+/// The offset in the lightmap atlas from a face's flat lightmap to each of its bumped lightmaps,
+/// see [`Lightmaps::bump_offset`](crate::lightmap::Lightmaps::bump_offset)
+pub const ATTRIBUTE_BUMP_LIGHTMAP_OFFSET: MeshVertexAttribute =
+    MeshVertexAttribute::new("BumpLightmapOffset", 971_203_118, VertexFormat::Float32);
+
+/// Pulls decals towards the camera, which is a larger depth since bevy uses reversed depth
+const DECAL_DEPTH_BIAS: DepthBiasState = DepthBiasState {
+    constant: 4,
+    slope_scale: 1.0,
+    clamp: 0.0,
+};
+
+// Keep in sync with the flags in `brush.wgsl`
+const FLAG_UNLIT: u32 = 1;
+const FLAG_SELF_ILLUM: u32 = 2;
+const FLAG_BASE_ALPHA_ENVMAP_MASK: u32 = 4;
+const FLAG_NORMAL_MAP_ALPHA_ENVMAP_MASK: u32 = 8;
+
+/// Adds the [`BrushMaterial`]
+pub struct BrushMaterialPlugin;
+impl Plugin for BrushMaterialPlugin {
+    fn build(&self, app: &mut App) {
+        embedded_asset!(app, "brush.wgsl");
+
+        app.add_plugins(MaterialPlugin::<BrushMaterial> {
+            // The shader has no prepass, and brushes don't cast dynamic shadows
+            prepass_enabled: false,
+            ..Default::default()
+        });
+    }
+}
+
+#[derive(Debug, Clone, Asset, TypePath, AsBindGroup)]
+#[bind_group_data(BrushMaterialKey)]
+#[uniform(0, BrushMaterialUniform)]
+pub struct BrushMaterial {
+    /// Multiplied with the base texture
+    pub base_color: Color,
+    #[texture(1)]
+    #[sampler(2)]
+    pub base_texture: Handle<Image>,
+    /// The map's lightmap atlas
+    #[texture(3)]
+    #[sampler(4)]
+    pub lightmap: Handle<Image>,
+    /// Only used by faces which have bumped lightmaps
+    #[texture(5)]
+    #[sampler(6)]
+    pub normal_map: Option<Handle<Image>>,
+    /// Blended to by the alpha of the mesh's vertex colors, ex: on displacements
+    #[texture(7)]
+    #[sampler(8)]
+    pub base_texture2: Option<Handle<Image>>,
+    /// Sharpens the blend to `base_texture2`. The green channel is the midpoint of the blend and
+    /// the red channel is its width.
+    #[texture(9)]
+    #[sampler(10)]
+    pub blend_modulate: Option<Handle<Image>>,
+    pub alpha_mode: AlphaMode,
+    /// `None` draws both sides
+    pub cull_mode: Option<Face>,
+    /// Ignore the lightmap, ex: `UnlitGeneric`
+    pub unlit: bool,
+    /// Whether the base texture's alpha masks parts of the material which ignore the lightmap
+    pub self_illum: bool,
+    /// Biases the depth so that the material draws on top of the coplanar faces it is projected
+    /// onto, ex: overlays and decals
+    pub decal: bool,
+    /// The cubemap reflected by water
+    #[texture(11, dimension = "cube")]
+    #[sampler(12)]
+    pub reflection: Option<Handle<Image>>,
+    /// Draws the material as water, which uses the normal map
+    pub water: Option<WaterParams>,
+    /// The cubemap reflected by the material, ex: the `env_cubemap` nearest to it
+    #[texture(13, dimension = "cube")]
+    #[sampler(14)]
+    pub envmap: Option<Handle<Image>>,
+    /// Masks the reflection by its color
+    #[texture(15)]
+    #[sampler(16)]
+    pub envmap_mask: Option<Handle<Image>>,
+    /// How the material reflects `envmap`
+    pub envmap_params: Option<EnvMapParams>,
+}
+impl Default for BrushMaterial {
+    fn default() -> Self {
+        BrushMaterial {
+            base_color: Color::WHITE,
+            base_texture: Handle::default(),
+            lightmap: Handle::default(),
+            normal_map: None,
+            base_texture2: None,
+            blend_modulate: None,
+            alpha_mode: AlphaMode::Opaque,
+            cull_mode: Some(Face::Back),
+            unlit: false,
+            self_illum: false,
+            decal: false,
+            reflection: None,
+            water: None,
+            envmap: None,
+            envmap_mask: None,
+            envmap_params: None,
+        }
+    }
+}
+impl Material for BrushMaterial {
+    fn vertex_shader() -> ShaderRef {
+        BRUSH_SHADER.into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        BRUSH_SHADER.into()
+    }
+
+    fn alpha_mode(&self) -> AlphaMode {
+        self.alpha_mode
+    }
+
+    fn reads_view_transmission_texture(&self) -> bool {
+        // Water refracts what is behind it
+        self.water.is_some()
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout,
+        key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        let mut attributes = vec![
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_UV_0.at_shader_location(1),
+            Mesh::ATTRIBUTE_UV_1.at_shader_location(2),
+            ATTRIBUTE_BUMP_LIGHTMAP_OFFSET.at_shader_location(3),
+        ];
+        // Only displacements have vertex colors, whose alpha is the blend to the second texture
+        let vertex_blend = layout.contains(Mesh::ATTRIBUTE_COLOR);
+        if vertex_blend {
+            attributes.push(Mesh::ATTRIBUTE_COLOR.at_shader_location(4));
+            descriptor.vertex.shader_defs.push("VERTEX_BLEND".into());
+        }
+        // The normal is only needed to reflect the cubemap
+        if key.bind_group_data.envmap {
+            attributes.push(Mesh::ATTRIBUTE_NORMAL.at_shader_location(5));
+            descriptor.vertex.shader_defs.push("ENVMAP".into());
+        }
+
+        let vertex_layout = layout.get_layout(&attributes)?;
+        descriptor.vertex.buffers = vec![vertex_layout];
+        descriptor.primitive.cull_mode = key.bind_group_data.cull_mode;
+        if key.bind_group_data.decal {
+            if let Some(depth_stencil) = descriptor.depth_stencil.as_mut() {
+                depth_stencil.bias = DECAL_DEPTH_BIAS;
+            }
+        }
+
+        if let Some(fragment) = descriptor.fragment.as_mut() {
+            if vertex_blend {
+                fragment.shader_defs.push("VERTEX_BLEND".into());
+            }
+            if key.bind_group_data.alpha_mask {
+                fragment.shader_defs.push("ALPHA_MASK".into());
+            }
+            if key.bind_group_data.additive {
+                fragment.shader_defs.push("ADDITIVE".into());
+            }
+            if key.bind_group_data.bumped {
+                fragment.shader_defs.push("BUMPED".into());
+            }
+            if key.bind_group_data.blend_texture {
+                fragment.shader_defs.push("BLEND_TEXTURE".into());
+            }
+            if key.bind_group_data.blend_modulate {
+                fragment.shader_defs.push("BLEND_MODULATE".into());
+            }
+            if key.bind_group_data.water {
+                fragment.shader_defs.push("WATER".into());
+            }
+            if key.bind_group_data.reflection {
+                fragment.shader_defs.push("REFLECTION".into());
+            }
+            if key.bind_group_data.envmap {
+                fragment.shader_defs.push("ENVMAP".into());
+            }
+            if key.bind_group_data.envmap_mask {
+                fragment.shader_defs.push("ENVMAP_MASK".into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The parts of the material which need a different pipeline
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BrushMaterialKey {
+    cull_mode: Option<Face>,
+    alpha_mask: bool,
+    additive: bool,
+    bumped: bool,
+    blend_texture: bool,
+    blend_modulate: bool,
+    decal: bool,
+    water: bool,
+    reflection: bool,
+    envmap: bool,
+    envmap_mask: bool,
+}
+impl From<&BrushMaterial> for BrushMaterialKey {
+    fn from(material: &BrushMaterial) -> Self {
+        BrushMaterialKey {
+            cull_mode: material.cull_mode,
+            alpha_mask: matches!(material.alpha_mode, AlphaMode::Mask(_)),
+            additive: material.alpha_mode == AlphaMode::Add,
+            bumped: material.normal_map.is_some(),
+            blend_texture: material.base_texture2.is_some(),
+            blend_modulate: material.base_texture2.is_some() && material.blend_modulate.is_some(),
+            decal: material.decal,
+            water: material.water.is_some(),
+            reflection: material.reflection.is_some(),
+            envmap: material.envmap.is_some(),
+            envmap_mask: material.envmap.is_some() && material.envmap_mask.is_some(),
+        }
+    }
+}
+
+/// The uniform data of a [`BrushMaterial`], matching the struct in `brush.wgsl`
+#[derive(Debug, Clone, Default, ShaderType)]
+pub struct BrushMaterialUniform {
+    pub base_color: Vec4,
+    pub alpha_cutoff: f32,
+    pub flags: u32,
+    // Only used by water, see `WaterParams`
+    pub fog_color: Vec4,
+    pub reflect_tint: Vec4,
+    pub refract_tint: Vec4,
+    pub scroll: Vec2,
+    /// In bevy units
+    pub fog_start: f32,
+    pub fog_end: f32,
+    pub reflect_amount: f32,
+    pub refract_amount: f32,
+    /// `$envmaptint`
+    pub envmap_tint: Vec4,
+}
+impl AsBindGroupShaderType<BrushMaterialUniform> for BrushMaterial {
+    fn as_bind_group_shader_type(&self, _images: &RenderAssets<Image>) -> BrushMaterialUniform {
+        let alpha_cutoff = match self.alpha_mode {
+            AlphaMode::Mask(cutoff) => cutoff,
+            _ => 0.5,
+        };
+
+        let mut flags = 0;
+        if self.unlit {
+            flags |= FLAG_UNLIT;
+        }
+        if self.self_illum {
+            flags |= FLAG_SELF_ILLUM;
+        }
+        if let Some(envmap) = &self.envmap_params {
+            if envmap.base_alpha_mask {
+                flags |= FLAG_BASE_ALPHA_ENVMAP_MASK;
+            }
+            if envmap.normal_map_alpha_mask {
+                flags |= FLAG_NORMAL_MAP_ALPHA_ENVMAP_MASK;
+            }
+        }
+
+        let mut uniform = BrushMaterialUniform {
+            base_color: self.base_color.as_linear_rgba_f32().into(),
+            alpha_cutoff,
+            flags,
+            ..Default::default()
+        };
+        let color = |[r, g, b]: [f32; 3]| Color::rgb(r, g, b).as_linear_rgba_f32().into();
+        if let Some(envmap) = &self.envmap_params {
+            uniform.envmap_tint = color(envmap.tint);
+        }
+        if let Some(water) = &self.water {
+            uniform.fog_color = color(water.fog_color);
+            uniform.reflect_tint = color(water.reflect_tint);
+            uniform.refract_tint = color(water.refract_tint);
+            uniform.scroll = water.scroll.into();
+            uniform.fog_start = water.fog_start * SCALE;
+            uniform.fog_end = water.fog_end * SCALE;
+            uniform.reflect_amount = water.reflect_amount;
+            uniform.refract_amount = water.refract_amount;
+        }
+
+        uniform
+    }
+}
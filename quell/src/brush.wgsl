@@ -0,0 +1,262 @@
+// Shader for brush faces, which are lit by their lightmap rather than by dynamic lights.
+#import bevy_pbr::mesh_functions::{
+    get_model_matrix, mesh_normal_local_to_world, mesh_position_local_to_clip,
+    mesh_position_local_to_world
+}
+#import bevy_pbr::mesh_view_bindings::{
+    globals, view, view_transmission_texture, view_transmission_sampler
+}
+
+struct BrushMaterial {
+    base_color: vec4<f32>,
+    alpha_cutoff: f32,
+    flags: u32,
+    // Only used by water
+    fog_color: vec4<f32>,
+    reflect_tint: vec4<f32>,
+    refract_tint: vec4<f32>,
+    scroll: vec2<f32>,
+    fog_start: f32,
+    fog_end: f32,
+    reflect_amount: f32,
+    refract_amount: f32,
+    envmap_tint: vec4<f32>,
+};
+
+// Keep in sync with the flags in `brush.rs`
+const FLAG_UNLIT: u32 = 1u;
+const FLAG_SELF_ILLUM: u32 = 2u;
+const FLAG_BASE_ALPHA_ENVMAP_MASK: u32 = 4u;
+const FLAG_NORMAL_MAP_ALPHA_ENVMAP_MASK: u32 = 8u;
+
+@group(1) @binding(0) var<uniform> material: BrushMaterial;
+@group(1) @binding(1) var base_texture: texture_2d<f32>;
+@group(1) @binding(2) var base_sampler: sampler;
+@group(1) @binding(3) var lightmap_texture: texture_2d<f32>;
+@group(1) @binding(4) var lightmap_sampler: sampler;
+@group(1) @binding(5) var normal_map: texture_2d<f32>;
+@group(1) @binding(6) var normal_map_sampler: sampler;
+@group(1) @binding(7) var base_texture_2: texture_2d<f32>;
+@group(1) @binding(8) var base_sampler_2: sampler;
+@group(1) @binding(9) var blend_modulate_texture: texture_2d<f32>;
+@group(1) @binding(10) var blend_modulate_sampler: sampler;
+@group(1) @binding(11) var reflection_texture: texture_cube<f32>;
+@group(1) @binding(12) var reflection_sampler: sampler;
+@group(1) @binding(13) var envmap_texture: texture_cube<f32>;
+@group(1) @binding(14) var envmap_sampler: sampler;
+@group(1) @binding(15) var envmap_mask_texture: texture_2d<f32>;
+@group(1) @binding(16) var envmap_mask_sampler: sampler;
+
+// The directions that the bumped lightmaps are lit from, in tangent space
+const BUMP_BASIS_0: vec3<f32> = vec3<f32>(0.81649658, 0.0, 0.57735027);
+const BUMP_BASIS_1: vec3<f32> = vec3<f32>(-0.40824830, 0.70710678, 0.57735027);
+const BUMP_BASIS_2: vec3<f32> = vec3<f32>(-0.40824830, -0.70710678, 0.57735027);
+
+// How far across the screen water's `$refractamount` offsets what is seen through it
+const REFRACT_SCALE: f32 = 0.05;
+
+struct Vertex {
+    @builtin(instance_index) instance_index: u32,
+    @location(0) position: vec3<f32>,
+    @location(1) uv: vec2<f32>,
+    @location(2) lightmap_uv: vec2<f32>,
+    @location(3) bump_offset: f32,
+#ifdef VERTEX_BLEND
+    @location(4) color: vec4<f32>,
+#endif
+#ifdef ENVMAP
+    @location(5) normal: vec3<f32>,
+#endif
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) lightmap_uv: vec2<f32>,
+    @location(2) bump_offset: f32,
+    // How much of the second base texture is used
+    @location(3) blend: f32,
+    @location(4) world_position: vec3<f32>,
+#ifdef ENVMAP
+    @location(5) world_normal: vec3<f32>,
+#endif
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+    let model = get_model_matrix(vertex.instance_index);
+    out.clip_position = mesh_position_local_to_clip(model, vec4<f32>(vertex.position, 1.0));
+    out.world_position = mesh_position_local_to_world(model, vec4<f32>(vertex.position, 1.0)).xyz;
+    out.uv = vertex.uv;
+    out.lightmap_uv = vertex.lightmap_uv;
+    out.bump_offset = vertex.bump_offset;
+#ifdef VERTEX_BLEND
+    out.blend = vertex.color.a;
+#else
+    out.blend = 0.0;
+#endif
+#ifdef ENVMAP
+    out.world_normal = mesh_normal_local_to_world(vertex.normal, vertex.instance_index);
+#endif
+    return out;
+}
+
+#ifdef ENVMAP
+// The cubemap reflected off of the surface, tinted by `$envmaptint`
+fn envmap_reflection(in: VertexOutput) -> vec3<f32> {
+    let view_dir = normalize(view.world_position - in.world_position);
+    let dir = reflect(-view_dir, normalize(in.world_normal));
+    return textureSample(envmap_texture, envmap_sampler, dir).rgb * material.envmap_tint.rgb;
+}
+#endif
+
+#ifdef WATER
+// Water refracts what is behind it, fogged by the distance to the surface, and reflects the sky.
+// Without the depth of what is under the surface, this is like the game's cheap water which fogs
+// by distance rather than by depth.
+fn water(in: VertexOutput) -> vec4<f32> {
+    // Two layers of the normal map scrolling across each other
+    let scroll = material.scroll * globals.time;
+    let normal_1 = textureSample(normal_map, normal_map_sampler, in.uv + scroll).xyz;
+    let normal_2 = textureSample(normal_map, normal_map_sampler, in.uv * 0.7 - scroll.yx).xyz;
+    let tangent_normal = normalize(normal_1 + normal_2 - 1.0);
+    // Water is always horizontal, so its tangent space is the world's
+    let normal = normalize(vec3<f32>(tangent_normal.x, tangent_normal.z, -tangent_normal.y));
+
+    let to_camera = view.world_position - in.world_position;
+    let eye_distance = length(to_camera);
+    let view_dir = to_camera / eye_distance;
+
+    let screen_uv = (in.clip_position.xy - view.viewport.xy) / view.viewport.zw;
+    let refract_uv = screen_uv + tangent_normal.xy * material.refract_amount * REFRACT_SCALE;
+    let behind = textureSampleLevel(
+        view_transmission_texture,
+        view_transmission_sampler,
+        clamp(refract_uv, vec2<f32>(0.0), vec2<f32>(1.0)),
+        0.0,
+    ).rgb;
+    var color = behind * material.refract_tint.rgb;
+    if material.fog_end > material.fog_start {
+        let fog_range = material.fog_end - material.fog_start;
+        let fog = saturate((eye_distance - material.fog_start) / fog_range);
+        color = mix(color, material.fog_color.rgb, fog);
+    }
+
+    let up = vec3<f32>(0.0, 1.0, 0.0);
+    let reflect_normal = normalize(mix(up, normal, material.reflect_amount));
+#ifdef REFLECTION
+    let reflected = textureSample(
+        reflection_texture,
+        reflection_sampler,
+        reflect(-view_dir, reflect_normal),
+    ).rgb;
+#else
+    // Without a sky there's nothing to reflect, so it looks like the deep water
+    let reflected = material.fog_color.rgb;
+#endif
+
+    // Schlick's approximation, with the reflectance of water
+    let fresnel = 0.02 + 0.98 * pow(1.0 - saturate(dot(view_dir, reflect_normal)), 5.0);
+    color = mix(color, reflected * material.reflect_tint.rgb, fresnel);
+
+    return vec4<f32>(color, 1.0);
+}
+#endif
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+#ifdef WATER
+    return water(in);
+#else
+    var base = textureSample(base_texture, base_sampler, in.uv);
+
+#ifdef BLEND_TEXTURE
+    let base_2 = textureSample(base_texture_2, base_sampler_2, in.uv);
+    var blend = in.blend;
+#ifdef BLEND_MODULATE
+    // Like WorldVertexTransition, the modulate texture narrows where the blend happens
+    let modulate = textureSample(blend_modulate_texture, blend_modulate_sampler, in.uv);
+    let min_blend = saturate(modulate.g - modulate.r);
+    let max_blend = max(saturate(modulate.g + modulate.r), min_blend + 0.001);
+    blend = smoothstep(min_blend, max_blend, blend);
+#endif
+    base = mix(base, base_2, blend);
+#endif
+
+    let albedo = material.base_color * base;
+    var light = textureSample(lightmap_texture, lightmap_sampler, in.lightmap_uv).rgb;
+
+#ifdef BUMPED
+    // Sampled even for faces without bumped lightmaps, since sampling has to be in uniform
+    // control flow
+    let normal_texel = textureSample(normal_map, normal_map_sampler, in.uv);
+    let normal = normalize(normal_texel.xyz * 2.0 - 1.0);
+    let offset = vec2<f32>(in.bump_offset, 0.0);
+    let uv_1 = in.lightmap_uv + offset;
+    let uv_2 = in.lightmap_uv + offset * 2.0;
+    let uv_3 = in.lightmap_uv + offset * 3.0;
+    let light_1 = textureSample(lightmap_texture, lightmap_sampler, uv_1).rgb;
+    let light_2 = textureSample(lightmap_texture, lightmap_sampler, uv_2).rgb;
+    let light_3 = textureSample(lightmap_texture, lightmap_sampler, uv_3).rgb;
+
+    if in.bump_offset > 0.0 {
+        // The normal map is in the same texture space as the bump basis, so it can be used as is
+        var dp = vec3<f32>(
+            saturate(dot(normal, BUMP_BASIS_0)),
+            saturate(dot(normal, BUMP_BASIS_1)),
+            saturate(dot(normal, BUMP_BASIS_2)),
+        );
+        dp *= dp;
+        let sum = dp.x + dp.y + dp.z;
+        if sum > 0.0 {
+            light = (dp.x * light_1 + dp.y * light_2 + dp.z * light_3) / sum;
+        }
+    }
+#endif
+
+#ifdef ENVMAP
+    var envmap = envmap_reflection(in);
+#ifdef ENVMAP_MASK
+    envmap *= textureSample(envmap_mask_texture, envmap_mask_sampler, in.uv).rgb;
+#endif
+    if (material.flags & FLAG_BASE_ALPHA_ENVMAP_MASK) != 0u {
+        // Inverted, like the game's `LightmappedGeneric`
+        envmap *= 1.0 - base.a;
+    }
+#ifdef BUMPED
+    if (material.flags & FLAG_NORMAL_MAP_ALPHA_ENVMAP_MASK) != 0u {
+        envmap *= normal_texel.a;
+    }
+#endif
+#endif
+
+#ifdef ALPHA_MASK
+    if albedo.a < material.alpha_cutoff {
+        discard;
+    }
+#endif
+
+    if (material.flags & FLAG_UNLIT) != 0u {
+        light = vec3<f32>(1.0);
+    }
+
+    var color = albedo.rgb * light;
+    var alpha = albedo.a;
+    if (material.flags & FLAG_SELF_ILLUM) != 0u {
+        // The base texture's alpha is the mask of what glows
+        color = mix(color, albedo.rgb, albedo.a);
+        alpha = 1.0;
+    }
+#ifdef ENVMAP
+    color += envmap;
+#endif
+
+#ifdef ADDITIVE
+    // Blended with premultiplied alpha, so no alpha means it is purely added
+    return vec4<f32>(color * alpha, 0.0);
+#else
+    return vec4<f32>(color, alpha);
+#endif
+#endif
+}
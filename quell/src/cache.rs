@@ -0,0 +1,523 @@
+//! On-disk cache of converted textures.
+//! Decoding vtfs out of the vpks dominates startup time, so we store the decoded GPU-ready data
+//! on disk and read that back directly on later runs.
+use std::{
+    fs::{File, Metadata},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+
+use bevy::render::render_resource::TextureFormat;
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever the layout of the cache files, or the way we convert textures, changes.
+/// Entries with a different version are treated as stale.
+pub const CACHE_VERSION: u32 = 2;
+
+const MAGIC: &[u8; 4] = b"QTEX";
+
+/// What a texture is used for.
+/// The same vtf may be converted differently depending on its role, so this is part of the key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum TextureRole {
+    /// Albedo texture, ex: `$basetexture`
+    #[default]
+    Base = 0,
+    /// Normal map, ex: `$bumpmap`
+    NormalMap = 1,
+    /// Blend control of `WorldVertexTransition`, ex: `$blendmodulatetexture`
+    BlendModulate = 2,
+    /// Masks the reflection of a cubemap, ex: `$envmapmask`
+    EnvMapMask = 3,
+}
+impl TextureRole {
+    /// The format that textures with this role are converted to
+    pub fn format(self) -> TextureFormat {
+        match self {
+            TextureRole::Base => TextureFormat::Rgba8UnormSrgb,
+            // Normal maps hold directions, not colors, so they must not be treated as srgb
+            TextureRole::NormalMap | TextureRole::BlendModulate | TextureRole::EnvMapMask => {
+                TextureFormat::Rgba8Unorm
+            }
+        }
+    }
+}
+
+/// Identifies the version of the source data that a cached texture was converted from.
+/// If this differs from what is stored then the entry is stale.  
+/// Both the modification time and the size are used, since some filesystems only store the time
+/// to the second (or coarser), and a loose file may be saved several times within that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SourceStamp {
+    /// Modification time of the file, in nanoseconds since the unix epoch
+    pub modified: u64,
+    /// Size of the file in bytes
+    pub len: u64,
+}
+impl SourceStamp {
+    /// Create a stamp for the file the texture is stored in.
+    /// Ex: the dir vpk, the bsp for pakfile textures, or the loose vtf itself.
+    pub fn new(modified: SystemTime, len: u64) -> SourceStamp {
+        let modified = modified
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        SourceStamp { modified, len }
+    }
+
+    pub fn from_metadata(meta: &Metadata) -> Option<SourceStamp> {
+        Some(SourceStamp::new(meta.modified().ok()?, meta.len()))
+    }
+}
+
+/// A decoded texture as it is stored in the cache.
+#[derive(Debug, Clone)]
+pub struct CachedImage {
+    pub width: u32,
+    pub height: u32,
+    pub format: TextureFormat,
+    pub data: Vec<u8>,
+}
+
+/// Persistent texture cache stored in a directory.
+/// Each entry is a single file, named by a hash of the texture name and role.
+/// The cache is safe to use from multiple threads at once.
+pub struct TextureCache {
+    dir: PathBuf,
+    /// The maximum size in bytes that the cache directory should take up.
+    max_size: u64,
+    /// Approximate current size of the cache directory.
+    size: AtomicU64,
+}
+impl TextureCache {
+    /// Open (or create) the cache at `dir`, trimming it down to `max_size` bytes if needed.
+    pub fn new(dir: impl AsRef<Path>, max_size: u64) -> std::io::Result<TextureCache> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+
+        let cache = TextureCache {
+            dir,
+            max_size,
+            size: AtomicU64::new(0),
+        };
+        cache.trim()?;
+
+        Ok(cache)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn entry_path(&self, name: &str, role: TextureRole) -> PathBuf {
+        let hash = fnv1a(name.to_lowercase().as_bytes()) ^ (role as u64);
+        self.dir.join(format!("{hash:016x}.qtex"))
+    }
+
+    /// Get the cached texture, if it exists and is not stale.
+    /// Stale or unreadable entries are removed.
+    pub fn get(&self, name: &str, role: TextureRole, stamp: SourceStamp) -> Option<CachedImage> {
+        let path = self.entry_path(name, role);
+        let file = File::open(&path).ok()?;
+
+        match read_entry(BufReader::new(&file), name, role, stamp) {
+            Ok(Some(image)) => {
+                // Touch the file so that trimming evicts the least recently used entries first
+                let _ = file.set_modified(SystemTime::now());
+                Some(image)
+            }
+            Ok(None) | Err(_) => {
+                drop(file);
+                self.remove(&path);
+                None
+            }
+        }
+    }
+
+    /// Store a converted texture in the cache.
+    pub fn insert(
+        &self,
+        name: &str,
+        role: TextureRole,
+        stamp: SourceStamp,
+        image: &CachedImage,
+    ) -> std::io::Result<()> {
+        let path = self.entry_path(name, role);
+        // We write to a temporary file first so that other threads (or a crash) never observe a
+        // partially written entry.
+        let tmp_path = path.with_extension(format!("tmp{:?}", std::thread::current().id()));
+        {
+            let file = File::create(&tmp_path)?;
+            let mut writer = BufWriter::new(file);
+            write_entry(&mut writer, name, role, stamp, image)?;
+            writer.flush()?;
+        }
+
+        let prev_len = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        std::fs::rename(&tmp_path, &path)?;
+        let len = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+
+        self.size.fetch_add(len, Ordering::SeqCst);
+        self.sub_size(prev_len);
+
+        if self.size.load(Ordering::SeqCst) > self.max_size {
+            self.trim()?;
+        }
+
+        Ok(())
+    }
+
+    /// Evict the least recently used entries until the cache is below its size cap.
+    pub fn trim(&self) -> std::io::Result<()> {
+        let mut entries = Vec::new();
+        let mut total = 0;
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if !meta.is_file() {
+                continue;
+            }
+
+            let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            total += meta.len();
+            entries.push((modified, meta.len(), entry.path()));
+        }
+
+        if total > self.max_size {
+            // Oldest first
+            entries.sort_unstable_by_key(|(modified, _, _)| *modified);
+            for (_, len, path) in entries {
+                if total <= self.max_size {
+                    break;
+                }
+
+                if std::fs::remove_file(&path).is_ok() {
+                    total -= len;
+                }
+            }
+        }
+
+        self.size.store(total, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// Remove every entry in the cache.
+    pub fn clear(&self) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                std::fs::remove_file(path)?;
+            }
+        }
+
+        self.size.store(0, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) {
+        if let Ok(meta) = std::fs::metadata(path) {
+            if std::fs::remove_file(path).is_ok() {
+                self.sub_size(meta.len());
+            }
+        }
+    }
+
+    fn sub_size(&self, len: u64) {
+        let _ = self
+            .size
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |size| {
+                Some(size.saturating_sub(len))
+            });
+    }
+}
+
+fn format_tag(format: TextureFormat) -> Option<u8> {
+    match format {
+        TextureFormat::Rgba8UnormSrgb => Some(0),
+        TextureFormat::Rgba8Unorm => Some(1),
+        _ => None,
+    }
+}
+
+fn tag_format(tag: u8) -> Option<TextureFormat> {
+    match tag {
+        0 => Some(TextureFormat::Rgba8UnormSrgb),
+        1 => Some(TextureFormat::Rgba8Unorm),
+        _ => None,
+    }
+}
+
+fn write_entry(
+    w: &mut impl Write,
+    name: &str,
+    role: TextureRole,
+    stamp: SourceStamp,
+    image: &CachedImage,
+) -> std::io::Result<()> {
+    let format = format_tag(image.format).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Unsupported texture format for cache: {:?}", image.format),
+        )
+    })?;
+    let name = name.to_lowercase();
+
+    w.write_all(MAGIC)?;
+    w.write_all(&CACHE_VERSION.to_le_bytes())?;
+    w.write_all(&[role as u8, format])?;
+    w.write_all(&stamp.modified.to_le_bytes())?;
+    w.write_all(&stamp.len.to_le_bytes())?;
+    w.write_all(&(name.len() as u32).to_le_bytes())?;
+    w.write_all(name.as_bytes())?;
+    w.write_all(&image.width.to_le_bytes())?;
+    w.write_all(&image.height.to_le_bytes())?;
+    w.write_all(&(image.data.len() as u64).to_le_bytes())?;
+    w.write_all(&image.data)?;
+
+    Ok(())
+}
+
+/// Read an entry, returning `None` if it does not match what we're looking for.
+fn read_entry(
+    mut r: impl Read,
+    name: &str,
+    role: TextureRole,
+    stamp: SourceStamp,
+) -> std::io::Result<Option<CachedImage>> {
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u32(&mut r)? != CACHE_VERSION {
+        return Ok(None);
+    }
+
+    let mut tags = [0; 2];
+    r.read_exact(&mut tags)?;
+    let stored_stamp = SourceStamp {
+        modified: read_u64(&mut r)?,
+        len: read_u64(&mut r)?,
+    };
+    if tags[0] != role as u8 || stored_stamp != stamp {
+        return Ok(None);
+    }
+    let Some(format) = tag_format(tags[1]) else {
+        return Ok(None);
+    };
+
+    // The name is stored to guard against hash collisions
+    let name_len = read_u32(&mut r)? as usize;
+    let mut stored_name = vec![0; name_len];
+    r.read_exact(&mut stored_name)?;
+    if !stored_name.eq_ignore_ascii_case(name.as_bytes()) {
+        return Ok(None);
+    }
+
+    let width = read_u32(&mut r)?;
+    let height = read_u32(&mut r)?;
+    let data_len = read_u64(&mut r)? as usize;
+    if data_len != width as usize * height as usize * 4 {
+        return Ok(None);
+    }
+
+    let mut data = vec![0; data_len];
+    r.read_exact(&mut data)?;
+
+    Ok(Some(CachedImage {
+        width,
+        height,
+        format,
+        data,
+    }))
+}
+
+fn read_u32(r: &mut impl Read) -> std::io::Result<u32> {
+    let mut b = [0; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_u64(r: &mut impl Read) -> std::io::Result<u64> {
+    let mut b = [0; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+/// FNV-1a, used since we need a hash that is stable between runs (and compiler versions).
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// A fresh cache directory for a test, removed when dropped
+    struct TestDir(PathBuf);
+    impl TestDir {
+        fn new(name: &str) -> TestDir {
+            let dir = std::env::temp_dir()
+                .join(format!("quell-cache-test-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            TestDir(dir)
+        }
+    }
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn image(fill: u8) -> CachedImage {
+        CachedImage {
+            width: 2,
+            height: 2,
+            format: TextureFormat::Rgba8UnormSrgb,
+            data: vec![fill; 2 * 2 * 4],
+        }
+    }
+
+    fn stamp(modified: u64) -> SourceStamp {
+        SourceStamp { modified, len: 64 }
+    }
+
+    /// Make the entry look like it was last used `secs` seconds ago
+    fn age(cache: &TextureCache, name: &str, role: TextureRole, secs: u64) {
+        let file = File::options()
+            .write(true)
+            .open(cache.entry_path(name, role))
+            .unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_insert_get() {
+        let dir = TestDir::new("insert");
+        let cache = TextureCache::new(&dir.0, u64::MAX).unwrap();
+        let stamp = stamp(1);
+
+        assert!(cache
+            .get("brick/brickwall001", TextureRole::Base, stamp)
+            .is_none());
+
+        cache
+            .insert("brick/brickwall001", TextureRole::Base, stamp, &image(7))
+            .unwrap();
+        let found = cache
+            .get("Brick/BrickWall001", TextureRole::Base, stamp)
+            .unwrap();
+        assert_eq!((found.width, found.height), (2, 2));
+        assert_eq!(found.format, TextureFormat::Rgba8UnormSrgb);
+        assert_eq!(found.data, image(7).data);
+
+        // The role and name are part of the key
+        assert!(cache
+            .get("brick/brickwall001", TextureRole::NormalMap, stamp)
+            .is_none());
+        assert!(cache
+            .get("brick/brickwall002", TextureRole::Base, stamp)
+            .is_none());
+
+        // Inserting again replaces the entry
+        cache
+            .insert("brick/brickwall001", TextureRole::Base, stamp, &image(9))
+            .unwrap();
+        let found = cache.get("brick/brickwall001", TextureRole::Base, stamp);
+        assert_eq!(found.unwrap().data, image(9).data);
+
+        cache.clear().unwrap();
+        assert!(cache
+            .get("brick/brickwall001", TextureRole::Base, stamp)
+            .is_none());
+    }
+
+    #[test]
+    fn test_stale_stamp() {
+        let dir = TestDir::new("stamp");
+        let cache = TextureCache::new(&dir.0, u64::MAX).unwrap();
+        let path = cache.entry_path("metal/metalfloor001a", TextureRole::Base);
+
+        cache
+            .insert(
+                "metal/metalfloor001a",
+                TextureRole::Base,
+                stamp(1),
+                &image(1),
+            )
+            .unwrap();
+        assert!(path.exists());
+
+        // The source changed, so the entry is stale and removed
+        assert!(cache
+            .get("metal/metalfloor001a", TextureRole::Base, stamp(2))
+            .is_none());
+        assert!(!path.exists());
+        assert!(cache
+            .get("metal/metalfloor001a", TextureRole::Base, stamp(1))
+            .is_none());
+    }
+
+    #[test]
+    fn test_stamp_precision() {
+        // Saving a loose file twice within the same second still changes its stamp
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let a = SourceStamp::new(time, 1024);
+        let b = SourceStamp::new(time + Duration::from_millis(200), 1024);
+        assert_ne!(a, b);
+        assert_eq!(a, SourceStamp::new(time, 1024));
+
+        // As does changing its size, even if the time is the same
+        assert_ne!(a, SourceStamp::new(time, 2048));
+    }
+
+    #[test]
+    fn test_trim_evicts_least_recently_used() {
+        let dir = TestDir::new("trim");
+        let stamp = stamp(1);
+
+        let cache = TextureCache::new(&dir.0, u64::MAX).unwrap();
+        cache
+            .insert("a", TextureRole::Base, stamp, &image(1))
+            .unwrap();
+        let entry_size = std::fs::metadata(cache.entry_path("a", TextureRole::Base))
+            .unwrap()
+            .len();
+
+        // Room for two entries
+        let cache = TextureCache::new(&dir.0, entry_size * 2 + entry_size / 2).unwrap();
+        cache
+            .insert("b", TextureRole::Base, stamp, &image(2))
+            .unwrap();
+        age(&cache, "a", TextureRole::Base, 200);
+        age(&cache, "b", TextureRole::Base, 100);
+
+        // Using `a` makes it the most recently used, so `b` is evicted instead
+        assert!(cache.get("a", TextureRole::Base, stamp).is_some());
+        cache
+            .insert("c", TextureRole::Base, stamp, &image(3))
+            .unwrap();
+
+        assert!(cache.get("a", TextureRole::Base, stamp).is_some());
+        assert!(cache.get("b", TextureRole::Base, stamp).is_none());
+        assert!(cache.get("c", TextureRole::Base, stamp).is_some());
+
+        // Reopening with a smaller cap trims down to it
+        let cache = TextureCache::new(&dir.0, entry_size).unwrap();
+        let remaining = ["a", "c"]
+            .iter()
+            .filter(|name| cache.get(name, TextureRole::Base, stamp).is_some())
+            .count();
+        assert_eq!(remaining, 1);
+    }
+}
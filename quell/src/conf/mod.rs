@@ -28,6 +28,25 @@ pub struct RenderConfig {
     /// `r_lockpvs`
     /// Prevents PVS from being recalculated.
     pub lock_pvs: bool,
+    /// `r_drawstaticprops`
+    // TODO: listen for when this changes
+    #[derivative(Default(value = "true"))]
+    pub draw_static_props: bool,
+    /// `r_drawdetailprops`
+    // TODO: listen for when this changes
+    #[derivative(Default(value = "true"))]
+    pub draw_detail_props: bool,
+    /// `cl_detaildist`
+    /// Distance from the camera at which detail props stop being drawn, in source units.
+    #[derivative(Default(value = "1200.0"))]
+    pub detail_dist: f32,
+    /// `cl_detailfade`
+    /// Distance over which detail props fade out before `detail_dist`.
+    #[derivative(Default(value = "400.0"))]
+    pub detail_fade: f32,
+    /// `showtriggers_toggle`
+    /// Draws trigger brushes, which are normally invisible, as translucent boxes.
+    pub show_triggers: bool,
     pub mat: MatRenderConfig,
 
     // engine specific configuration
@@ -37,6 +56,45 @@ pub struct RenderConfig {
     pub draw_map: bool,
     #[derivative(Default(value = "true"))]
     pub draw_lights: bool,
+    /// How the faces of the map are merged into meshes when it is loaded
+    pub face_batching: FaceBatching,
+    /// Color the faces of the map by the vis cluster they're in, instead of their material
+    pub cluster_colors: bool,
+    /// Draw the bounds of the clusters in the PVS that the map is being culled with
+    pub draw_pvs: bool,
+    /// Draw the planes splitting the bsp nodes on the way down to the camera's leaf
+    pub draw_node_planes: bool,
+    /// `r_visambient`
+    /// Draw the ambient lighting samples of the leaves near the cameras, as a line towards each
+    /// direction colored by the light from it.
+    pub draw_ambient_cubes: bool,
+}
+impl RenderConfig {
+    /// The batching to merge the faces of the map with when it is loaded.
+    /// `cluster_colors` needs each batch to be in a single cluster, so merging by material alone
+    /// is also split by cluster while it is enabled.
+    pub fn load_face_batching(&self) -> FaceBatching {
+        match self.face_batching {
+            FaceBatching::Material if self.cluster_colors => FaceBatching::MaterialCluster,
+            batching => batching,
+        }
+    }
+}
+
+/// Which faces of the map are merged together into one mesh (and entity).
+/// Fewer meshes means far fewer draw calls, but culling is less precise. The default keeps
+/// culling by the PVS working, at the cost of some more draw calls than [`FaceBatching::Material`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FaceBatching {
+    /// Every face is its own mesh
+    Off,
+    /// Faces sharing a material are merged.
+    /// A mesh is only hidden when none of its clusters are visible, so most of the map is drawn.
+    Material,
+    /// Faces sharing a material and vis cluster are merged, so that they can still be culled by
+    /// the PVS
+    #[default]
+    MaterialCluster,
 }
 
 #[derive(Debug, Default, Clone)]
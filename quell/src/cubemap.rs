@@ -0,0 +1,234 @@
+//! The cubemaps of the map, which are reflected by materials with an `$envmap`.
+//! Each `env_cubemap` is compiled into the cubemap lump, and the game bakes a view of the map from
+//! it into `materials/maps/<map>/c<x>_<y>_<z>.vtf` in the pakfile.
+//! vbsp replaces the materials of brush faces which use `$envmap env_cubemap` with patched copies
+//! naming the cubemap nearest to them, so faces find theirs by name. Models still use
+//! `env_cubemap`, and reflect the cubemap nearest to where they are lit from.
+
+use bevy::prelude::Image;
+
+use crate::{
+    data::{load_cube_faces, TextureError, TextureName, VpkState},
+    lump::{read_records, LumpType, Lumps},
+    map::GameMap,
+    skybox::{cube_image, resample_cube, SkySide},
+};
+
+/// Size of a `dcubemapsample_t`
+const CUBEMAP_SAMPLE_SIZE: usize = 16;
+
+/// The `$envmap` of materials which reflect the nearest cubemap of the map
+pub const ENV_CUBEMAP: &str = "env_cubemap";
+
+/// An `env_cubemap`, from the cubemap lump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CubemapSample {
+    /// In source coordinates, rounded to integers which are part of the cubemap's name
+    pub origin: [i32; 3],
+    /// The size of the cubemap's faces, as the log2 of their size plus one, or `0` for the default
+    pub size: i32,
+}
+
+/// The cubemaps of a map
+#[derive(Debug, Clone, Default)]
+pub struct MapCubemaps {
+    /// The name of the map's file without its extension, which the cubemaps are stored under
+    pub map_name: String,
+    pub samples: Vec<CubemapSample>,
+}
+impl MapCubemaps {
+    pub fn from_bsp(data: &[u8], map_name: &str) -> eyre::Result<MapCubemaps> {
+        let lumps = Lumps::new(data)?;
+        let samples = parse_cubemaps(&lumps.get(LumpType::Cubemaps)?)?;
+
+        Ok(MapCubemaps {
+            map_name: map_name.to_lowercase(),
+            samples,
+        })
+    }
+
+    /// The name of the cubemap's texture in the pakfile, ex: `maps/ctf_2fort/c-128_64_256`
+    pub fn texture_name(&self, sample: &CubemapSample) -> TextureName {
+        let [x, y, z] = sample.origin;
+        TextureName::from(format!("maps/{}/c{x}_{y}_{z}", self.map_name))
+    }
+
+    pub fn texture_names(&self) -> impl Iterator<Item = TextureName> + '_ {
+        self.samples.iter().map(|sample| self.texture_name(sample))
+    }
+
+    /// Find the cubemap closest to the point, in source coordinates.
+    pub fn nearest(&self, point: [f32; 3]) -> Option<&CubemapSample> {
+        let distance = |sample: &CubemapSample| {
+            let d = [0, 1, 2].map(|i| sample.origin[i] as f32 - point[i]);
+            d[0] * d[0] + d[1] * d[1] + d[2] * d[2]
+        };
+        self.samples
+            .iter()
+            .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+    }
+}
+
+fn parse_cubemaps(data: &[u8]) -> eyre::Result<Vec<CubemapSample>> {
+    read_records(data, CUBEMAP_SAMPLE_SIZE, |r| {
+        Ok(CubemapSample {
+            origin: [r.i32()?, r.i32()?, r.i32()?],
+            size: r.i32()?,
+        })
+    })
+}
+
+/// Load the map's cubemaps, along with the other cubemaps that are named by materials.
+/// Cubemaps which fail to load are skipped, so the materials using them don't reflect anything.
+pub fn load_cubemaps(
+    vpk: &VpkState,
+    map: &GameMap,
+    named: impl IntoIterator<Item = TextureName>,
+) -> Vec<(TextureName, Image)> {
+    let mut names = map.cubemaps.texture_names().collect::<Vec<_>>();
+    for name in named {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    names
+        .into_iter()
+        .filter_map(|name| match load_cubemap(vpk, map, &name) {
+            Ok(image) => Some((name, image)),
+            Err(err) => {
+                eprintln!("Failed to load cubemap {name:?}: {err:?}");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Load the cubemap texture as a cube image.
+pub fn load_cubemap(vpk: &VpkState, map: &GameMap, name: &str) -> Result<Image, TextureError> {
+    // TODO: maps compiled with only HDR lighting only have the `.hdr` version of their cubemaps,
+    // which hold float or compressed HDR colors that would need converting to linear rather than
+    // being loaded as sRGB, so those maps don't reflect anything yet
+    let faces = load_cube_faces(vpk, Some(map), name)?;
+    let faces = faces
+        .into_iter()
+        .map(|face| SkySide {
+            width: face.width(),
+            height: face.height(),
+            data: face.into_raw(),
+        })
+        .collect::<Vec<_>>();
+
+    let size = faces.iter().map(|face| face.width).max().unwrap_or(1);
+    let data = resample_cube(size, |dir| {
+        let (face, uv) = cube_face(dir);
+        faces[face].sample(uv)
+    });
+
+    Ok(cube_image(size, data))
+}
+
+/// Find which face of a cubemap texture the direction (in source coordinates) goes through, and
+/// where on it, with the uv in `[0, 1]` and `v` going down the face.
+/// The faces are laid out like a D3D cubemap in source's coordinates, which is how the game
+/// samples them.
+fn cube_face([x, y, z]: [f32; 3]) -> (usize, [f32; 2]) {
+    let (ax, ay, az) = (x.abs(), y.abs(), z.abs());
+    let (face, s, t, major) = if ax >= ay && ax >= az {
+        if x > 0.0 {
+            (0, -z, -y, ax)
+        } else {
+            (1, z, -y, ax)
+        }
+    } else if ay >= az {
+        if y > 0.0 {
+            (2, x, z, ay)
+        } else {
+            (3, x, -z, ay)
+        }
+    } else if z > 0.0 {
+        (4, x, -y, az)
+    } else {
+        (5, -x, -y, az)
+    };
+
+    (face, [(s / major + 1.0) / 2.0, (t / major + 1.0) / 2.0])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{mesh::rotate, skybox::cube_direction};
+
+    use super::*;
+
+    #[test]
+    fn test_cubemap_lump() {
+        let mut data = Vec::new();
+        for (origin, size) in [([-128, 64, 256], 0), ([1024, -512, 0], 6)] {
+            for v in origin {
+                data.extend(i32::to_le_bytes(v));
+            }
+            data.extend(i32::to_le_bytes(size));
+        }
+
+        let cubemaps = MapCubemaps {
+            map_name: "ctf_2fort".to_string(),
+            samples: parse_cubemaps(&data).unwrap(),
+        };
+        assert_eq!(cubemaps.samples.len(), 2);
+        assert_eq!(cubemaps.samples[1].size, 6);
+        assert_eq!(
+            &*cubemaps.texture_name(&cubemaps.samples[0]),
+            "maps/ctf_2fort/c-128_64_256"
+        );
+
+        assert_eq!(
+            cubemaps.nearest([0.0, 0.0, 200.0]),
+            Some(&cubemaps.samples[0])
+        );
+        assert_eq!(
+            cubemaps.nearest([900.0, -400.0, 0.0]),
+            Some(&cubemaps.samples[1])
+        );
+        assert_eq!(MapCubemaps::default().nearest([0.0; 3]), None);
+
+        assert!(parse_cubemaps(&data[..20]).is_err());
+    }
+
+    #[test]
+    fn test_cube_face() {
+        // The faces of the texture are in the same orientation as the cube image's, only in
+        // source coordinates rather than bevy's
+        for face in 0..6 {
+            for (u, v) in [(0.0, 0.0), (0.5, -0.25), (-0.75, 0.5)] {
+                let (found, uv) = cube_face(cube_direction(face, u, v));
+                assert_eq!(found, face);
+                assert_eq!(uv, [(u + 1.0) / 2.0, (v + 1.0) / 2.0]);
+            }
+        }
+
+        // Each face of the texture ends up on the face of the image in its direction
+        let faces = (0..6)
+            .map(|i| SkySide {
+                width: 2,
+                height: 2,
+                data: [i as u8, 0, 0, 255].repeat(4),
+            })
+            .collect::<Vec<_>>();
+        let data = resample_cube(2, |dir| {
+            let (face, uv) = cube_face(dir);
+            faces[face].sample(uv)
+        });
+        for (face, dir) in [
+            (0, [1.0, 0.0, 0.0]),
+            (3, [0.0, -1.0, 0.0]),
+            (5, [0.0, 0.0, -1.0]),
+        ] {
+            let bevy = rotate(dir);
+            let image_face = (0..6)
+                .find(|i| cube_direction(*i, 0.0, 0.0) == bevy)
+                .unwrap();
+            assert_eq!(data[image_face * 2 * 2 * 4], face, "face {face}");
+        }
+    }
+}
@@ -1,28 +1,37 @@
-use std::{borrow::Cow, collections::HashMap, hash::Hash, path::Path, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use bevy::{
-    pbr::{AlphaMode, StandardMaterial},
+    log::warn,
     prelude::{Assets, Handle, Image, Resource},
     render::{
-        render_resource::{
-            Extent3d, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
-        },
+        render_resource::{Extent3d, TextureDescriptor, TextureDimension, TextureUsages},
         texture::{ImageAddressMode, ImageSampler, ImageSamplerDescriptor},
     },
 };
+use dashmap::DashMap;
 use indexmap::Equivalent;
+use memmap2::Mmap;
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
-use vmt::{ShaderName, VMTError, VMTItem, VMT};
+use vmt::VMT;
 use vpk::{
     access::{DirFile, DirFileBigRefLowercase},
     vpk::{Ext, ProbableKind},
 };
 
-use crate::{map::GameMap, material::make_material};
-
-// TODO: We could preconvert vtf files to efficient formats, and then load those instead
-
-// TODO: on map change you should remove all 'map' textures
+use crate::{
+    asset::{material_path, texture_path},
+    brush::BrushMaterial,
+    cache::{CachedImage, SourceStamp, TextureCache, TextureRole},
+    map::GameMap,
+    material::{make_material, MaterialImages, MaterialParams},
+    model::ModelMaterial,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum VPKSrc {
@@ -40,6 +49,8 @@ pub enum VPKSrc {
 pub enum LSrc {
     Vpk(VPKSrc),
     Map,
+    /// From one of the loose file directories
+    Loose,
 }
 impl From<VPKSrc> for LSrc {
     fn from(src: VPKSrc) -> Self {
@@ -49,12 +60,15 @@ impl From<VPKSrc> for LSrc {
 
 pub type MaterialName = Arc<str>;
 pub type TextureName = Arc<str>;
+/// A texture along with what it is used for.
+/// The same vtf is converted differently depending on its role, so it is loaded once per role.
+pub type TextureKey = (TextureName, TextureRole);
 
 #[derive(Debug, Clone)]
 pub enum MaterialError {
     FindFailure(String),
-
-    Frozen,
+    /// The vmt has no base texture, or a tool texture for water
+    MissingBaseTexture(String),
 
     VMT(vmt::VMTError),
     Texture(TextureError),
@@ -81,7 +95,9 @@ impl std::fmt::Display for MaterialError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             MaterialError::FindFailure(name) => write!(f, "Failed to find material: {}", name),
-            MaterialError::Frozen => write!(f, "Cannot load more materials"),
+            MaterialError::MissingBaseTexture(name) => {
+                write!(f, "Material has no base texture: {}", name)
+            }
             MaterialError::VMT(err) => write!(f, "VMT error: {}", err),
             MaterialError::Texture(err) => write!(f, "Texture error: {}", err),
             MaterialError::Io(err) => write!(f, "IO error: {}", err),
@@ -93,7 +109,8 @@ impl std::fmt::Display for MaterialError {
 pub enum TextureError {
     NotLoaded,
     FindFailure(String),
-    Frozen,
+    /// A cubemap was asked for, but the texture doesn't have the six faces of one
+    NotCubemap,
 
     VPK(Arc<vpk::Error>),
     VTF(Arc<vtf::Error>),
@@ -120,7 +137,7 @@ impl std::fmt::Display for TextureError {
         match self {
             TextureError::NotLoaded => write!(f, "Texture not loaded"),
             TextureError::FindFailure(name) => write!(f, "Failed to find texture: {}", name),
-            TextureError::Frozen => write!(f, "Cannot load more textures"),
+            TextureError::NotCubemap => write!(f, "Texture is not a cubemap"),
             TextureError::VPK(err) => write!(f, "VPK error: {}", err),
             TextureError::VTF(err) => write!(f, "VTF error: {}", err),
             TextureError::Io(err) => write!(f, "IO error: {}", err),
@@ -133,13 +150,36 @@ pub struct LMaterial {
     /// Name of vtf
     pub image: Result<TextureName, TextureError>,
     pub vmt_src: LSrc,
-    pub mat: Handle<StandardMaterial>,
+    /// The vmt that this material includes, if any
+    pub include: Option<MaterialName>,
+    /// Every vtf the material uses, including the base texture
+    pub textures: Vec<TextureKey>,
+    pub mat: Handle<BrushMaterial>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct LImage {
     pub image: Handle<Image>,
     pub src: LSrc,
+    pub role: TextureRole,
+}
+
+/// The lowercase names of the materials and textures that were loaded when a map started loading,
+/// which it doesn't have to load again.
+#[derive(Debug, Clone, Default)]
+pub struct LoadedNames {
+    pub materials: HashSet<MaterialName>,
+    pub textures: HashSet<TextureKey>,
+}
+impl LoadedNames {
+    pub fn has_material(&self, name: &str) -> bool {
+        self.materials.contains(name.to_lowercase().as_str())
+    }
+
+    pub fn has_texture(&self, name: &str, role: TextureRole) -> bool {
+        self.textures
+            .contains(&(TextureName::from(name.to_lowercase()), role))
+    }
 }
 
 /// Textures that have been loaded, by their lowercase name  
@@ -147,13 +187,40 @@ pub struct LImage {
 #[derive(Default, Clone, Resource)]
 pub struct LoadedTextures {
     pub missing_texture: Handle<Image>,
-    pub missing_material: Handle<StandardMaterial>,
+    pub missing_material: Handle<BrushMaterial>,
+    /// The lightmap atlas of the current map, which is shared by all the materials.
+    /// The handle stays the same between maps, only the image is replaced.
+    pub lightmap: Handle<Image>,
+    /// The sky cubemap of the current map, if it has one, which water reflects
+    pub skybox: Option<Handle<Image>>,
+    /// The cubemaps of the current map, and the others named by its materials' `$envmap`s
+    pub cubemaps: HashMap<TextureName, Handle<Image>>,
+    /// The cubemap reflected by `env_cubemap` materials which vbsp didn't assign one to, ex:
+    /// overlays
+    pub default_cubemap: Option<Handle<Image>>,
     pub vmt: HashMap<MaterialName, LMaterial>,
-    pub vtf: HashMap<TextureName, LImage>,
-    /// Whether it should refuse to load any more materials/textures
-    pub frozen: bool,
+    pub vtf: HashMap<TextureKey, LImage>,
+    /// On-disk cache of converted textures, if enabled
+    pub cache: Option<Arc<TextureCache>>,
 }
 impl LoadedTextures {
+    /// The names of everything that is loaded, for loading a map in the background without
+    /// loading them again
+    pub fn loaded_names(&self) -> LoadedNames {
+        LoadedNames {
+            materials: self
+                .vmt
+                .keys()
+                .map(|name| MaterialName::from(name.to_lowercase()))
+                .collect(),
+            textures: self
+                .vtf
+                .keys()
+                .map(|(name, role)| (TextureName::from(name.to_lowercase()), *role))
+                .collect(),
+        }
+    }
+
     /// Find a material by its lowercase name
     pub fn find_material(&self, name: &str) -> Option<&LMaterial> {
         for (vmt_name, material) in self.vmt.iter() {
@@ -176,10 +243,10 @@ impl LoadedTextures {
         None
     }
 
-    /// Find a texture by its lowercase name
-    pub fn find_texture(&self, name: &str) -> Option<&LImage> {
-        for (vtf_name, image) in self.vtf.iter() {
-            if name.eq_ignore_ascii_case(vtf_name) {
+    /// Find a texture loaded for the role by its lowercase name
+    pub fn find_texture(&self, name: &str, role: TextureRole) -> Option<&LImage> {
+        for ((vtf_name, vtf_role), image) in self.vtf.iter() {
+            if *vtf_role == role && name.eq_ignore_ascii_case(vtf_name) {
                 return Some(image);
             }
         }
@@ -187,7 +254,7 @@ impl LoadedTextures {
         None
     }
 
-    pub fn find_material_handle(&self, name: &str) -> Option<Handle<StandardMaterial>> {
+    pub fn find_material_handle(&self, name: &str) -> Option<Handle<BrushMaterial>> {
         let lmaterial = self.find_material(name)?;
 
         Some(lmaterial.mat.clone())
@@ -198,7 +265,7 @@ impl LoadedTextures {
 
         match &lmaterial.image {
             Ok(name) => {
-                let ltexture = self.vtf.get(name).unwrap();
+                let ltexture = self.vtf.get(&(name.clone(), TextureRole::Base)).unwrap();
                 Some(Ok(ltexture.image.clone()))
             }
             Err(err) => Some(Err(err.clone())),
@@ -215,25 +282,19 @@ impl LoadedTextures {
     // the image.
     // Though this could be maybe avoided by some sort of hash, though that isn't unique enough?
 
-    // TODO: could we somehow make this an asset loader?
     /// Load a VMT file, and load the texture it points to
     pub fn load_material<'a>(
         &mut self,
         vpk: &VpkState,
         map: Option<&GameMap>,
         images: &mut Assets<Image>,
-        materials: &mut Assets<StandardMaterial>,
+        materials: &mut Assets<BrushMaterial>,
         name: &str,
-    ) -> Result<Handle<StandardMaterial>, MaterialError> {
+    ) -> Result<Handle<BrushMaterial>, MaterialError> {
         if let Some(mat) = self.find_material_handle(name) {
             return Ok(mat);
         }
 
-        if self.frozen {
-            println!("Frozen for {name:?}");
-            return Err(MaterialError::Frozen);
-        }
-
         let info = construct_material_info(vpk, map, name)?;
         let name: MaterialName = name.to_lowercase().into();
 
@@ -245,38 +306,131 @@ impl LoadedTextures {
         vpk: &VpkState,
         map: Option<&GameMap>,
         images: &mut Assets<Image>,
-        materials: &mut Assets<StandardMaterial>,
+        materials: &mut Assets<BrushMaterial>,
         name: MaterialName,
         info: LoadingMaterialInfo,
-    ) -> Result<Handle<StandardMaterial>, MaterialError> {
-        if self.frozen {
-            return Err(MaterialError::Frozen);
-        }
-
+    ) -> Result<Handle<BrushMaterial>, MaterialError> {
         let lmaterial = LMaterial {
             image: Err(TextureError::NotLoaded),
             mat: Handle::default(),
             vmt_src: info.vmt_src,
+            include: info.include.clone(),
+            textures: info.textures().collect(),
         };
 
         self.vmt.insert(name.clone(), lmaterial);
 
         // TODO: fallback materials?
-        // TODO: normal maps
-        // TODO: bump maps
 
-        self.load_texture(vpk, map, images, info.base_texture_name.clone())?;
+        self.load_material_textures(vpk, map, images, &info)?;
 
-        let image = self.vtf.get(&info.base_texture_name).unwrap().image.clone();
         self.vmt.get_mut(&name).unwrap().image = Ok(info.base_texture_name.clone());
 
-        let material = make_material(image);
+        let material = self.make_material_for(&info).unwrap();
         let material = materials.add(material);
         self.vmt.get_mut(&name).unwrap().mat = material.clone();
 
         Ok(material)
     }
 
+    /// Remove all the materials and textures that came from the map (its pakfile).  
+    /// Ones from the vpks are kept around, since the next map will probably use many of them.
+    /// Materials that reference a removed texture are also removed.
+    pub fn unload_map(&mut self) {
+        let vmt_count = self.vmt.len();
+        let vtf_count = self.vtf.len();
+
+        self.vtf.retain(|_, image| image.src != LSrc::Map);
+
+        let vtf = &self.vtf;
+        self.vmt.retain(|_, material| {
+            if material.vmt_src == LSrc::Map {
+                return false;
+            }
+
+            match &material.image {
+                Ok(image) => vtf.contains_key(&(image.clone(), TextureRole::Base)),
+                // It may have failed due to something missing which the next map provides
+                Err(_) => false,
+            }
+        });
+
+        println!(
+            "Unloaded map assets: vmt #{}; vtf #{}",
+            vmt_count - self.vmt.len(),
+            vtf_count - self.vtf.len()
+        );
+    }
+
+    /// Replace the lightmap atlas with the given map's.
+    pub fn set_lightmap(
+        &self,
+        images: &mut Assets<Image>,
+        materials: &mut Assets<BrushMaterial>,
+        lightmap: Image,
+    ) {
+        images.insert(self.lightmap.clone(), lightmap);
+
+        // Materials are only re-prepared when they change, so the ones which persist from the
+        // previous map would keep using its atlas
+        for material in self.vmt.values() {
+            materials.get_mut(&material.mat);
+        }
+    }
+
+    /// Replace the sky cubemap with the given map's, returning its handle.
+    pub fn set_skybox(
+        &mut self,
+        images: &mut Assets<Image>,
+        materials: &mut Assets<BrushMaterial>,
+        skybox: Option<Image>,
+    ) -> Option<Handle<Image>> {
+        self.skybox = skybox.map(|skybox| images.add(skybox));
+
+        // Water which persists from the previous map would reflect its sky
+        for material in self.vmt.values() {
+            if let Some(material) = materials.get_mut(&material.mat) {
+                if material.water.is_some() {
+                    material.reflection = self.skybox.clone();
+                }
+            }
+        }
+
+        self.skybox.clone()
+    }
+
+    /// Replace the cubemaps with the given map's.
+    pub fn set_cubemaps(
+        &mut self,
+        images: &mut Assets<Image>,
+        materials: &mut Assets<BrushMaterial>,
+        map: &GameMap,
+        cubemaps: Vec<(TextureName, Image)>,
+    ) {
+        self.cubemaps = cubemaps
+            .into_iter()
+            .map(|(name, image)| (name, images.add(image)))
+            .collect();
+        // Like the game, the first cubemap is the default
+        self.default_cubemap = map
+            .cubemaps
+            .texture_names()
+            .find_map(|name| self.cubemaps.get(&name).cloned());
+
+        // Materials which persist from the previous map would reflect its cubemap
+        for material in self.vmt.values() {
+            let Some(material) = materials.get_mut(&material.mat) else {
+                continue;
+            };
+            let Some(params) = &material.envmap_params else {
+                continue;
+            };
+            if params.cubemap.is_none() {
+                material.envmap = self.default_cubemap.clone();
+            }
+        }
+    }
+
     /// Typically this should not be used.
     pub fn insert_material(&mut self, name: Arc<str>, material: LMaterial) {
         self.vmt.insert(name, material);
@@ -290,40 +444,216 @@ impl LoadedTextures {
         map: Option<&GameMap>,
         images: &mut Assets<Image>,
         name: TextureName,
+        role: TextureRole,
+    ) -> Result<(), TextureError> {
+        let (image, image_src) =
+            construct_image(vpk, map, self.cache.as_deref(), None, &name, role)?;
+
+        self.insert_texture_of(images, name, image, image_src, role);
+
+        Ok(())
+    }
+
+    /// Load the textures used by the material which are not already loaded.
+    /// Only failing to load the base texture is an error, since the material is still usable
+    /// without the others.
+    fn load_material_textures(
+        &mut self,
+        vpk: &VpkState,
+        map: Option<&GameMap>,
+        images: &mut Assets<Image>,
+        info: &LoadingMaterialInfo,
     ) -> Result<(), TextureError> {
-        if self.frozen {
-            return Err(TextureError::Frozen);
+        for (texture, role) in info.textures() {
+            if self.vtf.contains_key(&(texture.clone(), role)) {
+                continue;
+            }
+
+            match self.load_texture(vpk, map, images, texture.clone(), role) {
+                Ok(()) => {}
+                Err(err) if role == TextureRole::Base => return Err(err),
+                Err(err) => eprintln!("Failed to load {role:?} texture {texture:?}: {err:?}"),
+            }
         }
 
-        let (image, image_src) = construct_image(vpk, map, &name)?;
+        Ok(())
+    }
+
+    /// Create the material for the info, if its base texture is loaded.
+    /// Other textures (like the normal map) are skipped if they're not loaded.
+    pub fn make_material_for(&self, info: &LoadingMaterialInfo) -> Option<BrushMaterial> {
+        let image_of = |name: &Option<TextureName>, role: TextureRole| {
+            name.as_ref()
+                .and_then(|name| self.vtf.get(&(name.clone(), role)))
+                .map(|limage| limage.image.clone())
+        };
+        let base_texture = self
+            .vtf
+            .get(&(info.base_texture_name.clone(), TextureRole::Base))?;
+        let images = MaterialImages {
+            base_texture: base_texture.image.clone(),
+            normal_map: image_of(&info.params.normal_map, TextureRole::NormalMap),
+            base_texture2: image_of(&info.params.base_texture2, TextureRole::Base),
+            blend_modulate: image_of(&info.params.blend_modulate, TextureRole::BlendModulate),
+            reflection: self.skybox.clone(),
+            envmap: info
+                .params
+                .envmap
+                .as_ref()
+                .and_then(|envmap| match &envmap.cubemap {
+                    Some(name) => self.cubemaps.get(name).cloned(),
+                    None => self.default_cubemap.clone(),
+                }),
+            envmap_mask: image_of(
+                &info
+                    .params
+                    .envmap
+                    .as_ref()
+                    .and_then(|envmap| envmap.mask.clone()),
+                TextureRole::EnvMapMask,
+            ),
+        };
+
+        Some(make_material(images, self.lightmap.clone(), &info.params))
+    }
+
+    /// Get the names of the loaded materials which are the given vmt, or include it.
+    pub fn materials_using_vmt(&self, name: &str) -> Vec<MaterialName> {
+        let path = material_path(name);
+        self.vmt
+            .iter()
+            .filter(|(vmt_name, material)| {
+                material_path(vmt_name) == path
+                    || material
+                        .include
+                        .as_deref()
+                        .map_or(false, |include| material_path(include) == path)
+            })
+            .map(|(vmt_name, _)| vmt_name.clone())
+            .collect()
+    }
+
+    /// Reload a material that is already loaded.
+    /// The material is replaced in place, so that everything using its handle picks up the change.
+    pub fn reload_material(
+        &mut self,
+        vpk: &VpkState,
+        map: Option<&GameMap>,
+        images: &mut Assets<Image>,
+        materials: &mut Assets<BrushMaterial>,
+        name: &MaterialName,
+    ) -> Result<(), MaterialError> {
+        let Some(handle) = self.vmt.get(name).map(|material| material.mat.clone()) else {
+            return Ok(());
+        };
+
+        let info = construct_material_info(vpk, map, name)?;
+        // It may now reference textures that we haven't loaded yet
+        self.load_material_textures(vpk, map, images, &info)?;
 
-        self.insert_texture_of(images, name, image, image_src)?;
+        if let Some(material) = materials.get_mut(&handle) {
+            *material = self.make_material_for(&info).unwrap();
+        }
+
+        let lmaterial = self.vmt.get_mut(name).unwrap();
+        lmaterial.textures = info.textures().collect();
+        lmaterial.image = Ok(info.base_texture_name);
+        lmaterial.vmt_src = info.vmt_src;
+        lmaterial.include = info.include;
 
         Ok(())
     }
 
+    /// Reload a texture that is already loaded.
+    /// The image is replaced in place, so that everything using its handle picks up the change.
+    /// Each role the texture is loaded as is reloaded separately, so a role that fails keeps its
+    /// old image without stopping the others. Returns the errors of the roles that failed.
+    pub fn reload_texture(
+        &mut self,
+        vpk: &VpkState,
+        map: Option<&GameMap>,
+        images: &mut Assets<Image>,
+        materials: &mut Assets<BrushMaterial>,
+        model_materials: &mut Assets<ModelMaterial>,
+        name: &str,
+    ) -> Vec<(TextureRole, TextureError)> {
+        // The texture is loaded once for each role it is used as
+        let keys = self
+            .vtf
+            .keys()
+            .filter(|(vtf_name, _)| vtf_name.eq_ignore_ascii_case(name))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut errors = Vec::new();
+        for key in keys {
+            let (vtf_name, role) = &key;
+            let (image, image_src) =
+                match construct_image(vpk, map, self.cache.as_deref(), None, vtf_name, *role) {
+                    Ok(image) => image,
+                    Err(err) => {
+                        errors.push((*role, err));
+                        continue;
+                    }
+                };
+
+            let limage = self.vtf.get_mut(&key).unwrap();
+            if let Some(old) = images.get_mut(&limage.image) {
+                *old = image;
+            }
+            limage.src = image_src;
+
+            // Materials are only re-prepared when they change, so we mark the ones using the
+            // texture as changed for them to use the new image
+            for material in self.vmt.values() {
+                if material.textures.contains(&key) {
+                    materials.get_mut(&material.mat);
+                }
+            }
+
+            // The props' materials aren't tracked by name, so they're found by the image
+            let image = &limage.image;
+            let props = model_materials
+                .iter()
+                .filter(|(_, material)| {
+                    material.base_texture == *image || material.envmap_mask.as_ref() == Some(image)
+                })
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>();
+            for id in props {
+                model_materials.get_mut(id);
+            }
+        }
+
+        errors
+    }
+
+    /// Add the decoded texture, returning its handle.
+    /// If the texture is already loaded then the existing handle is kept and returned, since
+    /// materials and meshes from before may be using it.
     pub fn insert_texture_of(
         &mut self,
         images: &mut Assets<Image>,
         name: TextureName,
         image: Image,
         image_src: LSrc,
-    ) -> Result<TextureName, TextureError> {
-        if self.frozen {
-            return Err(TextureError::Frozen);
+        role: TextureRole,
+    ) -> Handle<Image> {
+        if let Some(limage) = self.vtf.get(&(name.clone(), role)) {
+            return limage.image.clone();
         }
 
         let handle = images.add(image);
-
         self.vtf.insert(
-            name.clone(),
+            (name, role),
             LImage {
                 image: handle.clone(),
                 src: image_src,
+                role,
             },
         );
 
-        Ok(name)
+        handle
     }
 }
 
@@ -331,6 +661,42 @@ impl LoadedTextures {
 pub struct LoadingMaterialInfo {
     pub vmt_src: LSrc,
     pub base_texture_name: Arc<str>,
+    /// The vmt that was included, if any
+    pub include: Option<MaterialName>,
+    /// The rest of the vmt's parameters that we use
+    pub params: MaterialParams,
+}
+impl LoadingMaterialInfo {
+    /// The textures used by the material, along with what they're used for
+    pub fn textures(&self) -> impl Iterator<Item = (TextureName, TextureRole)> + '_ {
+        let params = &self.params;
+        std::iter::once((self.base_texture_name.clone(), TextureRole::Base))
+            .chain(
+                params
+                    .normal_map
+                    .clone()
+                    .map(|name| (name, TextureRole::NormalMap)),
+            )
+            .chain(
+                params
+                    .base_texture2
+                    .clone()
+                    .map(|name| (name, TextureRole::Base)),
+            )
+            .chain(
+                params
+                    .blend_modulate
+                    .clone()
+                    .map(|name| (name, TextureRole::BlendModulate)),
+            )
+            .chain(
+                params
+                    .envmap
+                    .as_ref()
+                    .and_then(|envmap| envmap.mask.clone())
+                    .map(|name| (name, TextureRole::EnvMapMask)),
+            )
+    }
 }
 
 pub fn construct_material_info(
@@ -340,6 +706,7 @@ pub fn construct_material_info(
 ) -> Result<LoadingMaterialInfo, MaterialError> {
     let (vmt, vmt_src) = find_vmt(vpk, map, name)?;
     let vmt = VMT::from_bytes(&vmt).map_err(MaterialError::VMT)?;
+    let include = vmt.include.as_deref().map(MaterialName::from);
     let mut tmp = None;
     // TODO: support resolving more than one level of vmt includes
     let vmt = vmt
@@ -351,205 +718,124 @@ pub fn construct_material_info(
         })
         .map_err(|x| x.flip(MaterialError::VMT))?;
 
-    let base_texture_name = match &vmt.shader_name {
-        vmt::ShaderName::Water => {
-            // TODO: water has things like refract texture and the normal map
-            if let Some(base_texture) = vmt.base_texture {
-                Arc::from(base_texture.to_lowercase())
-            } else if let Some(tool_texture) = vmt.other.get(b"%tooltexture") {
-                Arc::from(tool_texture)
-            } else {
-                panic!("Could not find water texture in vmt: {name:?}; vmt: {vmt:#?}");
-            }
-        }
-        _ => {
-            let Some(base_texture) = vmt.base_texture else {
-                panic!("Could not find base texture in vmt: {name:?}; vmt: {vmt:#?}");
-            };
-            Arc::from(base_texture.to_lowercase())
-        }
-    };
-
-    Ok(LoadingMaterialInfo {
-        vmt_src,
-        base_texture_name,
-    })
-}
-
-#[derive(Debug, Clone)]
-struct MinimalVMT<'a> {
-    pub shader_name: ShaderName<'a>,
-    pub base_texture: Option<&'a str>,
-    pub include: Option<&'a str>,
-
-    pub tool_texture: Option<&'a str>,
-}
-impl<'a> MinimalVMT<'a> {
-    pub fn from_bytes(bytes: &'a [u8]) -> Result<MinimalVMT<'a>, VMTError> {
-        let mut vmt_iter = vmt::vmt_from_bytes(bytes);
-
-        let mut vmt = MinimalVMT {
-            shader_name: ShaderName::LightmappedGeneric,
-            base_texture: None,
-            include: None,
-            tool_texture: None,
-        };
-
-        vmt.shader_name = vmt_iter
-            .next()
-            .transpose()?
-            .and_then(VMTItem::into_shader_name)
-            .ok_or(VMTError::MissingShaderName)?;
-
-        let mut sub_depth = 0;
-        for item in vmt_iter {
-            let item = item?;
-            match item {
-                VMTItem::ShaderName(_) => unreachable!(),
-                VMTItem::KeyValue(key, value) => {
-                    if sub_depth != 0 {
-                        continue;
-                    }
-
-                    if key.eq_ignore_ascii_case(b"$basetexture") {
-                        vmt.base_texture =
-                            Some(std::str::from_utf8(value).map_err(VMTError::from)?);
-                    } else if key.eq_ignore_ascii_case(b"include") {
-                        vmt.include = Some(std::str::from_utf8(value).map_err(VMTError::from)?);
-                    } else if key.eq_ignore_ascii_case(b"%tooltexture") {
-                        vmt.tool_texture =
-                            Some(std::str::from_utf8(value).map_err(VMTError::from)?);
-                    }
-                }
-                VMTItem::KeySub(_name) => {
-                    sub_depth += 1;
-                }
-                VMTItem::EndSub => {
-                    sub_depth -= 1;
-                }
-                VMTItem::Comment(_) => {}
-            }
-        }
-
-        Ok(vmt)
-    }
-
-    pub fn apply<'b>(self, other: &MinimalVMT<'b>) -> MinimalVMT<'b>
-    where
-        'a: 'b,
-    {
-        let mut vmt = MinimalVMT {
-            shader_name: self.shader_name,
-            base_texture: self.base_texture,
-            include: self.include,
-            tool_texture: self.tool_texture,
-        };
-
-        if let Some(base_texture_name) = other.base_texture {
-            vmt.base_texture = Some(base_texture_name);
-        }
-
-        // vmt.include = other.include;
-
-        if let Some(tool_texture) = other.tool_texture {
-            vmt.tool_texture = Some(tool_texture);
-        }
-
-        vmt
+    for (key, val) in vmt.invalid.iter() {
+        warn!(
+            "Ignoring invalid {} {val:?} in material {name:?}",
+            String::from_utf8_lossy(key)
+        );
     }
-}
 
-// TODO: compare the speed of this with construct_material_info
-// this should be faster but it might be dominated by reading the vpk out..
-pub fn construct_material_info2(
-    vpk: &VpkState,
-    map: Option<&GameMap>,
-    name: &str,
-) -> Result<LoadingMaterialInfo, MaterialError> {
-    // let start_time = std::time::Instant::now();
-    let (vmt, vmt_src) = find_vmt(vpk, map, name)?;
-    // let end_time = std::time::Instant::now();
-    // println!("Took {:?} to find vmt {name:?}", end_time - start_time);
-
-    let vmt = MinimalVMT::from_bytes(&vmt)?;
-    let tmp;
-    let vmt = if let Some(include) = vmt.include {
-        let (included_vmt, _) = find_vmt(vpk, map, include)?;
-        tmp = Some(included_vmt);
-        let included_vmt = MinimalVMT::from_bytes(tmp.as_ref().unwrap())?;
-
-        // vmt.apply(&included_vmt)
-        included_vmt.apply(&vmt)
-    } else {
-        vmt
+    let base_texture = match &vmt.shader_name {
+        // Water is drawn with its normal map, but usually has a texture for hammer
+        vmt::ShaderName::Water => vmt
+            .base_texture
+            .as_deref()
+            .or_else(|| vmt.other.get(b"%tooltexture")),
+        _ => vmt.base_texture.as_deref(),
     };
-
-    let base_texture_name = match &vmt.shader_name {
-        ShaderName::Water => {
-            // TODO: water has things like refract texture and the normal map
-            if let Some(base_texture) = vmt.base_texture {
-                Arc::from(base_texture.to_lowercase())
-            } else if let Some(tool_texture) = vmt.tool_texture {
-                Arc::from(tool_texture.to_lowercase())
-            } else {
-                panic!("Could not find water texture in vmt: {name:?}; vmt: {vmt:#?}");
-            }
-        }
-        _ => {
-            if let Some(base_texture) = vmt.base_texture {
-                Arc::from(base_texture.to_lowercase())
-            } else {
-                panic!("Could not find base texture in vmt: {name:?}; vmt: {vmt:#?}");
-            }
-        }
+    let Some(base_texture) = base_texture else {
+        return Err(MaterialError::MissingBaseTexture(name.to_string()));
     };
+    let base_texture_name = Arc::from(base_texture.to_lowercase());
+
+    let params = MaterialParams::from_vmt(&vmt);
 
     Ok(LoadingMaterialInfo {
         vmt_src,
         base_texture_name,
+        include,
+        params,
     })
 }
 
+/// Construct the image for the given texture name.
+/// If a `cache` is given then it is consulted before decoding the vtf, and any newly decoded
+/// texture is stored in it.  
+/// If `archives` is given then the vtf is read through it rather than by opening the archive.
 pub fn construct_image(
     vpk: &VpkState,
     map: Option<&GameMap>,
+    cache: Option<&TextureCache>,
+    archives: Option<&ArchiveMaps>,
     name: &str,
+    role: TextureRole,
 ) -> Result<(Image, LSrc), TextureError> {
-    let (image, image_src) = load_texture(vpk, map, name)?;
+    let stamp = cache.and_then(|_| texture_stamp(vpk, map, name));
+
+    if let (Some(cache), Some((src, stamp))) = (cache, stamp) {
+        if let Some(cached) = cache.get(name, role, stamp) {
+            return Ok((image_from_cached(cached), src));
+        }
+    }
+
+    let (image, image_src) = load_texture(vpk, map, archives, name)?;
 
     let (width, height) = image.dimensions();
-    let size = Extent3d {
+    let cached = CachedImage {
         width,
         height,
+        format: role.format(),
+        data: image.into_raw(),
+    };
+
+    if let (Some(cache), Some((_, stamp))) = (cache, stamp) {
+        if let Err(err) = cache.insert(name, role, stamp, &cached) {
+            eprintln!("Failed to cache texture {name:?}: {err:?}");
+        }
+    }
+
+    Ok((image_from_cached(cached), image_src))
+}
+
+pub(crate) fn image_from_cached(cached: CachedImage) -> Image {
+    let size = Extent3d {
+        width: cached.width,
+        height: cached.height,
         ..Default::default()
     };
 
-    Ok((
-        Image {
-            data: image.into_raw(),
-            texture_descriptor: TextureDescriptor {
-                label: None,
-                size,
-                mip_level_count: 1,
-                sample_count: 1,
-                dimension: TextureDimension::D2,
-                format: TextureFormat::Rgba8UnormSrgb,
-                usage: TextureUsages::TEXTURE_BINDING
-                    | TextureUsages::COPY_DST
-                    | TextureUsages::COPY_SRC,
-                view_formats: &[],
-            },
-            sampler: ImageSampler::Descriptor(ImageSamplerDescriptor {
-                // TODO: we might have to decide this based on usage?
-                address_mode_u: ImageAddressMode::Repeat,
-                address_mode_v: ImageAddressMode::Repeat,
-                address_mode_w: ImageAddressMode::Repeat,
-                ..Default::default()
-            }),
-            ..Default::default()
+    Image {
+        data: cached.data,
+        texture_descriptor: TextureDescriptor {
+            label: None,
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: cached.format,
+            usage: TextureUsages::TEXTURE_BINDING
+                | TextureUsages::COPY_DST
+                | TextureUsages::COPY_SRC,
+            view_formats: &[],
         },
-        image_src,
-    ))
+        sampler: ImageSampler::Descriptor(ImageSamplerDescriptor {
+            // TODO: we might have to decide this based on usage?
+            address_mode_u: ImageAddressMode::Repeat,
+            address_mode_v: ImageAddressMode::Repeat,
+            address_mode_w: ImageAddressMode::Repeat,
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Find where the texture would be loaded from, and the stamp identifying the version of the
+/// file it is in, without reading the texture itself.
+fn texture_stamp(vpk: &VpkState, map: Option<&GameMap>, name: &str) -> Option<(LSrc, SourceStamp)> {
+    // This has to follow the same precedence as `find_texture_data`
+    if let Some(path) = vpk.find_loose(&texture_path(name)) {
+        let meta = std::fs::metadata(path).ok()?;
+        Some((LSrc::Loose, SourceStamp::from_metadata(&meta)?))
+    } else if let Some((_, src)) = vpk.find_texture(name) {
+        Some((src.into(), vpk.src(&src)?.stamp?))
+    } else {
+        let map = map?;
+        if !map.has_texture(name) {
+            return None;
+        }
+
+        Some((LSrc::Map, map.stamp?))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -582,13 +868,17 @@ impl GameId {
     }
 }
 
-#[derive(Resource)]
+/// This is cheap to clone, so that it can be passed to tasks.
+#[derive(Clone, Resource)]
 pub struct VpkState {
     pub hl2_textures: VpkData,
     pub hl2_misc: VpkData,
     // TODO: should these even be named? Should we just have a general pool of vpks that we look at?
     pub textures: VpkData,
     pub misc: VpkData,
+    /// Directories of loose files, which are searched before the vpks.
+    /// Ex: `tf/custom/my_mod/`, which would have a `materials/` folder inside of it
+    pub loose_dirs: Arc<[PathBuf]>,
 }
 impl VpkState {
     /// Create a new [`VpkState`] from the path to the game folder.  
@@ -648,9 +938,25 @@ impl VpkState {
             hl2_misc,
             textures,
             misc,
+            loose_dirs: Arc::from([]),
         })
     }
 
+    pub fn with_loose_dirs(mut self, loose_dirs: Vec<PathBuf>) -> VpkState {
+        self.loose_dirs = Arc::from(loose_dirs);
+        self
+    }
+
+    /// Find a file (ex: `materials/concrete/wall.vmt`) in the loose directories, returning the
+    /// path to the first one that has it.
+    // TODO: this is case sensitive on linux, unlike the lookups in the vpks
+    pub fn find_loose(&self, path: &str) -> Option<PathBuf> {
+        self.loose_dirs
+            .iter()
+            .map(|dir| dir.join(path))
+            .find(|path| path.is_file())
+    }
+
     pub fn iter_vpks(&self) -> impl Iterator<Item = (VPKSrc, &VpkData)> {
         [
             (VPKSrc::HL2Textures, &self.hl2_textures),
@@ -702,6 +1008,14 @@ impl VpkState {
         None
     }
 
+    /// Find any file in the loaded vpks by its path, ex: `models/props_farm/tractor.mdl`.  
+    /// This ignores case.
+    pub fn find_file<'a>(&'a self, path: &str) -> Option<(vpk::entry::VPKEntryHandle<'a>, VPKSrc)> {
+        let (dir, filename) = path.rsplit_once('/').unwrap_or(("", path));
+        let (filename, ext) = filename.rsplit_once('.')?;
+        self.find(&Ext::from(ext), dir, filename)
+    }
+
     pub fn find_vmt<'a>(&'a self, name: &str) -> Option<(vpk::entry::VPKEntryHandle<'a>, VPKSrc)> {
         let name = name.strip_prefix("materials/").unwrap_or(name);
         let name = name.strip_suffix(".vmt").unwrap_or(name);
@@ -758,8 +1072,11 @@ impl VpkState {
     }
 }
 
+#[derive(Clone)]
 pub struct VpkData {
-    pub data: vpk::VPK,
+    pub data: Arc<vpk::VPK>,
+    /// Identifies the version of the dir vpk, used to know when cached data is stale
+    pub stamp: Option<SourceStamp>,
 }
 impl VpkData {
     // TODO: use paths
@@ -767,8 +1084,12 @@ impl VpkData {
         path: impl AsRef<Path>,
         probable_kind: ProbableKind,
     ) -> Result<VpkData, vpk::Error> {
-        let data = vpk::from_path(path, probable_kind)?;
-        Ok(VpkData { data })
+        let path = path.as_ref();
+        let stamp = std::fs::metadata(path)
+            .ok()
+            .and_then(|meta| SourceStamp::from_metadata(&meta));
+        let data = Arc::new(vpk::from_path(path, probable_kind)?);
+        Ok(VpkData { data, stamp })
     }
 
     /// Find an entry in the loaded vpk.
@@ -810,22 +1131,121 @@ impl VpkData {
 fn load_texture(
     vpk: &VpkState,
     map: Option<&GameMap>,
+    archives: Option<&ArchiveMaps>,
     name: &str,
 ) -> Result<(image::ImageBuffer<image::Rgba<u8>, Vec<u8>>, LSrc), TextureError> {
-    let (tex, src) = find_texture_data(vpk, map, name)?;
+    let (tex, src) = find_texture_data(vpk, map, archives, name)?;
     let tex = vtf::from_bytes(&tex)?;
     let image = tex.highres_image.decode(0)?;
     Ok((image.into_rgba8(), src))
 }
 
+/// `TEXTUREFLAGS_ENVMAP`, set on cubemap textures
+const TEXTURE_FLAG_ENVMAP: u32 = 0x4000;
+
+/// Decode the six faces of a cubemap texture, which are in the order `+X, -X, +Y, -Y, +Z, -Z` in
+/// source coordinates.
+pub(crate) fn load_cube_faces(
+    vpk: &VpkState,
+    map: Option<&GameMap>,
+    name: &str,
+) -> Result<Vec<image::RgbaImage>, TextureError> {
+    let (tex, _) = find_texture_data(vpk, map, None, name)?;
+    decode_cube_faces(&tex)
+}
+
+/// Decode the largest mipmap of the six faces of the first frame of a cubemap texture.
+fn decode_cube_faces(data: &[u8]) -> Result<Vec<image::RgbaImage>, TextureError> {
+    let tex = vtf::from_bytes(data)?;
+    let header = &tex.header;
+    if header.flags & TEXTURE_FLAG_ENVMAP == 0 {
+        return Err(TextureError::NotCubemap);
+    }
+    // Older cubemaps have a seventh face (a sphere map) after the others, which we skip
+    let faces = if header.version[1] < 5 && header.first_frame != 0xffff {
+        7
+    } else {
+        6
+    };
+
+    // The vtf crate only finds frames, which doesn't account for the faces in the smaller
+    // mipmaps. The image data goes from the smallest mipmap to the largest, each holding every
+    // face of every frame.
+    let format = header.highres_image_format;
+    let mip_size = |mip: u32| {
+        let size = |v: u16| u32::from(v).wrapping_shr(mip).max(1);
+        format
+            .frame_size(size(header.width), size(header.height))
+            .map(|size| size as usize)
+    };
+    let mut smaller_mips = 0;
+    for mip in 1..u32::from(header.mipmap_count) {
+        smaller_mips += mip_size(mip)?;
+    }
+    let face_size = mip_size(0)?;
+    let start = highres_offset(header)? + smaller_mips * usize::from(header.frames) * faces;
+
+    // Decode each face as the single frame of an image starting at it
+    let mut face_header = header.clone();
+    face_header.mipmap_count = 1;
+    face_header.frames = 1;
+    (0..6)
+        .map(|face| {
+            let offset = start + face * face_size;
+            if offset + face_size > data.len() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "cubemap face is past the end of the texture",
+                )
+                .into());
+            }
+            let image = vtf::image::VTFImage::new(
+                face_header.clone(),
+                format,
+                header.width,
+                header.height,
+                data,
+                offset,
+            );
+            Ok(image.decode(0)?.into_rgba8())
+        })
+        .collect()
+}
+
+/// Where the image data of the largest mipmaps starts, in the same way as the vtf crate finds it
+fn highres_offset(header: &vtf::header::VTFHeader) -> Result<usize, TextureError> {
+    use vtf::resources::ResourceType;
+
+    let resources = &header.resources;
+    if let Some(resource) = resources.get_by_type(ResourceType::VTF_LEGACY_RSRC_IMAGE) {
+        return Ok(resource.data as usize);
+    }
+    let lowres_offset = match resources.get_by_type(ResourceType::VTF_LEGACY_RSRC_LOW_RES_IMAGE) {
+        Some(resource) => resource.data,
+        None => header.header_size,
+    };
+    let lowres_size = header.lowres_image_format.frame_size(
+        u32::from(header.lowres_image_width),
+        u32::from(header.lowres_image_height),
+    )?;
+    Ok(lowres_offset as usize + lowres_size as usize)
+}
+
 fn find_texture_data<'a>(
     vpk: &'a VpkState,
     map: Option<&'a GameMap>,
+    archives: Option<&ArchiveMaps>,
     name: &str,
 ) -> Result<(Cow<'a, [u8]>, LSrc), TextureError> {
+    // Loose files take precedence over the vpks, like the game's custom folder
     // TODO: does map take precedence over vpks?
-    if let Some((tex, src)) = vpk.find_texture(name) {
-        let tex = tex.get()?;
+    if let Some(path) = vpk.find_loose(&texture_path(name)) {
+        Ok((Cow::Owned(std::fs::read(path)?), LSrc::Loose))
+    } else if let Some((tex, src)) = vpk.find_texture(name) {
+        let tex = match archives {
+            Some(archives) => archives.read(vpk, src, &tex)?,
+            None => tex.get()?,
+        };
         Ok((tex, src.into()))
     } else if let Some(map) = map {
         let tex = map
@@ -833,46 +1253,135 @@ fn find_texture_data<'a>(
             .ok_or_else(|| TextureError::FindFailure(name.to_string()))?;
         Ok((Cow::Owned(tex), LSrc::Map))
     } else {
-        // TODO: don't panic, this is mostly for testing
-        panic!("Failed to find texture {name:?}");
+        Err(TextureError::FindFailure(name.to_string()))
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Where a file is stored.
+/// The ordering groups files in the same archive together, with the vpks before the map.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum FileLoc {
     Vpk { src: VPKSrc, archive_index: u16 },
     Map,
+    Loose,
 }
 
+/// Find where the texture is stored, and its offset within that file.
+/// The offset is only meaningful for textures in the vpk archives, it is `0` otherwise.
 pub(crate) fn find_texture<'a>(
     vpk: &'a VpkState,
     map: Option<&'a GameMap>,
     name: &str,
-) -> Result<FileLoc, TextureError> {
-    if let Some((tex, src)) = vpk.find_texture(name) {
-        Ok(FileLoc::Vpk {
+) -> Result<(FileLoc, u32), TextureError> {
+    if vpk.find_loose(&texture_path(name)).is_some() {
+        Ok((FileLoc::Loose, 0))
+    } else if let Some((tex, src)) = vpk.find_texture(name) {
+        let loc = FileLoc::Vpk {
             src,
             archive_index: tex.archive_index(),
-        })
+        };
+        Ok((loc, tex.archive_offset()))
     } else if let Some(map) = map {
         if map.has_texture(name) {
-            Ok(FileLoc::Map)
+            Ok((FileLoc::Map, 0))
         } else {
             Err(TextureError::FindFailure(name.to_string()))
         }
     } else {
-        // TODO: don't panic, this is mostly for testing
-        panic!("Failed to find texture {name:?}");
+        Err(TextureError::FindFailure(name.to_string()))
     }
 }
 
-fn find_vmt<'a>(
+/// The archive index used by entries whose data is stored in the dir vpk itself
+const DIR_ARCHIVE_INDEX: u16 = 0x7fff;
+
+/// Memory maps of the vpk archive files (ex: `tf2_textures_004.vpk`), opened on first use.
+/// Reading entries through these avoids reopening the archive for every file, and since there is
+/// no file cursor they can be shared between threads.
+#[derive(Default)]
+pub struct ArchiveMaps {
+    maps: DashMap<(VPKSrc, u16), Arc<Mmap>>,
+}
+impl ArchiveMaps {
+    /// Get the map of the archive, opening it if it is not already open.
+    pub fn get(
+        &self,
+        vpk: &VpkState,
+        src: VPKSrc,
+        archive_index: u16,
+    ) -> Result<Arc<Mmap>, TextureError> {
+        if let Some(archive) = self.maps.get(&(src, archive_index)) {
+            return Ok(archive.clone());
+        }
+
+        let path = vpk.archive_path(&src, archive_index).ok_or_else(|| {
+            TextureError::FindFailure(format!("archive {archive_index} of {src:?}"))
+        })?;
+        let file = std::fs::File::open(path)?;
+        // Safety: the vpks are not modified while we're running. If something were to truncate
+        // them then reading the map could fault, but there's not much we can do about that.
+        let archive = Arc::new(unsafe { Mmap::map(&file)? });
+
+        // Another thread may have opened it at the same time, in which case we just use theirs
+        let archive = self
+            .maps
+            .entry((src, archive_index))
+            .or_insert(archive)
+            .clone();
+        Ok(archive)
+    }
+
+    /// Read the data of an entry from the vpk given by `src`.
+    pub fn read<'a>(
+        &self,
+        vpk: &VpkState,
+        src: VPKSrc,
+        entry: &vpk::entry::VPKEntryHandle<'a>,
+    ) -> Result<Cow<'a, [u8]>, TextureError> {
+        let archive_index = entry.archive_index();
+        let len = entry.file_length() as usize;
+        if archive_index == DIR_ARCHIVE_INDEX || len == 0 {
+            // Entirely in the dir vpk, which is already in memory
+            return Ok(entry.get()?);
+        }
+
+        let archive = self.get(vpk, src, archive_index)?;
+        let start = entry.archive_offset() as usize;
+        let data = archive.get(start..start + len).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("Entry extends past the end of archive {archive_index} of {src:?}"),
+            )
+        })?;
+
+        // The preload data comes before the data in the archive
+        let preload = entry.preload_data();
+        let mut res = Vec::with_capacity(preload.len() + data.len());
+        res.extend_from_slice(preload);
+        res.extend_from_slice(data);
+
+        Ok(Cow::Owned(res))
+    }
+
+    /// The number of archives that have been opened
+    pub fn len(&self) -> usize {
+        self.maps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.maps.is_empty()
+    }
+}
+
+pub(crate) fn find_vmt<'a>(
     vpk: &'a VpkState,
     map: Option<&'a GameMap>,
     name: &str,
 ) -> Result<(Cow<'a, [u8]>, LSrc), MaterialError> {
     // TODO: does map take precedence over vpks?
-    if let Some((tex, src)) = vpk.find_vmt(name) {
+    if let Some(path) = vpk.find_loose(&material_path(name)) {
+        Ok((Cow::Owned(std::fs::read(path)?), LSrc::Loose))
+    } else if let Some((tex, src)) = vpk.find_vmt(name) {
         let tex = tex.get()?;
         Ok((tex, src.into()))
     } else if let Some(map) = map {
@@ -884,3 +1393,62 @@ fn find_vmt<'a>(
         Err(MaterialError::FindFailure(name.to_string()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cubemap of 4x4 `RGBA8888` faces with three mipmaps, where face `i` of the largest mipmap
+    /// is filled with `i` and the smaller mipmaps are filled with `255`.
+    fn make_cubemap(minor_version: u32, first_frame: u16, faces: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(0x0046_5456u32.to_le_bytes());
+        data.extend([7u32, minor_version].iter().flat_map(|v| v.to_le_bytes()));
+        data.extend(80u32.to_le_bytes());
+        data.extend([4u16, 4].iter().flat_map(|v| v.to_le_bytes()));
+        data.extend(TEXTURE_FLAG_ENVMAP.to_le_bytes());
+        data.extend([1u16, first_frame].iter().flat_map(|v| v.to_le_bytes()));
+        // Padding, reflectivity, padding and bumpmap scale
+        data.resize(data.len() + 24, 0);
+        // RGBA8888 with three mipmaps, and no low resolution image
+        data.extend(0u32.to_le_bytes());
+        data.push(3);
+        data.extend(13u32.to_le_bytes());
+        data.extend([0, 0]);
+        data.extend(1u16.to_le_bytes());
+        data.resize(80, 0);
+
+        for size in [1, 2] {
+            data.resize(data.len() + size * size * 4 * faces, 255);
+        }
+        for face in 0..faces {
+            data.resize(data.len() + 4 * 4 * 4, face as u8);
+        }
+        data
+    }
+
+    #[test]
+    fn test_decode_cube_faces() {
+        for (minor_version, first_frame, faces) in [(2, 0xffff, 6), (2, 0, 7), (5, 0, 6)] {
+            let data = make_cubemap(minor_version, first_frame, faces);
+            let decoded = decode_cube_faces(&data).unwrap();
+            assert_eq!(decoded.len(), 6);
+            for (i, face) in decoded.iter().enumerate() {
+                assert_eq!(face.dimensions(), (4, 4));
+                assert!(face.as_raw().iter().all(|&v| v == i as u8), "face {i}");
+            }
+
+            // Missing the last face
+            let truncated = &data[..data.len() - 64 * (faces - 5)];
+            assert!(decode_cube_faces(truncated).is_err());
+        }
+
+        // Not marked as a cubemap
+        let mut data = make_cubemap(2, 0xffff, 6);
+        data[20..24].copy_from_slice(&0u32.to_le_bytes());
+        assert!(matches!(
+            decode_cube_faces(&data),
+            Err(TextureError::NotCubemap)
+        ));
+    }
+}
@@ -0,0 +1,363 @@
+//! Detail props, which are the grass and other small foliage scattered over the ground.
+//! vbsp places them from the `detail.vbsp` file and stores them in the `dprp` game lump. Most are
+//! sprites cut out of one sheet, the map's `detailmaterial`, which turn to face the camera, while
+//! the rest are small models.
+//! They are only drawn near the camera, like the game does with `cl_detaildist`.
+
+use std::sync::Arc;
+
+use bevy::{
+    prelude::{Component, Mesh},
+    render::{mesh::Indices, render_resource::PrimitiveTopology},
+};
+
+use crate::{
+    lightmap::decode_luxel,
+    lump::{find_game_lump, read_records, LumpReader},
+    map::GameMap,
+    mesh::SCALE,
+};
+
+/// `dprp`, the id of the detail prop game lump
+const DETAIL_PROP_LUMP_ID: i32 = i32::from_be_bytes(*b"dprp");
+/// The only version of the lump which has sprites, and the one every game uses
+const DETAIL_PROP_VERSION: u16 = 4;
+/// Length of the model names in the detail prop dictionary
+const DETAIL_NAME_LENGTH: usize = 128;
+/// Size of a `DetailObjectLump_t`
+const DETAIL_PROP_SIZE: usize = 52;
+/// The sprite sheet used when the worldspawn doesn't have a `detailmaterial`
+pub const DEFAULT_DETAIL_MATERIAL: &str = "detail/detailsprites";
+
+/// A rectangle of the detail material, which sprites are drawn with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DetailSprite {
+    /// The upper left and lower right corners of the quad in source units, relative to the prop
+    pub ul: [f32; 2],
+    pub lr: [f32; 2],
+    /// The texture coordinates of the corners
+    pub tex_ul: [f32; 2],
+    pub tex_lr: [f32; 2],
+}
+impl DetailSprite {
+    /// The quad of the sprite, facing +Z, with x to the right and y up.
+    pub fn mesh(&self) -> Mesh {
+        let [left, top] = self.ul.map(|v| v * SCALE);
+        let [right, bottom] = self.lr.map(|v| v * SCALE);
+        let [u0, v0] = self.tex_ul;
+        let [u1, v1] = self.tex_lr;
+
+        let positions = vec![
+            [left, bottom, 0.0],
+            [right, bottom, 0.0],
+            [right, top, 0.0],
+            [left, top, 0.0],
+        ];
+        let uvs = vec![[u0, v1], [u1, v1], [u1, v0], [u0, v0]];
+
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 0.0, 1.0]; 4]);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+        mesh.set_indices(Some(Indices::U32(vec![0, 1, 2, 0, 2, 3])));
+        mesh
+    }
+}
+
+/// Which way a detail prop faces
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DetailOrientation {
+    /// Fixed, by the prop's angles
+    #[default]
+    Normal,
+    /// Always facing the camera
+    ScreenAligned,
+    /// Turning around its vertical axis to face the camera
+    ScreenAlignedVertical,
+}
+impl DetailOrientation {
+    pub fn from_value(value: u8) -> DetailOrientation {
+        match value {
+            1 => DetailOrientation::ScreenAligned,
+            2 => DetailOrientation::ScreenAlignedVertical,
+            _ => DetailOrientation::Normal,
+        }
+    }
+}
+
+/// What a detail prop is drawn with
+#[derive(Debug, Clone, PartialEq)]
+pub enum DetailKind {
+    /// Ex: `models/props_foliage/grass_cluster01.mdl`
+    Model(Arc<str>),
+    /// Index into the sprites of the map
+    // TODO: the cross and triangle shapes are drawn as a single sprite
+    Sprite(usize),
+}
+
+/// A detail prop, as stored in the `dprp` game lump
+#[derive(Debug, Clone, PartialEq)]
+pub struct DetailProp {
+    pub kind: DetailKind,
+    /// In source coordinates
+    pub origin: [f32; 3],
+    /// Pitch, yaw and roll in degrees
+    pub angles: [f32; 3],
+    pub orientation: DetailOrientation,
+    /// Only used by sprites
+    pub scale: f32,
+    /// The light at the prop, in linear color
+    pub lighting: [f32; 3],
+}
+
+/// The detail props of a map, along with the sprites that they use.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DetailProps {
+    pub sprites: Vec<DetailSprite>,
+    pub props: Vec<DetailProp>,
+}
+impl DetailProps {
+    /// Read the detail props of the map. Maps without the lump have no detail props.
+    pub fn from_bsp(data: &[u8]) -> eyre::Result<DetailProps> {
+        match find_game_lump(data, DETAIL_PROP_LUMP_ID)? {
+            Some((lump, version)) => DetailProps::parse(&lump, version),
+            None => Ok(DetailProps::default()),
+        }
+    }
+
+    /// Parse the `dprp` game lump: the dictionary of model names, then of sprites, and then the
+    /// props themselves.
+    fn parse(lump: &[u8], version: u16) -> eyre::Result<DetailProps> {
+        if version != DETAIL_PROP_VERSION {
+            eyre::bail!("Unsupported detail prop lump version {version}");
+        }
+
+        let mut r = LumpReader::new(lump);
+        let name_count = r.i32()?;
+        let names = (0..name_count)
+            .map(|_| {
+                let name = r.bytes::<DETAIL_NAME_LENGTH>()?;
+                let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+                Ok(Arc::from(
+                    String::from_utf8_lossy(&name[..end]).to_lowercase(),
+                ))
+            })
+            .collect::<eyre::Result<Vec<Arc<str>>>>()?;
+
+        let sprite_count = r.i32()?.max(0) as usize;
+        let sprites = (0..sprite_count)
+            .map(|_| {
+                Ok(DetailSprite {
+                    ul: [r.f32()?, r.f32()?],
+                    lr: [r.f32()?, r.f32()?],
+                    tex_ul: [r.f32()?, r.f32()?],
+                    tex_lr: [r.f32()?, r.f32()?],
+                })
+            })
+            .collect::<eyre::Result<Vec<_>>>()?;
+
+        let prop_count = r.i32()?.max(0) as usize;
+        let props = r.remaining();
+        if props.len() < prop_count * DETAIL_PROP_SIZE {
+            eyre::bail!("Detail prop lump is too short for its {prop_count} props");
+        }
+
+        let props = read_records(
+            &props[..prop_count * DETAIL_PROP_SIZE],
+            DETAIL_PROP_SIZE,
+            |r| {
+                let origin = r.vec3()?;
+                let angles = r.vec3()?;
+                let model = r.u16()? as usize;
+                let _leaf = r.u16()?;
+                let lighting = decode_luxel(&r.bytes::<4>()?);
+                // Light styles, sway amount, and the angle and size of shapes
+                r.skip(4 + 1 + 1 + 1 + 1);
+                let orientation = DetailOrientation::from_value(r.u8()?);
+                r.skip(3);
+                let prop_type = r.u8()?;
+                r.skip(3);
+                let scale = r.f32()?;
+
+                let kind = if prop_type == 0 {
+                    let name = names.get(model).cloned().ok_or_else(|| {
+                        eyre::eyre!("Detail prop has invalid model index {model}")
+                    })?;
+                    DetailKind::Model(name)
+                } else {
+                    if model >= sprites.len() {
+                        eyre::bail!("Detail prop has invalid sprite index {model}");
+                    }
+                    DetailKind::Sprite(model)
+                };
+
+                Ok(DetailProp {
+                    kind,
+                    origin,
+                    angles,
+                    orientation,
+                    scale,
+                    lighting,
+                })
+            },
+        )?;
+
+        Ok(DetailProps { sprites, props })
+    }
+
+    /// The models used by the detail props which aren't sprites
+    pub fn models(&self) -> impl Iterator<Item = &Arc<str>> {
+        self.props.iter().filter_map(|prop| match &prop.kind {
+            DetailKind::Model(model) => Some(model),
+            DetailKind::Sprite(_) => None,
+        })
+    }
+}
+
+/// The material which the detail sprites of the map are cut out of, the worldspawn's
+/// `detailmaterial`.
+pub fn detail_material(map: &GameMap) -> String {
+    map.bsp
+        .entities
+        .iter()
+        .find_map(|raw_ent| {
+            let mut class_name = "";
+            let mut material = None;
+            for (key, value) in raw_ent.properties() {
+                match key {
+                    "classname" => class_name = value,
+                    "detailmaterial" => material = Some(value),
+                    _ => {}
+                }
+            }
+            (class_name == "worldspawn").then_some(material)
+        })
+        .flatten()
+        .filter(|material| !material.is_empty())
+        .map_or_else(
+            || DEFAULT_DETAIL_MATERIAL.to_string(),
+            |material| material.replace('\\', "/").to_lowercase(),
+        )
+}
+
+/// A spawned detail prop, which is hidden and turned to face the camera by `update_detail_props`.
+#[derive(Debug, Clone, Copy, PartialEq, Component)]
+pub struct DetailInstance {
+    pub orientation: DetailOrientation,
+    /// The scale of the prop when it isn't fading out
+    pub scale: f32,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lump::{make_game_lump_bsp, write_names, write_records};
+
+    use super::*;
+
+    fn write_prop(
+        data: &mut Vec<u8>,
+        origin: [f32; 3],
+        model: u16,
+        lighting: [u8; 4],
+        orientation: u8,
+        prop_type: u8,
+        scale: f32,
+    ) {
+        for v in origin.into_iter().chain([0.0, 45.0, 0.0]) {
+            data.extend(v.to_le_bytes());
+        }
+        data.extend(model.to_le_bytes());
+        data.extend(7u16.to_le_bytes());
+        data.extend(lighting);
+        data.extend([0; 8]);
+        data.push(orientation);
+        data.extend([0; 3]);
+        data.push(prop_type);
+        data.extend([0; 3]);
+        data.extend(scale.to_le_bytes());
+    }
+
+    fn make_lump() -> Vec<u8> {
+        let mut data = Vec::new();
+        let names = ["models/Props_Foliage/Grass01.mdl"];
+        write_names(&mut data, &names, DETAIL_NAME_LENGTH);
+        let sprites = [[-8.0f32, 16.0, 8.0, 0.0, 0.0, 0.25, 0.5, 0.5]];
+        write_records(&mut data, &sprites, |data, sprite| {
+            data.extend(sprite.iter().flat_map(|v| v.to_le_bytes()))
+        });
+        let props = [
+            ([1.0, 2.0, 3.0], [255, 128, 0, 0], 2, 1, 0.5),
+            ([-4.0, 0.0, 0.0], [255, 255, 255, 1], 0, 0, 1.0),
+        ];
+        write_records(
+            &mut data,
+            &props,
+            |data, &(origin, lighting, orientation, ty, scale)| {
+                write_prop(data, origin, 0, lighting, orientation, ty, scale)
+            },
+        );
+        data
+    }
+
+    #[test]
+    fn test_detail_props() {
+        let bsp = make_game_lump_bsp(&[(DETAIL_PROP_LUMP_ID, 4, make_lump())]);
+        let props = DetailProps::from_bsp(&bsp).unwrap();
+        assert_eq!(
+            props.sprites,
+            vec![DetailSprite {
+                ul: [-8.0, 16.0],
+                lr: [8.0, 0.0],
+                tex_ul: [0.0, 0.25],
+                tex_lr: [0.5, 0.5],
+            }]
+        );
+        assert_eq!(
+            props.props,
+            vec![
+                DetailProp {
+                    kind: DetailKind::Sprite(0),
+                    origin: [1.0, 2.0, 3.0],
+                    angles: [0.0, 45.0, 0.0],
+                    orientation: DetailOrientation::ScreenAlignedVertical,
+                    scale: 0.5,
+                    lighting: [255.0, 128.0, 0.0].map(|c| c * (1.0 / 255.0)),
+                },
+                DetailProp {
+                    kind: DetailKind::Model(Arc::from("models/props_foliage/grass01.mdl")),
+                    origin: [-4.0, 0.0, 0.0],
+                    angles: [0.0, 45.0, 0.0],
+                    orientation: DetailOrientation::Normal,
+                    scale: 1.0,
+                    lighting: [255.0 * (2.0 / 255.0); 3],
+                },
+            ]
+        );
+        assert_eq!(
+            props.models().collect::<Vec<_>>(),
+            vec![&Arc::from("models/props_foliage/grass01.mdl")]
+        );
+
+        assert!(DetailProps::parse(&make_lump(), 3).is_err());
+        let lump = make_lump();
+        assert!(DetailProps::parse(&lump[..lump.len() - 10], 4).is_err());
+    }
+
+    #[test]
+    fn test_sprite_mesh() {
+        let sprite = DetailSprite {
+            ul: [-8.0, 16.0],
+            lr: [8.0, 0.0],
+            tex_ul: [0.0, 0.25],
+            tex_lr: [0.5, 0.5],
+        };
+        let mesh = sprite.mesh();
+        let positions = mesh
+            .attribute(Mesh::ATTRIBUTE_POSITION)
+            .unwrap()
+            .as_float3()
+            .unwrap();
+        assert_eq!(positions[0], [-8.0 * SCALE, 0.0, 0.0]);
+        assert_eq!(positions[2], [8.0 * SCALE, 16.0 * SCALE, 0.0]);
+    }
+}
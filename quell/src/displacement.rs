@@ -0,0 +1,457 @@
+//! Displacements, which are faces subdivided into a grid of vertices that are each offset to form
+//! terrain.
+//! vbsp gives us each displacement's vertices, but not which displacements neighbor each other,
+//! so that is read from the dispinfo lump to smooth the normals across the seams between them.
+use std::collections::HashMap;
+
+use bevy::prelude::Vec3;
+
+use crate::lump::{read_records, LumpType, Lumps};
+
+/// Size of a `ddispinfo_t`
+const DISP_INFO_SIZE: usize = 176;
+/// Neighbor index of an unused neighbor slot
+const NO_NEIGHBOR: u16 = 0xFFFF;
+/// Displacements can be at most `2^4` quads wide
+pub const MAX_DISPLACEMENT_POWER: u32 = 4;
+/// How close (in source units) vertices of neighboring displacements have to be to be considered
+/// the same vertex
+const WELD_DISTANCE: f32 = 0.5;
+
+/// The parts of a `ddispinfo_t` that we use
+#[derive(Debug, Clone, PartialEq)]
+pub struct DispInfo {
+    pub start_position: [f32; 3],
+    /// Index of the displacement's first vertex in the displacement vertices
+    pub vertex_start: usize,
+    /// The displacement is `2^power` quads wide
+    pub power: u32,
+    /// The face that was turned into this displacement
+    pub map_face: usize,
+    /// Indices of the displacements which share an edge or a corner with this one
+    pub neighbors: Vec<usize>,
+}
+
+/// The displacements of the map, by their index in the dispinfo lump
+#[derive(Debug, Clone, Default)]
+pub struct Displacements {
+    pub infos: Vec<DispInfo>,
+    /// Face index to displacement index
+    by_face: HashMap<usize, usize>,
+}
+impl Displacements {
+    pub fn from_bsp(data: &[u8]) -> eyre::Result<Displacements> {
+        let lumps = Lumps::new(data)?;
+
+        let infos = read_records(&lumps.get(LumpType::DispInfo)?, DISP_INFO_SIZE, |r| {
+            let start_position = r.vec3()?;
+            let vertex_start = r.i32()? as usize;
+            // triangle tag start
+            r.skip(4);
+            let power = r.i32()? as u32;
+            // minimum tessellation, smoothing angle, contents
+            r.skip(4 + 4 + 4);
+            let map_face = r.u16()? as usize;
+            // padding, lightmap alpha start, lightmap sample position start
+            r.skip(2 + 4 + 4);
+
+            let mut neighbors = Vec::new();
+            let mut add_neighbor = |neighbor: u16| {
+                let neighbor = neighbor as usize;
+                if neighbor != NO_NEIGHBOR as usize && !neighbors.contains(&neighbor) {
+                    neighbors.push(neighbor);
+                }
+            };
+
+            // Each edge can have two neighbors, if they are of different sizes
+            for _ in 0..4 * 2 {
+                add_neighbor(r.u16()?);
+                // orientation, span, neighbor span, padding
+                r.skip(1 + 1 + 1 + 1);
+            }
+
+            for _ in 0..4 {
+                let corner = [r.u16()?, r.u16()?, r.u16()?, r.u16()?];
+                let count = r.u8()? as usize;
+                for neighbor in corner.into_iter().take(count) {
+                    add_neighbor(neighbor);
+                }
+                // padding
+                r.skip(1);
+            }
+
+            Ok(DispInfo {
+                start_position,
+                vertex_start,
+                power,
+                map_face,
+                neighbors,
+            })
+        })?;
+
+        let by_face = infos
+            .iter()
+            .enumerate()
+            .map(|(i, info)| (info.map_face, i))
+            .collect();
+
+        Ok(Displacements { infos, by_face })
+    }
+
+    /// Get the displacement made from the face, along with its index.
+    pub fn for_face(&self, face: usize) -> Option<(usize, &DispInfo)> {
+        let i = *self.by_face.get(&face)?;
+        Some((i, &self.infos[i]))
+    }
+}
+
+/// A vertex of a displacement, relative to its position on the flat face
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DispVertex {
+    /// Normalized direction of the offset
+    pub vector: [f32; 3],
+    pub distance: f32,
+    /// How much of a `WorldVertexTransition` material's second texture is used, in `0..=255`
+    pub alpha: f32,
+}
+
+/// The vertices of a displacement, in source coordinates.
+/// The vertices are in rows of `verts_wide`, where rows go from the start corner towards the next
+/// corner of the face, and columns go from the start corner towards the previous corner.
+#[derive(Debug, Clone)]
+pub struct DispGrid {
+    pub verts_wide: usize,
+    /// Positions on the flat face, before being displaced. Used for texture coordinates.
+    pub flat: Vec<[f32; 3]>,
+    pub positions: Vec<[f32; 3]>,
+    pub alphas: Vec<f32>,
+    /// Indices of the vertices of each triangle, wound counter-clockwise when seen from the front
+    pub triangles: Vec<[usize; 3]>,
+}
+impl DispGrid {
+    /// Build the grid of the displacement.
+    /// `corners` are the vertices of the face, in the order of its edges.
+    /// `vertices` are the displacement's vertices, which there must be one of for each vertex of
+    /// the grid.
+    pub fn new(
+        corners: [[f32; 3]; 4],
+        start_position: [f32; 3],
+        power: u32,
+        vertices: &[DispVertex],
+    ) -> eyre::Result<DispGrid> {
+        if power == 0 || power > MAX_DISPLACEMENT_POWER {
+            eyre::bail!("Displacement has an invalid power of {power}");
+        }
+
+        let verts_wide = (1 << power) + 1;
+        if vertices.len() != verts_wide * verts_wide {
+            eyre::bail!(
+                "Displacement of power {power} has {} vertices rather than {}",
+                vertices.len(),
+                verts_wide * verts_wide
+            );
+        }
+
+        let corners = corners.map(Vec3::from);
+        let start_position = Vec3::from(start_position);
+
+        // The grid starts from the corner that is closest to the start position
+        let base_i = (0..4)
+            .min_by(|&a, &b| {
+                let a = corners[a].distance_squared(start_position);
+                let b = corners[b].distance_squared(start_position);
+                a.total_cmp(&b)
+            })
+            .unwrap();
+
+        let low_base = corners[base_i];
+        let low_ray = corners[(base_i + 1) % 4] - low_base;
+        let high_base = corners[(base_i + 3) % 4];
+        let high_ray = corners[(base_i + 2) % 4] - high_base;
+
+        let mut flat = Vec::with_capacity(vertices.len());
+        let mut positions = Vec::with_capacity(vertices.len());
+        let mut alphas = Vec::with_capacity(vertices.len());
+        let last = (verts_wide - 1) as f32;
+        for y in 0..verts_wide {
+            let fy = y as f32 / last;
+            let mid_base = low_base + low_ray * fy;
+            let mid_ray = high_base + high_ray * fy - mid_base;
+
+            for x in 0..verts_wide {
+                let fx = x as f32 / last;
+                let vertex = vertices[x + y * verts_wide];
+
+                let flat_pos = mid_base + mid_ray * fx;
+                flat.push(flat_pos.to_array());
+                positions.push((flat_pos + Vec3::from(vertex.vector) * vertex.distance).to_array());
+                alphas.push(vertex.alpha);
+            }
+        }
+
+        let mut triangles = grid_triangles(verts_wide);
+
+        // The grid's orientation depends on which corner it starts from, so the triangles are
+        // flipped if they don't face the same way as the face.
+        // The flat grid is planar, so one triangle is enough to tell.
+        let [a, b, c] = triangles[0].map(|i| Vec3::from(flat[i]));
+        if (b - a).cross(c - a).dot(face_normal(corners)) < 0.0 {
+            for triangle in &mut triangles {
+                triangle.swap(1, 2);
+            }
+        }
+
+        Ok(DispGrid {
+            verts_wide,
+            flat,
+            positions,
+            alphas,
+            triangles,
+        })
+    }
+
+    /// Whether the vertex is on one of the grid's edges
+    pub fn is_boundary(&self, i: usize) -> bool {
+        let (x, y) = (i % self.verts_wide, i / self.verts_wide);
+        let last = self.verts_wide - 1;
+        x == 0 || y == 0 || x == last || y == last
+    }
+
+    /// The sum of the normals of the triangles around each vertex, weighted by their area.
+    pub fn normal_sums(&self) -> Vec<Vec3> {
+        let mut sums = vec![Vec3::ZERO; self.positions.len()];
+        for triangle in &self.triangles {
+            let [a, b, c] = triangle.map(|i| Vec3::from(self.positions[i]));
+            // The length of the cross product is twice the area of the triangle
+            let normal = (b - a).cross(c - a);
+            for i in *triangle {
+                sums[i] += normal;
+            }
+        }
+
+        sums
+    }
+
+    /// Smooth normals for each vertex, which are stitched with the given neighboring displacements
+    /// so that there is no visible seam between them.
+    pub fn smooth_normals(&self, neighbors: &[DispGrid]) -> Vec<[f32; 3]> {
+        let mut sums = self.normal_sums();
+        let own_sums = sums.clone();
+
+        for neighbor in neighbors {
+            let neighbor_sums = neighbor.normal_sums();
+            for (i, sum) in sums.iter_mut().enumerate() {
+                if !self.is_boundary(i) {
+                    continue;
+                }
+
+                let position = Vec3::from(self.positions[i]);
+                let shared = (0..neighbor.positions.len()).find(|&j| {
+                    neighbor.is_boundary(j)
+                        && Vec3::from(neighbor.positions[j]).distance_squared(position)
+                            < WELD_DISTANCE * WELD_DISTANCE
+                });
+                if let Some(j) = shared {
+                    *sum += neighbor_sums[j];
+                }
+            }
+        }
+
+        sums.iter()
+            .zip(&own_sums)
+            .map(|(sum, own)| {
+                sum.try_normalize()
+                    .or_else(|| own.try_normalize())
+                    .unwrap_or(Vec3::ZERO)
+                    .to_array()
+            })
+            .collect()
+    }
+}
+
+/// The triangles of a grid `verts_wide` vertices wide, wound counter-clockwise in grid space.
+/// Like the game, the diagonal of each quad alternates so that the triangles form a diamond
+/// pattern.
+pub fn grid_triangles(verts_wide: usize) -> Vec<[usize; 3]> {
+    let quads_wide = verts_wide - 1;
+    let mut triangles = Vec::with_capacity(quads_wide * quads_wide * 2);
+    for y in 0..quads_wide {
+        for x in 0..quads_wide {
+            let v1 = x + y * verts_wide;
+            let v2 = v1 + 1;
+            let v3 = v1 + verts_wide;
+            let v4 = v3 + 1;
+
+            if !v1.is_multiple_of(2) {
+                triangles.push([v2, v3, v1]);
+                triangles.push([v4, v3, v2]);
+            } else {
+                triangles.push([v4, v3, v1]);
+                triangles.push([v4, v1, v2]);
+            }
+        }
+    }
+
+    triangles
+}
+
+/// The normal of the front of the face.
+/// The game's faces are wound clockwise when seen from the front.
+fn face_normal(corners: [Vec3; 4]) -> Vec3 {
+    let normal: Vec3 = (0..4).map(|i| corners[i].cross(corners[(i + 1) % 4])).sum();
+    -normal
+}
+
+#[cfg(test)]
+mod test {
+    use bevy::prelude::Vec3;
+
+    use super::{grid_triangles, DispGrid, DispVertex};
+
+    /// A square on the `z = 0` plane, wound clockwise when seen from above like the game's faces
+    const UP_CORNERS: [[f32; 3]; 4] = [
+        [0.0, 0.0, 0.0],
+        [0.0, 64.0, 0.0],
+        [64.0, 64.0, 0.0],
+        [64.0, 0.0, 0.0],
+    ];
+
+    fn flat_vertices(power: u32) -> Vec<DispVertex> {
+        let verts_wide = (1 << power) + 1;
+        vec![
+            DispVertex {
+                vector: [0.0, 0.0, 1.0],
+                distance: 0.0,
+                alpha: 0.0,
+            };
+            verts_wide * verts_wide
+        ]
+    }
+
+    fn assert_close(a: [f32; 3], b: [f32; 3]) {
+        let close = a.iter().zip(&b).all(|(a, b)| (a - b).abs() < 1e-4);
+        assert!(close, "{a:?} != {b:?}");
+    }
+
+    fn triangle_normal(grid: &DispGrid, triangle: [usize; 3]) -> Vec3 {
+        let [a, b, c] = triangle.map(|i| Vec3::from(grid.positions[i]));
+        (b - a).cross(c - a)
+    }
+
+    #[test]
+    fn test_grid_sizes() {
+        for power in 1..=4 {
+            let grid = DispGrid::new(UP_CORNERS, [0.0; 3], power, &flat_vertices(power)).unwrap();
+            let verts_wide = (1 << power) + 1;
+            assert_eq!(grid.verts_wide, verts_wide);
+            assert_eq!(grid.positions.len(), verts_wide * verts_wide);
+            assert_eq!(
+                grid.triangles.len(),
+                (verts_wide - 1) * (verts_wide - 1) * 2
+            );
+            assert_eq!(
+                grid.positions[verts_wide * verts_wide - 1],
+                [64.0, 64.0, 0.0]
+            );
+        }
+
+        assert!(DispGrid::new(UP_CORNERS, [0.0; 3], 0, &flat_vertices(0)).is_err());
+        assert!(DispGrid::new(UP_CORNERS, [0.0; 3], 5, &flat_vertices(5)).is_err());
+        // Too few vertices for the power
+        assert!(DispGrid::new(UP_CORNERS, [0.0; 3], 3, &flat_vertices(2)).is_err());
+    }
+
+    #[test]
+    fn test_grid_triangles_wind_consistently() {
+        let verts_wide = 5;
+        for triangle in grid_triangles(verts_wide) {
+            let [a, b, c] = triangle.map(|i| [(i % verts_wide) as f32, (i / verts_wide) as f32]);
+            let cross = (b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0]);
+            assert!(cross > 0.0, "{triangle:?} is not counter-clockwise");
+        }
+    }
+
+    #[test]
+    fn test_winding_faces_front() {
+        // Each start corner gives the grid a different orientation
+        for start in UP_CORNERS {
+            let grid = DispGrid::new(UP_CORNERS, start, 2, &flat_vertices(2)).unwrap();
+            for triangle in &grid.triangles {
+                assert!(triangle_normal(&grid, *triangle).z > 0.0);
+            }
+            for normal in grid.smooth_normals(&[]) {
+                assert_close(normal, [0.0, 0.0, 1.0]);
+            }
+        }
+
+        // Winding the face the other way makes it face down
+        let mut down_corners = UP_CORNERS;
+        down_corners.reverse();
+        let grid = DispGrid::new(down_corners, [0.0; 3], 2, &flat_vertices(2)).unwrap();
+        for triangle in &grid.triangles {
+            assert!(triangle_normal(&grid, *triangle).z < 0.0);
+        }
+        for normal in grid.smooth_normals(&[]) {
+            assert_close(normal, [0.0, 0.0, -1.0]);
+        }
+    }
+
+    #[test]
+    fn test_smooth_normals() {
+        // Raise the middle vertex of a power 1 displacement into a peak
+        let mut vertices = flat_vertices(1);
+        vertices[4].distance = 32.0;
+        let grid = DispGrid::new(UP_CORNERS, [0.0; 3], 1, &vertices).unwrap();
+        assert_eq!(grid.positions[4], [32.0, 32.0, 32.0]);
+
+        let normals = grid.smooth_normals(&[]);
+        // The peak is surrounded evenly, so it points straight up
+        assert_close(normals[4], [0.0, 0.0, 1.0]);
+        // The other vertices lean away from the peak
+        for (i, normal) in normals.iter().enumerate() {
+            let normal = Vec3::from(*normal);
+            assert!((normal.length() - 1.0).abs() < 1e-4);
+            assert!(normal.z > 0.0);
+            if i != 4 {
+                let away = Vec3::from(grid.positions[i]) - Vec3::from(grid.positions[4]);
+                assert!(normal.x * away.x + normal.y * away.y >= 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stitch_neighbors() {
+        // A flat displacement, next to one that slopes up away from it.
+        // They share the edge along `x = 64`.
+        let flat = DispGrid::new(UP_CORNERS, [0.0; 3], 2, &flat_vertices(2)).unwrap();
+
+        let corners = UP_CORNERS.map(|[x, y, z]| [x + 64.0, y, z]);
+        let mut vertices = flat_vertices(2);
+        let sloped = DispGrid::new(corners, corners[0], 2, &vertices).unwrap();
+        for (i, vertex) in vertices.iter_mut().enumerate() {
+            vertex.distance = sloped.flat[i][0] - 64.0;
+        }
+        let sloped = DispGrid::new(corners, corners[0], 2, &vertices).unwrap();
+
+        let flat_normals = flat.smooth_normals(std::slice::from_ref(&sloped));
+        let sloped_normals = sloped.smooth_normals(std::slice::from_ref(&flat));
+
+        let mut shared = 0;
+        for (i, position) in flat.positions.iter().enumerate() {
+            let Some(j) = sloped.positions.iter().position(|p| p == position) else {
+                // Vertices away from the seam are unaffected
+                assert_close(flat_normals[i], [0.0, 0.0, 1.0]);
+                continue;
+            };
+
+            shared += 1;
+            assert_close(flat_normals[i], sloped_normals[j]);
+            // Tilted between the two surfaces
+            assert!(flat_normals[i][0] < 0.0 && flat_normals[i][2] > 0.0);
+        }
+        assert_eq!(shared, 5);
+
+        // Without the neighbor, the seam is not smoothed
+        assert_close(flat.smooth_normals(&[])[4], [0.0, 0.0, 1.0]);
+    }
+}
@@ -1,6 +1,23 @@
+pub mod ambient;
+pub mod asset;
+pub mod brush;
+pub mod cache;
 pub mod conf;
+pub mod cubemap;
 pub mod data;
+pub mod detail;
+pub mod displacement;
+pub mod light;
+pub mod lightmap;
+pub mod loading;
+pub mod lump;
 pub mod map;
 pub mod material;
 pub mod mesh;
+pub mod model;
+pub mod overlay;
+pub mod prop;
+pub mod reload;
+pub mod skybox;
 pub mod util;
+pub mod vis;
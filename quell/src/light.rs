@@ -0,0 +1,647 @@
+//! Light entities, read from their keyvalues the same way vrad does and converted into bevy's
+//! physical units.
+//! Source describes a light by how bright it makes a surface at some distance, with `1.0` being a
+//! fully lit surface. Bevy's lights are physical: a diffuse surface reflects `illuminance / PI` of
+//! its color, and point and spot lights fall off with the inverse square of the distance. So we
+//! pick the intensity which makes the surface as bright as it is in the game at the distance the
+//! light's brightness is given for.
+//! Lights with linear or constant attenuation can't be represented exactly, they match at that
+//! distance and their range is cut off where the game's falloff would make them too dark to see.
+
+use std::f32::consts::{FRAC_PI_2, PI};
+
+use bevy::{
+    pbr::{AmbientLight, DirectionalLight, PointLight, SpotLight},
+    prelude::{Color, Transform, Vec3},
+};
+
+use crate::{
+    mesh::{degrees_to_radians, rotate, scale, SCALE},
+    prop::EntityKeyValues,
+    util::parse_vector,
+};
+
+/// The distance, in source units, at which the brightness of a light with the usual attenuation
+/// keys applies
+pub const REFERENCE_DISTANCE: f32 = 100.0;
+/// How bright a surface has to be lit for the light to still be visible on it, this is where the
+/// range of a light ends
+pub const MIN_LIGHT_VALUE: f32 = 1.0 / 255.0;
+/// Lights which don't fall off (only constant attenuation) are limited to the size of a map
+pub const MAX_LIGHT_RANGE: f32 = 32768.0;
+/// Spawnflag of `light` and `light_spot` which makes them start off
+const SF_LIGHT_START_OFF: u32 = 1;
+/// Spawnflag of `point_spotlight` which stops it from casting a dynamic light
+const SF_SPOTLIGHT_NO_DYNAMIC_LIGHT: u32 = 2;
+/// Values of the legacy `angle` key for lights pointing straight up or down
+const ANGLE_UP: f32 = -1.0;
+const ANGLE_DOWN: f32 = -2.0;
+
+/// Parse a light color keyvalue, ex: `_light` of `255 240 200 300`, into a linear color and the
+/// brightness it is scaled by.
+/// Like vrad, a single value is a grey light and a missing brightness leaves the color unscaled.
+pub fn parse_light_value(value: &str) -> Option<([f32; 3], f32)> {
+    let values = value
+        .split_whitespace()
+        .map(|v| v.parse::<f32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let (color, brightness) = match values.as_slice() {
+        [v] => ([*v; 3], 255.0),
+        [r, g, b] => ([*r, *g, *b], 255.0),
+        [r, g, b, brightness, ..] => ([*r, *g, *b], *brightness),
+        _ => return None,
+    };
+
+    // vrad treats the color as gamma 2.2
+    let color = color.map(|c| (c.max(0.0) / 255.0).powf(2.2));
+    Some((color, brightness / 255.0))
+}
+
+/// The direction, in source coordinates, which a light shines in.
+/// Mirrors vrad's `SetupLightNormalFromProps`, where the `angle` and `pitch` keys override the
+/// yaw and pitch of `angles`. Unlike other entities, negative pitch points down.
+pub fn light_direction(angles: [f32; 3], angle: f32, pitch: f32) -> [f32; 3] {
+    if angle == ANGLE_UP {
+        return [0.0, 0.0, 1.0];
+    } else if angle == ANGLE_DOWN {
+        return [0.0, 0.0, -1.0];
+    }
+
+    let yaw = degrees_to_radians(if angle == 0.0 { angles[1] } else { angle });
+    let pitch = degrees_to_radians(if pitch == 0.0 { angles[0] } else { pitch });
+    [
+        yaw.cos() * pitch.cos(),
+        yaw.sin() * pitch.cos(),
+        pitch.sin(),
+    ]
+}
+
+/// The transform of a light at `origin` shining in `direction`, both in source coordinates
+fn light_transform(origin: [f32; 3], direction: [f32; 3]) -> Transform {
+    let direction = Vec3::from(rotate(direction)).normalize_or_zero();
+    // Looking along the up vector would leave the rotation undefined
+    let up = if direction.y.abs() > 0.999 {
+        Vec3::X
+    } else {
+        Vec3::Y
+    };
+    Transform::from_translation(Vec3::from(rotate(scale(origin)))).looking_to(direction, up)
+}
+
+/// The distance attenuation of a light, which divides its brightness as
+/// `constant + linear * distance + quadratic * distance^2`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attenuation {
+    pub constant: f32,
+    pub linear: f32,
+    pub quadratic: f32,
+}
+impl Attenuation {
+    /// The attenuation from the `_constant_attn`, `_linear_attn` and `_quadratic_attn` keys,
+    /// which are purely quadratic when none are set.
+    pub fn new(constant: f32, linear: f32, quadratic: f32) -> Attenuation {
+        if constant == 0.0 && linear == 0.0 && quadratic == 0.0 {
+            Attenuation {
+                constant: 0.0,
+                linear: 0.0,
+                quadratic: 1.0,
+            }
+        } else {
+            Attenuation {
+                constant,
+                linear,
+                quadratic,
+            }
+        }
+    }
+
+    /// The attenuation from `_fifty_percent_distance` and `_zero_percent_distance`.
+    /// Like vrad, this is fitted so that the light is at full brightness at the light, half at
+    /// `fifty` and 1/256th at `zero`. When that would need the light to get brighter with
+    /// distance, the zero distance is kept and the fifty percent distance moves instead.
+    /// Without a usable zero distance it is just quadratic.
+    pub fn from_distances(fifty: f32, zero: Option<f32>) -> Attenuation {
+        let Some(zero) = zero.filter(|zero| *zero > fifty) else {
+            return Attenuation {
+                constant: 1.0,
+                linear: 0.0,
+                quadratic: 1.0 / (fifty * fifty),
+            };
+        };
+
+        // Solve `q * fifty^2 + l * fifty = 1` and `q * zero^2 + l * zero = 255`
+        let quadratic = (255.0 - zero / fifty) / (zero * (zero - fifty));
+        let linear = (1.0 - quadratic * fifty * fifty) / fifty;
+        if quadratic < 0.0 {
+            // The zero distance is so far that even a linear falloff doesn't reach it
+            Attenuation {
+                constant: 1.0,
+                linear: 1.0 / fifty,
+                quadratic: 0.0,
+            }
+        } else if linear < 0.0 {
+            // The falloff would have to get brighter before it gets darker
+            Attenuation {
+                constant: 1.0,
+                linear: 0.0,
+                quadratic: 255.0 / (zero * zero),
+            }
+        } else {
+            Attenuation {
+                constant: 1.0,
+                linear,
+                quadratic,
+            }
+        }
+    }
+
+    /// What the brightness is divided by at `distance`
+    pub fn at(&self, distance: f32) -> f32 {
+        self.constant + self.linear * distance + self.quadratic * distance * distance
+    }
+
+    /// The distance at which the attenuation reaches `attenuation`, if it ever does
+    pub fn distance_to(&self, attenuation: f32) -> Option<f32> {
+        let c = self.constant - attenuation;
+        if c >= 0.0 {
+            return Some(0.0);
+        }
+
+        if self.quadratic > 0.0 {
+            let discriminant = self.linear * self.linear - 4.0 * self.quadratic * c;
+            Some((-self.linear + discriminant.sqrt()) / (2.0 * self.quadratic))
+        } else if self.linear > 0.0 {
+            Some(-c / self.linear)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightKind {
+    /// Shines in every direction, `light`
+    Point,
+    /// Shines in a cone, `light_spot` and the dynamic light of `point_spotlight`.
+    /// The angles are in degrees from the center of the cone.
+    Spot { inner_cone: f32, outer_cone: f32 },
+}
+
+/// A `light`, `light_spot` or `point_spotlight` entity
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapLight {
+    pub kind: LightKind,
+    /// In source coordinates
+    pub origin: [f32; 3],
+    /// The direction the light shines in, in source coordinates
+    pub direction: [f32; 3],
+    /// Linear color
+    pub color: [f32; 3],
+    /// How bright a surface is lit at `reference_distance`, where `1.0` is fully lit
+    pub brightness: f32,
+    pub attenuation: Attenuation,
+    /// The distance at which the light has its `brightness`, in source units
+    pub reference_distance: f32,
+    /// Where the light is cut off, from `_zero_percent_distance` or `_distance`
+    pub max_distance: Option<f32>,
+}
+impl MapLight {
+    /// Read the light from the keyvalues of an entity, if it is one which lights the map and
+    /// starts on.
+    pub fn from_properties<'a>(
+        properties: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Option<MapLight> {
+        let keyvalues = EntityKeyValues::from_properties(properties);
+        let class_name = keyvalues.get("classname")?;
+        let parse_f32 = |key| keyvalues.get(key)?.trim().parse::<f32>().ok();
+        let spawn_flags = keyvalues
+            .get("spawnflags")
+            .and_then(|flags| flags.trim().parse::<u32>().ok())
+            .unwrap_or(0);
+
+        let origin = keyvalues.get("origin").and_then(parse_vector)?;
+        let angles = keyvalues
+            .get("angles")
+            .and_then(parse_vector)
+            .unwrap_or_default();
+
+        if class_name == "point_spotlight" {
+            return Self::from_spotlight(&keyvalues, origin, angles, spawn_flags);
+        }
+
+        let kind = match class_name {
+            "light" => LightKind::Point,
+            "light_spot" => {
+                let outer_cone = parse_f32("_cone").unwrap_or(45.0);
+                LightKind::Spot {
+                    inner_cone: parse_f32("_inner_cone").unwrap_or(30.0).min(outer_cone),
+                    outer_cone,
+                }
+            }
+            _ => return None,
+        };
+        // We don't run the entity IO which would turn these on
+        if spawn_flags & SF_LIGHT_START_OFF != 0 {
+            return None;
+        }
+
+        let (color, brightness) = keyvalues
+            .get("_light")
+            .and_then(parse_light_value)
+            .unwrap_or(([1.0; 3], 200.0 / 255.0));
+        let direction = light_direction(
+            angles,
+            parse_f32("angle").unwrap_or(0.0),
+            parse_f32("pitch").unwrap_or(0.0),
+        );
+
+        let fifty = parse_f32("_fifty_percent_distance").filter(|fifty| *fifty > 0.0);
+        let zero = parse_f32("_zero_percent_distance").filter(|zero| *zero > 0.0);
+        let distance = parse_f32("_distance").filter(|distance| *distance > 0.0);
+        let (attenuation, reference_distance, max_distance) = match fifty {
+            Some(fifty) => (
+                Attenuation::from_distances(fifty, zero),
+                fifty,
+                zero.filter(|zero| *zero > fifty).or(distance),
+            ),
+            None => (
+                Attenuation::new(
+                    parse_f32("_constant_attn").unwrap_or(0.0),
+                    parse_f32("_linear_attn").unwrap_or(0.0),
+                    parse_f32("_quadratic_attn").unwrap_or(0.0),
+                ),
+                REFERENCE_DISTANCE,
+                distance,
+            ),
+        };
+        // With the distance keys the brightness is at the light itself, which can't be matched
+        // by an inverse square falloff, so it is moved out to the fifty percent distance
+        let brightness = match fifty {
+            Some(_) => brightness / attenuation.at(reference_distance),
+            None => brightness,
+        };
+
+        Some(MapLight {
+            kind,
+            origin,
+            direction,
+            color,
+            brightness,
+            attenuation,
+            reference_distance,
+            max_distance,
+        })
+    }
+
+    /// A `point_spotlight` draws a beam, and unless disabled lights what is in front of it.
+    /// The light covers the width of the beam at its end.
+    fn from_spotlight(
+        keyvalues: &EntityKeyValues,
+        origin: [f32; 3],
+        angles: [f32; 3],
+        spawn_flags: u32,
+    ) -> Option<MapLight> {
+        if spawn_flags & SF_SPOTLIGHT_NO_DYNAMIC_LIGHT != 0 {
+            return None;
+        }
+
+        let parse_f32 = |key| keyvalues.get(key)?.trim().parse::<f32>().ok();
+        let length = parse_f32("spotlightlength")
+            .filter(|length| *length > 0.0)
+            .unwrap_or(500.0);
+        let width = parse_f32("spotlightwidth")
+            .filter(|width| *width > 0.0)
+            .unwrap_or(50.0);
+        let outer_cone = (width / length).atan().to_degrees();
+
+        let color = keyvalues
+            .get("rendercolor")
+            .and_then(parse_vector)
+            .unwrap_or([255.0; 3])
+            .map(|c| (c.clamp(0.0, 255.0) / 255.0).powf(2.2));
+
+        // Unlike lights, the entity points the usual way, with positive pitch looking down
+        let direction = light_direction([-angles[0], angles[1], angles[2]], 0.0, 0.0);
+
+        // Fully bright at the light, and gone at the end of the beam
+        let attenuation = Attenuation::from_distances(length * 0.5, Some(length));
+        Some(MapLight {
+            kind: LightKind::Spot {
+                inner_cone: outer_cone * 0.5,
+                outer_cone,
+            },
+            origin,
+            direction,
+            color,
+            brightness: 1.0 / attenuation.at(length * 0.5),
+            attenuation,
+            reference_distance: length * 0.5,
+            max_distance: Some(length),
+        })
+    }
+
+    /// How bright a surface is lit at `distance` (in source units) by the light in the game
+    pub fn value_at(&self, distance: f32) -> f32 {
+        self.brightness * self.attenuation.at(self.reference_distance)
+            / self.attenuation.at(distance)
+    }
+
+    /// The luminous power, in lumens, which lights a surface at the reference distance as much
+    /// as the game does.
+    pub fn intensity(&self) -> f32 {
+        inverse_square_intensity(self.brightness, self.reference_distance)
+    }
+
+    /// The range of the light in bevy units, where it gets too dark to be visible in the game
+    pub fn range(&self) -> f32 {
+        let brightest = self.color.into_iter().fold(0.0, f32::max) * self.brightness;
+        let attenuation =
+            brightest * self.attenuation.at(self.reference_distance) / MIN_LIGHT_VALUE;
+        let range = self
+            .attenuation
+            .distance_to(attenuation)
+            .unwrap_or(MAX_LIGHT_RANGE)
+            .min(MAX_LIGHT_RANGE);
+        let range = match self.max_distance {
+            Some(max) => range.min(max),
+            None => range,
+        };
+        range * SCALE
+    }
+
+    pub fn bevy_color(&self) -> Color {
+        let [r, g, b] = self.color;
+        Color::rgb_linear(r, g, b)
+    }
+
+    pub fn transform(&self) -> Transform {
+        light_transform(self.origin, self.direction)
+    }
+
+    pub fn point_light(&self) -> PointLight {
+        PointLight {
+            color: self.bevy_color(),
+            intensity: self.intensity(),
+            range: self.range(),
+            shadows_enabled: false,
+            ..Default::default()
+        }
+    }
+
+    /// The spot light of a [`LightKind::Spot`], or a spot light which covers everything in front
+    /// of the light otherwise
+    pub fn spot_light(&self) -> SpotLight {
+        let (inner_cone, outer_cone) = match self.kind {
+            LightKind::Spot {
+                inner_cone,
+                outer_cone,
+            } => (inner_cone, outer_cone),
+            LightKind::Point => (90.0, 90.0),
+        };
+        // Bevy can't have spot lights wider than a hemisphere
+        let outer_angle = degrees_to_radians(outer_cone).clamp(0.0, FRAC_PI_2);
+        SpotLight {
+            color: self.bevy_color(),
+            intensity: self.intensity(),
+            range: self.range(),
+            shadows_enabled: false,
+            outer_angle,
+            inner_angle: degrees_to_radians(inner_cone).clamp(0.0, outer_angle),
+            ..Default::default()
+        }
+    }
+}
+
+/// The intensity of a bevy point or spot light which lights a surface `distance` source units
+/// away with `value`, where `1.0` is fully lit.
+fn inverse_square_intensity(value: f32, distance: f32) -> f32 {
+    // Bevy's illuminance at the distance is `intensity / (4 * PI * distance^2)`, and a diffuse
+    // surface reflects `illuminance / PI` of its color
+    let distance = distance * SCALE;
+    4.0 * PI * PI * distance * distance * value
+}
+
+/// The `light_environment` entity, the sun and the light from the sky
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvironmentLight {
+    /// The direction the sun shines in, in source coordinates
+    pub direction: [f32; 3],
+    /// Linear color of the sun
+    pub color: [f32; 3],
+    /// How bright the sun lights a surface facing it, where `1.0` is fully lit
+    pub brightness: f32,
+    /// Linear color and brightness of the light from the sky, `_ambient`
+    pub ambient: Option<([f32; 3], f32)>,
+}
+impl EnvironmentLight {
+    /// Read the light from the keyvalues of an entity, if it is a `light_environment`
+    pub fn from_properties<'a>(
+        properties: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Option<EnvironmentLight> {
+        let keyvalues = EntityKeyValues::from_properties(properties);
+        if keyvalues.get("classname")? != "light_environment" {
+            return None;
+        }
+
+        let parse_f32 = |key| keyvalues.get(key)?.trim().parse::<f32>().ok();
+        let angles = keyvalues
+            .get("angles")
+            .and_then(parse_vector)
+            .unwrap_or_default();
+        let (color, brightness) = keyvalues
+            .get("_light")
+            .and_then(parse_light_value)
+            .unwrap_or(([1.0; 3], 200.0 / 255.0));
+
+        Some(EnvironmentLight {
+            direction: light_direction(
+                angles,
+                parse_f32("angle").unwrap_or(0.0),
+                parse_f32("pitch").unwrap_or(0.0),
+            ),
+            color,
+            brightness,
+            ambient: keyvalues.get("_ambient").and_then(parse_light_value),
+        })
+    }
+
+    pub fn transform(&self) -> Transform {
+        light_transform([0.0; 3], self.direction)
+    }
+
+    pub fn directional_light(&self) -> DirectionalLight {
+        let [r, g, b] = self.color;
+        DirectionalLight {
+            color: Color::rgb_linear(r, g, b),
+            // A diffuse surface reflects `illuminance / PI` of its color
+            illuminance: PI * self.brightness,
+            shadows_enabled: false,
+            ..Default::default()
+        }
+    }
+
+    /// The ambient light of the sky, which bevy applies to the color of a surface as is
+    pub fn ambient_light(&self) -> Option<AmbientLight> {
+        let ([r, g, b], brightness) = self.ambient?;
+        Some(AmbientLight {
+            color: Color::rgb_linear(r, g, b),
+            brightness,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4 * b.abs().max(1.0), "{a} != {b}");
+    }
+
+    #[test]
+    fn test_parse_light_value() {
+        let (color, brightness) = parse_light_value("255 0 128 300").unwrap();
+        assert_eq!(color[0], 1.0);
+        assert_eq!(color[1], 0.0);
+        assert_close(color[2], (128.0f32 / 255.0).powf(2.2));
+        assert_close(brightness, 300.0 / 255.0);
+
+        assert_eq!(parse_light_value("255 255 255"), Some(([1.0; 3], 1.0)));
+        assert_eq!(parse_light_value("255"), Some(([1.0; 3], 1.0)));
+        assert_eq!(parse_light_value("255 255"), None);
+        assert_eq!(parse_light_value("a b c d"), None);
+    }
+
+    #[test]
+    fn test_light_direction() {
+        let [x, y, z] = light_direction([0.0, 90.0, 0.0], 0.0, -90.0);
+        assert_close(x, 0.0);
+        assert_close(y, 0.0);
+        assert_close(z, -1.0);
+
+        // The pitch of the angles is used when the key is missing, and `angle` overrides the yaw
+        let [x, y, z] = light_direction([-45.0, 0.0, 0.0], 90.0, 0.0);
+        assert_close(x, 0.0);
+        assert_close(y, 0.5f32.sqrt());
+        assert_close(z, -(0.5f32.sqrt()));
+
+        assert_eq!(light_direction([0.0; 3], ANGLE_UP, 0.0), [0.0, 0.0, 1.0]);
+        assert_eq!(light_direction([0.0; 3], ANGLE_DOWN, -90.0)[2], -1.0);
+
+        // Shining down is -Y in bevy, the forward of the light
+        let transform = light_transform([0.0; 3], [0.0, 0.0, -1.0]);
+        assert!(transform.forward().abs_diff_eq(Vec3::NEG_Y, 1e-5));
+        let transform = light_transform([0.0; 3], [1.0, 0.0, 0.0]);
+        assert!(transform.forward().abs_diff_eq(Vec3::NEG_Z, 1e-5));
+    }
+
+    #[test]
+    fn test_attenuation() {
+        assert_eq!(
+            Attenuation::new(0.0, 0.0, 0.0),
+            Attenuation::new(0.0, 0.0, 1.0)
+        );
+
+        let attenuation = Attenuation::from_distances(100.0, Some(2000.0));
+        assert_close(attenuation.at(0.0), 1.0);
+        assert_close(attenuation.at(100.0), 2.0);
+        assert_close(attenuation.at(2000.0), 256.0);
+        assert_close(attenuation.distance_to(256.0).unwrap(), 2000.0);
+
+        // Too close to get dark enough without getting brighter first, the zero distance is kept
+        let attenuation = Attenuation::from_distances(100.0, Some(400.0));
+        assert_eq!(attenuation.linear, 0.0);
+        assert_close(attenuation.at(0.0), 1.0);
+        assert_close(attenuation.at(400.0), 256.0);
+
+        let attenuation = Attenuation::from_distances(100.0, None);
+        assert_close(attenuation.at(100.0), 2.0);
+
+        assert_eq!(Attenuation::new(1.0, 0.0, 0.0).distance_to(10.0), None);
+        assert_close(
+            Attenuation::new(1.0, 0.5, 0.0).distance_to(11.0).unwrap(),
+            20.0,
+        );
+    }
+
+    #[test]
+    fn test_map_light() {
+        let light = MapLight::from_properties([
+            ("classname", "light"),
+            ("origin", "0 0 64"),
+            ("_light", "255 255 255 255"),
+        ])
+        .unwrap();
+        assert_eq!(light.kind, LightKind::Point);
+        assert_eq!(light.brightness, 1.0);
+        assert_close(light.value_at(100.0), 1.0);
+        assert_close(light.value_at(200.0), 0.25);
+        // A fully lit surface at 100 units
+        let distance = REFERENCE_DISTANCE * SCALE;
+        let illuminance = light.intensity() / (4.0 * PI * distance * distance);
+        assert_close(illuminance / PI, 1.0);
+        assert_close(light.range(), 100.0 * 255.0f32.sqrt() * SCALE);
+
+        let light = MapLight::from_properties([
+            ("classname", "light_spot"),
+            ("origin", "0 0 64"),
+            ("angles", "-90 0 0"),
+            ("_light", "255 255 255 255"),
+            ("_inner_cone", "20"),
+            ("_cone", "40"),
+            ("_fifty_percent_distance", "150"),
+            ("_zero_percent_distance", "3000"),
+        ])
+        .unwrap();
+        assert_eq!(
+            light.kind,
+            LightKind::Spot {
+                inner_cone: 20.0,
+                outer_cone: 40.0
+            }
+        );
+        assert_close(light.direction[2], -1.0);
+        assert_close(light.value_at(0.0), 1.0);
+        assert_close(light.value_at(150.0), 0.5);
+        assert_close(light.value_at(3000.0), 1.0 / 256.0);
+        // Just before the zero percent distance, where it gets too dark
+        assert!(light.range() < 3000.0 * SCALE && light.range() > 2900.0 * SCALE);
+        let spot = light.spot_light();
+        assert_close(spot.outer_angle, degrees_to_radians(40.0));
+        assert_close(spot.inner_angle, degrees_to_radians(20.0));
+
+        // Lights which start off and other entities aren't lights
+        assert_eq!(
+            MapLight::from_properties([
+                ("classname", "light"),
+                ("origin", "0 0 0"),
+                ("spawnflags", "1")
+            ]),
+            None
+        );
+        assert_eq!(
+            MapLight::from_properties([("classname", "info_target"), ("origin", "0 0 0")]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_environment_light() {
+        let light = EnvironmentLight::from_properties([
+            ("classname", "light_environment"),
+            ("angles", "0 45 0"),
+            ("pitch", "-60"),
+            ("_light", "255 255 255 510"),
+            ("_ambient", "255 255 255 51"),
+        ])
+        .unwrap();
+        assert_close(light.direction[2], -(60.0f32.to_radians().sin()));
+        assert_close(light.directional_light().illuminance, 2.0 * PI);
+        let ambient = light.ambient_light().unwrap();
+        assert_close(ambient.brightness, 0.2);
+        assert_eq!(
+            EnvironmentLight::from_properties([("classname", "light")]),
+            None
+        );
+    }
+}
@@ -0,0 +1,601 @@
+//! The baked lighting of the map.
+//! Each face has its own small lightmap in the lighting lump, which we pack into a single atlas
+//! texture so that all of the brush materials can share it.
+use bevy::{
+    prelude::Image,
+    render::{
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+        texture::ImageSampler,
+    },
+};
+
+use crate::lump::{read_records, LumpType, Lumps};
+
+/// Empty space around each face's lightmap in the atlas, filled with the edge luxels, so that
+/// filtering doesn't bleed between faces.
+const LIGHTMAP_PADDING: u32 = 1;
+/// The atlas is packed into rows of at most this width, unless it would be too tall
+const MAX_ATLAS_WIDTH: u32 = 4096;
+/// The largest atlas on either axis, which is wgpu's default `max_texture_dimension_2d`
+const MAX_ATLAS_SIZE: u32 = 8192;
+/// `styles` value for an unused light style
+const NO_LIGHT_STYLE: u8 = 255;
+/// `SURF_BUMPLIGHT`, the texinfo flag for faces which have bumped lightmaps
+const SURF_BUMPLIGHT: u32 = 0x800;
+/// The number of lightmaps that bumped faces have: the flat one, and then one for each of the
+/// three bump basis directions
+pub const BUMP_LIGHTMAP_COUNT: u32 = 4;
+
+/// Size of a `dtexinfo_t`
+const TEX_INFO_SIZE: usize = 72;
+/// Size of a `dface_t`
+const FACE_SIZE: usize = 56;
+
+/// The lighting information of a face, from the faces lump
+#[derive(Debug, Clone, Copy)]
+struct RawFace {
+    tex_info: i16,
+    styles: [u8; 4],
+    /// Offset in bytes into the lighting lump, or `-1` if the face has no lightmap
+    light_offset: i32,
+    mins: [i32; 2],
+    /// One less than the number of luxels on each axis
+    size: [i32; 2],
+}
+impl RawFace {
+    fn has_lightmap(&self) -> bool {
+        self.light_offset >= 0 && self.styles[0] != NO_LIGHT_STYLE
+    }
+}
+
+/// The lighting information of a texinfo, from the texinfo lump
+#[derive(Debug, Clone, Copy, Default)]
+struct RawTexInfo {
+    vecs: LightmapVecs,
+    flags: u32,
+}
+
+/// The projection from world space (in source coordinates) to luxel space
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LightmapVecs {
+    pub s: [f32; 4],
+    pub t: [f32; 4],
+}
+impl LightmapVecs {
+    /// Get the luxel coordinates of the vertex, which is in source coordinates.
+    pub fn luxel(&self, v: [f32; 3]) -> [f32; 2] {
+        let [s, t] = [self.s, self.t].map(|a| a[0] * v[0] + a[1] * v[1] + a[2] * v[2] + a[3]);
+        [s, t]
+    }
+}
+
+/// Where a face's lightmap is in the atlas
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaceLightmap {
+    /// Position of the face's first luxel in the atlas
+    pub x: u32,
+    pub y: u32,
+    /// Number of luxels on each axis
+    pub width: u32,
+    pub height: u32,
+    /// The luxel coordinates of the face's first luxel
+    pub mins: [f32; 2],
+    pub vecs: LightmapVecs,
+    /// Whether the face has bumped lightmaps, which are to the right of the flat lightmap, each
+    /// [`FaceLightmap::page_stride`] apart
+    pub bumped: bool,
+}
+impl FaceLightmap {
+    /// The distance in pixels between each of the face's lightmaps
+    pub fn page_stride(&self) -> u32 {
+        self.width + LIGHTMAP_PADDING * 2
+    }
+}
+
+/// The lightmaps of all the faces in the map, packed into one atlas.
+#[derive(Debug, Clone)]
+pub struct Lightmaps {
+    pub width: u32,
+    pub height: u32,
+    /// [`TextureFormat::Rgba16Float`] pixels
+    pub data: Vec<u8>,
+    /// Whether this is from the HDR lighting lump
+    pub hdr: bool,
+    /// Indexed by the face index, `None` if the face has no lightmap
+    faces: Vec<Option<FaceLightmap>>,
+}
+impl Lightmaps {
+    /// Lightmaps which light everything fully, for maps without any lighting.
+    pub fn fullbright() -> Lightmaps {
+        let mut atlas = Atlas::new(white_block_size(), white_block_size());
+        atlas.fill_white();
+
+        Lightmaps {
+            width: atlas.width,
+            height: atlas.height,
+            data: atlas.into_bytes(),
+            hdr: false,
+            faces: Vec::new(),
+        }
+    }
+
+    /// Read the lightmaps from the bsp file's data.
+    /// HDR lighting is used if the map has it.
+    pub fn from_bsp(data: &[u8]) -> eyre::Result<Lightmaps> {
+        let lumps = Lumps::new(data)?;
+
+        let lighting_hdr = lumps.get(LumpType::LightingHdr)?;
+        let hdr = !lighting_hdr.is_empty();
+        let (lighting, faces) = if hdr {
+            // Like the game, the HDR faces are only used if they exist
+            let faces_hdr = lumps.get(LumpType::FacesHdr)?;
+            let faces = if faces_hdr.is_empty() {
+                lumps.get(LumpType::Faces)?
+            } else {
+                faces_hdr
+            };
+            (lighting_hdr, faces)
+        } else {
+            (lumps.get(LumpType::Lighting)?, lumps.get(LumpType::Faces)?)
+        };
+
+        if lighting.is_empty() {
+            println!("Map has no lighting, using fullbright lightmaps");
+            return Ok(Lightmaps::fullbright());
+        }
+
+        let tex_infos = read_records(&lumps.get(LumpType::TexInfo)?, TEX_INFO_SIZE, |r| {
+            // Skip the texture vecs
+            r.skip(4 * 8);
+            let s = [r.f32()?, r.f32()?, r.f32()?, r.f32()?];
+            let t = [r.f32()?, r.f32()?, r.f32()?, r.f32()?];
+            let flags = r.u32()?;
+            Ok(RawTexInfo {
+                vecs: LightmapVecs { s, t },
+                flags,
+            })
+        })?;
+
+        let faces = read_records(&faces, FACE_SIZE, |r| {
+            // plane, side, on node, first edge, edge count
+            r.skip(2 + 1 + 1 + 4 + 2);
+            let tex_info = r.i16()?;
+            // displacement info, surface fog volume id
+            r.skip(2 + 2);
+            let styles = r.bytes::<4>()?;
+            let light_offset = r.i32()?;
+            // area
+            r.skip(4);
+            let mins = [r.i32()?, r.i32()?];
+            let size = [r.i32()?, r.i32()?];
+            Ok(RawFace {
+                tex_info,
+                styles,
+                light_offset,
+                mins,
+                size,
+            })
+        })?;
+
+        pack(&lighting, &tex_infos, &faces, hdr)
+    }
+
+    pub fn face(&self, face: usize) -> Option<&FaceLightmap> {
+        self.faces.get(face)?.as_ref()
+    }
+
+    /// Get the uv in the atlas of the vertex on the face, which should be in source coordinates.
+    /// Faces without a lightmap are given a fully lit part of the atlas.
+    pub fn uv(&self, face: usize, vertex: [f32; 3]) -> [f32; 2] {
+        match self.face(face) {
+            Some(lightmap) => self.luxel_uv(lightmap, lightmap.vecs.luxel(vertex)),
+            None => self.white_uv(),
+        }
+    }
+
+    /// Get the uv in the atlas of the luxel coordinates on the face.
+    pub fn luxel_uv(&self, lightmap: &FaceLightmap, luxel: [f32; 2]) -> [f32; 2] {
+        // Luxel `i` is centered on the pixel, so it is at `i + 0.5`
+        let s = luxel[0] - lightmap.mins[0] + 0.5;
+        let t = luxel[1] - lightmap.mins[1] + 0.5;
+        [
+            (lightmap.x as f32 + s) / self.width as f32,
+            (lightmap.y as f32 + t) / self.height as f32,
+        ]
+    }
+
+    /// Get the offset in the atlas' u coordinate from the face's flat lightmap to its first
+    /// bumped lightmap, and from each bumped lightmap to the next. `0` if it has none.
+    pub fn bump_offset(&self, face: usize) -> f32 {
+        match self.face(face) {
+            Some(lightmap) if lightmap.bumped => lightmap.page_stride() as f32 / self.width as f32,
+            _ => 0.0,
+        }
+    }
+
+    fn white_uv(&self) -> [f32; 2] {
+        let center = white_block_size() as f32 / 2.0;
+        [center / self.width as f32, center / self.height as f32]
+    }
+
+    pub fn image(&self) -> Image {
+        let mut image = Image::new(
+            Extent3d {
+                width: self.width,
+                height: self.height,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            self.data.clone(),
+            TextureFormat::Rgba16Float,
+        );
+        // Clamped, since faces are padded with their edges
+        image.sampler = ImageSampler::linear();
+
+        image
+    }
+}
+
+/// The size of the fully lit block in the top left of the atlas
+fn white_block_size() -> u32 {
+    1 + LIGHTMAP_PADDING * 2
+}
+
+fn pack(
+    lighting: &[u8],
+    tex_infos: &[RawTexInfo],
+    faces: &[RawFace],
+    hdr: bool,
+) -> eyre::Result<Lightmaps> {
+    let tex_info_of = |face: &RawFace| {
+        tex_infos
+            .get(face.tex_info as usize)
+            .copied()
+            .unwrap_or_default()
+    };
+    let page_count = |face: &RawFace| {
+        if tex_info_of(face).flags & SURF_BUMPLIGHT != 0 {
+            BUMP_LIGHTMAP_COUNT
+        } else {
+            1
+        }
+    };
+
+    // The fully lit block is placed first, like it was the first face
+    let mut rects = vec![(None, white_block_size(), white_block_size())];
+    for (i, face) in faces.iter().enumerate() {
+        if !face.has_lightmap() || face.size[0] < 0 || face.size[1] < 0 {
+            continue;
+        }
+
+        // A bumped face's lightmaps are placed next to each other
+        let width = (face.size[0] as u32 + 1 + LIGHTMAP_PADDING * 2) * page_count(face);
+        let height = face.size[1] as u32 + 1 + LIGHTMAP_PADDING * 2;
+        rects.push((Some(i), width, height));
+    }
+
+    // Packing the tallest first keeps the rows tight
+    rects[1..].sort_by(|(_, aw, ah), (_, bw, bh)| bh.cmp(ah).then(bw.cmp(aw)));
+
+    let area = rects.iter().map(|(_, w, h)| (w * h) as u64).sum::<u64>();
+    let widest = rects.iter().map(|(_, w, _)| *w).max().unwrap_or(1);
+    if widest > MAX_ATLAS_SIZE {
+        eyre::bail!("A face lightmap is {widest} luxels wide, past the limit of {MAX_ATLAS_SIZE}");
+    }
+    let mut atlas_width = ((area as f64).sqrt().ceil() as u32)
+        .next_power_of_two()
+        .min(MAX_ATLAS_WIDTH)
+        .max(widest);
+
+    // Widen the atlas if it would be too tall for the gpu
+    let (positions, atlas_height) = loop {
+        let (positions, atlas_height) = shelf_pack(&rects, atlas_width);
+        if atlas_height <= MAX_ATLAS_SIZE {
+            break (positions, atlas_height);
+        } else if atlas_width >= MAX_ATLAS_SIZE {
+            eyre::bail!(
+                "Lightmap atlas would be {atlas_width}x{atlas_height}, past the limit of \
+                 {MAX_ATLAS_SIZE}x{MAX_ATLAS_SIZE}"
+            );
+        }
+
+        atlas_width = (atlas_width * 2).min(MAX_ATLAS_SIZE);
+    };
+
+    let mut atlas = Atlas::new(atlas_width, atlas_height);
+    let mut face_lightmaps = vec![None; faces.len()];
+    for (face_i, x, y) in positions {
+        let Some(face_i) = face_i else {
+            atlas.fill_rect(x, y, white_block_size(), white_block_size(), [1.0; 3]);
+            continue;
+        };
+
+        let face = &faces[face_i];
+        let width = face.size[0] as u32 + 1;
+        let height = face.size[1] as u32 + 1;
+        let x = x + LIGHTMAP_PADDING;
+        let y = y + LIGHTMAP_PADDING;
+
+        // TODO: light styles other than the first one, which are for switchable lights
+        // The first style's lightmaps come first, in the same order as the pages
+        let pages = page_count(face);
+        let page_len = (width * height) as usize * 4;
+        let start = face.light_offset as usize;
+        let end = start + page_len * pages as usize;
+        let Some(samples) = lighting.get(start..end) else {
+            eprintln!("Lightmap of face {face_i} extends past the lighting lump");
+            continue;
+        };
+
+        let stride = width + LIGHTMAP_PADDING * 2;
+        for (page, samples) in samples.chunks_exact(page_len).enumerate() {
+            atlas.write_luxels(x + stride * page as u32, y, width, height, samples);
+        }
+
+        face_lightmaps[face_i] = Some(FaceLightmap {
+            x,
+            y,
+            width,
+            height,
+            mins: [face.mins[0] as f32, face.mins[1] as f32],
+            vecs: tex_info_of(face).vecs,
+            bumped: pages == BUMP_LIGHTMAP_COUNT,
+        });
+    }
+
+    println!(
+        "Packed {} face lightmaps into a {atlas_width}x{atlas_height} atlas; hdr: {hdr}",
+        face_lightmaps.iter().flatten().count()
+    );
+
+    Ok(Lightmaps {
+        width: atlas_width,
+        height: atlas_height,
+        data: atlas.into_bytes(),
+        hdr,
+        faces: face_lightmaps,
+    })
+}
+
+/// Simple shelf packing of the `(face, width, height)` rects, left to right in rows.
+/// Returns the position of each rect and the height of the atlas.
+fn shelf_pack(
+    rects: &[(Option<usize>, u32, u32)],
+    atlas_width: u32,
+) -> (Vec<(Option<usize>, u32, u32)>, u32) {
+    let mut positions = Vec::with_capacity(rects.len());
+    let (mut x, mut y, mut row_height) = (0, 0, 0);
+    for &(face, width, height) in rects {
+        if x + width > atlas_width {
+            x = 0;
+            y += row_height;
+            row_height = 0;
+        }
+
+        positions.push((face, x, y));
+        x += width;
+        row_height = row_height.max(height);
+    }
+
+    (positions, y + row_height)
+}
+
+/// Decode a `ColorRGBExp32` luxel into linear color
+pub(crate) fn decode_luxel(luxel: &[u8]) -> [f32; 3] {
+    let exponent = luxel[3] as i8 as i32;
+    let scale = 2f32.powi(exponent) / 255.0;
+    [
+        luxel[0] as f32 * scale,
+        luxel[1] as f32 * scale,
+        luxel[2] as f32 * scale,
+    ]
+}
+
+/// A half float of `1.0`
+const HALF_ONE: u16 = 0x3c00;
+
+/// The atlas while it is being filled in.
+/// The pixels are written straight into the [`TextureFormat::Rgba16Float`] data of the image, since
+/// at the largest atlas size a separate buffer of floats would take more memory than the image.
+struct Atlas {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+impl Atlas {
+    fn new(width: u32, height: u32) -> Atlas {
+        let black = [0, 0, 0, HALF_ONE].map(u16::to_le_bytes).concat();
+        Atlas {
+            width,
+            height,
+            data: black.repeat((width * height) as usize),
+        }
+    }
+
+    fn set(&mut self, x: u32, y: u32, [r, g, b]: [f32; 3]) {
+        let i = (x + y * self.width) as usize * 8;
+        let pixel = [f32_to_f16(r), f32_to_f16(g), f32_to_f16(b), HALF_ONE];
+        for (c, value) in pixel.into_iter().enumerate() {
+            self.data[i + c * 2..i + c * 2 + 2].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn fill_white(&mut self) {
+        self.fill_rect(0, 0, self.width, self.height, [1.0; 3]);
+    }
+
+    fn fill_rect(&mut self, x: u32, y: u32, width: u32, height: u32, color: [f32; 3]) {
+        for py in y..y + height {
+            for px in x..x + width {
+                self.set(px, py, color);
+            }
+        }
+    }
+
+    /// Write the `ColorRGBExp32` luxels, and copy the edges into the padding around them.
+    fn write_luxels(&mut self, x: u32, y: u32, width: u32, height: u32, samples: &[u8]) {
+        let pad = LIGHTMAP_PADDING as i64;
+        for py in -pad..height as i64 + pad {
+            for px in -pad..width as i64 + pad {
+                let sx = px.clamp(0, width as i64 - 1) as u32;
+                let sy = py.clamp(0, height as i64 - 1) as u32;
+                let i = (sx + sy * width) as usize * 4;
+                let color = decode_luxel(&samples[i..i + 4]);
+
+                let ax = (x as i64 + px) as u32;
+                let ay = (y as i64 + py) as u32;
+                self.set(ax, ay, color);
+            }
+        }
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Convert a non-negative float to a half float, flushing tiny values to zero and clamping large
+/// values to the largest half.
+fn f32_to_f16(v: f32) -> u16 {
+    const MAX_HALF: u16 = 0x7bff;
+    if v.is_nan() || v <= 0.0 {
+        return 0;
+    }
+
+    let bits = v.to_bits();
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    if exponent >= 0x1f {
+        return MAX_HALF;
+    } else if exponent <= 0 {
+        return 0;
+    }
+
+    // Round the mantissa to the nearest, which can carry into the exponent
+    let mantissa = bits & 0x7f_ffff;
+    let half = ((exponent as u32) << 10) | (mantissa >> 13);
+    let half = half + ((mantissa >> 12) & 1);
+    (half as u16).min(MAX_HALF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn face(tex_info: i16, light_offset: i32, size: [i32; 2]) -> RawFace {
+        RawFace {
+            tex_info,
+            styles: [0, NO_LIGHT_STYLE, NO_LIGHT_STYLE, NO_LIGHT_STYLE],
+            light_offset,
+            mins: [0, 0],
+            size,
+        }
+    }
+
+    /// The red channel of the atlas pixel, as a half float
+    fn red(lightmaps: &Lightmaps, x: u32, y: u32) -> u16 {
+        let i = (x + y * lightmaps.width) as usize * 8;
+        u16::from_le_bytes([lightmaps.data[i], lightmaps.data[i + 1]])
+    }
+
+    #[test]
+    fn test_decode_luxel() {
+        assert_eq!(decode_luxel(&[255, 0, 0, 0]), [1.0, 0.0, 0.0]);
+        // The exponent is signed
+        assert_eq!(decode_luxel(&[255, 255, 255, 2]), [4.0; 3]);
+        assert_eq!(decode_luxel(&[255, 255, 255, -1i8 as u8]), [0.5; 3]);
+    }
+
+    #[test]
+    fn test_f32_to_f16() {
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(0.5), 0x3800);
+        assert_eq!(f32_to_f16(2.0), 0x4000);
+        assert_eq!(f32_to_f16(1.0 + 1.0 / 1024.0), 0x3c01);
+        // Rounds to the nearest, carrying into the exponent
+        assert_eq!(f32_to_f16(2.0 - 1.0 / 4096.0), 0x4000);
+
+        for v in [0.0, -1.0, f32::NAN, 1e-10] {
+            assert_eq!(f32_to_f16(v), 0, "{v}");
+        }
+        for v in [65504.0, 1e10, f32::INFINITY] {
+            assert_eq!(f32_to_f16(v), 0x7bff, "{v}");
+        }
+    }
+
+    #[test]
+    fn test_atlas() {
+        let mut atlas = Atlas::new(2, 1);
+        atlas.set(1, 0, [1.0, 0.5, 0.0]);
+        let halves = atlas
+            .into_bytes()
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect::<Vec<_>>();
+        // Unwritten pixels are black
+        assert_eq!(halves, [0, 0, 0, HALF_ONE, HALF_ONE, 0x3800, 0, HALF_ONE]);
+    }
+
+    #[test]
+    fn test_pack() {
+        let tex_infos = [
+            RawTexInfo::default(),
+            RawTexInfo {
+                flags: SURF_BUMPLIGHT,
+                ..Default::default()
+            },
+        ];
+        let faces = [
+            // 2x1 luxels
+            face(0, 0, [1, 0]),
+            face(0, -1, [3, 3]),
+            // 1x1, with a bumped lightmap for each basis
+            face(1, 8, [0, 0]),
+            // Past the end of the lighting lump
+            face(0, 64, [0, 0]),
+        ];
+        let mut lighting = vec![255, 0, 0, 0, 255, 0, 0, 1];
+        for page in 0..BUMP_LIGHTMAP_COUNT as u8 {
+            lighting.extend([255, 0, 0, page]);
+        }
+
+        let lightmaps = pack(&lighting, &tex_infos, &faces, false).unwrap();
+        assert!(lightmaps.face(1).is_none());
+        assert!(lightmaps.face(3).is_none());
+        assert_eq!(red(&lightmaps, 1, 1), f32_to_f16(1.0));
+
+        let flat = *lightmaps.face(0).unwrap();
+        assert_eq!((flat.width, flat.height, flat.bumped), (2, 1, false));
+        assert_eq!(red(&lightmaps, flat.x, flat.y), f32_to_f16(1.0));
+        assert_eq!(red(&lightmaps, flat.x + 1, flat.y), f32_to_f16(2.0));
+        // The padding repeats the edges
+        assert_eq!(red(&lightmaps, flat.x - 1, flat.y - 1), f32_to_f16(1.0));
+        assert_eq!(red(&lightmaps, flat.x + 2, flat.y + 1), f32_to_f16(2.0));
+
+        let bumped = *lightmaps.face(2).unwrap();
+        assert!(bumped.bumped);
+        for page in 0..BUMP_LIGHTMAP_COUNT {
+            let x = bumped.x + bumped.page_stride() * page;
+            assert_eq!(
+                red(&lightmaps, x, bumped.y),
+                f32_to_f16(2f32.powi(page as i32))
+            );
+        }
+        assert_eq!(
+            lightmaps.bump_offset(2),
+            bumped.page_stride() as f32 / lightmaps.width as f32
+        );
+        assert_eq!(lightmaps.bump_offset(0), 0.0);
+    }
+
+    #[test]
+    fn test_pack_too_large() {
+        // Too wide for any atlas
+        let faces = [face(0, 0, [MAX_ATLAS_SIZE as i32, 0])];
+        assert!(pack(&[], &[RawTexInfo::default()], &faces, false).is_err());
+
+        // Each fills a whole row, and together they're too tall
+        let width = (MAX_ATLAS_SIZE - LIGHTMAP_PADDING * 2) as i32 - 1;
+        let faces = [face(0, 0, [width, 4000]); 3];
+        assert!(pack(&[], &[RawTexInfo::default()], &faces, false).is_err());
+    }
+}
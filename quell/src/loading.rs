@@ -0,0 +1,538 @@
+//! Asynchronous loading of the game data and maps.
+//! Everything expensive is done on the [`AsyncComputeTaskPool`] so that we can show a loading
+//! screen, and the map is streamed in as the materials for each group of faces finish.
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use bevy::{
+    prelude::{
+        default, BuildChildren, Color, Commands, Component, DespawnRecursiveExt, Entity, Mesh,
+        NodeBundle, Query, Res, Resource, Style, Text, TextBundle, TextStyle, Transform, Val, With,
+    },
+    render::texture::Image,
+    tasks::{AsyncComputeTaskPool, Task},
+    ui::{AlignItems, FlexDirection, PositionType},
+};
+use indexmap::IndexMap;
+
+use crate::{
+    cache::{TextureCache, TextureRole},
+    conf::FaceBatching,
+    cubemap::load_cubemaps,
+    data::{
+        construct_image, construct_material_info, find_texture, ArchiveMaps, GameId, LSrc,
+        LoadedNames, LoadingMaterialInfo, MaterialName, TextureError, TextureName, VpkState,
+    },
+    map::GameMap,
+    material::material_names,
+    mesh::{construct_face, faces_by_material, merge_faces, FaceRange, FaceRef},
+    overlay::overlay_material_names,
+    skybox::load_skybox,
+};
+
+/// How many textures each streaming task decodes.
+/// Smaller batches make the map appear more gradually, but have more overhead.
+pub const TEXTURES_PER_BATCH: usize = 8;
+
+/// Where to load the game's vpks from
+#[derive(Debug, Clone, Resource)]
+pub struct VpkSource {
+    /// Ex: `./ex/tf/`
+    pub root_path: PathBuf,
+    pub game_id: GameId,
+    /// Directories of loose files, which are searched before the vpks
+    pub loose_dirs: Vec<PathBuf>,
+}
+
+/// The task loading the [`VpkState`], which is inserted as a resource once it finishes.
+#[derive(Resource)]
+pub struct VpkLoadTask(pub Task<eyre::Result<VpkState>>);
+
+pub fn spawn_vpk_load(source: VpkSource) -> VpkLoadTask {
+    let task = AsyncComputeTaskPool::get().spawn(async move {
+        let start_time = std::time::Instant::now();
+        let vpk =
+            VpkState::new(&source.root_path, source.game_id)?.with_loose_dirs(source.loose_dirs);
+        let end_time = std::time::Instant::now();
+        println!("Loaded VPKs in {:?}", end_time - start_time);
+        Ok(vpk)
+    });
+
+    VpkLoadTask(task)
+}
+
+/// The map along with the information needed to start streaming it in.
+pub struct ReadMap {
+    pub map: GameMap,
+    /// The batches of work to do to load the map's materials and faces
+    pub batches: Vec<BatchPlan>,
+    /// The cubemap of the 2D skybox, if the map has one
+    pub skybox: Option<Image>,
+    /// The cubemaps that the materials reflect, by their texture name
+    pub cubemaps: Vec<(TextureName, Image)>,
+}
+
+/// Read the map and its vmts.
+/// The vmts are cheap to load (they're typically in the preload of the dir vpks) and we need them
+/// to know which textures are shared between materials, so they're all done up front.  
+/// The batches are ordered by where their textures are stored, so that each archive is mostly
+/// read front to back.  
+/// Materials and textures in `loaded` are kept from the previous map, so they are not loaded again.
+pub fn spawn_map_read(
+    path: PathBuf,
+    vpk: VpkState,
+    cache: Option<Arc<TextureCache>>,
+    loaded: LoadedNames,
+) -> Task<eyre::Result<ReadMap>> {
+    AsyncComputeTaskPool::get().spawn(async move {
+        let start_time = std::time::Instant::now();
+
+        let map = GameMap::from_path(&path)?;
+
+        let mut names = material_names(&map);
+        for name in overlay_material_names(&map) {
+            if !names.iter().any(|other| other.eq_ignore_ascii_case(&name)) {
+                names.push(name);
+            }
+        }
+
+        let mut infos = names
+            .into_iter()
+            .filter(|material_name| !loaded.has_material(material_name))
+            .filter_map(|material_name| {
+                match construct_material_info(&vpk, Some(&map), &material_name) {
+                    Ok(info) => Some((material_name, info)),
+                    Err(err) => {
+                        eprintln!(
+                            "Failed to construct material info for {}: {:?}",
+                            material_name, err
+                        );
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        infos.sort_by_cached_key(|(_, info)| {
+            find_texture(&vpk, Some(&map), &info.base_texture_name).ok()
+        });
+
+        let named_cubemaps = infos
+            .iter()
+            .filter_map(|(_, info)| info.params.envmap.as_ref()?.cubemap.clone())
+            .collect::<Vec<_>>();
+        let cubemaps = load_cubemaps(&vpk, &map, named_cubemaps);
+
+        let faces = faces_by_material(&map);
+        let batches = plan_batches(infos, faces, &loaded, TEXTURES_PER_BATCH);
+
+        let skybox = load_skybox(&vpk, &map, cache.as_deref());
+
+        let end_time = std::time::Instant::now();
+        println!(
+            "Read map {path:?} in {:?}; {} batches, {} cubemaps",
+            end_time - start_time,
+            batches.len(),
+            cubemaps.len()
+        );
+
+        Ok(ReadMap {
+            map,
+            batches,
+            skybox,
+            cubemaps,
+        })
+    })
+}
+
+/// A unit of work for streaming in the map: the textures to decode, the materials which use
+/// them, and the faces which use those materials.
+#[derive(Debug, Clone, Default)]
+pub struct BatchPlan {
+    pub textures: Vec<(TextureName, TextureRole)>,
+    pub materials: Vec<(MaterialName, LoadingMaterialInfo)>,
+    pub faces: Vec<(MaterialName, FaceRef)>,
+}
+
+/// Materials which share textures (directly, or through other materials), along with all of their
+/// textures.
+#[derive(Default)]
+struct TextureGroup {
+    textures: Vec<(TextureName, TextureRole)>,
+    materials: Vec<(MaterialName, LoadingMaterialInfo)>,
+}
+
+/// Split the materials into batches such that every texture is decoded by exactly one batch, and
+/// the materials (and their faces) are in the same batch as all of their textures.
+/// Textures in `loaded` are not decoded again, and the faces of materials in it come first, in
+/// batches without textures since they can be spawned straight away.
+/// Any faces whose material was not found are put in a final batch, without textures.
+pub fn plan_batches(
+    infos: Vec<(MaterialName, LoadingMaterialInfo)>,
+    mut faces: HashMap<Arc<str>, Vec<FaceRef>>,
+    loaded: &LoadedNames,
+    textures_per_batch: usize,
+) -> Vec<BatchPlan> {
+    let mut batches = Vec::new();
+
+    let mut kept = faces
+        .keys()
+        .filter(|name| loaded.has_material(name))
+        .cloned()
+        .collect::<Vec<_>>();
+    kept.sort_unstable();
+    for names in kept.chunks(textures_per_batch.max(1)) {
+        let faces = names
+            .iter()
+            .flat_map(|name| {
+                let material_faces = faces.remove(name).unwrap_or_default();
+                material_faces
+                    .into_iter()
+                    .map(move |face| (name.clone(), face))
+            })
+            .collect();
+        batches.push(BatchPlan {
+            faces,
+            ..Default::default()
+        });
+    }
+
+    // Group the materials by the textures they use. Since a material can use several textures
+    // (ex: a base texture and a normal map) groups are merged when a material bridges them.
+    let mut groups: Vec<TextureGroup> = Vec::new();
+    // The group that each group was merged into, if any
+    let mut parent: Vec<usize> = Vec::new();
+    // The same vtf is decoded separately for each role it is used as
+    let mut texture_group: HashMap<(TextureName, TextureRole), usize> = HashMap::new();
+    fn root(parent: &[usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            i = parent[i];
+        }
+        i
+    }
+
+    for (material_name, info) in infos {
+        let existing = info
+            .textures()
+            .filter(|(texture, role)| !loaded.has_texture(texture, *role))
+            .filter_map(|key| texture_group.get(&key))
+            .map(|&i| root(&parent, i))
+            .collect::<Vec<_>>();
+        let group = match existing.first() {
+            Some(&group) => group,
+            None => {
+                groups.push(TextureGroup::default());
+                parent.push(groups.len() - 1);
+                groups.len() - 1
+            }
+        };
+        for &other in existing.iter().skip(1) {
+            parent[other] = group;
+        }
+
+        for key in info.textures() {
+            if !loaded.has_texture(&key.0, key.1) && !texture_group.contains_key(&key) {
+                texture_group.insert(key.clone(), group);
+                groups[group].textures.push(key);
+            }
+        }
+        groups[group].materials.push((material_name, info));
+    }
+
+    // Flatten the merged groups, keeping the order in which they were first seen
+    let mut merged: IndexMap<usize, TextureGroup> = IndexMap::with_capacity(groups.len());
+    for (i, group) in groups.into_iter().enumerate() {
+        let target = merged.entry(root(&parent, i)).or_default();
+        target.textures.extend(group.textures);
+        target.materials.extend(group.materials);
+    }
+
+    batches.reserve(merged.len() / textures_per_batch.max(1) + 2);
+    let mut iter = merged.into_values().peekable();
+    while iter.peek().is_some() {
+        let mut batch = BatchPlan::default();
+        while batch.textures.len() < textures_per_batch.max(1) {
+            let Some(group) = iter.next() else {
+                break;
+            };
+
+            batch.textures.extend(group.textures);
+            for (material_name, info) in group.materials {
+                if let Some(material_faces) = faces.remove(&material_name) {
+                    batch.faces.extend(
+                        material_faces
+                            .into_iter()
+                            .map(|face| (material_name.clone(), face)),
+                    );
+                }
+                batch.materials.push((material_name, info));
+            }
+        }
+        batches.push(batch);
+    }
+
+    // Whatever is left over has no material we can load, but we still want the faces (they'll
+    // use the missing material, or are skipped entirely when constructed)
+    let leftover = faces
+        .into_iter()
+        .flat_map(|(material_name, faces)| {
+            faces
+                .into_iter()
+                .map(move |face| (material_name.clone(), face))
+        })
+        .collect::<Vec<_>>();
+    if !leftover.is_empty() {
+        batches.push(BatchPlan {
+            faces: leftover,
+            ..Default::default()
+        });
+    }
+
+    batches
+}
+
+/// A mesh of one or more faces which has been constructed, and is ready to be spawned.
+pub struct BuiltMesh {
+    /// The index of the bsp model that the faces are in
+    pub model: usize,
+    pub material_name: MaterialName,
+    pub mesh: Mesh,
+    pub transform: Transform,
+    /// The faces in the mesh, and where their triangles are
+    pub faces: Vec<FaceRange>,
+    /// The vis clusters that any of the faces are in
+    pub clusters: Vec<i16>,
+    /// Whether the faces are part of the 3D skybox
+    pub skybox: bool,
+}
+
+/// The finished result of a [`BatchPlan`]
+pub struct MaterialBatch {
+    pub images: Vec<(
+        TextureName,
+        TextureRole,
+        Result<(Image, LSrc), TextureError>,
+    )>,
+    pub materials: Vec<(MaterialName, LoadingMaterialInfo)>,
+    pub meshes: Vec<BuiltMesh>,
+}
+
+/// Load the batch's textures and construct its faces, merging them according to `batching`.
+/// `archives` should be shared between the batches of a map, so they don't each open the archives.
+pub fn spawn_batch(
+    vpk: VpkState,
+    map: GameMap,
+    cache: Option<Arc<TextureCache>>,
+    archives: Arc<ArchiveMaps>,
+    batching: FaceBatching,
+    plan: BatchPlan,
+) -> Task<MaterialBatch> {
+    AsyncComputeTaskPool::get().spawn(async move {
+        let images = plan
+            .textures
+            .into_iter()
+            .map(|(name, role)| {
+                let cache = cache.as_deref();
+                let res = construct_image(&vpk, Some(&map), cache, Some(&archives), &name, role);
+                (name, role, res)
+            })
+            .collect();
+
+        // Faces can only be merged if they're in the same model, since each model has its own
+        // transform, and the faces of the 3D skybox are drawn by a different camera
+        let mut groups: IndexMap<(usize, bool, MaterialName, Option<usize>), Vec<_>> =
+            IndexMap::new();
+        for (material_name, face_ref) in plan.faces {
+            let info = match construct_face(&map, face_ref) {
+                Ok(Some(info)) => info,
+                Ok(None) => continue,
+                Err(err) => {
+                    eprintln!("Failed to construct face: {:?}", err);
+                    continue;
+                }
+            };
+
+            // What else the faces have to share to be merged
+            let split = match batching {
+                FaceBatching::Off => Some(face_ref.face),
+                FaceBatching::Material => None,
+                // Faces in multiple clusters go with their first one
+                FaceBatching::MaterialCluster => map
+                    .face_clusters
+                    .get(face_ref.face)
+                    .first()
+                    .map(|&cluster| cluster as usize),
+            };
+            let skybox = map.is_sky_face(face_ref.face);
+            groups
+                .entry((face_ref.model, skybox, material_name, split))
+                .or_default()
+                .push(info);
+        }
+
+        let meshes = groups
+            .into_iter()
+            .map(|((model, skybox, material_name, _), faces)| {
+                let transform = faces[0].transform;
+                let (mesh, faces) = merge_faces(faces);
+
+                let mut clusters = faces
+                    .iter()
+                    .flat_map(|range| map.face_clusters.get(range.face_i))
+                    .copied()
+                    .collect::<Vec<_>>();
+                clusters.sort_unstable();
+                clusters.dedup();
+
+                BuiltMesh {
+                    model,
+                    material_name,
+                    mesh,
+                    transform,
+                    faces,
+                    clusters,
+                    skybox,
+                }
+            })
+            .collect();
+
+        MaterialBatch {
+            images,
+            materials: plan.materials,
+            meshes,
+        }
+    })
+}
+
+/// What part of loading the map we are in
+pub enum MapLoad {
+    /// We can't read the map's materials until the vpks are loaded
+    WaitingForVpks,
+    ReadingMap(Task<eyre::Result<ReadMap>>),
+    Streaming(Vec<Task<MaterialBatch>>),
+}
+
+/// The loading progress, as shown on the loading screen
+#[derive(Debug, Clone, Default, Resource)]
+pub struct LoadingProgress {
+    pub stage: &'static str,
+    pub batches_done: usize,
+    pub batches_total: usize,
+    pub faces_spawned: usize,
+    pub meshes_spawned: usize,
+    /// Why loading stopped, if it failed
+    pub error: Option<String>,
+}
+impl LoadingProgress {
+    /// The fraction of the work that is done, from `0.0` to `1.0`
+    pub fn fraction(&self) -> f32 {
+        if self.batches_total == 0 {
+            0.0
+        } else {
+            self.batches_done as f32 / self.batches_total as f32
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Component)]
+pub struct LoadingScreen;
+
+#[derive(Debug, Clone, Copy, Component)]
+pub struct LoadingText;
+
+#[derive(Debug, Clone, Copy, Component)]
+pub struct LoadingBar;
+
+/// Spawn the loading overlay.
+/// This sits at the bottom of the screen rather than covering it, so that the map can be seen as
+/// it streams in.
+pub fn spawn_loading_screen(mut commands: Commands) {
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    bottom: Val::Px(16.0),
+                    left: Val::Px(16.0),
+                    width: Val::Px(400.0),
+                    flex_direction: FlexDirection::Column,
+                    align_items: AlignItems::Stretch,
+                    row_gap: Val::Px(4.0),
+                    ..default()
+                },
+                ..default()
+            },
+            LoadingScreen,
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                TextBundle::from_section(
+                    "Loading",
+                    TextStyle {
+                        font_size: 20.0,
+                        color: Color::WHITE,
+                        ..default()
+                    },
+                ),
+                LoadingText,
+            ));
+
+            parent
+                .spawn(NodeBundle {
+                    style: Style {
+                        height: Val::Px(8.0),
+                        ..default()
+                    },
+                    background_color: Color::rgba(0.0, 0.0, 0.0, 0.6).into(),
+                    ..default()
+                })
+                .with_children(|parent| {
+                    parent.spawn((
+                        NodeBundle {
+                            style: Style {
+                                width: Val::Percent(0.0),
+                                height: Val::Percent(100.0),
+                                ..default()
+                            },
+                            background_color: Color::rgb(0.9, 0.6, 0.1).into(),
+                            ..default()
+                        },
+                        LoadingBar,
+                    ));
+                });
+        });
+}
+
+pub fn update_loading_screen(
+    progress: Res<LoadingProgress>,
+    mut texts: Query<&mut Text, With<LoadingText>>,
+    mut bars: Query<&mut Style, With<LoadingBar>>,
+) {
+    if !progress.is_changed() {
+        return;
+    }
+
+    for mut text in texts.iter_mut() {
+        text.sections[0].value = if let Some(error) = &progress.error {
+            error.clone()
+        } else if progress.batches_total == 0 {
+            progress.stage.to_string()
+        } else {
+            format!(
+                "{} {}/{} ({} faces)",
+                progress.stage,
+                progress.batches_done,
+                progress.batches_total,
+                progress.faces_spawned
+            )
+        };
+    }
+
+    for mut style in bars.iter_mut() {
+        style.width = Val::Percent(progress.fraction() * 100.0);
+    }
+}
+
+pub fn despawn_loading_screen(mut commands: Commands, screens: Query<Entity, With<LoadingScreen>>) {
+    for entity in screens.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
@@ -0,0 +1,407 @@
+//! Direct access to the lumps of a bsp, for the data that vbsp doesn't expose to us.
+//! See <https://developer.valvesoftware.com/wiki/BSP_(Source)> for the layout of each lump.
+use std::borrow::Cow;
+
+/// The number of lumps in the header of a bsp
+pub const LUMP_COUNT: usize = 64;
+
+/// `VBSP`, as a little-endian integer
+const BSP_IDENT: u32 = u32::from_le_bytes(*b"VBSP");
+/// `LZMA`, as a little-endian integer, at the start of compressed lumps
+const LZMA_IDENT: u32 = u32::from_le_bytes(*b"LZMA");
+/// Flag on a game lump entry which is set when it is compressed
+const GAME_LUMP_COMPRESSED: u16 = 1;
+/// Size of the bsp header: ident, version, lump entries, map revision
+#[cfg(test)]
+const HEADER_SIZE: usize = 8 + LUMP_COUNT * 16 + 4;
+
+/// The lumps that we read ourselves.
+/// The value is the index of the lump in the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LumpType {
+    TexInfo = 6,
+    Faces = 7,
+    Lighting = 8,
+    Leafs = 10,
+    Areas = 20,
+    AreaPortals = 21,
+    ClipPortalVerts = 22,
+    DispInfo = 26,
+    GameLump = 35,
+    Cubemaps = 42,
+    Overlays = 45,
+    LeafAmbientIndexHdr = 51,
+    LeafAmbientIndex = 52,
+    LightingHdr = 53,
+    LeafAmbientLightingHdr = 55,
+    LeafAmbientLighting = 56,
+    FacesHdr = 58,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct LumpEntry {
+    offset: u32,
+    length: u32,
+    version: i32,
+    /// Non-zero if the lump is LZMA compressed
+    uncompressed_length: u32,
+}
+
+/// The lumps of a bsp file, borrowing from its data.
+#[derive(Debug, Clone)]
+pub struct Lumps<'a> {
+    data: &'a [u8],
+    version: i32,
+    entries: [LumpEntry; LUMP_COUNT],
+}
+impl<'a> Lumps<'a> {
+    pub fn new(data: &'a [u8]) -> eyre::Result<Lumps<'a>> {
+        let mut r = LumpReader::new(data);
+        if r.u32()? != BSP_IDENT {
+            eyre::bail!("Not a bsp file");
+        }
+
+        let version = r.i32()?;
+
+        let mut entries = [LumpEntry::default(); LUMP_COUNT];
+        for entry in entries.iter_mut() {
+            *entry = LumpEntry {
+                offset: r.u32()?,
+                length: r.u32()?,
+                version: r.i32()?,
+                uncompressed_length: r.u32()?,
+            };
+        }
+
+        Ok(Lumps {
+            data,
+            version,
+            entries,
+        })
+    }
+
+    /// The version of the bsp format, ex: `20` for tf2
+    pub fn version(&self) -> i32 {
+        self.version
+    }
+
+    pub fn lump_version(&self, lump: LumpType) -> i32 {
+        self.entries[lump as usize].version
+    }
+
+    /// Get the data of the lump, decompressing it if needed. Missing lumps are empty.
+    pub fn get(&self, lump: LumpType) -> eyre::Result<Cow<'a, [u8]>> {
+        let entry = self.entries[lump as usize];
+        let start = entry.offset as usize;
+        let end = start + entry.length as usize;
+        let data = self
+            .data
+            .get(start..end)
+            .ok_or_else(|| eyre::eyre!("Lump {lump:?} extends past the end of the file"))?;
+
+        if entry.uncompressed_length == 0 {
+            return Ok(Cow::Borrowed(data));
+        }
+
+        let data = decompress_lzma(data)
+            .map_err(|err| eyre::eyre!("Failed to decompress lump {lump:?}: {err}"))?;
+        if data.len() != entry.uncompressed_length as usize {
+            eyre::bail!(
+                "Lump {lump:?} decompressed to {} bytes, expected {}",
+                data.len(),
+                entry.uncompressed_length
+            );
+        }
+
+        Ok(Cow::Owned(data))
+    }
+}
+
+/// Find the game lump with the id in the bsp, along with its version.
+pub(crate) fn find_game_lump(data: &[u8], id: i32) -> eyre::Result<Option<(Cow<'_, [u8]>, u16)>> {
+    let lumps = Lumps::new(data)?;
+    let game_lump = lumps.get(LumpType::GameLump)?;
+    let mut r = LumpReader::new(&game_lump);
+    // A missing game lump is empty
+    let count = r.i32().unwrap_or(0);
+    for _ in 0..count {
+        let lump_id = r.i32()?;
+        let flags = r.u16()?;
+        let version = r.u16()?;
+        // Unlike the normal lumps, the offset is from the start of the file
+        let offset = r.i32()?;
+        let length = r.i32()?;
+        if lump_id != id {
+            continue;
+        }
+
+        let name = String::from_utf8_lossy(&id.to_be_bytes()).into_owned();
+        let (Ok(start), Ok(length)) = (usize::try_from(offset), usize::try_from(length)) else {
+            eyre::bail!("Game lump {name} has a negative offset ({offset}) or length ({length})");
+        };
+        let lump = start
+            .checked_add(length)
+            .and_then(|end| data.get(start..end))
+            .ok_or_else(|| eyre::eyre!("Game lump {name} extends past the end of the file"))?;
+        if flags & GAME_LUMP_COMPRESSED != 0 {
+            let lump = decompress_lzma(lump)
+                .map_err(|err| eyre::eyre!("Failed to decompress game lump {name}: {err}"))?;
+            return Ok(Some((Cow::Owned(lump), version)));
+        }
+        return Ok(Some((Cow::Borrowed(lump), version)));
+    }
+
+    Ok(None)
+}
+
+/// Decompress a lump in Source's LZMA format, which has its own header in place of the standard
+/// `.lzma` one.
+fn decompress_lzma(data: &[u8]) -> eyre::Result<Vec<u8>> {
+    let mut r = LumpReader::new(data);
+    if r.u32()? != LZMA_IDENT {
+        eyre::bail!("Missing LZMA header");
+    }
+    let actual_size = r.u32()?;
+    let lzma_size = r.u32()? as usize;
+    let properties = r.bytes::<5>()?;
+    let compressed = r.remaining();
+    let compressed = compressed.get(..lzma_size).unwrap_or(compressed);
+
+    // Rebuild the standard header: the properties, then the 64-bit uncompressed size
+    let mut input = Vec::with_capacity(13 + compressed.len());
+    input.extend_from_slice(&properties);
+    input.extend_from_slice(&(actual_size as u64).to_le_bytes());
+    input.extend_from_slice(compressed);
+
+    let mut output = Vec::with_capacity(actual_size as usize);
+    lzma_rs::lzma_decompress(&mut input.as_slice(), &mut output)
+        .map_err(|err| eyre::eyre!("{err:?}"))?;
+
+    Ok(output)
+}
+
+/// Split the lump into fixed size records and parse each of them.
+pub fn read_records<T>(
+    data: &[u8],
+    size: usize,
+    mut parse: impl FnMut(&mut LumpReader<'_>) -> eyre::Result<T>,
+) -> eyre::Result<Vec<T>> {
+    if !data.len().is_multiple_of(size) {
+        eyre::bail!(
+            "Lump length {} is not a multiple of the record size {size}",
+            data.len()
+        );
+    }
+
+    data.chunks_exact(size)
+        .map(|record| parse(&mut LumpReader::new(record)))
+        .collect()
+}
+
+/// Reads little-endian values out of lump data.
+#[derive(Debug, Clone)]
+pub struct LumpReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+impl<'a> LumpReader<'a> {
+    pub fn new(data: &'a [u8]) -> LumpReader<'a> {
+        LumpReader { data, pos: 0 }
+    }
+
+    pub fn bytes<const N: usize>(&mut self) -> eyre::Result<[u8; N]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + N)
+            .ok_or_else(|| eyre::eyre!("Unexpected end of lump data at {}", self.pos))?;
+        self.pos += N;
+
+        Ok(bytes.try_into().unwrap())
+    }
+
+    pub fn skip(&mut self, count: usize) {
+        self.pos += count;
+    }
+
+    /// The data that hasn't been read yet
+    pub fn remaining(&self) -> &'a [u8] {
+        self.data.get(self.pos..).unwrap_or(&[])
+    }
+
+    pub fn u8(&mut self) -> eyre::Result<u8> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    pub fn i16(&mut self) -> eyre::Result<i16> {
+        self.bytes().map(i16::from_le_bytes)
+    }
+
+    pub fn u16(&mut self) -> eyre::Result<u16> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+
+    pub fn i32(&mut self) -> eyre::Result<i32> {
+        self.bytes().map(i32::from_le_bytes)
+    }
+
+    pub fn u32(&mut self) -> eyre::Result<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    pub fn f32(&mut self) -> eyre::Result<f32> {
+        self.bytes().map(f32::from_le_bytes)
+    }
+
+    pub fn vec3(&mut self) -> eyre::Result<[f32; 3]> {
+        Ok([self.f32()?, self.f32()?, self.f32()?])
+    }
+}
+
+/// Build a bsp file which only has the given lumps, as `(lump, version, data)`
+#[cfg(test)]
+pub(crate) fn make_bsp(lumps: &[(LumpType, i32, Vec<u8>)]) -> Vec<u8> {
+    let mut header = vec![0; HEADER_SIZE];
+    header[0..4].copy_from_slice(b"VBSP");
+    header[4..8].copy_from_slice(&20i32.to_le_bytes());
+
+    let mut data = Vec::new();
+    for (lump, version, lump_data) in lumps {
+        let entry = 8 + *lump as usize * 16;
+        let offset = (HEADER_SIZE + data.len()) as u32;
+        header[entry..entry + 4].copy_from_slice(&offset.to_le_bytes());
+        header[entry + 4..entry + 8].copy_from_slice(&(lump_data.len() as u32).to_le_bytes());
+        header[entry + 8..entry + 12].copy_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(lump_data);
+    }
+
+    header.extend(data);
+    header
+}
+
+/// Build a bsp file whose game lump only has the given game lumps, as `(id, version, data)`
+#[cfg(test)]
+pub(crate) fn make_game_lump_bsp(lumps: &[(i32, u16, Vec<u8>)]) -> Vec<u8> {
+    // The game lump is the only lump, so it is right after the header
+    let mut offset = HEADER_SIZE + 4 + lumps.len() * 16;
+    let mut directory = Vec::new();
+    directory.extend((lumps.len() as i32).to_le_bytes());
+    for (id, version, data) in lumps {
+        directory.extend(id.to_le_bytes());
+        directory.extend(0u16.to_le_bytes());
+        directory.extend(version.to_le_bytes());
+        directory.extend((offset as i32).to_le_bytes());
+        directory.extend((data.len() as i32).to_le_bytes());
+        offset += data.len();
+    }
+    for (_, _, data) in lumps {
+        directory.extend(data);
+    }
+
+    make_bsp(&[(LumpType::GameLump, 0, directory)])
+}
+
+/// Write a dictionary of names, as in the prop game lumps: the count, and then each name padded
+/// to `length` bytes
+#[cfg(test)]
+pub(crate) fn write_names(data: &mut Vec<u8>, names: &[&str], length: usize) {
+    data.extend((names.len() as i32).to_le_bytes());
+    for name in names {
+        let mut name = name.as_bytes().to_vec();
+        name.resize(length, 0);
+        data.extend(name);
+    }
+}
+
+/// Write the count of the records, and then each record
+#[cfg(test)]
+pub(crate) fn write_records<T>(
+    data: &mut Vec<u8>,
+    records: &[T],
+    mut write: impl FnMut(&mut Vec<u8>, &T),
+) {
+    data.extend((records.len() as i32).to_le_bytes());
+    for record in records {
+        write(data, record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compress the data the way Source does, with its LZMA header
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut lzma = Vec::new();
+        lzma_rs::lzma_compress(&mut &data[..], &mut lzma).unwrap();
+        // The standard header is the properties and then the uncompressed size
+        let (properties, compressed) = (&lzma[..5], &lzma[13..]);
+
+        let mut out = Vec::new();
+        out.extend(LZMA_IDENT.to_le_bytes());
+        out.extend((data.len() as u32).to_le_bytes());
+        out.extend((compressed.len() as u32).to_le_bytes());
+        out.extend(properties);
+        out.extend(compressed);
+        out
+    }
+
+    /// Build a bsp with just the compressed lump, and the uncompressed length in its entry
+    fn make_compressed_bsp(lump: LumpType, data: &[u8], uncompressed_length: u32) -> Vec<u8> {
+        let mut bsp = make_bsp(&[(lump, 0, compress(data))]);
+        let entry = 8 + lump as usize * 16;
+        bsp[entry + 12..entry + 16].copy_from_slice(&uncompressed_length.to_le_bytes());
+        bsp
+    }
+
+    const SPRP: i32 = i32::from_be_bytes(*b"sprp");
+
+    /// Build a bsp whose game lump directory has a single `sprp` entry, with any bounds
+    fn make_directory_bsp(offset: i32, length: i32) -> Vec<u8> {
+        let mut directory = Vec::new();
+        directory.extend(1i32.to_le_bytes());
+        directory.extend(SPRP.to_le_bytes());
+        directory.extend(0u16.to_le_bytes());
+        directory.extend(10u16.to_le_bytes());
+        directory.extend(offset.to_le_bytes());
+        directory.extend(length.to_le_bytes());
+        make_bsp(&[(LumpType::GameLump, 0, directory)])
+    }
+
+    #[test]
+    fn test_game_lump_bounds() {
+        let bsp = make_directory_bsp(0, 4);
+        let (lump, version) = find_game_lump(&bsp, SPRP).unwrap().unwrap();
+        assert_eq!(*lump, bsp[..4]);
+        assert_eq!(version, 10);
+        assert_eq!(find_game_lump(&bsp, 0).unwrap(), None);
+
+        assert!(find_game_lump(&make_directory_bsp(-4, 4), SPRP).is_err());
+        assert!(find_game_lump(&make_directory_bsp(0, -4), SPRP).is_err());
+        let past_end = make_directory_bsp(i32::MAX, i32::MAX);
+        assert!(find_game_lump(&past_end, SPRP).is_err());
+    }
+
+    #[test]
+    fn test_compressed_lump() {
+        let data = (0..1000u32)
+            .flat_map(|i| (i % 7).to_le_bytes())
+            .collect::<Vec<_>>();
+
+        let bsp = make_bsp(&[(LumpType::Lighting, 0, data.clone())]);
+        let lumps = Lumps::new(&bsp).unwrap();
+        assert!(matches!(
+            lumps.get(LumpType::Lighting).unwrap(),
+            Cow::Borrowed(_)
+        ));
+        assert!(lumps.get(LumpType::Faces).unwrap().is_empty());
+
+        let bsp = make_compressed_bsp(LumpType::Lighting, &data, data.len() as u32);
+        let lumps = Lumps::new(&bsp).unwrap();
+        assert_eq!(*lumps.get(LumpType::Lighting).unwrap(), data[..]);
+
+        // The size in the lump entry has to match
+        let bsp = make_compressed_bsp(LumpType::Lighting, &data, 12);
+        let lumps = Lumps::new(&bsp).unwrap();
+        assert!(lumps.get(LumpType::Lighting).is_err());
+    }
+}
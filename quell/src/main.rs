@@ -1,51 +1,110 @@
 use bevy::{
+    asset::io::AssetSourceId,
+    core_pipeline::{clear_color::ClearColorConfig, Skybox},
     diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
+    pbr::NotShadowCaster,
     prelude::*,
+    render::view::RenderLayers,
 };
 
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
 use bevy_mod_outline::OutlinePlugin;
+use futures_lite::future;
 use quell::{
-    conf::{Config, MatLeafvis},
-    data::{GameId, LoadedTextures, VpkState},
-    map::GameMap,
-    material::load_materials,
-    mesh::{
-        angle_map, construct_meshes, degrees_to_radians, rotate, scale, unrotate, unscale, FaceInfo,
+    ambient::{AmbientCube, AMBIENT_CUBE_DIRECTIONS},
+    asset::{source_asset_source, AssetMounts, VmtLoader, VtfLoader},
+    brush::{BrushMaterial, BrushMaterialPlugin},
+    cache::{TextureCache, TextureRole},
+    conf::{Config, MatLeafvis, RenderConfig},
+    data::{ArchiveMaps, GameId, LoadedTextures, TextureName, VpkState},
+    detail::{DetailInstance, DetailKind, DetailOrientation},
+    light::{EnvironmentLight, LightKind, MapLight},
+    lightmap::Lightmaps,
+    loading::{
+        despawn_loading_screen, spawn_batch, spawn_loading_screen, spawn_map_read, spawn_vpk_load,
+        update_loading_screen, LoadingProgress, MapLoad, MaterialBatch, ReadMap, VpkLoadTask,
+        VpkSource,
+    },
+    map::{
+        AreaPortal, BrushEntity, ChangeMap, FaceBatch, FaceIndex, GameMap, MapEntity, MapState,
+        MapToLoad,
+    },
+    material::insert_materials,
+    mesh::{rotate, scale, unrotate, unscale, SCALE},
+    model::{ModelMaterial, ModelMaterialPlugin},
+    overlay::{overlay_mesh, InfoDecal, Overlay},
+    prop::{
+        source_rotation, spawn_prop_load, EntityKeyValues, LoadedProps, PropFade, PropLoadTask,
+        PropMaterial, PropMesh, RenderMode,
     },
+    reload::{LooseKind, LooseWatcher},
+    skybox::{SkyboxFace, SKYBOX_LAYER},
     util::transform_to_vbsp,
+    vis::{Bounds, ClusterSet, Split, Vis},
 };
 
-use rayon::prelude::ParallelIterator;
 use smooth_bevy_cameras::{
     controllers::unreal::{UnrealCameraBundle, UnrealCameraController, UnrealCameraPlugin},
     LookTransformPlugin,
 };
 
-fn main() {
-    // TODO: we should probably load vpks in setup so we can have a loading screen nicely
-    let start_time = std::time::Instant::now();
+const DEFAULT_MAP: &str = "ex/ctf_2fort.bsp";
+// const DEFAULT_MAP: &str = "ex/tf/tf/maps/test.bsp";
+
+const TEXTURE_CACHE_DIR: &str = "./cache/textures";
+/// 2 GiB
+const TEXTURE_CACHE_MAX_SIZE: u64 = 2 * 1024 * 1024 * 1024;
 
+fn main() {
     let mut conf = Config::default();
     // TODO: load console commands from file or allow them via cli
 
     conf.render.mat.leafvis = MatLeafvis::CurrentVisleaf;
-    conf.render.no_vis = true;
     // conf.render.draw_map = false;
     conf.render.draw_lights = false;
 
-    let game_id = GameId::Tf2;
-    let root_path = "./ex/tf/";
-    let vpk = VpkState::new(root_path, game_id).expect("Failed to load VPKs for the game");
-    let loaded_textures = LoadedTextures::default();
+    // The vpks are loaded in the background once the app starts
+    let root_path = PathBuf::from("./ex/tf/");
+    // Like the game, every folder in `custom/` is mounted as loose files
+    let loose_dirs = std::fs::read_dir(root_path.join("tf/custom"))
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.is_dir())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let loose_watcher = LooseWatcher::new(loose_dirs.clone());
+    let vpk_source = VpkSource {
+        root_path,
+        game_id: GameId::Tf2,
+        loose_dirs,
+    };
+    let mut loaded_textures = LoadedTextures::default();
+    match TextureCache::new(TEXTURE_CACHE_DIR, TEXTURE_CACHE_MAX_SIZE) {
+        Ok(cache) => loaded_textures.cache = Some(Arc::new(cache)),
+        Err(err) => eprintln!("Failed to open texture cache, continuing without it: {err:?}"),
+    }
 
-    let end_time = std::time::Instant::now();
-    println!("Loaded VPKs in {:?}", end_time - start_time);
+    // Any maps given on the command line can be cycled between at runtime
+    let mut maps = std::env::args()
+        .skip(1)
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    if maps.is_empty() {
+        maps.push(PathBuf::from(DEFAULT_MAP));
+    }
 
     // use std::io::Write;
     // let root = &vpk.textures.data;
     // let mut out_file = std::fs::File::create("tf2_textures.txt").unwrap();
     // write!(out_file, "{root:#?}").unwrap();
 
+    // Lets the asset server load materials out of the vpks and map once they're loaded
+    let mounts = AssetMounts::default();
+
     #[allow(clippy::default_constructed_unit_structs)]
     let mut app = App::new();
 
@@ -58,66 +117,119 @@ fn main() {
         //     features: WgpuFeatures::POLYGON_MODE_LINE,
         //     ..Default::default()
         // })
-        .insert_resource(vpk)
+        .insert_resource(vpk_source)
         .insert_resource(loaded_textures)
+        .insert_resource(loose_watcher)
+        .init_resource::<LoadingProgress>()
+        .init_resource::<Pvs>()
         .insert_resource(conf)
+        .insert_resource(MapToLoad(maps[0].clone()))
+        .insert_resource(MapList { maps, current: 0 })
+        .insert_resource(mounts.clone())
+        // Has to be registered before the asset plugin
+        .register_asset_source(AssetSourceId::Default, source_asset_source(mounts))
         .add_plugins(DefaultPlugins)
+        .add_plugins(BrushMaterialPlugin)
+        .add_plugins(ModelMaterialPlugin)
+        .init_asset_loader::<VmtLoader>()
+        .init_asset_loader::<VtfLoader>()
         // .add_plugins(WireframePlugin)
         .add_plugins(LookTransformPlugin)
         .add_plugins(UnrealCameraPlugin::default())
         .add_plugins(LogDiagnosticsPlugin::default())
         .add_plugins(FrameTimeDiagnosticsPlugin::default())
         .add_plugins(OutlinePlugin)
+        .add_state::<MapState>()
+        .add_event::<ChangeMap>()
         .add_systems(Startup, setup)
+        .add_systems(
+            OnEnter(MapState::Loading),
+            (start_map_load, spawn_loading_screen),
+        )
+        .add_systems(
+            OnExit(MapState::Loading),
+            (finish_map_load, despawn_loading_screen),
+        )
+        .add_systems(OnExit(MapState::Loaded), unload_map)
+        .add_systems(Update, (poll_vpk_load, poll_prop_load))
+        .add_systems(Update, (update_prop_fade, update_detail_props))
+        .add_systems(
+            Update,
+            (poll_map_load, update_loading_screen)
+                .chain()
+                .run_if(in_state(MapState::Loading)),
+        )
+        .add_systems(Update, (cycle_maps, change_map).chain())
+        .add_systems(Update, update_skybox_camera)
+        .add_systems(
+            Update,
+            toggle_area_portal.run_if(resource_exists::<GameMap>()),
+        )
+        .add_systems(Update, (toggle_triggers, update_trigger_visibility).chain())
+        .add_systems(Update, reload_loose_files)
         // .add_systems(Update, update_light_gizmos)
         .add_systems(Update, update_light_vis)
-        // Not sure if this should be preupdate or not
-        // .add_systems(PreUpdate, update_visibility)
-        // .add_systems(Update, leafvis_frame)
+        .add_systems(
+            Update,
+            update_visibility.run_if(resource_exists::<GameMap>()),
+        )
+        .add_systems(
+            Update,
+            (leafvis_frame, ambient_cube_frame, update_cluster_colors)
+                .run_if(resource_exists::<GameMap>()),
+        )
         .run();
 }
 
-/// The index of a face in the BSP
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Component)]
-pub struct FaceIndex(pub usize);
+/// The maps that can be cycled between at runtime
+#[derive(Debug, Clone, Resource)]
+pub struct MapList {
+    pub maps: Vec<PathBuf>,
+    pub current: usize,
+}
 
 #[allow(clippy::too_many_arguments)]
 fn setup(
     mut commands: Commands,
     mut asset_server: ResMut<AssetServer>,
     mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut materials: ResMut<Assets<BrushMaterial>>,
+    mut std_materials: ResMut<Assets<StandardMaterial>>,
     mut gizmo_conf: ResMut<GizmoConfig>,
     mut images: ResMut<Assets<Image>>,
     mut shaders: ResMut<Assets<Shader>>,
-    vpk: Res<VpkState>,
     mut loaded_textures: ResMut<LoadedTextures>,
-    conf: Res<Config>,
+    mut next_state: ResMut<NextState<MapState>>,
+    vpk_source: Res<VpkSource>,
 ) {
+    commands.insert_resource(spawn_vpk_load(vpk_source.clone()));
+
     loaded_textures.missing_texture = images.add(quell::material::missing_texture());
-    loaded_textures.missing_material = materials.add(StandardMaterial {
-        base_color_texture: Some(loaded_textures.missing_texture.clone()),
+    // Replaced by each map's lightmaps as they're loaded
+    loaded_textures.lightmap = images.add(Lightmaps::fullbright().image());
+    loaded_textures.missing_material = materials.add(BrushMaterial {
+        base_texture: loaded_textures.missing_texture.clone(),
+        lightmap: loaded_textures.lightmap.clone(),
         alpha_mode: AlphaMode::Blend,
         unlit: true,
         ..default()
     });
 
+    commands.insert_resource(TriggerMaterial(std_materials.add(StandardMaterial {
+        base_color: Color::rgba(1.0, 0.5, 0.0, 0.3),
+        alpha_mode: AlphaMode::Blend,
+        unlit: true,
+        double_sided: true,
+        cull_mode: None,
+        ..default()
+    })));
+
     gizmo_conf.enabled = true;
     gizmo_conf.depth_bias = -1.;
 
     // ambient_light.color = Color::WHITE;
     // ambient_light.brightness = 0.05;
 
-    // light
-    commands.spawn(PointLightBundle {
-        point_light: PointLight {
-            intensity: 1500.0,
-            shadows_enabled: false,
-            ..default()
-        },
-        transform: Transform::from_xyz(4.0, 200.0, 4.0),
-        ..default()
-    });
     // camera
     // commands.spawn(Camera3dBundle {
     //     transform: Transform::from_xyz(-2.0, 2.5, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
@@ -221,107 +333,1051 @@ fn setup(
     //         ..default()
     //     });
 
-    // Process the map
-
-    let map_path = "ex/ctf_2fort.bsp";
-    // let map_path = "ex/tf/tf/maps/test.bsp";
-    let mut map = GameMap::from_path(map_path).unwrap();
-    {
-        load_materials(
-            &vpk,
-            &mut loaded_textures,
-            &mut images,
-            &mut materials,
-            &map,
-        )
-        .unwrap();
+    // The map itself is loaded once we enter the loading state
+    next_state.set(MapState::Loading);
+}
+
+fn poll_vpk_load(
+    mut commands: Commands,
+    task: Option<ResMut<VpkLoadTask>>,
+    mounts: Res<AssetMounts>,
+    mut progress: ResMut<LoadingProgress>,
+) {
+    let Some(mut task) = task else {
+        return;
+    };
 
-        let start_time = std::time::Instant::now();
+    let Some(res) = future::block_on(future::poll_once(&mut task.0)) else {
+        return;
+    };
 
-        // TODO: would it be better to do a Task based system likes the async_compute example?
+    commands.remove_resource::<VpkLoadTask>();
+    let vpk = match res {
+        Ok(vpk) => vpk,
+        Err(err) => {
+            // Loading stays stopped on the loading screen, which shows why
+            eprintln!("Failed to load VPKs for the game: {err:?}");
+            progress.error = Some(format!("Failed to load VPKs for the game: {err:#}"));
+            return;
+        }
+    };
+    mounts.set_vpk(vpk.clone());
+    commands.insert_resource(vpk);
+}
 
-        // TODO: We might be able to do something wacky and maybe more efficient by reserving
-        // handles before hand.
+fn start_map_load(mut commands: Commands, mut progress: ResMut<LoadingProgress>) {
+    *progress = LoadingProgress {
+        stage: "Loading VPKs",
+        // The vpks are only loaded once, so if that failed no map can be loaded
+        error: progress.error.take(),
+        ..Default::default()
+    };
+    commands.insert_resource(MapLoading(MapLoad::WaitingForVpks));
+}
 
-        println!("Model count: #{}", map.bsp.models.len());
+/// The in-progress map load, which is advanced by [`poll_map_load`]
+#[derive(Resource)]
+struct MapLoading(MapLoad);
 
-        if conf.render.draw_map {
-            setup_map(
+#[allow(clippy::too_many_arguments)]
+fn poll_map_load(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut brush_materials: ResMut<Assets<BrushMaterial>>,
+    mut images: ResMut<Assets<Image>>,
+    vpk: Option<Res<VpkState>>,
+    mut loaded_textures: ResMut<LoadedTextures>,
+    conf: Res<Config>,
+    map_to_load: Res<MapToLoad>,
+    mut map: Option<ResMut<GameMap>>,
+    mut loading: ResMut<MapLoading>,
+    mut progress: ResMut<LoadingProgress>,
+    mut next_state: ResMut<NextState<MapState>>,
+    mounts: Res<AssetMounts>,
+    trigger_material: Res<TriggerMaterial>,
+) {
+    match &mut loading.0 {
+        MapLoad::WaitingForVpks => {
+            let Some(vpk) = vpk else {
+                return;
+            };
+
+            progress.stage = "Reading map";
+            let task = spawn_map_read(
+                map_to_load.0.clone(),
+                VpkState::clone(&vpk),
+                loaded_textures.cache.clone(),
+                // What the previous map left loaded is reused rather than loaded again
+                loaded_textures.loaded_names(),
+            );
+            loading.0 = MapLoad::ReadingMap(task);
+        }
+        MapLoad::ReadingMap(task) => {
+            let Some(res) = future::block_on(future::poll_once(task)) else {
+                return;
+            };
+
+            let ReadMap {
+                mut map,
+                batches,
+                skybox,
+                cubemaps,
+            } = match res {
+                Ok(read) => read,
+                Err(err) => {
+                    eprintln!("Failed to load map {:?}: {err:?}", map_to_load.0);
+                    next_state.set(MapState::Unloaded);
+                    return;
+                }
+            };
+
+            println!("Model count: #{}", map.bsp.models.len());
+
+            loaded_textures.set_lightmap(&mut images, &mut brush_materials, map.lightmaps.image());
+            loaded_textures.set_cubemaps(&mut images, &mut brush_materials, &map, cubemaps);
+
+            setup_entities(
                 &mut commands,
                 &mut meshes,
                 &mut materials,
                 &loaded_textures,
                 &mut map,
             );
+            let skybox = loaded_textures.set_skybox(&mut images, &mut brush_materials, skybox);
+            spawn_skybox_camera(&mut commands, &map, skybox);
+
+            if let Some(vpk) = &vpk {
+                let task = spawn_prop_load(
+                    VpkState::clone(vpk),
+                    map.clone(),
+                    loaded_textures.cache.clone(),
+                    // Textures kept from the previous map are reused rather than loaded again
+                    loaded_textures.loaded_names(),
+                    conf.render.draw_static_props,
+                    conf.render.draw_detail_props,
+                );
+                commands.insert_resource(PropLoadTask(task));
+            }
+
+            let tasks = if conf.render.draw_map {
+                // Vpk must exist since we only start reading the map once it is loaded
+                let vpk = vpk.unwrap();
+                // Shared so that each archive is only opened once
+                let archives = Arc::new(ArchiveMaps::default());
+                batches
+                    .into_iter()
+                    .map(|plan| {
+                        spawn_batch(
+                            VpkState::clone(&vpk),
+                            map.clone(),
+                            loaded_textures.cache.clone(),
+                            archives.clone(),
+                            conf.render.load_face_batching(),
+                            plan,
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            } else {
+                Vec::new()
+            };
+
+            mounts.set_map(map.clone());
+
+            progress.stage = "Loading materials";
+            progress.batches_total = tasks.len();
+
+            commands.insert_resource(map);
+            loading.0 = MapLoad::Streaming(tasks);
+        }
+        MapLoad::Streaming(tasks) => {
+            // The map is inserted by the previous stage, so it might not exist quite yet
+            let Some(map) = map.as_deref_mut() else {
+                return;
+            };
+
+            let mut finished = Vec::new();
+            tasks.retain_mut(|task| match future::block_on(future::poll_once(task)) {
+                Some(batch) => {
+                    finished.push(batch);
+                    false
+                }
+                None => true,
+            });
+
+            for batch in finished {
+                progress.batches_done += 1;
+                progress.faces_spawned += batch.meshes.iter().map(|m| m.faces.len()).sum::<usize>();
+                progress.meshes_spawned += batch.meshes.len();
+                spawn_batch_faces(
+                    &mut commands,
+                    &mut meshes,
+                    &mut brush_materials,
+                    &mut images,
+                    &mut loaded_textures,
+                    &trigger_material.0,
+                    map,
+                    batch,
+                );
+            }
+
+            if tasks.is_empty() {
+                // Only now are all of the materials loaded
+                spawn_overlays(&mut commands, &mut meshes, &images, &loaded_textures, map);
+                next_state.set(MapState::Loaded);
+            }
+        }
+    }
+}
+
+fn finish_map_load(mut commands: Commands, progress: Res<LoadingProgress>) {
+    // Dropping any remaining tasks cancels them
+    commands.remove_resource::<MapLoading>();
+    println!(
+        "Finished loading map; {} faces as {} meshes in {} batches",
+        progress.faces_spawned, progress.meshes_spawned, progress.batches_done
+    );
+}
+
+/// Insert the textures and materials of a finished batch, and spawn its meshes.
+fn spawn_batch_faces(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<BrushMaterial>,
+    images: &mut Assets<Image>,
+    loaded_textures: &mut LoadedTextures,
+    trigger_material: &Handle<StandardMaterial>,
+    map: &mut GameMap,
+    batch: MaterialBatch,
+) {
+    for (texture_name, role, res) in batch.images {
+        match res {
+            Ok((image, img_src)) => {
+                loaded_textures.insert_texture_of(images, texture_name, image, img_src, role);
+            }
+            Err(err) => {
+                eprintln!("Failed to construct image for texture {texture_name}: {err:?}");
+            }
         }
+    }
 
-        setup_entities(
-            &mut commands,
-            &mut meshes,
-            &mut materials,
-            &loaded_textures,
-            &mut map,
-        );
+    insert_materials(loaded_textures, materials, batch.materials);
 
-        let end_time = std::time::Instant::now();
+    for built in batch.meshes {
+        let mesh = meshes.add(built.mesh);
+        let brush_entity = map.brush_entities.get(&built.model).copied();
+        // The faces of brush entities are positioned by their entity instead
+        let transform = match brush_entity {
+            Some(_) => Transform::default(),
+            None => built.transform,
+        };
 
-        println!("Loaded map in {:?}", end_time - start_time);
+        let mut ent = match brush_entity {
+            Some((_, brush)) if brush.trigger => commands.spawn((
+                PbrBundle {
+                    mesh,
+                    material: trigger_material.clone(),
+                    transform,
+                    ..Default::default()
+                },
+                NotShadowCaster,
+            )),
+            _ => {
+                let material = loaded_textures
+                    .find_material_handle(&built.material_name)
+                    .unwrap_or_else(|| {
+                        println!("Failed to find material {:?}", built.material_name);
+                        loaded_textures.missing_material.clone()
+                    });
+                commands.spawn((
+                    MaterialMeshBundle {
+                        mesh,
+                        material,
+                        transform,
+                        ..Default::default()
+                    },
+                    // Brushes are lit by their lightmaps
+                    NotShadowCaster,
+                ))
+            }
+        };
+        // Faces of brush entities are despawned along with their entity
+        match brush_entity {
+            Some((parent, _)) => {
+                ent.set_parent(parent);
+            }
+            None => {
+                ent.insert(MapEntity);
+            }
+        }
+        if let [face] = built.faces.as_slice() {
+            ent.insert(FaceIndex(face.face_i));
+        }
+        for face in &built.faces {
+            map.faces.insert(face.face_i, ent.id());
+        }
+        ent.insert(FaceBatch {
+            faces: built.faces,
+            clusters: built.clusters,
+        });
+        if built.skybox {
+            ent.insert((SkyboxFace, RenderLayers::layer(SKYBOX_LAYER)));
+        }
     }
+}
 
-    // spawn_leaf_boundaries(&mut commands, &map, &mut *meshes, &mut *materials);
+#[allow(clippy::too_many_arguments)]
+fn poll_prop_load(
+    mut commands: Commands,
+    task: Option<ResMut<PropLoadTask>>,
+    map: Option<Res<GameMap>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut model_materials: ResMut<Assets<ModelMaterial>>,
+    mut images: ResMut<Assets<Image>>,
+    mut loaded_textures: ResMut<LoadedTextures>,
+) {
+    let (Some(mut task), Some(map)) = (task, map) else {
+        return;
+    };
 
-    commands.insert_resource(map);
+    let Some(loaded) = future::block_on(future::poll_once(&mut task.0)) else {
+        return;
+    };
+    commands.remove_resource::<PropLoadTask>();
+
+    spawn_props(
+        &mut commands,
+        &mut meshes,
+        &mut materials,
+        &mut model_materials,
+        &mut images,
+        &mut loaded_textures,
+        &map,
+        loaded,
+    );
 }
 
-fn setup_map(
+/// Where the ambient lighting of a prop is sampled
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PropLighting {
+    /// At the center of its model
+    Center,
+    /// At the point, in source coordinates
+    Origin([f32; 3]),
+    /// Not lit at all, ex: detail props which are tinted by their lighting instead
+    Unlit,
+}
+
+/// The center of the bounds of the model, in its local space
+fn model_center(model: &[PropMesh]) -> Vec3 {
+    model
+        .iter()
+        .filter_map(|mesh| mesh.mesh.compute_aabb())
+        .map(|aabb| (Vec3::from(aabb.min()), Vec3::from(aabb.max())))
+        .reduce(|(min_a, max_a), (min_b, max_b)| (min_a.min(min_b), max_a.max(max_b)))
+        .map_or(Vec3::ZERO, |(min, max)| (min + max) * 0.5)
+}
+
+/// Spawn the props, sharing the meshes and materials of their models between them.
+#[allow(clippy::too_many_arguments)]
+fn spawn_props(
     commands: &mut Commands,
     meshes: &mut Assets<Mesh>,
     materials: &mut Assets<StandardMaterial>,
-    loaded_textures: &LoadedTextures,
-    map: &mut GameMap,
+    model_materials: &mut Assets<ModelMaterial>,
+    images: &mut Assets<Image>,
+    loaded_textures: &mut LoadedTextures,
+    map: &GameMap,
+    loaded: LoadedProps,
 ) {
-    let faces = construct_meshes(loaded_textures, map).collect::<Vec<_>>();
-    let cmds = faces
+    for (texture_name, role, res) in loaded.images {
+        match res {
+            Ok((image, img_src)) => {
+                loaded_textures.insert_texture_of(images, texture_name, image, img_src, role);
+            }
+            Err(err) => {
+                eprintln!("Failed to construct image for texture {texture_name}: {err:?}");
+            }
+        }
+    }
+    for (name, image) in loaded.cubemaps {
+        loaded_textures
+            .cubemaps
+            .entry(name)
+            .or_insert_with(|| images.add(image));
+    }
+
+    let models = loaded
+        .models
         .into_iter()
-        .map(move |face_info| {
-            let FaceInfo {
-                mesh,
-                material_name,
-                transform,
-                face_i,
-            } = face_info;
-            let mesh = meshes.add(mesh);
-            // TODO: unwrap to missing texture and log warning if it doesn't exist
-            let material = loaded_textures
-                .find_material_handle(material_name)
-                .unwrap_or_else(|| {
-                    println!("Failed to find material {material_name:?}");
-                    loaded_textures.missing_material.clone()
-                });
+        .map(|(name, model)| {
+            let center = model_center(&model);
+            let model = model
+                .into_iter()
+                .map(|mesh| (meshes.add(mesh.mesh), mesh.materials))
+                .collect::<Vec<_>>();
+            (name, (model, center))
+        })
+        .collect::<HashMap<_, _>>();
+
+    // Entities can tint their model, and props are lit differently, so the materials are shared
+    // by color, lighting and the cubemap they reflect as well
+    let mut prop_materials = HashMap::new();
+    let mut material_handle = |material: Option<&PropMaterial>,
+                               color: [u8; 4],
+                               mode: RenderMode,
+                               ambient: Option<AmbientCube>,
+                               cubemap: Option<&TextureName>| {
+        let image_of = |name: &TextureName, role| {
+            loaded_textures
+                .find_texture(name, role)
+                .map(|t| t.image.clone())
+        };
+        let texture = material
+            .and_then(|material| image_of(&material.base_texture, TextureRole::Base))
+            .unwrap_or_else(|| loaded_textures.missing_texture.clone());
+        let envmap_params = material.and_then(|material| material.envmap.clone());
+        let envmap = envmap_params
+            .as_ref()
+            .and_then(|envmap| envmap.cubemap.as_ref().or(cubemap))
+            .and_then(|name| loaded_textures.cubemaps.get(name))
+            .cloned();
+        let envmap_mask = envmap_params
+            .as_ref()
+            .and_then(|envmap| envmap.mask.as_ref())
+            .and_then(|mask| image_of(mask, TextureRole::EnvMapMask));
+        let [r, g, b, a] = color;
+        // TODO: light static props with their vertex lighting from the pakfile (`sp_*.vhv`)
+        model_materials.add(ModelMaterial {
+            base_color: Color::rgba_u8(r, g, b, a),
+            base_texture: texture,
+            alpha_mode: mode.alpha_mode(),
+            ambient,
+            envmap,
+            envmap_mask,
+            envmap_params,
+        })
+    };
 
-            (
-                PbrBundle {
-                    mesh,
+    let mut spawn_model = |commands: &mut Commands,
+                           model: &str,
+                           transform: Transform,
+                           skin: i32,
+                           color: [u8; 4],
+                           mode: RenderMode,
+                           shadows: bool,
+                           lighting: PropLighting| {
+        let (model, center) = models.get(model)?;
+        let lighting_origin = match lighting {
+            PropLighting::Center => Some(unrotate(unscale(
+                transform.transform_point(*center).to_array(),
+            ))),
+            PropLighting::Origin(origin) => Some(origin),
+            PropLighting::Unlit => None,
+        };
+        // Props inside of walls, or in maps without ambient lighting, are drawn fullbright
+        let ambient = lighting_origin.and_then(|origin| map.ambient.sample(&map.vis, origin));
+        // `env_cubemap` is the cubemap nearest to where the prop is lit from
+        let nearest_cubemap = lighting_origin
+            .and_then(|origin| map.cubemaps.nearest(origin))
+            .map(|sample| map.cubemaps.texture_name(sample));
+
+        // Props in the 3D skybox are drawn by the skybox camera, like its faces
+        let skybox = map.is_sky_point(unrotate(unscale(transform.translation.to_array())));
+
+        let mut ent = commands.spawn((SpatialBundle::from_transform(transform), MapEntity));
+        if let Some(ambient) = ambient {
+            ent.insert(ambient);
+        }
+        if skybox {
+            ent.insert((SkyboxFace, RenderLayers::layer(SKYBOX_LAYER)));
+        }
+        ent.with_children(|parent| {
+            for (mesh, skins) in model {
+                // Models with fewer skins than the prop asks for use their first skin
+                let material = skins
+                    .get(skin.max(0) as usize)
+                    .or(skins.first())
+                    .and_then(|material| material.as_deref());
+                let prop_material = material.and_then(|material| loaded.materials.get(material));
+                // Only the materials reflecting `env_cubemap` depend on where the prop is
+                let cubemap = nearest_cubemap.clone().filter(|_| {
+                    prop_material
+                        .and_then(|material| material.envmap.as_ref())
+                        .is_some_and(|envmap| envmap.cubemap.is_none())
+                });
+                // Props in similar lighting share a material, lit by their rounded lighting so
+                // that they all look the same
+                let quantized = ambient.as_ref().map(AmbientCube::quantize);
+                let key = (material, color, mode, quantized, cubemap.clone());
+                let material = prop_materials
+                    .entry(key)
+                    .or_insert_with(|| {
+                        let ambient = quantized.map(AmbientCube::from_quantized);
+                        material_handle(prop_material, color, mode, ambient, cubemap.as_ref())
+                    })
+                    .clone();
+
+                let mut child = parent.spawn(MaterialMeshBundle {
+                    mesh: mesh.clone(),
                     material,
+                    ..default()
+                });
+                if !shadows {
+                    child.insert(NotShadowCaster);
+                }
+                // Render layers aren't inherited
+                if skybox {
+                    child.insert(RenderLayers::layer(SKYBOX_LAYER));
+                }
+            }
+        });
+        Some(ent.id())
+    };
+
+    for prop in &loaded.props {
+        let transform = Transform::from_translation(Vec3::from(rotate(scale(prop.origin))))
+            .with_rotation(source_rotation(prop.angles))
+            .with_scale(Vec3::splat(prop.scale));
+        let white = [255; 4];
+        let lighting = prop
+            .lighting_origin
+            .map_or(PropLighting::Center, PropLighting::Origin);
+        let Some(ent) = spawn_model(
+            commands,
+            &prop.model,
+            transform,
+            prop.skin,
+            white,
+            RenderMode::Normal,
+            true,
+            lighting,
+        ) else {
+            continue;
+        };
+
+        commands.entity(ent).insert(PropFade {
+            min: prop.fade_min,
+            max: prop.fade_max,
+        });
+    }
+
+    for prop in loaded.entity_props {
+        if prop.render_mode == RenderMode::None {
+            continue;
+        }
+
+        let transform = Transform::from_translation(Vec3::from(rotate(scale(prop.origin))))
+            .with_rotation(source_rotation(prop.angles))
+            .with_scale(Vec3::splat(prop.scale));
+        let Some(ent) = spawn_model(
+            commands,
+            &prop.model,
+            transform,
+            prop.skin,
+            prop.color,
+            prop.render_mode,
+            prop.shadows,
+            PropLighting::Center,
+        ) else {
+            continue;
+        };
+
+        commands.entity(ent).insert(prop.keyvalues);
+    }
+
+    // Detail props are tinted by the light where they are, which is rounded so that they can share
+    // their materials
+    let lighting_color = |lighting: [f32; 3]| {
+        let [r, g, b] = lighting.map(|c| c.min(1.0));
+        let [r, g, b, _] = Color::rgb_linear(r, g, b).as_rgba_u8();
+        [r & !7, g & !7, b & !7, 255]
+    };
+
+    let mut sprites = Vec::new();
+    for prop in &loaded.detail_props.props {
+        let transform = Transform::from_translation(Vec3::from(rotate(scale(prop.origin))));
+        let transform = match prop.orientation {
+            DetailOrientation::Normal => transform.with_rotation(source_rotation(prop.angles)),
+            // Turned to face the camera by `update_detail_props`
+            _ => transform,
+        };
+        let color = lighting_color(prop.lighting);
+
+        match &prop.kind {
+            DetailKind::Model(model) => {
+                let Some(ent) = spawn_model(
+                    commands,
+                    model,
                     transform,
-                    ..Default::default()
-                },
-                FaceIndex(face_i),
-            )
+                    0,
+                    color,
+                    RenderMode::Normal,
+                    false,
+                    PropLighting::Unlit,
+                ) else {
+                    continue;
+                };
+                commands.entity(ent).insert(DetailInstance {
+                    orientation: prop.orientation,
+                    scale: 1.0,
+                });
+            }
+            DetailKind::Sprite(sprite) => sprites.push((*sprite, transform, color, prop)),
+        }
+    }
+
+    if sprites.is_empty() {
+        return;
+    }
+
+    let sheet = loaded
+        .materials
+        .get(&loaded.detail_material)
+        .and_then(|material| {
+            loaded_textures.find_texture(&material.base_texture, TextureRole::Base)
         })
-        // We have to collect a second time because spawn_batch requires a 'static
-        // iterator
+        .map(|texture| texture.image.clone())
+        .unwrap_or_else(|| loaded_textures.missing_texture.clone());
+    let sprite_meshes = loaded
+        .detail_props
+        .sprites
+        .iter()
+        .map(|sprite| meshes.add(sprite.mesh()))
         .collect::<Vec<_>>();
+    let mut sprite_materials = HashMap::new();
+    for (sprite, transform, color, prop) in sprites {
+        let material = sprite_materials
+            .entry(color)
+            .or_insert_with(|| {
+                let [r, g, b, a] = color;
+                materials.add(StandardMaterial {
+                    base_color: Color::rgba_u8(r, g, b, a),
+                    base_color_texture: Some(sheet.clone()),
+                    // TODO: use the parameters of the detail material
+                    alpha_mode: AlphaMode::Mask(0.5),
+                    perceptual_roughness: 1.0,
+                    unlit: true,
+                    double_sided: true,
+                    cull_mode: None,
+                    ..default()
+                })
+            })
+            .clone();
+
+        let mut ent = commands.spawn((
+            PbrBundle {
+                mesh: sprite_meshes[sprite].clone(),
+                material,
+                transform: transform.with_scale(Vec3::splat(prop.scale)),
+                ..default()
+            },
+            DetailInstance {
+                orientation: prop.orientation,
+                scale: prop.scale,
+            },
+            NotShadowCaster,
+            MapEntity,
+        ));
+        if map.is_sky_point(prop.origin) {
+            ent.insert((SkyboxFace, RenderLayers::layer(SKYBOX_LAYER)));
+        }
+    }
+}
+
+/// Hide the static props that are further from the camera than their fade distance.
+/// Props in the 3D skybox are left alone, since they're seen from the skybox camera.
+// TODO: fade them out between the min and max distances, rather than popping out at the max
+fn update_prop_fade(
+    mut props: Query<(&PropFade, &Transform, &mut Visibility), Without<SkyboxFace>>,
+    cameras: Query<&Transform, With<UnrealCameraController>>,
+) {
+    let Some(camera) = cameras.iter().next() else {
+        return;
+    };
+
+    for (fade, transform, mut visibility) in props.iter_mut() {
+        let distance = camera.translation.distance(transform.translation) / SCALE;
+        let visible = fade.max <= 0.0 || distance <= fade.max;
+        visibility.set_if_neq(if visible {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        });
+    }
+}
+
+/// Hide the detail props that are further from the camera than `cl_detaildist`, and turn the
+/// sprites to face the camera.
+/// Rather than blending out like in the game, which would need a material for every prop, they
+/// shrink away over `cl_detailfade`.
+/// The ones in the 3D skybox are measured from and turned to the skybox camera instead.
+fn update_detail_props(
+    conf: Res<Config>,
+    mut props: Query<(
+        &DetailInstance,
+        &mut Transform,
+        &mut Visibility,
+        Has<SkyboxFace>,
+    )>,
+    cameras: Query<&Transform, (With<UnrealCameraController>, Without<DetailInstance>)>,
+    sky_cameras: Query<&Transform, (With<SkyboxCamera>, Without<DetailInstance>)>,
+) {
+    let Some(main_camera) = cameras.iter().next() else {
+        return;
+    };
+    let sky_camera = sky_cameras.iter().next();
+    let max = conf.render.detail_dist;
+    let fade = conf.render.detail_fade.clamp(0.0, max.max(0.0));
+
+    for (detail, mut transform, mut visibility, skybox) in props.iter_mut() {
+        let camera = match (skybox, sky_camera) {
+            (false, _) => main_camera,
+            (true, Some(sky_camera)) => sky_camera,
+            // Nothing draws them without a skybox camera
+            (true, None) => continue,
+        };
+        let distance = camera.translation.distance(transform.translation) / SCALE;
+        if distance > max {
+            visibility.set_if_neq(Visibility::Hidden);
+            continue;
+        }
+        visibility.set_if_neq(Visibility::Inherited);
+
+        let mut new = *transform;
+        let fade_scale = if fade > 0.0 {
+            ((max - distance) / fade).min(1.0)
+        } else {
+            1.0
+        };
+        new.scale = Vec3::splat(detail.scale * fade_scale);
+        match detail.orientation {
+            DetailOrientation::Normal => {}
+            DetailOrientation::ScreenAligned => new.rotation = camera.rotation,
+            DetailOrientation::ScreenAlignedVertical => {
+                let to_camera = camera.translation - new.translation;
+                new.rotation = Quat::from_rotation_y(to_camera.x.atan2(to_camera.z));
+            }
+        }
+        transform.set_if_neq(new);
+    }
+}
+
+/// Spawn the overlays and decals of the map, which need their materials to be loaded.
+fn spawn_overlays(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    images: &Assets<Image>,
+    loaded_textures: &LoadedTextures,
+    map: &GameMap,
+) {
+    let decals = map
+        .bsp
+        .entities
+        .iter()
+        .filter_map(|raw_ent| InfoDecal::from_properties(raw_ent.properties()))
+        .filter_map(|decal| {
+            // Decals are the size of their texture
+            let texture = loaded_textures
+                .find_material_texture(&decal.material)?
+                .ok()?;
+            let size = images.get(&texture)?.texture_descriptor.size;
+            let size = [size.width as f32, size.height as f32];
+            Overlay::decal(&map.bsp, decal.material, decal.origin, size)
+        });
+
+    let mut count = 0;
+    // TODO: draw overlays with a higher render order on top of others
+    for overlay in map.overlays.iter().cloned().chain(decals) {
+        let Some(mesh) = overlay_mesh(map, &overlay) else {
+            continue;
+        };
+        let material = loaded_textures
+            .find_material_handle(&overlay.material)
+            .unwrap_or_else(|| loaded_textures.missing_material.clone());
+
+        let mut ent = commands.spawn((
+            MaterialMeshBundle {
+                mesh: meshes.add(mesh),
+                material,
+                ..default()
+            },
+            NotShadowCaster,
+            MapEntity,
+        ));
+        if overlay.faces.iter().any(|face| map.is_sky_face(*face)) {
+            ent.insert((SkyboxFace, RenderLayers::layer(SKYBOX_LAYER)));
+        }
+        count += 1;
+    }
+
+    println!("Spawned {count} overlays and decals");
+}
+
+/// Despawn everything belonging to the current map and drop its assets.  
+/// Assets from the vpks are kept loaded so that the next map can reuse them.
+fn unload_map(
+    mut commands: Commands,
+    map_entities: Query<Entity, With<MapEntity>>,
+    mut loaded_textures: ResMut<LoadedTextures>,
+    mounts: Res<AssetMounts>,
+) {
+    // Every entity of the map is either marked or the child of one that is
+    for entity in map_entities.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    commands.remove_resource::<GameMap>();
+    // Dropping the task cancels it, if the props are still loading
+    commands.remove_resource::<PropLoadTask>();
+    // The clusters of the old map mean nothing in the next one
+    commands.insert_resource(Pvs::default());
+    // The ambient light comes from the `light_environment` of the map
+    commands.insert_resource(AmbientLight::default());
+    mounts.clear_map();
+    loaded_textures.unload_map();
+}
+
+/// Switch to the map requested by a [`ChangeMap`] event.
+fn change_map(
+    mut commands: Commands,
+    mut events: EventReader<ChangeMap>,
+    state: Res<State<MapState>>,
+    mut next_state: ResMut<NextState<MapState>>,
+    mut maps: ResMut<MapList>,
+) {
+    // Only the most recent request matters
+    let Some(ChangeMap(path)) = events.read().last() else {
+        return;
+    };
+
+    if *state.get() == MapState::Loading {
+        // TODO: we could cancel the current load instead
+        println!("Ignoring map change to {path:?} since a map is currently loading");
+        return;
+    }
+
+    println!("Changing map to {path:?}");
+    if let Some(i) = maps.maps.iter().position(|map| map == path) {
+        maps.current = i;
+    }
+    commands.insert_resource(MapToLoad(path.clone()));
+    // Leaving `Loaded` will unload the current map
+    next_state.set(MapState::Loading);
+}
+
+/// Reload any materials and textures whose loose files have changed.
+fn reload_loose_files(
+    time: Res<Time>,
+    mut watcher: ResMut<LooseWatcher>,
+    vpk: Option<Res<VpkState>>,
+    map: Option<Res<GameMap>>,
+    mut loaded_textures: ResMut<LoadedTextures>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<BrushMaterial>>,
+    mut model_materials: ResMut<Assets<ModelMaterial>>,
+) {
+    // Nothing is loaded without the vpks, so there is nothing to reload yet
+    let Some(vpk) = vpk else {
+        return;
+    };
+    let map = map.as_deref();
+
+    for change in watcher.update(time.delta()) {
+        match change.kind {
+            LooseKind::Vtf => {
+                println!("Reloading texture {:?}", change.name);
+                let errors = loaded_textures.reload_texture(
+                    &vpk,
+                    map,
+                    &mut images,
+                    &mut materials,
+                    &mut model_materials,
+                    &change.name,
+                );
+                for (role, err) in errors {
+                    eprintln!(
+                        "Failed to reload texture {:?} as {role:?}: {err:?}",
+                        change.name
+                    );
+                }
+            }
+            LooseKind::Vmt => {
+                // Materials which include the changed vmt have to be refreshed too
+                for name in loaded_textures.materials_using_vmt(&change.name) {
+                    println!("Reloading material {name:?}");
+                    if let Err(err) = loaded_textures.reload_material(
+                        &vpk,
+                        map,
+                        &mut images,
+                        &mut materials,
+                        &name,
+                    ) {
+                        eprintln!("Failed to reload material {name:?}: {err:?}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Go to the next map in the [`MapList`] when F5 is pressed.  
+/// The current map only changes once [`change_map`] accepts the request.
+fn cycle_maps(keys: Res<Input<KeyCode>>, maps: Res<MapList>, mut events: EventWriter<ChangeMap>) {
+    if !keys.just_pressed(KeyCode::F5) {
+        return;
+    }
+
+    let next = (maps.current + 1) % maps.maps.len();
+    events.send(ChangeMap(maps.maps[next].clone()));
+}
+
+/// Draws the skyboxes, before the main camera draws the map over them.
+/// It follows the main camera around the 3D skybox, scaled down by its scale.
+#[derive(Debug, Clone, Copy, Component)]
+struct SkyboxCamera;
+
+fn spawn_skybox_camera(commands: &mut Commands, map: &GameMap, skybox: Option<Handle<Image>>) {
+    if skybox.is_none() && map.sky.camera.is_none() {
+        return;
+    }
+
+    let mut camera = commands.spawn((
+        Camera3dBundle {
+            camera: Camera {
+                order: -1,
+                ..default()
+            },
+            ..default()
+        },
+        RenderLayers::layer(SKYBOX_LAYER),
+        SkyboxCamera,
+        MapEntity,
+    ));
+    if let Some(skybox) = skybox {
+        camera.insert(Skybox(skybox));
+    }
+}
+
+fn update_skybox_camera(
+    map: Option<Res<GameMap>>,
+    mut main_cameras: Query<
+        (&Transform, &mut Camera3d),
+        (With<UnrealCameraController>, Without<SkyboxCamera>),
+    >,
+    mut sky_cameras: Query<&mut Transform, With<SkyboxCamera>>,
+) {
+    let has_sky = !sky_cameras.is_empty();
+    let Some((transform, mut camera)) = main_cameras.iter_mut().next() else {
+        return;
+    };
+
+    // The skybox camera clears the screen, so the main camera draws on top of what it drew
+    if has_sky != matches!(camera.clear_color, ClearColorConfig::None) {
+        camera.clear_color = if has_sky {
+            ClearColorConfig::None
+        } else {
+            ClearColorConfig::Default
+        };
+    }
+
+    let sky_camera = map.and_then(|map| map.sky.camera);
+    let (origin, sky_scale) = sky_camera.map_or((Vec3::ZERO, 1.0), |camera| {
+        (Vec3::from_array(rotate(scale(camera.origin))), camera.scale)
+    });
+    for mut sky_transform in sky_cameras.iter_mut() {
+        sky_transform.translation = origin + transform.translation / sky_scale;
+        sky_transform.rotation = transform.rotation;
+    }
+}
+
+/// The translucent material that trigger brushes are drawn with
+#[derive(Resource)]
+struct TriggerMaterial(Handle<StandardMaterial>);
 
-    // commands.spawn_batch(cmds);
-    // Ugh, spawn batch doesn't spawn immediately and so doesn't give us any way to get the entity
-    // ids!
-    for (pbr, face_i) in cmds {
-        let ent = commands.spawn((pbr, face_i));
-        map.faces.insert(face_i.0, ent.id());
+/// Toggle drawing the trigger brushes, like `showtriggers_toggle`.
+fn toggle_triggers(keys: Res<Input<KeyCode>>, mut conf: ResMut<Config>) {
+    if keys.just_pressed(KeyCode::T) {
+        conf.render.show_triggers = !conf.render.show_triggers;
     }
 }
 
+/// Hide the trigger brushes unless they're being shown for debugging.
+fn update_trigger_visibility(
+    conf: Res<Config>,
+    mut brushes: Query<(&BrushEntity, &mut Visibility)>,
+) {
+    let visibility = if conf.render.show_triggers {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    };
+    for (brush, mut brush_visibility) in brushes.iter_mut() {
+        if brush.trigger {
+            brush_visibility.set_if_neq(visibility);
+        }
+    }
+}
+
+/// Open or close the areaportal that the camera is looking at, or the closest one if it isn't
+/// looking at any. With shift held, every areaportal is closed, or opened if they all are closed.
+fn toggle_area_portal(
+    keys: Res<Input<KeyCode>>,
+    map: Res<GameMap>,
+    cameras: Query<&Transform, With<UnrealCameraController>>,
+    mut portals: Query<&mut AreaPortal>,
+) {
+    /// How far from the center of the view a portal can be while being looked at, in radians
+    const AIM_ANGLE: f32 = 0.2;
+
+    if !keys.just_pressed(KeyCode::P) {
+        return;
+    }
+
+    if keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+        let open = portals.iter().all(|portal| !portal.open);
+        for mut portal in portals.iter_mut() {
+            portal.open = open;
+        }
+        let state = if open { "Opened" } else { "Closed" };
+        info!("{state} all {} areaportals", portals.iter().count());
+        return;
+    }
+
+    let Some(camera) = cameras.iter().next() else {
+        return;
+    };
+    let position = transform_to_vbsp(*camera);
+    let position = Vec3::new(position.x, position.y, position.z);
+    let forward = Vec3::from_array(unrotate(camera.forward().to_array()));
+
+    let aimed = |angle: f32| angle <= AIM_ANGLE;
+    let target = portals
+        .iter_mut()
+        .filter_map(|portal| {
+            let to_portal = Vec3::from_array(map.vis.portal_center(portal.portal)?) - position;
+            Some((forward.angle_between(to_portal), to_portal.length(), portal))
+        })
+        // The portal nearest the center of the view, or else the closest portal
+        .min_by(|(a_angle, a_dist, _), (b_angle, b_dist, _)| {
+            aimed(*b_angle).cmp(&aimed(*a_angle)).then_with(|| {
+                if aimed(*a_angle) {
+                    a_angle.total_cmp(b_angle)
+                } else {
+                    a_dist.total_cmp(b_dist)
+                }
+            })
+        });
+    let Some((_, _, mut portal)) = target else {
+        return;
+    };
+
+    portal.open = !portal.open;
+    let state = if portal.open { "Opened" } else { "Closed" };
+    info!("{state} areaportal {}", portal.portal);
+}
+
 fn setup_entities(
     commands: &mut Commands,
     meshes: &mut Assets<Mesh>,
@@ -333,6 +1389,43 @@ fn setup_entities(
     for raw_ent in map.bsp.entities.iter() {
         // let props = raw_ent.properties().collect::<Vec<_>>();
         // println!("Entity: {raw_ent:?}\n\t{props:#?}\n\n");
+        // vbsp doesn't give us the portal number, so it is read from the keyvalues
+        if let Some(portal) = AreaPortal::from_properties(raw_ent.properties()) {
+            commands.spawn((portal, MapEntity));
+        }
+        // The faces of the model are parented to this as they're streamed in
+        if let Some(brush) = BrushEntity::from_properties(raw_ent.properties()) {
+            let transform = Transform::from_translation(Vec3::from(rotate(scale(brush.origin))))
+                .with_rotation(source_rotation(brush.angles));
+            let entity = commands
+                .spawn((
+                    SpatialBundle::from_transform(transform),
+                    brush,
+                    EntityKeyValues::from_properties(raw_ent.properties()),
+                    MapEntity,
+                ))
+                .id();
+            map.brush_entities.insert(brush.model, (entity, brush));
+        }
+        // vbsp doesn't read the attenuation of lights, so they're read from the keyvalues
+        if let Some(light) = MapLight::from_properties(raw_ent.properties()) {
+            spawn_light(commands, &light);
+        }
+        if let Some(environment) = EnvironmentLight::from_properties(raw_ent.properties()) {
+            commands.spawn((
+                DirectionalLightBundle {
+                    directional_light: environment.directional_light(),
+                    transform: environment.transform(),
+                    ..default()
+                },
+                EntityLight,
+                MapEntity,
+            ));
+            if let Some(ambient) = environment.ambient_light() {
+                commands.insert_resource(ambient);
+            }
+        }
+
         let ent = raw_ent.parse().unwrap();
         // println!("Ent: {ent:?}");
         spawn_entity(commands, meshes, materials, loaded_textures, map, &ent);
@@ -342,6 +1435,24 @@ fn setup_entities(
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Component)]
 pub struct EntityLight;
 
+/// Spawn a `light`, `light_spot` or `point_spotlight`
+fn spawn_light(commands: &mut Commands, light: &MapLight) {
+    let transform = light.transform();
+    match light.kind {
+        LightKind::Point => commands.spawn(PointLightBundle {
+            point_light: light.point_light(),
+            transform,
+            ..default()
+        }),
+        LightKind::Spot { .. } => commands.spawn(SpotLightBundle {
+            spot_light: light.spot_light(),
+            transform,
+            ..default()
+        }),
+    }
+    .insert((EntityLight, MapEntity));
+}
+
 fn spawn_entity(
     commands: &mut Commands,
     meshes: &mut Assets<Mesh>,
@@ -360,129 +1471,24 @@ fn spawn_entity(
         Entity::ObserverPoint(_) => {}
         Entity::SkyCamera(_) => {}
         // Lights
-        Entity::Light(light) => {
-            // Lights are a point which shines in all directions
-            let origin = <[f32; 3]>::from(light.origin);
-            let origin = rotate(scale(origin));
-            let [r, g, b, brightness] = light.light;
-
-            let color = Color::rgb_u8(r as u8, g as u8, b as u8);
-            let brightness = brightness as f32 * 100.0;
-
-            let transform = Transform::from_xyz(origin[0], origin[1], origin[2]);
-
-            println!("Creating point light at {transform:?}; {r},{g},{b}; {brightness}");
-
-            commands
-                .spawn(PointLightBundle {
-                    point_light: PointLight {
-                        intensity: brightness,
-                        color,
-                        shadows_enabled: false,
-                        ..default()
-                    },
-                    transform,
-                    ..default()
-                })
-                .insert(EntityLight);
-        }
-        Entity::SpotLight(spot_light) => {
-            let origin = <[f32; 3]>::from(spot_light.origin);
-            let origin = rotate(scale(origin));
-            let angles = angle_map(spot_light.angles);
-            let [r, g, b] = spot_light.color;
-            // also known as spotlight width
-            // the outer (fading) angle
-            let cone = spot_light.cone;
-            // TODO: it might have other things like entity to point at, pitch, inner cone, focus...
-
-            let color = Color::rgb_u8(r, g, b);
-
-            let x_r = degrees_to_radians(angles[0]);
-            let y_r = degrees_to_radians(angles[1]);
-            let z_r = degrees_to_radians(angles[2]);
-            let transform = Transform::from_xyz(origin[0], origin[1], origin[2])
-                // .looking_at(Vec3::ZERO, Vec3::Y)
-                .with_rotation(Quat::from_euler(EulerRot::XYZ, x_r, y_r, z_r));
-
-            println!(
-                "Creating spot light at {:?} -> {angles:?} -> {:?}; {r},{g},{b}; {cone}",
-                spot_light.angles, transform.rotation
-            );
-
-            commands
-                .spawn(SpotLightBundle {
-                    spot_light: SpotLight {
-                        // color,
-                        // intensity: todo!(),
-                        // range: todo!(),
-                        // radius: todo!(),
-                        // shadows_enabled: todo!(),
-                        // shadow_depth_bias: todo!(),
-                        // shadow_normal_bias: todo!(),
-                        // outer_angle: todo!(),
-                        // inner_angle: todo!(),
-                        color,
-                        intensity: 800.0,
-                        range: 40.0,
-                        radius: 20.0,
-                        shadows_enabled: false,
-                        ..Default::default()
-                    },
-                    transform,
-                    ..default()
-                })
-                .insert(EntityLight);
-        }
-        Entity::LightSpot(light_spot) => {
-            let origin = <[f32; 3]>::from(light_spot.origin);
-            let origin = rotate(scale(origin));
-            let angles = angle_map(light_spot.angles);
-            let [r, g, b, brightness] = light_spot.light;
-            let cone = light_spot.cone;
-
-            let color = Color::rgb_u8(r as u8, g as u8, b as u8);
-            let brightness = brightness as f32 * 100.0;
-
-            let pitch = degrees_to_radians(angles[0]);
-            let yaw = degrees_to_radians(angles[1]);
-            let roll = degrees_to_radians(angles[2]);
-
-            let transform = Transform::from_xyz(origin[0], origin[1], origin[2])
-                .looking_at(Vec3::ZERO, Vec3::Y)
-                .with_rotation(Quat::from_euler(EulerRot::XYZ, pitch, yaw, roll));
-
-            println!("Creating spot light at {transform:?}; {r},{g},{b}; {cone}");
-
-            // commands.spawn(SpotLightBundle {
-            //     spot_light: SpotLight {
-            //         color,
-            //         intensity: brightness,
-            //         range: 40.0,
-            //         radius: 20.0,
-            //         shadows_enabled: false,
-            //         ..Default::default()
-            //     },
-            //     transform,
-            //     ..default()
-            // })
-            // .insert(EntityLight);
-        }
+        // Spawned by `setup_entities`
+        Entity::Light(_) | Entity::SpotLight(_) | Entity::LightSpot(_) => {}
         Entity::LightGlow(light_glow) => {
             // TODO
         }
         // Models
-        Entity::AmmoPackSmall(_ammo)
-        | Entity::AmmoPackMedium(_ammo)
-        | Entity::AmmoPackFull(_ammo) => {}
-        Entity::HealthPackSmall(_health)
-        | Entity::HealthPackMedium(_health)
-        | Entity::HealthPackFull(_health) => {}
+        // Spawned once their models are loaded, see `spawn_prop_load`
+        Entity::AmmoPackSmall(_)
+        | Entity::AmmoPackMedium(_)
+        | Entity::AmmoPackFull(_)
+        | Entity::HealthPackSmall(_)
+        | Entity::HealthPackMedium(_)
+        | Entity::HealthPackFull(_)
+        | Entity::PropDynamic(_)
+        | Entity::PropDynamicOverride(_)
+        | Entity::PropPhysics(_) => {}
         Entity::Door(_door) => {}
         Entity::Brush(brush) => {}
-        Entity::PropDynamic(prop) => {}
-        Entity::PropDynamicOverride(prop) => {}
-        Entity::PropPhysics(prop) => {}
         // Particles / Decals
         Entity::ParticleSystem(_) => {}
         Entity::EnvSprite(_) => {}
@@ -499,6 +1505,7 @@ fn spawn_entity(
         Entity::WorldSpawn(world_spawn) => {
             // world_spawns.push(world_spawn);
         }
+        // Spawned by `setup_entities`
         Entity::AreaPortal(_) => {}
         Entity::RespawnVisualizer(_) => {}
         Entity::RespawnRoom(room) => {
@@ -511,144 +1518,100 @@ fn spawn_entity(
     }
 }
 
-// TODO: possibly we should group faces under one parent node so we can hide them all at once?
+/// The clusters that are potentially visible from the cameras, which `r_lockpvs` freezes.
+#[derive(Debug, Default, Resource)]
+pub struct Pvs {
+    /// The clusters the cameras were in when the visible set was last computed
+    clusters: Vec<i16>,
+    /// `None` when everything is visible, ex: when a camera is outside the map
+    visible: Option<ClusterSet>,
+}
+
+/// Hide the face entities which aren't in any cluster visible from the cameras, or which are
+/// behind closed areaportals.
+// `func_occluder` isn't used: in Source it only culls props and entities, by testing their
+// screen space bounds against the occluder polygons every frame.
 fn update_visibility(
-    // commands: Commands,
-    // meshes: Res<Assets<Mesh>>,
     map: Res<GameMap>,
-    mut nodes: Query<(&FaceIndex, &mut Visibility, &Transform)>,
-    cameras: Query<(&UnrealCameraController, &Transform)>,
+    mut pvs: ResMut<Pvs>,
+    // The 3D skybox is drawn from its own camera, so the PVS of the main camera doesn't apply
+    mut batches: Query<(&FaceBatch, &mut Visibility), Without<SkyboxFace>>,
+    portals: Query<Ref<AreaPortal>>,
+    cameras: Query<&Transform, With<UnrealCameraController>>,
     conf: Res<Config>,
 ) {
-    if conf.render.lock_pvs {
-        return;
-    }
+    if !conf.render.no_vis && !conf.render.lock_pvs {
+        // TODO: use a smallvec
+        let mut clusters = Vec::with_capacity(2);
+        let mut outside = false;
+        for transform in cameras.iter() {
+            let p = transform_to_vbsp(*transform);
+            match map.vis.cluster_at([p.x, p.y, p.z]) {
+                Some(cluster) => clusters.push(cluster),
+                // A camera outside the map (or a map without vis data) can see everything.
+                // This should typically not happen during normal gameplay.
+                None => outside = true,
+            }
+        }
+        clusters.sort_unstable();
+        clusters.dedup();
+        if outside {
+            clusters.clear();
+        }
 
-    if conf.render.no_vis {
-        // TODO: We should cache that we've already done this somehow, or listen for when it
-        // changes and do it once.
-        for (_, mut vis, _) in nodes.iter_mut() {
-            *vis = Visibility::Visible;
+        let portals_changed = portals.iter().any(|portal| portal.is_changed());
+        if clusters != pvs.clusters || portals_changed {
+            let mut visible = (!clusters.is_empty()).then(ClusterSet::default);
+            for cluster in &clusters {
+                match map.vis.visible_clusters(*cluster) {
+                    Ok(set) => {
+                        if let Some(visible) = &mut visible {
+                            visible.union_with(&set);
+                        }
+                    }
+                    Err(err) => {
+                        eprintln!("Failed to get the PVS of cluster {cluster}: {err:?}");
+                        visible = None;
+                    }
+                }
+            }
+
+            if let Some(visible) = &mut visible {
+                let closed = portals
+                    .iter()
+                    .filter(|portal| !portal.open)
+                    .map(|portal| portal.portal)
+                    .collect::<Vec<_>>();
+                map.vis
+                    .cull_areas(visible, &clusters, |key| !closed.contains(&key));
+            }
+
+            pvs.clusters = clusters;
+            pvs.visible = visible;
         }
-        return;
     }
-    // It seems like if we go to the blu spawn then we get in proper clusters, is everything
-    // shifted badly somehow?? Or are positions supposed to be relative to some origin?
-    // for (_camera, transform) in cameras.iter() {
-    //     let pos = transform.translation.to_array();
-    //     let pos = unrotate(pos);
-    //     let pos = unscale(pos);
-    //     let pos = vbsp::Vector {
-    //         x: pos[0],
-    //         y: pos[1],
-    //         z: pos[2],
-    //     };
-
-    //     let leaf = map.bsp.leaf_at(pos);
-    //     if leaf.cluster != -1 {
-    //         println!("Camera: {transform:?} -> {pos:?} -> {:?}", leaf.cluster);
-    //     }
-    // }
-
-    // The way visibility works in BSP is that each point is in exactly one leaf (which are convex,
-    // but whatever).
-    // Enterable leaves (visleaves) get a 'cluster number'.
-    // Essentially the cluster number is just an id for areas you can be in, which determines what
-    // other areas are visible, thus saving work at runtime.
-
-    // TODO: bsp article mentions that there is only ever one leaf per cluster in old source maps,
-    // but some CS:GO maps have multiple leaves in the same cluster, do we support that?
-
-    // FIXME: This code is broken!
-    // It works in my very simple test map where everything is seemingly visible from everywhere
-    // else, but it does not work in ctf_2fort at all!
-    // It seems like it basically always gets a leaf with -1 cluster, which is nothing, so it
-    // doesn't show anything.
-    // If we zoom out very far then we might get something, but I expect that it is going outside
-    // the skybox, and at times it crashed due to the bitvec.set in vbsp being out of bounds.
-    // (though I've added a check in that code).
-    //
-    // Later addendum: Various changes I've tried having marginally improved the situation, but it
-    // still puts them in seemingly the wrong spot on the map. I'm thinking that maybe some sort of
-    // transformation is being done wrong, but I'm not sure what.
-    //
-    // I'm unsure what the underlying issue is. I've glanced at alternate implementations and they
-    // seem like mine.
-    // The parsing code in vbsp seems fine for visdata, and swapping it to reading pvs/pas
-    // separately did not help.
-    // Rewriting the leaf at function and trying to rewrite the visdata decompression didn't help
-    // either.
-    //
-    // It is possible that I'm getting the position of the camera incorrectly, but I'm not sure how
-    // it would be so.
-
-    // // TODO: use a smallvec
-    // let mut visible_sets = Vec::with_capacity(2);
-    // for (_camera, transform) in cameras.iter() {
-    //     let pos = transform_to_vbsp(*transform);
-    //     // TODO: I don't know if this is the best method to find the leaf?
-    //     let leaf = map.bsp.leaf_at(pos);
-    //     // println!("Camera: {transform:?} -> {pos:?} -> {:?}", leaf.cluster);
-
-    //     if let Some(vis_set) = leaf.visible_set() {
-    //         visible_sets.push(vis_set);
-    //     }
-    // }
-
-    // // let zero_leaf = map.bsp.leaf_at(vbsp::Vector {
-    // //     x: 0.0,
-    // //     y: 0.0,
-    // //     z: 0.0,
-    // // });
-    // // if let Some(vis_set) = zero_leaf.visible_set() {
-    // //     visible_sets.push(vis_set);
-    // // }
-    // // let zero_leaf = &*zero_leaf;
-    // // println!("Zero leaf: {zero_leaf:?}");
-
-    // // TODO: will this run change detection immediately, or is bevy smart and only does that if it
-    // // actually changed?
-    // We first have to set all the visibility to hidden
-    // for (_, mut vis, _) in nodes.iter_mut() {
-    //     *vis = Visibility::Hidden;
-    // }
-
-    // let mut visible_count = 0;
-    // let mut face_count = 0;
-    // let mut skipped_faces = 0;
-    // for visible_leaf in visible_sets.into_iter().flatten() {
-    //     for (face_i, _face) in visible_leaf.faces_enumerate() {
-    //         face_count += 1;
-    //         // println!("Face i: {face_i}");
-    //         // println!("Faces: {:?}", map.faces);
-    //         let Some(entity) = map.faces.get(&face_i) else {
-    //             // That we don't have an index implies that there's faces we don't create..
-    //             // I at first thought this must be displacements (which would also fit!) but it
-    //             // even happens for my small test map.
-    //             skipped_faces += 1;
-    //             continue;
-    //         };
-    //         if let Ok((_, mut vis, _)) = nodes.get_mut(*entity) {
-    //             *vis = Visibility::Visible;
-    //             visible_count += 1;
-    //         }
-    //     }
-    // }
-    // println!(
-    //     "Visible faces: {visible_count}; face count: {face_count}; skipped faces: {skipped_faces}",
-    // );
-
-    // if visible_count == 0 {
-    //     // No visible faces, so they're probably outside the map, so we simply add the entire map
-    //     // This should typically not happen during normal gameplay, and if it does happen remotely
-    //     // often then we should try methods to avoid it.
-    //     // (ex: like if cameras for mirrors end up being considered inside the wall then we should
-    //     // try fixing that, via something smarter)
-
-    //     for (_, mut vis, _) in nodes.iter_mut() {
-    //         *vis = Visibility::Visible;
-    //     }
-    // }
+
+    let visible = if conf.render.no_vis {
+        None
+    } else {
+        pvs.visible.as_ref()
+    };
+    for (batch, mut vis) in batches.iter_mut() {
+        // Faces that aren't in any leaf, like those of brush entities, are never culled
+        let shown = match visible {
+            Some(visible) => {
+                batch.clusters.is_empty() || batch.clusters.iter().any(|c| visible.contains(*c))
+            }
+            None => true,
+        };
+        let target = if shown {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+        // Avoids triggering change detection every frame
+        vis.set_if_neq(target);
+    }
 }
 
 // TODO: This could be useful if we made it update the color of the leaf boundaries based on the
@@ -703,155 +1666,270 @@ fn update_visibility(
 // TODO(minor): Can we do something where it only shows the leaf boundaries to the relevant camera
 // and none of the other cameras? Too expensive?
 
-#[derive(Debug, Clone, Component)]
-pub struct LeafvisFrame;
+/// How far from the cameras `r_visambient` draws ambient samples, in source units
+const AMBIENT_CUBE_DRAW_DIST: f32 = 1024.0;
+/// Length of the line drawn towards each direction of an ambient sample, in source units
+const AMBIENT_CUBE_LINE_LENGTH: f32 = 12.0;
 
+/// Draw `r_visambient`, the ambient lighting samples of the leaves near the cameras.
+fn ambient_cube_frame(
+    cameras: Query<&Transform, With<UnrealCameraController>>,
+    map: Res<GameMap>,
+    conf: Res<Config>,
+    mut gizmos: Gizmos,
+) {
+    if !conf.render.draw_ambient_cubes {
+        return;
+    }
+
+    let points = cameras
+        .iter()
+        .map(|transform| {
+            let p = transform_to_vbsp(*transform);
+            Vec3::new(p.x, p.y, p.z)
+        })
+        .collect::<Vec<_>>();
+    for sample in map.ambient.samples() {
+        let position = Vec3::from(sample.position);
+        let near = points
+            .iter()
+            .any(|p| p.distance_squared(position) < AMBIENT_CUBE_DRAW_DIST.powi(2));
+        if !near {
+            continue;
+        }
+
+        let center = Vec3::from(rotate(scale(sample.position)));
+        for (direction, [r, g, b]) in AMBIENT_CUBE_DIRECTIONS.into_iter().zip(sample.cube.colors) {
+            let end = center + direction * AMBIENT_CUBE_LINE_LENGTH * SCALE;
+            gizmos.line(center, end, Color::rgb_linear(r, g, b));
+        }
+    }
+}
+
+/// The lines drawn by [`leafvis_frame`], which are only rebuilt when what they show changes.
+#[derive(Debug, Default)]
+struct LeafvisLines {
+    /// The leaves the cameras were in when the lines were built
+    leaves: Vec<usize>,
+    lines: Vec<(Vec3, Vec3, Color)>,
+}
+
+/// Draw `mat_leafvis` and the vis debug overlays.
 fn leafvis_frame(
-    mut commands: Commands,
-    mut mesh: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    cameras: Query<(&UnrealCameraController, &Transform)>,
+    cameras: Query<&Transform, With<UnrealCameraController>>,
     map: Res<GameMap>,
     conf: Res<Config>,
-    mut ex_leaves: Query<(Entity, &LeafvisFrame)>,
+    pvs: Res<Pvs>,
+    mut cache: Local<LeafvisLines>,
     mut gizmos: Gizmos,
 ) {
-    // FIXME: only add these if it specifically has changed and they aren't already added!
-    // And we want to remove the old ones if we change value
-    let leaves = match conf.render.mat.leafvis {
+    let r = &conf.render;
+    if r.mat.leafvis == MatLeafvis::Off && !r.draw_pvs && !r.draw_node_planes {
+        cache.lines.clear();
+        return;
+    }
+
+    let points = cameras
+        .iter()
+        .map(|transform| {
+            let p = transform_to_vbsp(*transform);
+            [p.x, p.y, p.z]
+        })
+        .collect::<Vec<_>>();
+    let leaves = points
+        .iter()
+        .filter_map(|p| map.vis.leaf_at(*p))
+        .collect::<Vec<_>>();
+
+    if conf.is_changed() || map.is_changed() || pvs.is_changed() || leaves != cache.leaves {
+        cache.lines = leafvis_lines(&map.vis, r, &points, &pvs);
+        cache.leaves = leaves;
+    }
+
+    for (start, end, color) in &cache.lines {
+        gizmos.line(*start, *end, *color);
+    }
+}
+
+fn leafvis_lines(
+    vis: &Vis,
+    conf: &RenderConfig,
+    points: &[[f32; 3]],
+    pvs: &Pvs,
+) -> Vec<(Vec3, Vec3, Color)> {
+    let mut lines = Vec::new();
+
+    let leaves = points.iter().filter_map(|p| vis.leaf_at(*p));
+    let clusters = points
+        .iter()
+        .filter_map(|p| vis.cluster_at(*p))
+        .collect::<Vec<_>>();
+    let leaves = match conf.mat.leafvis {
         MatLeafvis::Off => Vec::new(),
-        MatLeafvis::CurrentVisleaf => cameras
+        MatLeafvis::CurrentVisleaf => leaves
+            .filter(|leaf| vis.leaf_cluster(*leaf) != -1)
+            .collect(),
+        MatLeafvis::CurrentViscluster => clusters
             .iter()
-            .map(|(_camera, transform)| {
-                let p = transform_to_vbsp(*transform);
-                let leaf = map.bsp.leaf_at(p);
-                if leaf.cluster != -1 {
-                    // println!("Camera: {transform:?} -> {p:?}; leaf: {leaf:?}");
+            .flat_map(|cluster| vis.cluster_leaves(*cluster))
+            .collect(),
+        MatLeafvis::AllVisleaves => {
+            // Computed from the cameras rather than taken from `Pvs`, so that it ignores r_lockpvs
+            let mut visible = ClusterSet::new(vis.cluster_count());
+            for cluster in &clusters {
+                if let Ok(set) = vis.visible_clusters(*cluster) {
+                    visible.union_with(&set);
                 }
-                leaf
-            })
-            .filter(|leaf| leaf.cluster != -1)
-            .collect::<Vec<_>>(),
-        MatLeafvis::CurrentViscluster => todo!(),
-        MatLeafvis::AllVisleaves => todo!(),
+            }
+            (0..vis.leaf_count())
+                .filter(|leaf| visible.contains(vis.leaf_cluster(*leaf)))
+                .collect()
+        }
     };
+    for leaf in leaves {
+        if let Some(bounds) = vis.leaf_bounds(leaf) {
+            box_lines(&mut lines, bounds, Color::rgb(1.0, 0.0, 0.0));
+        }
+    }
 
-    // let leaves = {
-    //     let p = transform_to_vbsp(Transform::from_xyz(-123.6, 32., 140.));
-    //     println!("p: {p:?}");
-    //     let leaf = map.bsp.leaf_at(p);
-    //     if leaf.cluster != -1 {
-    //         // println!("Camera: {p:?}; leaf: {leaf:?}");
-    //         vec![leaf]
-    //     } else {
-    //         vec![]
-    //     }
-    // };
-    // TODO: be smarter about this
-    for (ent, _) in ex_leaves.iter_mut() {
-        commands.entity(ent).despawn();
+    if conf.draw_pvs {
+        if let Some(visible) = &pvs.visible {
+            for cluster in 0..vis.cluster_count() as i16 {
+                if !visible.contains(cluster) {
+                    continue;
+                }
+
+                let color = if pvs.clusters.contains(&cluster) {
+                    Color::rgb(1.0, 1.0, 0.0)
+                } else {
+                    Color::rgb(0.0, 1.0, 0.0)
+                };
+                if let Some(bounds) = vis.cluster_bounds(cluster) {
+                    box_lines(&mut lines, bounds, color);
+                }
+            }
+        }
     }
 
-    for (camera, transform) in cameras.iter() {
-        let tra = transform.translation;
-        // println!("Camera: {tra:?}");
+    if conf.draw_node_planes {
+        for point in points {
+            let splits = vis.splits_at(*point);
+            let count = splits.len();
+            for (depth, split) in splits.into_iter().enumerate() {
+                // Deeper splits, which are closer to the camera, are brighter
+                let t = (depth + 1) as f32 / count as f32;
+                plane_lines(&mut lines, split, Color::rgb(0.0, 0.3 * t, t));
+            }
+        }
     }
 
-    // println!("Leaf count: {}", leaves.len());
-    for leaf in leaves {
-        // For each leaf we will use its min/max to create a wireframe box.
-
-        let mins = leaf.mins;
-        let maxs = leaf.maxs;
-
-        let mins = [mins[0] as f32, mins[1] as f32, mins[2] as f32];
-        let maxs = [maxs[0] as f32, maxs[1] as f32, maxs[2] as f32];
-
-        let mins = rotate(scale(mins));
-        let maxs = rotate(scale(maxs));
-
-        let mins: Vec3 = Vec3::from_array(mins);
-        let maxs: Vec3 = Vec3::from_array(maxs);
-        let color = Color::rgb(1.0, 0.0, 0.0);
-        // gizmos.rect(position, rotation, size, color);
-        // For some reason it doesn't have a 3d box, so we have to do it manually
-        // size is a vec2
-
-        // Calculate the corners of the box
-        let front_bottom_left = mins;
-        let front_bottom_right = Vec3::new(maxs.x, mins.y, mins.z);
-        let front_top_left = Vec3::new(mins.x, maxs.y, mins.z);
-        let front_top_right = Vec3::new(maxs.x, maxs.y, mins.z);
-
-        let back_bottom_left = Vec3::new(mins.x, mins.y, maxs.z);
-        let back_bottom_right = Vec3::new(maxs.x, mins.y, maxs.z);
-        let back_top_left = Vec3::new(mins.x, maxs.y, maxs.z);
-        let back_top_right = maxs;
-
-        // Draw the 12 edges of the box
-        gizmos.line(front_bottom_left, front_bottom_right, color);
-        gizmos.line(front_bottom_right, front_top_right, color);
-        gizmos.line(front_top_right, front_top_left, color);
-        gizmos.line(front_top_left, front_bottom_left, color);
-
-        gizmos.line(back_bottom_left, back_bottom_right, color);
-        gizmos.line(back_bottom_right, back_top_right, color);
-        gizmos.line(back_top_right, back_top_left, color);
-        gizmos.line(back_top_left, back_bottom_left, color);
-
-        gizmos.line(front_bottom_left, back_bottom_left, color);
-        gizmos.line(front_bottom_right, back_bottom_right, color);
-        gizmos.line(front_top_left, back_top_left, color);
-        gizmos.line(front_top_right, back_top_right, color);
-    }
-
-    // Way too noisy, might be more useful if we make it stop rendering ones which are farther away
-    // for leaf in map.bsp.leaves.iter() {
-    //     let mins = leaf.mins;
-    //     let maxs = leaf.maxs;
-
-    //     let mins = [mins[0] as f32, mins[1] as f32, mins[2] as f32];
-    //     let maxs = [maxs[0] as f32, maxs[1] as f32, maxs[2] as f32];
-
-    //     let mins = rotate(scale(mins));
-    //     let maxs = rotate(scale(maxs));
-
-    //     let mins: Vec3 = Vec3::from_array(mins);
-    //     let maxs: Vec3 = Vec3::from_array(maxs);
-    //     let color = Color::rgba(0.0, 1.0, 0.0, 0.1);
-    //     // gizmos.rect(position, rotation, size, color);
-    //     // For some reason it doesn't have a 3d box, so we have to do it manually
-    //     // size is a vec2
-
-    //     // Define corners of the box
-
-    //     // Calculate the corners of the box
-    //     let front_bottom_left = mins;
-    //     let front_bottom_right = Vec3::new(maxs.x, mins.y, mins.z);
-    //     let front_top_left = Vec3::new(mins.x, maxs.y, mins.z);
-    //     let front_top_right = Vec3::new(maxs.x, maxs.y, mins.z);
-
-    //     let back_bottom_left = Vec3::new(mins.x, mins.y, maxs.z);
-    //     let back_bottom_right = Vec3::new(maxs.x, mins.y, maxs.z);
-    //     let back_top_left = Vec3::new(mins.x, maxs.y, maxs.z);
-    //     let back_top_right = maxs;
-
-    //     // Draw the 12 edges of
-    //     gizmos.line(front_bottom_left, front_bottom_right, color);
-    //     gizmos.line(front_bottom_right, front_top_right, color);
-    //     gizmos.line(front_top_right, front_top_left, color);
-    //     gizmos.line(front_top_left, front_bottom_left, color);
-
-    //     gizmos.line(back_bottom_left, back_bottom_right, color);
-    //     gizmos.line(back_bottom_right, back_top_right, color);
-    //     gizmos.line(back_top_right, back_top_left, color);
-    //     gizmos.line(back_top_left, back_bottom_left, color);
-
-    //     gizmos.line(front_bottom_left, back_bottom_left, color);
-    //     gizmos.line(front_bottom_right, back_bottom_right, color);
-    //     gizmos.line(front_top_left, back_top_left, color);
-    //     gizmos.line(front_top_right, back_top_right, color);
-    // }
+    lines
+}
+
+fn to_bevy(p: Vec3) -> Vec3 {
+    Vec3::from_array(rotate(scale(p.to_array())))
+}
+
+/// Add the 12 edges of the box
+fn box_lines(lines: &mut Vec<(Vec3, Vec3, Color)>, bounds: Bounds, color: Color) {
+    let mins = Vec3::from_array(bounds.mins);
+    let maxs = Vec3::from_array(bounds.maxs);
+    let corner = |i: usize| {
+        to_bevy(Vec3::new(
+            if i & 1 == 0 { mins.x } else { maxs.x },
+            if i & 2 == 0 { mins.y } else { maxs.y },
+            if i & 4 == 0 { mins.z } else { maxs.z },
+        ))
+    };
+
+    // Each edge connects corners which differ in one axis
+    for i in 0..8 {
+        for axis in [1, 2, 4] {
+            if i & axis == 0 {
+                lines.push((corner(i), corner(i | axis), color));
+            }
+        }
+    }
+}
+
+/// Add the outline of the split plane, limited to the bounds of the node it splits
+fn plane_lines(lines: &mut Vec<(Vec3, Vec3, Color)>, split: Split, color: Color) {
+    let normal = Vec3::from_array(split.normal);
+    let mins = Vec3::from_array(split.bounds.mins);
+    let maxs = Vec3::from_array(split.bounds.maxs);
+    let half = (maxs - mins) / 2.0;
+
+    let center = (mins + maxs) / 2.0;
+    let center = center - normal * (normal.dot(center) - split.dist);
+    let u = normal.any_orthonormal_vector();
+    let v = normal.cross(u);
+    // Exact for axial planes, which most are
+    let u = u * u.abs().dot(half);
+    let v = v * v.abs().dot(half);
+
+    let corners = [
+        center - u - v,
+        center + u - v,
+        center + u + v,
+        center - u + v,
+    ]
+    .map(to_bevy);
+    for i in 0..4 {
+        lines.push((corners[i], corners[(i + 1) % 4], color));
+    }
+    lines.push((corners[0], corners[2], color));
+}
+
+/// The material that a face had before `cluster_colors` replaced it
+#[derive(Debug, Clone, Component)]
+struct ClusterColored(Handle<BrushMaterial>);
+
+/// Swap the materials of the faces for a color per cluster when `cluster_colors` is enabled.
+fn update_cluster_colors(
+    mut commands: Commands,
+    conf: Res<Config>,
+    map: Res<GameMap>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut colors: Local<HashMap<i16, Handle<StandardMaterial>>>,
+    uncolored: Query<(Entity, &FaceBatch, &Handle<BrushMaterial>), Without<ClusterColored>>,
+    colored: Query<(Entity, &ClusterColored)>,
+) {
+    if !conf.render.cluster_colors {
+        for (entity, ClusterColored(material)) in colored.iter() {
+            commands
+                .entity(entity)
+                .remove::<(ClusterColored, Handle<StandardMaterial>)>()
+                .insert(material.clone());
+        }
+        return;
+    }
+
+    for (entity, batch, material) in uncolored.iter() {
+        // Batches are split by their faces' first cluster (see `load_face_batching`), though
+        // the faces can be in other clusters too.
+        // Faces of brush entities aren't in any cluster, so they keep their material.
+        let Some(cluster) = batch
+            .faces
+            .first()
+            .and_then(|face| map.face_clusters.get(face.face_i).first())
+        else {
+            continue;
+        };
+        let color = colors.entry(*cluster).or_insert_with(|| {
+            // Spread the hues out so that neighboring clusters are easy to tell apart
+            let hue = (*cluster as f32 * 137.508) % 360.0;
+            materials.add(StandardMaterial {
+                base_color: Color::hsl(hue, 0.7, 0.5),
+                unlit: true,
+                ..default()
+            })
+        });
+
+        commands
+            .entity(entity)
+            .remove::<Handle<BrushMaterial>>()
+            .insert((color.clone(), ClusterColored(material.clone())));
+    }
 }
 
 /// Update light visibility based on conf
@@ -1,28 +1,310 @@
-use std::{borrow::Cow, path::Path};
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use bevy::{
-    prelude::{Entity, Resource},
+    prelude::{Component, Entity, Event, Resource, States},
     utils::HashMap,
 };
 use vbsp::Bsp;
 
-use crate::data::LSrc;
+use crate::{
+    ambient::LeafAmbient,
+    cache::SourceStamp,
+    cubemap::MapCubemaps,
+    data::LSrc,
+    detail::DetailProps,
+    displacement::Displacements,
+    lightmap::Lightmaps,
+    mesh::{FaceClusters, FaceRange},
+    overlay::{read_overlays, Overlay},
+    prop::{read_static_props, StaticProp},
+    skybox::MapSky,
+    util::parse_vector,
+    vis::Vis,
+};
+
+/// The lifecycle of the current map.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, States)]
+pub enum MapState {
+    /// No map is loaded
+    #[default]
+    Unloaded,
+    /// The map in [`MapToLoad`] is being loaded
+    Loading,
+    /// The map is loaded, and there is a [`GameMap`] resource
+    Loaded,
+}
+
+/// The path of the map that should be loaded when entering [`MapState::Loading`]
+#[derive(Debug, Clone, Resource)]
+pub struct MapToLoad(pub PathBuf);
+
+/// Request that the current map (if any) is unloaded and the given map is loaded in its place.
+#[derive(Debug, Clone, Event)]
+pub struct ChangeMap(pub PathBuf);
+
+/// Marker for entities which belong to the current map, and so should be despawned when it is
+/// unloaded.  
+/// Face entities are tracked separately in [`GameMap::faces`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Component)]
+pub struct MapEntity;
+
+/// A `func_areaportal`, which is typically a door that closes off the areas behind it.
+/// Toggling `open` shows or hides the areas that are only visible through the portal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Component)]
+pub struct AreaPortal {
+    /// The key of the portal in the areaportal lump
+    pub portal: u16,
+    pub open: bool,
+}
+impl AreaPortal {
+    /// Read the areaportal from the keyvalues of a `func_areaportal` entity.
+    pub fn from_properties<'a>(
+        properties: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Option<AreaPortal> {
+        let mut class_name = "";
+        let mut portal = None;
+        let mut open = true;
+        for (key, value) in properties {
+            match key {
+                "classname" => class_name = value,
+                "portalnumber" => portal = value.parse().ok(),
+                "StartOpen" => open = value.trim() != "0",
+                _ => {}
+            }
+        }
+
+        // Areaportal windows are left open, since they only close when far away
+        if class_name != "func_areaportal" {
+            return None;
+        }
+
+        Some(AreaPortal {
+            portal: portal?,
+            open,
+        })
+    }
+}
+
+/// An entity which is drawn with one of the bsp's models, such as a `func_door` or a trigger.
+/// The faces of the model are spawned as children of the entity, so that they can be hidden or
+/// moved along with it.
+#[derive(Debug, Clone, Copy, PartialEq, Component)]
+pub struct BrushEntity {
+    /// The index of the model in the bsp, which the entity refers to as `*N`
+    pub model: usize,
+    /// In source coordinates
+    pub origin: [f32; 3],
+    /// Pitch, yaw and roll in degrees
+    pub angles: [f32; 3],
+    /// Whether the entity is a trigger, which the game doesn't draw
+    pub trigger: bool,
+}
+impl BrushEntity {
+    /// Read the brush entity from the keyvalues of an entity, if it has a brush model.
+    pub fn from_properties<'a>(
+        properties: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Option<BrushEntity> {
+        let mut class_name = "";
+        let mut model = None;
+        let mut origin = None;
+        let mut angles = None;
+        for (key, value) in properties {
+            match key {
+                "classname" => class_name = value,
+                // Entities with a `.mdl` model aren't brush entities
+                "model" => model = value.trim().strip_prefix('*')?.parse::<usize>().ok(),
+                "origin" => origin = parse_vector(value),
+                "angles" => angles = parse_vector(value),
+                _ => {}
+            }
+        }
+
+        // Model 0 is the world itself
+        let model = model.filter(|model| *model != 0)?;
+        let trigger = class_name.starts_with("trigger_")
+            || matches!(
+                class_name,
+                "func_respawnroom" | "func_regenerate" | "func_capturezone" | "func_nobuild"
+            );
+
+        Some(BrushEntity {
+            model,
+            origin: origin.unwrap_or_default(),
+            angles: angles.unwrap_or_default(),
+            trigger,
+        })
+    }
+}
+
+/// The index of a face in the BSP
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Component)]
+pub struct FaceIndex(pub usize);
 
-#[derive(Debug, Resource)]
+/// The faces whose triangles are in the entity's mesh, for when faces are merged together.
+#[derive(Debug, Clone, PartialEq, Eq, Component)]
+pub struct FaceBatch {
+    /// Ordered by where the faces are in the mesh
+    pub faces: Vec<FaceRange>,
+    /// The vis clusters that any of the faces are in
+    pub clusters: Vec<i16>,
+}
+impl FaceBatch {
+    pub fn face(&self, face_i: usize) -> Option<&FaceRange> {
+        self.faces.iter().find(|range| range.face_i == face_i)
+    }
+
+    /// Get the face that the triangle (ex: from picking) belongs to.
+    pub fn face_at_triangle(&self, triangle: u32) -> Option<usize> {
+        let i = self
+            .faces
+            .partition_point(|range| range.triangles().end <= triangle);
+        let range = self.faces.get(i)?;
+        range
+            .triangles()
+            .contains(&triangle)
+            .then_some(range.face_i)
+    }
+}
+
+/// Cloning this is cheap, since the bsp is shared, which lets the map be used from tasks.
+#[derive(Debug, Clone, Resource)]
 pub struct GameMap {
-    pub bsp: Bsp,
+    pub bsp: Arc<Bsp>,
     /// Keeps track of the mapping between the face index in the current bsp map, and the face
-    /// entities.
+    /// entities.  
+    /// When faces are merged, multiple faces map to the same entity, which has a [`FaceBatch`].
     pub faces: HashMap<usize, Entity>,
+    /// The entities using each of the bsp's models, which their faces are spawned as children of
+    pub brush_entities: HashMap<usize, (Entity, BrushEntity)>,
+    /// Identifies the version of the bsp file, used to know when cached pakfile data is stale
+    pub stamp: Option<SourceStamp>,
+    pub lightmaps: Arc<Lightmaps>,
+    pub displacements: Arc<Displacements>,
+    pub face_clusters: Arc<FaceClusters>,
+    pub vis: Arc<Vis>,
+    /// The ambient lighting of each leaf, which lights the props
+    pub ambient: Arc<LeafAmbient>,
+    /// The `env_cubemap`s, which reflective materials reflect
+    pub cubemaps: Arc<MapCubemaps>,
+    pub sky: MapSky,
+    pub static_props: Arc<[StaticProp]>,
+    pub detail_props: Arc<DetailProps>,
+    /// The `info_overlay`s of the map
+    pub overlays: Arc<[Overlay]>,
 }
 impl GameMap {
     pub fn from_path(path: impl AsRef<Path>) -> eyre::Result<GameMap> {
+        let path = path.as_ref();
+        let stamp = std::fs::metadata(path)
+            .ok()
+            .and_then(|meta| SourceStamp::from_metadata(&meta));
         let data = std::fs::read(path)?;
         let bsp = Bsp::read(&data)?;
 
+        // The map is still usable without its lighting
+        let lightmaps = Lightmaps::from_bsp(&data).unwrap_or_else(|err| {
+            eprintln!("Failed to read lightmaps of {path:?}, using fullbright: {err:?}");
+            Lightmaps::fullbright()
+        });
+        // Without these the displacements are still created, but without smoothing across them
+        let displacements = Displacements::from_bsp(&data).unwrap_or_else(|err| {
+            eprintln!("Failed to read displacements of {path:?}: {err:?}");
+            Displacements::default()
+        });
+
+        // Without the vis data every face is always drawn
+        let vis = Vis::from_bsp(&bsp, &data).unwrap_or_else(|err| {
+            eprintln!("Failed to read vis data of {path:?}: {err:?}");
+            Vis::default()
+        });
+
+        // Props are drawn fullbright without it
+        let ambient = LeafAmbient::from_bsp(&data, &vis).unwrap_or_else(|err| {
+            eprintln!("Failed to read ambient lighting of {path:?}: {err:?}");
+            LeafAmbient::default()
+        });
+
+        // Reflective materials don't reflect anything without them
+        let map_name = path
+            .file_stem()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let cubemaps = MapCubemaps::from_bsp(&data, &map_name).unwrap_or_else(|err| {
+            eprintln!("Failed to read cubemaps of {path:?}: {err:?}");
+            MapCubemaps::default()
+        });
+
+        let face_clusters = FaceClusters::new(&bsp);
+
+        // The map is still usable without its props
+        let static_props = read_static_props(&data).unwrap_or_else(|err| {
+            eprintln!("Failed to read static props of {path:?}: {err:?}");
+            Vec::new()
+        });
+        let detail_props = DetailProps::from_bsp(&data).unwrap_or_else(|err| {
+            eprintln!("Failed to read detail props of {path:?}: {err:?}");
+            DetailProps::default()
+        });
+
+        let overlays = read_overlays(&data, |texinfo| {
+            let texinfo = bsp.textures_info.get(texinfo)?;
+            Some(Arc::from(
+                vbsp::Handle::new(&bsp, texinfo).name().to_lowercase(),
+            ))
+        })
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to read overlays of {path:?}: {err:?}");
+            Vec::new()
+        });
+
+        let mut sky = MapSky::from_bsp(&bsp);
+        // Area 0 is outside the map, which would be a badly placed sky_camera
+        sky.area = sky
+            .camera
+            .and_then(|camera| vis.area_at(camera.origin))
+            .filter(|area| *area != 0);
+
         Ok(GameMap {
-            bsp,
+            bsp: Arc::new(bsp),
             faces: HashMap::new(),
+            brush_entities: HashMap::new(),
+            stamp,
+            lightmaps: Arc::new(lightmaps),
+            displacements: Arc::new(displacements),
+            face_clusters: Arc::new(face_clusters),
+            vis: Arc::new(vis),
+            ambient: Arc::new(ambient),
+            cubemaps: Arc::new(cubemaps),
+            sky,
+            static_props: Arc::from(static_props),
+            detail_props: Arc::new(detail_props),
+            overlays: Arc::from(overlays),
+        })
+    }
+
+    /// Whether the area is the one the 3D skybox is in, whose contents are drawn by the skybox
+    /// camera instead of the main one.
+    pub fn is_sky_area(&self, area: u16) -> bool {
+        self.sky.area == Some(area)
+    }
+
+    /// Whether the point, in source coordinates, is in the 3D skybox.
+    pub fn is_sky_point(&self, point: [f32; 3]) -> bool {
+        self.vis
+            .area_at(point)
+            .is_some_and(|area| self.is_sky_area(area))
+    }
+
+    /// Whether any of the clusters the face is in are in the 3D skybox.
+    pub fn is_sky_face(&self, face: usize) -> bool {
+        self.face_clusters.get(face).iter().any(|cluster| {
+            self.vis
+                .cluster_area(*cluster)
+                .is_some_and(|area| self.is_sky_area(area))
         })
     }
 
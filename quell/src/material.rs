@@ -1,16 +1,15 @@
 use std::{
-    cmp::Ordering,
     collections::HashSet,
     sync::{atomic::AtomicUsize, Arc, Mutex},
 };
 
 use bevy::{
     asset::Handle,
-    pbr::StandardMaterial,
-    prelude::{Assets, Image},
+    pbr::AlphaMode,
+    prelude::{Assets, Color, Image},
     render::{
         render_resource::{
-            Extent3d, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+            Extent3d, Face, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
         },
         texture::{ImageAddressMode, ImageSampler, ImageSamplerDescriptor},
     },
@@ -20,11 +19,15 @@ use rayon::{
     prelude::{IntoParallelIterator, ParallelIterator},
     slice::ParallelSliceMut,
 };
+use vmt::{ShaderName, VMT};
 
 use crate::{
+    brush::BrushMaterial,
+    cache::TextureRole,
+    cubemap::ENV_CUBEMAP,
     data::{
-        construct_image, construct_material_info2, find_texture, FileLoc, LMaterial,
-        LoadedTextures, VpkState,
+        construct_image, construct_material_info, find_texture, ArchiveMaps, FileLoc, LMaterial,
+        LoadedTextures, LoadingMaterialInfo, MaterialName, TextureKey, TextureName, VpkState,
     },
     map::GameMap,
     util::SeriesCalc,
@@ -59,11 +62,6 @@ pub fn material_names(map: &GameMap) -> Vec<Arc<str>> {
                 continue;
             }
 
-            if let Some(_disp) = face.displacement() {
-                // TODO: displacements have textures too!
-                continue;
-            }
-
             prob_vis_face_count += 1;
 
             let texture_name_index = texture_info.texture_data().name_string_table_id;
@@ -92,7 +90,7 @@ pub fn load_materials(
     vpk: &VpkState,
     loaded_textures: &mut LoadedTextures,
     images: &mut Assets<Image>,
-    materials: &mut Assets<StandardMaterial>,
+    materials: &mut Assets<BrushMaterial>,
     map: &GameMap,
 ) -> eyre::Result<()> {
     let material_names = material_names(map);
@@ -102,7 +100,7 @@ pub fn load_materials(
     let duplicate_counts = AtomicUsize::new(0);
 
     // The loaded/loading textures
-    let l: DashSet<Arc<str>> = DashSet::with_capacity(material_names.len());
+    let l: DashSet<TextureKey> = DashSet::with_capacity(material_names.len());
 
     // // Load all the files we'll need to use
     // // But we don't do anything with them, because we are trying to rely on the OS being smart
@@ -123,11 +121,12 @@ pub fn load_materials(
 
     let m_mean = material_m.clone();
     let img_mean = image_m.clone();
+    let cache = loaded_textures.cache.clone();
     let iter = material_names
         .into_par_iter()
         .filter_map(move |material_name| {
             let start_time = std::time::Instant::now();
-            let res = match construct_material_info2(vpk, Some(map), &material_name) {
+            let res = match construct_material_info(vpk, Some(map), &material_name) {
                 Ok(info) => Some((material_name, info)),
                 Err(err) => {
                     eprintln!(
@@ -144,88 +143,60 @@ pub fn load_materials(
 
             res
         })
-        // Check if we need to be the instance loading the texture
+        // Find the textures that we need to be the instance loading
         .map(|(material_name, info)| {
-            if l.contains(&info.base_texture_name) {
-                duplicate_counts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                return (material_name, info, false);
+            let mut to_load = Vec::new();
+            for (texture_name, role) in info.textures() {
+                if l.insert((texture_name.clone(), role)) {
+                    to_load.push((texture_name, role));
+                } else {
+                    duplicate_counts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                }
             }
 
-            l.insert(info.base_texture_name.clone());
-
-            (material_name, info, true)
+            (material_name, info, to_load)
         })
-        .filter_map(|(material_name, info, should_load_img)| {
-            if !should_load_img {
-                return Some((material_name, info, None));
-            }
-
+        .filter_map(|(material_name, info, to_load)| {
             let start_time = std::time::Instant::now();
-            let res = construct_image(vpk, Some(map), &info.base_texture_name);
-            let res = match res {
-                Ok((image, img_src)) => Some((material_name, info, Some((image, img_src)))),
-                Err(err) => {
-                    eprintln!(
-                        "Failed to construct image for material {}, texture {}: {:?}",
-                        material_name, info.base_texture_name, err
-                    );
-                    None
+            let mut loaded = Vec::with_capacity(to_load.len());
+            for (texture_name, role) in to_load {
+                let res =
+                    construct_image(vpk, Some(map), cache.as_deref(), None, &texture_name, role);
+                match res {
+                    Ok((image, img_src)) => loaded.push((texture_name, role, image, img_src)),
+                    Err(err) => {
+                        eprintln!(
+                            "Failed to construct image for material {}, texture {}: {:?}",
+                            material_name, texture_name, err
+                        );
+                        // Only the base texture is required for the material to be usable
+                        if texture_name == info.base_texture_name {
+                            return None;
+                        }
+                    }
                 }
-            };
+            }
             let end_time = std::time::Instant::now();
 
             let mut mean = img_mean.lock().unwrap();
             mean.update_dur(end_time - start_time);
 
-            res
+            Some((material_name, info, loaded))
         })
         .collect::<Vec<_>>();
 
     println!("L size: #{}", l.len());
 
     let mut materials_to_load = Vec::with_capacity(iter.len());
-    for (material_name, info, image) in iter {
-        if let Some((image, img_src)) = image {
-            loaded_textures.insert_texture_of(
-                images,
-                info.base_texture_name.clone(),
-                image,
-                img_src,
-            )?;
+    for (material_name, info, loaded) in iter {
+        for (texture_name, role, image, img_src) in loaded {
+            loaded_textures.insert_texture_of(images, texture_name, image, img_src, role);
         }
 
-        let material = LMaterial {
-            image: Ok(info.base_texture_name.clone()),
-            mat: Handle::default(),
-            vmt_src: info.vmt_src,
-        };
-
-        loaded_textures.insert_material(material_name.clone(), material);
-
         materials_to_load.push((material_name, info));
     }
 
-    for (material_name, info) in materials_to_load {
-        // let image = loaded_textures
-        //     .find_material_texture(&info.base_texture_name)
-        //     .unwrap_or_else(|| {
-        //         panic!("Failed to find {:?}", info.base_texture_name)
-        //     })?;
-        let image = loaded_textures
-            .vtf
-            .get(&info.base_texture_name)
-            .unwrap()
-            .image
-            .clone();
-
-        let material = make_material(image);
-        let material = materials.add(material);
-
-        loaded_textures
-            .find_material_mut(&material_name)
-            .unwrap()
-            .mat = material;
-    }
+    insert_materials(loaded_textures, materials, materials_to_load);
 
     println!(
         "V: vmt #{}; vtf #{}",
@@ -249,273 +220,437 @@ pub fn load_materials(
         duplicate_counts.load(std::sync::atomic::Ordering::SeqCst)
     );
 
-    // Stop new textures from being loaded.
-    // This is primarily for testing to ensure we don't skip anything.
-    loaded_textures.frozen = true;
-
     Ok(())
 }
 
-pub fn make_material(image: Handle<Image>) -> StandardMaterial {
-    StandardMaterial {
-        // base_color: color,
-        base_color_texture: Some(image.clone()),
-        // alpha_mode: AlphaMode::Blend,
-        // unlit: true,
-        // emissive_texture: texture,
-        // TODO: determine this properly
-        // alpha_mode: if color.a() < 1.0 {
-        //     AlphaMode::Blend
-        // } else {
-        //     AlphaMode::Opaque
-        // },
-
-        // TODO: might be needed since source uses DX
-        // flip_normal_map_y
-
-        // unlit: true,
-        // metallic: 0.0,
-        // emissive: color,
-        // unlit: true,
-        // reflectance: 0.0,
-        ..Default::default()
+/// Create the materials for the given material infos, whose textures should already be loaded.
+/// Materials whose texture is not loaded are skipped.
+pub fn insert_materials(
+    loaded_textures: &mut LoadedTextures,
+    materials: &mut Assets<BrushMaterial>,
+    infos: impl IntoIterator<Item = (MaterialName, LoadingMaterialInfo)>,
+) {
+    for (material_name, info) in infos {
+        // Materials kept from the previous map are already in use, so they're left as they are
+        if loaded_textures.find_material(&material_name).is_some() {
+            continue;
+        }
+
+        let Some(material) = loaded_textures.make_material_for(&info) else {
+            eprintln!(
+                "Texture {:?} for material {material_name:?} was not loaded",
+                info.base_texture_name
+            );
+            continue;
+        };
+
+        let material = materials.add(material);
+        let textures = info.textures().collect();
+
+        loaded_textures.insert_material(
+            material_name,
+            LMaterial {
+                image: Ok(info.base_texture_name.clone()),
+                mat: material,
+                vmt_src: info.vmt_src,
+                include: info.include,
+                textures,
+            },
+        );
+    }
+}
+
+/// The value that pixels' alpha is compared against, if a material has `$alphatest` without
+/// `$alphatestreference`
+pub const DEFAULT_ALPHA_TEST_REFERENCE: f32 = 0.5;
+
+/// How the material is blended with what is behind it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaterialBlend {
+    Opaque,
+    /// `$alphatest`, discarding pixels with an alpha below the given reference
+    AlphaTest(f32),
+    /// `$translucent`
+    Translucent,
+    /// `$additive`
+    Additive,
+}
+
+/// The parameters of a vmt which affect the created material, other than the base texture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaterialParams {
+    /// Name of the vtf used as the normal map, from `$bumpmap` or `$normalmap`
+    pub normal_map: Option<TextureName>,
+    /// `$basetexture2`, which `WorldVertexTransition` blends to by the vertex alpha
+    pub base_texture2: Option<TextureName>,
+    /// `$blendmodulatetexture`
+    pub blend_modulate: Option<TextureName>,
+    pub blend: MaterialBlend,
+    /// `$color` multiplied by `$color2`
+    pub color: [f32; 3],
+    /// Whether the material ignores lighting, ex: `UnlitGeneric`
+    pub unlit: bool,
+    /// `$nocull`
+    pub double_sided: bool,
+    /// `$selfillum`
+    pub self_illum: bool,
+    /// `$decal`, which is drawn on top of the surfaces it is projected onto
+    pub decal: bool,
+    /// The parameters of `Water` materials
+    pub water: Option<WaterParams>,
+    /// How the material reflects a cubemap, if it has an `$envmap`
+    pub envmap: Option<EnvMapParams>,
+}
+impl Default for MaterialParams {
+    fn default() -> Self {
+        MaterialParams {
+            normal_map: None,
+            base_texture2: None,
+            blend_modulate: None,
+            blend: MaterialBlend::Opaque,
+            color: [1.0, 1.0, 1.0],
+            unlit: false,
+            double_sided: false,
+            self_illum: false,
+            decal: false,
+            water: None,
+            envmap: None,
+        }
+    }
+}
+impl MaterialParams {
+    /// Get the parameters from a vmt, which should already have its includes resolved.
+    pub fn from_vmt(vmt: &VMT<'_>) -> MaterialParams {
+        let normal_map = vmt
+            .bump_map
+            .as_deref()
+            .or(vmt.normal_map.as_deref())
+            .map(texture_name);
+        let base_texture2 = vmt.base_texture2.as_deref().map(texture_name);
+        let blend_modulate = vmt.blend_modulate_texture.as_deref().map(texture_name);
+
+        // If a material sets more than one of these, the game prefers them in this order
+        let blend = if vmt.additive == Some(true) {
+            MaterialBlend::Additive
+        } else if vmt.translucent == Some(true) {
+            MaterialBlend::Translucent
+        } else if vmt.alpha_test == Some(true) {
+            let reference = vmt
+                .alpha_test_reference
+                .unwrap_or(DEFAULT_ALPHA_TEST_REFERENCE);
+            MaterialBlend::AlphaTest(reference)
+        } else {
+            MaterialBlend::Opaque
+        };
+
+        let color = vmt.color.unwrap_or([1.0; 3]);
+        let color2 = vmt.color2.unwrap_or([1.0; 3]);
+        let color = [
+            color[0] * color2[0],
+            color[1] * color2[1],
+            color[2] * color2[2],
+        ];
+
+        MaterialParams {
+            normal_map,
+            base_texture2,
+            blend_modulate,
+            blend,
+            color,
+            unlit: vmt.shader_name == ShaderName::UnlitGeneric,
+            double_sided: vmt.no_cull == Some(true),
+            self_illum: vmt.self_illum == Some(true),
+            decal: vmt.decal == Some(true),
+            water: (vmt.shader_name == ShaderName::Water).then(|| WaterParams::from_vmt(vmt)),
+            envmap: EnvMapParams::from_vmt(vmt),
+        }
+    }
+}
+
+/// The parameters of a material which reflects a cubemap (`$envmap`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvMapParams {
+    /// The cubemap texture, or `None` for `env_cubemap` which is the map's cubemap nearest to
+    /// the surface
+    pub cubemap: Option<TextureName>,
+    /// `$envmaptint`
+    pub tint: [f32; 3],
+    /// `$envmapmask`
+    pub mask: Option<TextureName>,
+    /// `$basealphaenvmapmask`, which masks the reflection by the base texture's alpha
+    pub base_alpha_mask: bool,
+    /// `$normalmapalphaenvmapmask`, which masks the reflection by the normal map's alpha
+    pub normal_map_alpha_mask: bool,
+}
+impl EnvMapParams {
+    pub fn from_vmt(vmt: &VMT<'_>) -> Option<EnvMapParams> {
+        let envmap = texture_name(vmt.env_map.as_deref()?);
+        if envmap.is_empty() {
+            return None;
+        }
+        // The masking flags are left in `other`, along with the rest of the parameters that only
+        // some shaders use
+        let flag = |key: &[u8]| vmt.other.get(key).map_or(false, |val| val.trim() != "0");
+
+        Some(EnvMapParams {
+            cubemap: (&*envmap != ENV_CUBEMAP).then_some(envmap),
+            tint: vmt.env_map_tint.unwrap_or([1.0; 3]),
+            mask: vmt.env_map_mask.as_deref().map(texture_name),
+            base_alpha_mask: flag(b"$basealphaenvmapmask"),
+            normal_map_alpha_mask: flag(b"$normalmapalphaenvmapmask"),
+        })
+    }
+}
+
+/// The parameters of a `Water` material, whose surface refracts and reflects through its scrolling
+/// normal map, and is fogged under the surface.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WaterParams {
+    /// `$fogcolor`
+    pub fog_color: [f32; 3],
+    /// `$fogstart` and `$fogend`, in source units. There is no fog if the end isn't past the start.
+    pub fog_start: f32,
+    pub fog_end: f32,
+    /// `$reflecttint`
+    pub reflect_tint: [f32; 3],
+    /// `$refracttint`
+    pub refract_tint: [f32; 3],
+    /// `$reflectamount`
+    pub reflect_amount: f32,
+    /// `$refractamount`
+    pub refract_amount: f32,
+    /// How fast the normal map scrolls, in texture coordinates per second, from the material's
+    /// `TextureScroll` proxy of `$bumptransform`
+    pub scroll: [f32; 2],
+}
+impl WaterParams {
+    pub fn from_vmt(vmt: &VMT<'_>) -> WaterParams {
+        WaterParams {
+            fog_color: vmt.fog_color.unwrap_or([0.0; 3]),
+            fog_start: vmt.fog_start.unwrap_or(0.0),
+            fog_end: vmt.fog_end.unwrap_or(0.0),
+            reflect_tint: vmt.reflect_tint.unwrap_or([1.0; 3]),
+            refract_tint: vmt.refract_tint.unwrap_or([1.0; 3]),
+            reflect_amount: vmt.reflect_amount.unwrap_or(DEFAULT_REFLECT_AMOUNT),
+            refract_amount: vmt.refract_amount.unwrap_or(DEFAULT_REFRACT_AMOUNT),
+            scroll: bump_scroll(vmt).unwrap_or(DEFAULT_WATER_SCROLL),
+        }
+    }
+}
+
+/// The name of a texture from a vmt, lowercase and with `/` separators like the texture paths in
+/// the vpks
+fn texture_name(name: &str) -> TextureName {
+    TextureName::from(name.trim().replace('\\', "/").to_lowercase())
+}
+
+/// The `$reflectamount` of water which doesn't set it, same as the game
+pub const DEFAULT_REFLECT_AMOUNT: f32 = 0.8;
+/// The `$refractamount` of water which doesn't set it
+pub const DEFAULT_REFRACT_AMOUNT: f32 = 0.5;
+/// How fast the normal map of water without a `TextureScroll` proxy scrolls, which is what most
+/// of the game's water uses (a rate of `0.05` at `45` degrees)
+pub const DEFAULT_WATER_SCROLL: [f32; 2] = [0.035355, 0.035355];
+
+/// Find how fast the `TextureScroll` proxy of the material scrolls `$bumptransform`, if it has one.
+fn bump_scroll(vmt: &VMT<'_>) -> Option<[f32; 2]> {
+    let proxies = vmt.sub.get(b"proxies")?.as_sub()?;
+    proxies.0.iter().find_map(|(name, proxy)| {
+        if !name.starts_with(b"texturescroll") {
+            return None;
+        }
+
+        let proxy = proxy.as_sub()?;
+        let var = proxy.get(b"texturescrollvar")?.as_val()?;
+        if !var.eq_ignore_ascii_case("$bumptransform") {
+            return None;
+        }
+
+        let parse = |key: &[u8]| proxy.get(key)?.as_val()?.trim().parse::<f32>().ok();
+        let rate = parse(b"texturescrollrate")?;
+        let angle = parse(b"texturescrollangle").unwrap_or(0.0).to_radians();
+        Some([rate * angle.cos(), rate * angle.sin()])
+    })
+}
+
+/// The images used by a material, which are passed to [`make_material`]
+#[derive(Debug, Clone, Default)]
+pub struct MaterialImages {
+    pub base_texture: Handle<Image>,
+    pub normal_map: Option<Handle<Image>>,
+    pub base_texture2: Option<Handle<Image>>,
+    pub blend_modulate: Option<Handle<Image>>,
+    /// The map's sky cubemap, which water reflects
+    pub reflection: Option<Handle<Image>>,
+    /// The cubemap of the material's `$envmap`
+    pub envmap: Option<Handle<Image>>,
+    pub envmap_mask: Option<Handle<Image>>,
+}
+
+pub fn make_material(
+    images: MaterialImages,
+    lightmap: Handle<Image>,
+    params: &MaterialParams,
+) -> BrushMaterial {
+    let alpha_mode = match params.blend {
+        // Water is drawn over what is behind it with refraction, rather than blended
+        _ if params.water.is_some() => AlphaMode::Opaque,
+        MaterialBlend::Opaque => AlphaMode::Opaque,
+        MaterialBlend::AlphaTest(reference) => AlphaMode::Mask(reference),
+        MaterialBlend::Translucent => AlphaMode::Blend,
+        MaterialBlend::Additive => AlphaMode::Add,
+    };
+
+    let [r, g, b] = params.color;
+
+    // Water reflects the sky instead
+    let envmap_params = params.envmap.clone().filter(|_| params.water.is_none());
+
+    BrushMaterial {
+        base_color: Color::rgb(r, g, b),
+        base_texture: images.base_texture,
+        lightmap,
+        normal_map: images.normal_map,
+        base_texture2: images.base_texture2,
+        blend_modulate: images.blend_modulate,
+        alpha_mode,
+        cull_mode: if params.double_sided {
+            None
+        } else {
+            Some(Face::Back)
+        },
+        unlit: params.unlit,
+        self_illum: params.self_illum,
+        decal: params.decal,
+        reflection: params.water.as_ref().and(images.reflection),
+        water: params.water.clone(),
+        envmap: envmap_params.as_ref().and(images.envmap),
+        envmap_mask: envmap_params.as_ref().and(images.envmap_mask),
+        envmap_params,
     }
 }
 
-// fn load_materials2(
-//     vpk: &VpkState,
-//     loaded_textures: &mut LoadedTextures,
-//     images: &mut Assets<Image>,
-//     map: &GameMap,
-// ) -> eyre::Result<()> {
-//     let material_names = material_names(map);
-
-//     let start_time = std::time::Instant::now();
-
-//     // The loaded/loading textures
-//     let l: DashSet<Arc<str>> = DashSet::with_capacity(material_names.len());
-
-//     // Our first stages finds all of the VMTs and loads them.
-//     // Currently this assumes that the VMTs are cheap to load, which is
-//     // probably usually/always true because they'll be in the dir VPK's preload
-//     // but I have not actually checked.
-//     // TODO: check how many materials are actually in storage files, the average time in my
-//     // previous tests makes me think at least some of them are. If a notable amount are, then
-//     // we can swap to getting them in the order we need to load them.
-
-//     let material_m = Arc::new(Mutex::new(SeriesCalc::new()));
-//     let image_m = Arc::new(Mutex::new(SeriesCalc::new()));
-
-//     let m_mean = material_m.clone();
-//     let img_mean = image_m.clone();
-
-//     let iter = material_names
-//         .into_par_iter()
-//         .filter_map(move |material_name| {
-//             let start_time = std::time::Instant::now();
-//             let res = match construct_material_info2(vpk, Some(map), &material_name) {
-//                 Ok(info) => Some((material_name, info)),
-//                 Err(err) => {
-//                     eprintln!(
-//                         "Failed to construct material info for {}: {:?}",
-//                         material_name, err
-//                     );
-//                     None
-//                 }
-//             };
-
-//             let end_time = std::time::Instant::now();
-
-//             let mut mean = m_mean.lock().unwrap();
-//             mean.update_dur(end_time - start_time);
-
-//             res
-//         })
-//         // Deduplicate any textures. We still need to add all the different materials, but if they
-//         // reference the same texture then we only want to load it once
-//         .map(|(material_name, info)| {
-//             // TODO: we'll need to extend this when we're loading more texture info from files
-//             if l.contains(&info.base_texture_name) {
-//                 return (material_name, info, false);
-//             }
-
-//             l.insert(info.base_texture_name.clone());
-
-//             // (material_name, info, should_load_img)
-//             (material_name, info, true)
-//         })
-//         .filter_map(|(material_name, info, should_load_img)| {
-//             let img_loc = if should_load_img {
-//                 Some(find_texture(vpk, Some(map), &info.base_texture_name))
-//             } else {
-//                 None
-//             };
-
-//             Some((material_name, info, img_loc))
-//         })
-//         .collect::<Vec<_>>();
-
-//     // We've collected the materials and dedup'd the texture references
-//     // Now we want to add all the materials that don't need to
-//     let mut texture_loc = iter
-//         .into_iter()
-//         // Add each material to the definition
-//         .filter_map(|(material_name, info, img_loc)| {
-//             let material = LMaterial {
-//                 image: Ok(info.base_texture_name.clone()),
-//                 vmt_src: info.vmt_src,
-//             };
-
-//             loaded_textures.insert_material(material_name.clone(), material);
-
-//             match img_loc {
-//                 Some(Ok(loc)) => Some((info, loc)),
-//                 Some(Err(err)) => {
-//                     eprintln!(
-//                         "Failed to find texture for material {}: {:?}",
-//                         material_name, err
-//                     );
-//                     None
-//                 }
-//                 None => None,
-//             }
-//         })
-//         .collect::<Vec<_>>();
-
-//     // Now our iter is purely of the textures we need to load
-//     // Most textures will not be in the preload, but rather will be in one of the many individual
-//     // vpk storage files (suffixed by 000, 001, etc.)
-//     // We want to load all of these in parallel, but also do so efficiently.
-//     //
-//     // We can't simply just open the `File`s and pass them in, because the position is managed by
-//     // the `File`, and so that would just completely break in a multithreaded environment.
-//     //
-//     // It is also dispreferred to open/close the files separately. It might be fine, but it might
-//     // also be slower due to constantly talking to the OS.
-//     // This is especially a problem because the default order of the textures we're loading
-//     // will naturally jump around randomly between the different storage files!
-//     //
-//     // So what we do here is sort the textures by their storage file.
-//     // TODO(minor): We could sort them by their offset in the archive too
-//     texture_loc.par_sort_unstable_by(|(_, a), (_, b)| match (a, b) {
-//         // We sort by src and then by the archive index within that src
-//         (
-//             FileLoc::Vpk {
-//                 src: a,
-//                 archive_index: a_idx,
-//             },
-//             FileLoc::Vpk {
-//                 src: b,
-//                 archive_index: b_idx,
-//             },
-//         ) => match a.cmp(b) {
-//             Ordering::Equal => a_idx.cmp(b_idx),
-//             other => other,
-//         },
-//         // TODO(minor): might it be better to put maps in between two vpk loads, so that
-//         // there is more time where the threads aren't touching the filesystem?
-//         // VPKs are always before maps
-//         (FileLoc::Vpk { .. }, FileLoc::Map) => Ordering::Less,
-//         (FileLoc::Map, FileLoc::Vpk { .. }) => Ordering::Greater,
-//         // TODO(minor): there's probably some ordering we could do for loading from the map's
-//         // packfile but it almost certainly doesn't matter much.
-//         (FileLoc::Map, FileLoc::Map) => Ordering::Equal,
-//     });
-
-//     // Now we can load the textures in parallel
-//     // I'm currently breaking it into pieces based on the file type, but that seems less than ideal.
-
-//     // TODO: don't assume there's at least one texture
-//     let mut cur_type: FileLoc = texture_loc[0].1.clone();
-//     let mut cur_start = 0;
-//     let mut work = Vec::new();
-//     for (i, (_info, loc)) in texture_loc.iter().enumerate() {
-//         if loc != &cur_type {
-//             let end = i;
-//             work.push((cur_type, cur_start..end));
-//             cur_type = loc.clone();
-//             cur_start = end;
-//         }
-//     }
-//     if cur_start != texture_loc.len() {
-//         work.push((cur_type, cur_start..texture_loc.len()));
-//     }
-
-//     let res = work
-//         .into_par_iter()
-//         .filter_map(|(kind, range)| {
-//             let data = &texture_loc[range];
-
-//             let reader = match kind {
-//                 FileLoc::Vpk { src, archive_index } => {
-//                     let path = vpk.archive_path(&src, archive_index).unwrap();
-//                     let Ok(file) = std::fs::File::open(path) else {
-//                         eprintln!("Failed to open file: {:?}", path);
-//                         return None;
-//                     };
-//                     Some(file)
-//                 }
-//                 FileLoc::Map => None,
-//             };
-
-//             let mut images = Vec::new();
-//             for (info, loc) in data {
-//                 assert_eq!(loc, &kind);
-
-//                 let start_time = std::time::Instant::now();
-//                 let res = construct_image(vpk, Some(map), &info.base_texture_name);
-//                 let res = match res {
-//                     Ok((image, img_src)) => Some((info, (image, img_src))),
-//                     Err(err) => {
-//                         eprintln!(
-//                             "Failed to construct image for material, texture {}: {:?}",
-//                             info.base_texture_name, err
-//                         );
-//                         None
-//                     }
-//                 };
-//                 let end_time = std::time::Instant::now();
-
-//                 let mut mean = image_m.lock().unwrap();
-//                 mean.update_dur(end_time - start_time);
-
-//                 if let Some(res) = res {
-//                     images.push(res);
-//                 }
-//             }
-
-//             Some(images)
-//         })
-//         .flat_map(|x| x)
-//         .collect::<Vec<_>>();
-
-//     for (info, (image, img_src)) in res {
-//         loaded_textures.insert_texture_of(
-//             images,
-//             info.base_texture_name.clone(),
-//             image,
-//             img_src,
-//         )?;
-//     }
-
-//     println!(
-//         "V: vmt #{}; vtf #{}",
-//         loaded_textures.vmt.len(),
-//         loaded_textures.vtf.len()
-//     );
-
-//     let end_time = std::time::Instant::now();
-
-//     println!("Loaded textures in {:?};", end_time - start_time);
-//     let material_m = material_m.lock().unwrap();
-//     let image_m = image_m.lock().unwrap();
-//     println!("Material mean: {:?}", material_m.mean() / 1000.0);
-//     println!("Image mean: {:?}", image_m.mean() / 1000.0);
-
-//     loaded_textures.frozen = true;
-
-//     Ok(())
-// }
+/// Load all the (materials -> textures), reading the textures in the order they're stored.
+/// The vtfs are spread across many archive files, and [`load_materials`] ends up jumping randomly
+/// between them. Instead, we sort the textures by their archive and offset, then load each
+/// archive's textures in sequence while loading the different archives in parallel.
+pub fn load_materials2(
+    vpk: &VpkState,
+    loaded_textures: &mut LoadedTextures,
+    images: &mut Assets<Image>,
+    materials: &mut Assets<BrushMaterial>,
+    map: &GameMap,
+) -> eyre::Result<()> {
+    let material_names = material_names(map);
+
+    let start_time = std::time::Instant::now();
+
+    // Our first stage finds all of the VMTs and loads them.
+    // Currently this assumes that the VMTs are cheap to load, which is
+    // probably usually/always true because they'll be in the dir VPK's preload
+    // but I have not actually checked.
+    // TODO: check how many materials are actually in storage files, the average time in my
+    // previous tests makes me think at least some of them are. If a notable amount are, then
+    // we can swap to getting them in the order we need to load them.
+    let infos = material_names
+        .into_par_iter()
+        .filter_map(
+            |material_name| match construct_material_info(vpk, Some(map), &material_name) {
+                Ok(info) => Some((material_name, info)),
+                Err(err) => {
+                    eprintln!(
+                        "Failed to construct material info for {}: {:?}",
+                        material_name, err
+                    );
+                    None
+                }
+            },
+        )
+        .collect::<Vec<_>>();
+
+    // Deduplicate any textures. We still need to add all the different materials, but if they
+    // reference the same texture then we only want to load it once.
+    // A vtf used in different roles is converted differently, so it is loaded once for each.
+    let mut seen = HashSet::with_capacity(infos.len());
+    let mut texture_loc = infos
+        .iter()
+        .flat_map(|(material_name, info)| info.textures().map(move |tex| (material_name, tex)))
+        .filter(|(_, tex)| seen.insert(tex.clone()))
+        .filter_map(
+            |(material_name, (name, role))| match find_texture(vpk, Some(map), &name) {
+                Ok((loc, offset)) => Some((name, role, loc, offset)),
+                Err(err) => {
+                    eprintln!(
+                        "Failed to find texture {} for material {}: {:?}",
+                        name, material_name, err
+                    );
+                    None
+                }
+            },
+        )
+        .collect::<Vec<_>>();
+
+    // Sort by the file they're in, then by where they are in that file, so that each archive is
+    // read front to back.
+    // TODO(minor): might it be better to put maps in between two vpk loads, so that
+    // there is more time where the threads aren't touching the filesystem?
+    texture_loc.par_sort_unstable_by(|(_, _, a, a_offset), (_, _, b, b_offset)| {
+        a.cmp(b).then(a_offset.cmp(b_offset))
+    });
+
+    // Split into runs of textures from the same file
+    let mut work: Vec<&[(TextureName, TextureRole, FileLoc, u32)]> = Vec::new();
+    let mut rest = texture_loc.as_slice();
+    while let Some((_, _, loc, _)) = rest.first() {
+        let end = rest
+            .iter()
+            .position(|(_, _, l, _)| l != loc)
+            .unwrap_or(rest.len());
+        let (run, next) = rest.split_at(end);
+        work.push(run);
+        rest = next;
+    }
+
+    let archive_count = work.len();
+    let archives = ArchiveMaps::default();
+    let cache = loaded_textures.cache.as_deref();
+    let res = work
+        .into_par_iter()
+        .flat_map_iter(|run| {
+            run.iter().filter_map(|(name, role, _, _)| {
+                match construct_image(vpk, Some(map), cache, Some(&archives), name, *role) {
+                    Ok((image, img_src)) => Some((name.clone(), *role, image, img_src)),
+                    Err(err) => {
+                        eprintln!("Failed to construct image for texture {}: {:?}", name, err);
+                        None
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for (name, role, image, img_src) in res {
+        loaded_textures.insert_texture_of(images, name, image, img_src, role);
+    }
+
+    insert_materials(loaded_textures, materials, infos);
+
+    println!(
+        "V: vmt #{}; vtf #{}",
+        loaded_textures.vmt.len(),
+        loaded_textures.vtf.len()
+    );
+
+    let end_time = std::time::Instant::now();
+
+    println!(
+        "Loaded textures in {:?}; {archive_count} files, {} archives mapped",
+        end_time - start_time,
+        archives.len()
+    );
+
+    Ok(())
+}
 
 pub fn missing_texture() -> Image {
     // Pink and black checkerboard
@@ -552,3 +687,91 @@ pub fn missing_texture() -> Image {
         ..Default::default()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_water_params() {
+        let text = r#""Water"
+        {
+            "$normalmap" "water/tfwater001_normal"
+            "$fogcolor" "{0 51 102}"
+            "$fogstart" 16
+            "$fogend" 256
+            "$reflecttint" "[.5 .5 .5]"
+            "Proxies"
+            {
+                "TextureScroll"
+                {
+                    "texturescrollvar" "$bumptransform"
+                    "texturescrollrate" .1
+                    "texturescrollangle" 90
+                }
+            }
+        }"#;
+        let vmt = VMT::from_bytes(text.as_bytes()).unwrap();
+        let params = MaterialParams::from_vmt(&vmt);
+        let water = params.water.unwrap();
+        assert_eq!(water.fog_color, [0.0, 0.2, 0.4]);
+        assert_eq!((water.fog_start, water.fog_end), (16.0, 256.0));
+        assert_eq!(water.reflect_tint, [0.5; 3]);
+        assert_eq!(water.refract_tint, [1.0; 3]);
+        assert_eq!(water.reflect_amount, DEFAULT_REFLECT_AMOUNT);
+        assert!(water.scroll[0].abs() < 1e-6);
+        assert!((water.scroll[1] - 0.1).abs() < 1e-6);
+
+        // Water without a scroll proxy still moves
+        let vmt = VMT::from_bytes(br#""Water" { "$normalmap" "water/water_normal" }"#).unwrap();
+        let water = MaterialParams::from_vmt(&vmt).water.unwrap();
+        assert_eq!(water.scroll, DEFAULT_WATER_SCROLL);
+
+        let vmt = VMT::from_bytes(br#""LightmappedGeneric" { "$basetexture" "a" }"#).unwrap();
+        assert_eq!(MaterialParams::from_vmt(&vmt).water, None);
+    }
+
+    #[test]
+    fn test_envmap_params() {
+        let text = r#""LightmappedGeneric"
+        {
+            "$basetexture" "metal/metalwall001"
+            "$envmap" "env_cubemap"
+            "$envmaptint" "[.5 .5 .25]"
+            "$envmapmask" "Metal/MetalWall001_Mask"
+            "$normalmapalphaenvmapmask" 1
+        }"#;
+        let vmt = VMT::from_bytes(text.as_bytes()).unwrap();
+        let envmap = MaterialParams::from_vmt(&vmt).envmap.unwrap();
+        assert_eq!(envmap.cubemap, None);
+        assert_eq!(envmap.tint, [0.5, 0.5, 0.25]);
+        assert_eq!(envmap.mask.as_deref(), Some("metal/metalwall001_mask"));
+        assert!(!envmap.base_alpha_mask);
+        assert!(envmap.normal_map_alpha_mask);
+
+        // vbsp's patched materials name the cubemap, and include the original material
+        let base = VMT::from_bytes(
+            br#""LightmappedGeneric" { "$basetexture" "a" "$envmap" "env_cubemap" }"#,
+        )
+        .unwrap();
+        let text = r#""patch"
+        {
+            "include" "materials/metal/metalwall001.vmt"
+            "insert"
+            {
+                "$envmap" "Maps\ctf_2fort\c-128_64_256"
+            }
+        }"#;
+        let patch = VMT::from_bytes(text.as_bytes()).unwrap();
+        let vmt = patch.resolve(|_| Ok::<_, ()>(base)).unwrap();
+        let envmap = MaterialParams::from_vmt(&vmt).envmap.unwrap();
+        assert_eq!(
+            envmap.cubemap.as_deref(),
+            Some("maps/ctf_2fort/c-128_64_256")
+        );
+        assert_eq!(envmap.tint, [1.0; 3]);
+
+        let vmt = VMT::from_bytes(br#""LightmappedGeneric" { "$basetexture" "a" }"#).unwrap();
+        assert_eq!(MaterialParams::from_vmt(&vmt).envmap, None);
+    }
+}
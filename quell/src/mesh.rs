@@ -1,11 +1,23 @@
+use std::{collections::HashMap, ops::Range, sync::Arc};
+
 use bevy::{
-    prelude::{AlphaMode, Color, Handle, Image, Mesh, StandardMaterial, Transform, Vec3},
-    render::render_resource::PrimitiveTopology,
+    prelude::{AlphaMode, Mesh, StandardMaterial, Transform, Vec3},
+    render::{
+        mesh::{Indices, MeshVertexAttribute, VertexAttributeValues},
+        render_resource::PrimitiveTopology,
+    },
+};
+use rayon::prelude::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
 };
-use rayon::prelude::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use vbsp::{Bsp, DisplacementInfo};
 
-use crate::{data::LoadedTextures, map::GameMap};
+use crate::{
+    brush::ATTRIBUTE_BUMP_LIGHTMAP_OFFSET,
+    displacement::{DispGrid, DispVertex, MAX_DISPLACEMENT_POWER},
+    lightmap::Lightmaps,
+    map::GameMap,
+};
 
 // pub const SCALE: f32 = 0.1;
 pub const SCALE: f32 = 1.0 / (1.905 * 100.0);
@@ -13,10 +25,7 @@ pub const SCALE: f32 = 1.0 / (1.905 * 100.0);
 // TODO: use a trait or something to build the meshes so that this can be used by other libraries
 // if desired.
 
-pub fn construct_meshes<'c>(
-    loaded_textures: &'c LoadedTextures,
-    map: &'c GameMap,
-) -> impl ParallelIterator<Item = FaceInfo<'c>> + 'c {
+pub fn construct_meshes(map: &GameMap) -> impl ParallelIterator<Item = FaceInfo<'_>> + '_ {
     // I had some trouble determining what the right way to construct the meshes early on is for
     // the map.
     // At first I did this, models -> faces
@@ -28,27 +37,20 @@ pub fn construct_meshes<'c>(
     map.bsp
         .models
         .par_iter()
-        .flat_map(|m| {
+        .enumerate()
+        .flat_map(|(model, m)| {
             let start = m.first_face as usize;
             let end = start + m.face_count as usize;
 
-            map.bsp.faces[start..end]
-                .par_iter()
-                .enumerate()
-                .map(move |(i, x)| (m, i, x))
+            (start..end)
+                .into_par_iter()
+                .map(move |face| FaceRef { model, face })
         })
-        .filter_map(move |(m, face_i, face)| {
-            // TODO: do these coordinates need to be rotated?
-            let origin = Vec3::new(m.origin.x, m.origin.y, m.origin.z);
-
-            let face = vbsp::Handle::new(&map.bsp, face);
-            let res = construct_face_cmd(loaded_textures, map, face, origin).transpose()?;
+        .filter_map(move |face_ref| {
+            let res = construct_face(map, face_ref).transpose()?;
             // TODO: use tracing
             match res {
-                Ok(mut face_info) => {
-                    face_info.face_i = face_i;
-                    Some(face_info)
-                }
+                Ok(face_info) => Some(face_info),
                 Err(err) => {
                     eprintln!("Failed to construct face: {:?}", err);
                     None
@@ -57,6 +59,50 @@ pub fn construct_meshes<'c>(
         })
 }
 
+/// A face in the map, identified by the model it belongs to and its index in the bsp's faces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FaceRef {
+    pub model: usize,
+    pub face: usize,
+}
+
+/// Group all of the faces in the map by the name of the material they use.
+pub fn faces_by_material(map: &GameMap) -> HashMap<Arc<str>, Vec<FaceRef>> {
+    let mut groups: HashMap<Arc<str>, Vec<FaceRef>> = HashMap::new();
+    for (model, m) in map.bsp.models.iter().enumerate() {
+        let start = m.first_face as usize;
+        let end = start + m.face_count as usize;
+        for face in start..end {
+            let face_h = vbsp::Handle::new(&map.bsp, &map.bsp.faces[face]);
+            let name = face_h.texture().name();
+
+            // Most faces share a handful of materials, so avoid allocating a name for each
+            if let Some(faces) = groups.get_mut(name) {
+                faces.push(FaceRef { model, face });
+            } else {
+                groups.insert(Arc::from(name), vec![FaceRef { model, face }]);
+            }
+        }
+    }
+
+    groups
+}
+
+/// Construct the mesh for a single face, if it is one that we render.
+pub fn construct_face(map: &GameMap, face_ref: FaceRef) -> eyre::Result<Option<FaceInfo<'_>>> {
+    let m = &map.bsp.models[face_ref.model];
+    // TODO: do these coordinates need to be rotated?
+    let origin = Vec3::new(m.origin.x, m.origin.y, m.origin.z);
+
+    let face = vbsp::Handle::new(&map.bsp, &map.bsp.faces[face_ref.face]);
+    let face_info = construct_face_cmd(map, face, face_ref.face, origin)?.map(|mut face_info| {
+        face_info.face_i = face_ref.face;
+        face_info
+    });
+
+    Ok(face_info)
+}
+
 // pub fn construct_meshes<'c>(
 //     loaded_textures: &'c LoadedTextures,
 //     map: &'c GameMap,
@@ -110,20 +156,183 @@ pub struct FaceInfo<'a> {
     pub face_i: usize,
 }
 
+/// Where a face's triangles are in a merged mesh
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FaceRange {
+    pub face_i: usize,
+    /// The face's first index in the mesh's indices
+    pub first_index: u32,
+    pub index_count: u32,
+}
+impl FaceRange {
+    /// The indices of the face's triangles in the mesh
+    pub fn triangles(&self) -> Range<u32> {
+        let first = self.first_index / 3;
+        first..first + self.index_count / 3
+    }
+}
+
+/// The vertex data of a merged mesh, see [`merge_faces`]
+#[derive(Default)]
+struct MergedMesh {
+    positions: Vec<[f32; 3]>,
+    normals: Vec<[f32; 3]>,
+    uvs: Vec<[f32; 2]>,
+    lightmap_uvs: Vec<[f32; 2]>,
+    bump_offsets: Vec<f32>,
+    colors: Vec<[f32; 4]>,
+    /// Whether any of the faces had vertex colors
+    has_colors: bool,
+    indices: Vec<u32>,
+}
+
+/// Merge the meshes of the faces into a single indexed mesh, along with where each face's
+/// triangles ended up in it.  
+/// The faces must share a transform, since the merged mesh only has one. Identical vertices of
+/// a face are shared between its triangles.
+pub fn merge_faces<'a>(faces: impl IntoIterator<Item = FaceInfo<'a>>) -> (Mesh, Vec<FaceRange>) {
+    let mut merged = MergedMesh::default();
+    let mut ranges = Vec::new();
+    let mut seen = HashMap::new();
+    for face in faces {
+        let mesh = &face.mesh;
+        let positions = float32x3(mesh, Mesh::ATTRIBUTE_POSITION);
+        let normals = float32x3(mesh, Mesh::ATTRIBUTE_NORMAL);
+        let uvs = float32x2(mesh, Mesh::ATTRIBUTE_UV_0);
+        let lightmap_uvs = float32x2(mesh, Mesh::ATTRIBUTE_UV_1);
+        let bump_offsets = match mesh.attribute(ATTRIBUTE_BUMP_LIGHTMAP_OFFSET) {
+            Some(VertexAttributeValues::Float32(values)) => values.as_slice(),
+            _ => &[],
+        };
+        let colors = match mesh.attribute(Mesh::ATTRIBUTE_COLOR) {
+            Some(VertexAttributeValues::Float32x4(values)) => values.as_slice(),
+            _ => &[],
+        };
+        merged.has_colors |= !colors.is_empty();
+
+        let first_index = merged.indices.len() as u32;
+        // Vertices are only shared within a face, since their lightmap uvs differ between faces
+        seen.clear();
+        for (i, &position) in positions.iter().enumerate() {
+            let normal = normals.get(i).copied().unwrap_or_default();
+            let uv = uvs.get(i).copied().unwrap_or_default();
+            let lightmap_uv = lightmap_uvs.get(i).copied().unwrap_or_default();
+            let bump_offset = bump_offsets.get(i).copied().unwrap_or_default();
+            // Faces without vertex colors don't blend to a second texture
+            let color = colors.get(i).copied().unwrap_or([1.0, 1.0, 1.0, 0.0]);
+
+            let key = [
+                position[0],
+                position[1],
+                position[2],
+                normal[0],
+                normal[1],
+                normal[2],
+                uv[0],
+                uv[1],
+                lightmap_uv[0],
+                lightmap_uv[1],
+                bump_offset,
+                color[0],
+                color[1],
+                color[2],
+                color[3],
+            ]
+            .map(f32::to_bits);
+            let index = *seen.entry(key).or_insert_with(|| {
+                merged.positions.push(position);
+                merged.normals.push(normal);
+                merged.uvs.push(uv);
+                merged.lightmap_uvs.push(lightmap_uv);
+                merged.bump_offsets.push(bump_offset);
+                merged.colors.push(color);
+                merged.positions.len() as u32 - 1
+            });
+            merged.indices.push(index);
+        }
+
+        ranges.push(FaceRange {
+            face_i: face.face_i,
+            first_index,
+            index_count: merged.indices.len() as u32 - first_index,
+        });
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, merged.positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, merged.normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, merged.uvs);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_1, merged.lightmap_uvs);
+    mesh.insert_attribute(ATTRIBUTE_BUMP_LIGHTMAP_OFFSET, merged.bump_offsets);
+    if merged.has_colors {
+        mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, merged.colors);
+    }
+    mesh.set_indices(Some(Indices::U32(merged.indices)));
+
+    (mesh, ranges)
+}
+
+fn float32x3(mesh: &Mesh, attribute: MeshVertexAttribute) -> &[[f32; 3]] {
+    match mesh.attribute(attribute) {
+        Some(VertexAttributeValues::Float32x3(values)) => values,
+        _ => &[],
+    }
+}
+
+fn float32x2(mesh: &Mesh, attribute: MeshVertexAttribute) -> &[[f32; 2]] {
+    match mesh.attribute(attribute) {
+        Some(VertexAttributeValues::Float32x2(values)) => values,
+        _ => &[],
+    }
+}
+
+/// The vis clusters that each face is in, from the leaves which reference it.
+/// Faces of brush entities aren't in any leaf, so they have no clusters.
+#[derive(Debug, Clone, Default)]
+pub struct FaceClusters(Vec<Vec<i16>>);
+impl FaceClusters {
+    pub fn new(bsp: &Bsp) -> FaceClusters {
+        let mut clusters = vec![Vec::new(); bsp.faces.len()];
+        for leaf in bsp.leaves.iter() {
+            if leaf.cluster == -1 {
+                continue;
+            }
+
+            let start = leaf.first_leaf_face as usize;
+            let end = start + leaf.leaf_face_count as usize;
+            for leaf_face in bsp.leaf_faces.get(start..end).unwrap_or_default() {
+                if let Some(face_clusters) = clusters.get_mut(usize::from(leaf_face.face)) {
+                    face_clusters.push(leaf.cluster);
+                }
+            }
+        }
+
+        for face_clusters in &mut clusters {
+            face_clusters.sort_unstable();
+            face_clusters.dedup();
+        }
+
+        FaceClusters(clusters)
+    }
+
+    /// The clusters the face is in, in ascending order
+    pub fn get(&self, face: usize) -> &[i16] {
+        self.0.get(face).map_or(&[], Vec::as_slice)
+    }
+}
+
 /// Construct the information needed to create a face.
-/// This currently expects any textures to already be loaded so that it can easily be used in
-/// parallel.
+/// This does not depend on the textures being loaded, so that it can easily be used in parallel.
 fn construct_face_cmd<'a>(
-    loaded_textures: &LoadedTextures,
     map: &'a GameMap,
     face: vbsp::Handle<'a, vbsp::Face>,
+    face_i: usize,
     offset: Vec3,
 ) -> eyre::Result<Option<FaceInfo<'a>>> {
     let texture_info = face.texture();
-    let texture_data = texture_info.texture_data();
 
     // TODO: create nodraw meshes but hide them so we can render them in debug mode
-    // TODO: create the skybox
+    // Sky faces are left out so that the skybox shows through them
     if texture_info.flags.contains(vbsp::TextureFlags::NODRAW)
         || texture_info.flags.contains(vbsp::TextureFlags::SKY)
     {
@@ -137,48 +346,14 @@ fn construct_face_cmd<'a>(
         return Ok(None);
     }
 
-    let reflect = texture_data.reflectivity;
-    let color = if texture_info.flags.contains(vbsp::TextureFlags::SKY) {
-        Color::rgb(0.0, 0.0, 0.0)
-    } else {
-        let alpha = if texture_info.flags.contains(vbsp::TextureFlags::TRANS) {
-            0.2
-        } else {
-            1.0
-        };
-
-        // TODO: actually just get this texture
-        if texture_name == "water/water_2fort_beneath.vmt" {
-            Color::rgba(0.0, 0.0, 0.8, 0.4)
-        } else {
-            Color::rgba(reflect.x, reflect.y, reflect.z, alpha)
-        }
-    };
-
+    let lightmaps = &map.lightmaps;
     if let Some(disp) = face.displacement() {
         Ok(Some(create_displacement_mesh(
-            &map.bsp, face, disp, offset, color,
-        )))
+            map, face, face_i, disp, offset,
+        )?))
     } else {
-        let texture_name = texture_info.name();
-        let texture = match loaded_textures.find_material_texture(texture_name) {
-            Some(Ok(texture)) => texture,
-            Some(Err(err)) => {
-                eprintln!("Failed to load texture: {:?}", err);
-                loaded_textures.missing_texture.clone()
-            }
-            None => {
-                eprintln!("Missing texture: {:?}", texture_name);
-                loaded_textures.missing_texture.clone()
-            }
-        };
-
         Ok(Some(create_basic_map_mesh(
-            &map.bsp,
-            face,
-            offset,
-            color,
-            Some(texture),
+            &map.bsp, lightmaps, face, face_i, offset,
         )))
     }
 }
@@ -188,10 +363,10 @@ fn construct_face_cmd<'a>(
 // 0,0,0 and then apply a transform to make it work nicer with other transform stuff
 fn create_basic_map_mesh<'a>(
     bsp: &'a Bsp,
+    lightmaps: &Lightmaps,
     face: vbsp::Handle<'a, vbsp::Face>,
+    face_i: usize,
     offset: Vec3,
-    color: Color,
-    texture: Option<Handle<Image>>,
 ) -> FaceInfo<'a> {
     let texture_info = face.texture();
     let tex_width = texture_info.texture().width as f32;
@@ -212,9 +387,12 @@ fn create_basic_map_mesh<'a>(
     let mut face_triangles = Vec::new();
     let mut face_normals = Vec::new();
     let mut face_uvs = Vec::new();
+    let mut face_lightmap_uvs = Vec::new();
 
     let mut triangle_vert = 0;
     let mut triangle = [[0.0, 0.0, 0.0], [0.0, 0.0, 0.0], [0.0, 0.0, 0.0]];
+    // The lightmap uvs, which are calculated from the original source vertices
+    let mut triangle_lightmap = [[0.0, 0.0], [0.0, 0.0], [0.0, 0.0]];
     for i in 0..face.num_edges {
         let surface_edge = bsp
             .surface_edges
@@ -228,41 +406,40 @@ fn create_basic_map_mesh<'a>(
 
         let vertex = bsp.vertices.get(vertex_index as usize).unwrap();
         let vertex = <[f32; 3]>::from(vertex.position);
+        let lightmap_uv = lightmaps.uv(face_i, vertex);
         let vertex = scale(vertex);
         let vertex = rotate(vertex);
 
         triangle[triangle_vert] = vertex;
+        triangle_lightmap[triangle_vert] = lightmap_uv;
         triangle_vert += 1;
 
         if triangle_vert > 2 {
             // TODO: I swapped the order of these because my rotate also made the z neg
             // and that seems to fix things, but I don't completely understand the details
-            let vert = triangle[2];
-            face_triangles.push(vert);
-            face_normals.push(normal);
-            face_uvs.push(calc_uv(&texture_info, vert, tex_width, tex_height));
-
-            let vert = triangle[1];
-            face_triangles.push(vert);
-            face_normals.push(normal);
-            face_uvs.push(calc_uv(&texture_info, vert, tex_width, tex_height));
-
-            let vert = triangle[0];
-            face_triangles.push(vert);
-            face_normals.push(normal);
-            face_uvs.push(calc_uv(&texture_info, vert, tex_width, tex_height));
+            for i in [2, 1, 0] {
+                let vert = triangle[i];
+                face_triangles.push(vert);
+                face_normals.push(normal);
+                face_uvs.push(calc_uv(&texture_info, vert, tex_width, tex_height));
+                face_lightmap_uvs.push(triangle_lightmap[i]);
+            }
 
             triangle[1] = triangle[2];
+            triangle_lightmap[1] = triangle_lightmap[2];
             triangle_vert = 2;
         }
     }
 
+    let vertex_count = face_triangles.len();
     let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
     mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, face_triangles);
     mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, face_normals);
     // panic!();
     mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, face_uvs);
-    // TODO: lightmaps with UV_1?
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_1, face_lightmap_uvs);
+    let bump_offsets = vec![lightmaps.bump_offset(face_i); vertex_count];
+    mesh.insert_attribute(ATTRIBUTE_BUMP_LIGHTMAP_OFFSET, bump_offsets);
 
     FaceInfo {
         mesh,
@@ -308,189 +485,143 @@ fn calc_uv(
 }
 
 fn create_displacement_mesh<'a>(
-    bsp: &'a vbsp::Bsp,
+    map: &'a GameMap,
     face: vbsp::Handle<'a, vbsp::Face>,
+    face_i: usize,
     disp: vbsp::Handle<'a, DisplacementInfo>,
     offset: Vec3,
-    color: Color,
-) -> FaceInfo<'a> {
-    let low_base = disp.start_position; // * SCALE;
-    let low_base = <[f32; 3]>::from(low_base);
-    // let low_base = rotate(low_base);
-
-    if face.num_edges != 4 {
-        panic!("Bad displacement!\n");
-    }
-
-    let mut corner_verts = [[0.0, 0.0, 0.0]; 4];
-    let mut base_i = None;
-    let mut base_dist = std::f32::INFINITY;
-    for (i, corner_vert) in corner_verts.iter_mut().enumerate() {
-        let surface_edge = bsp
-            .surface_edges
-            .get((face.first_edge + i as i32) as usize)
-            .unwrap();
-        let edge = bsp.edges.get(surface_edge.edge_index() as usize).unwrap();
-        let vertex_index = match surface_edge.direction() {
-            vbsp::EdgeDirection::FirstToLast => edge.start_index,
-            vbsp::EdgeDirection::LastToFirst => edge.end_index,
-        };
-
-        let vertex = bsp.vertices.get(vertex_index as usize).unwrap();
-        let vertex = <[f32; 3]>::from(vertex.position);
-        // let vertex = [vertex[0] * SCALE, vertex[1] * SCALE, vertex[2] * SCALE];
-        // let vertex = rotate(vertex);
-
-        *corner_vert = vertex;
-
-        let this_dist = (vertex[0] - low_base[0]).abs()
-            + (vertex[2] - low_base[2]).abs()
-            + (vertex[1] - low_base[1]).abs();
-        if this_dist < base_dist {
-            base_dist = this_dist;
-            base_i = Some(i);
-        }
-    }
+) -> eyre::Result<FaceInfo<'a>> {
+    let bsp = &map.bsp;
+    let lightmaps = &map.lightmaps;
+
+    let grid = displacement_grid(
+        bsp,
+        &face,
+        <[f32; 3]>::from(disp.start_position),
+        disp.displacement_vertex_start as usize,
+        disp.power as u32,
+    )?;
+
+    // The normals are smoothed across the seams with the neighboring displacements.
+    // A neighbor that fails to build just leaves a seam.
+    let neighbors = match map.displacements.for_face(face_i) {
+        Some((_, info)) => info
+            .neighbors
+            .iter()
+            .filter_map(|&neighbor| {
+                let info = map.displacements.infos.get(neighbor)?;
+                let face = bsp.faces.get(info.map_face)?;
+                displacement_grid(
+                    bsp,
+                    face,
+                    info.start_position,
+                    info.vertex_start,
+                    info.power,
+                )
+                .ok()
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    let smooth_normals = grid.smooth_normals(&neighbors);
 
-    let base_i = base_i.expect("Bad base in displacement");
-
-    let high_base = corner_verts[(base_i + 3) % 4];
-    let high_ray = corner_verts[(base_i + 2) % 4];
-    let high_ray = [
-        high_ray[0] - high_base[0],
-        high_ray[1] - high_base[1],
-        high_ray[2] - high_base[2],
-    ];
-    let low_ray = corner_verts[(base_i + 1) % 4];
-    let low_ray = [
-        low_ray[0] - low_base[0],
-        low_ray[1] - low_base[1],
-        low_ray[2] - low_base[2],
-    ];
-
-    let verts_wide = (2 << (disp.power - 1)) + 1;
-
-    let mut base_verts = vec![[0.0, 0.0, 0.0]; 289];
-    let mut base_alphas = vec![0.0; 289];
-
-    for y in 0..verts_wide {
-        let fy = y as f32 / (verts_wide as f32 - 1.0);
-
-        let mid_base = [
-            low_base[0] + low_ray[0] * fy,
-            low_base[1] + low_ray[1] * fy,
-            low_base[2] + low_ray[2] * fy,
-        ];
-        let mid_ray = [
-            high_base[0] + high_ray[0] * fy - mid_base[0],
-            high_base[1] + high_ray[1] * fy - mid_base[1],
-            high_base[2] + high_ray[2] * fy - mid_base[2],
-        ];
-
-        for x in 0..verts_wide {
-            let fx = x as f32 / (verts_wide as f32 - 1.0);
-            let i = x + y * verts_wide;
-
-            // TODO: use disp.displacement_vertices
-            let disp_vert = bsp
-                .displacement_vertices
-                .get((disp.displacement_vertex_start + i) as usize)
-                .unwrap();
-            let offset = <[f32; 3]>::from(disp_vert.vector);
-            let scale = disp_vert.distance;
-            let alpha = disp_vert.alpha;
-
-            base_verts[i as usize] = [
-                mid_base[0] + mid_ray[0] * fx + offset[0] * scale,
-                mid_base[1] + mid_ray[1] * fx + offset[1] * scale,
-                mid_base[2] + mid_ray[2] * fx + offset[2] * scale,
-            ];
-            base_alphas[i as usize] = alpha;
-        }
-    }
+    let texture_info = face.texture();
+    let tex_width = texture_info.texture().width as f32;
+    let tex_height = texture_info.texture().height as f32;
 
-    let mut tris = Vec::new();
-    let mut normals = Vec::new();
-
-    for y in 0..(verts_wide - 1) {
-        for x in 0..(verts_wide - 1) {
-            let i = x + y * verts_wide;
-
-            let v1 = scale(rotate(base_verts[i as usize]));
-            let v2 = scale(rotate(base_verts[(i + 1) as usize]));
-            let v3 = scale(rotate(base_verts[(i + verts_wide) as usize]));
-            let v4 = scale(rotate(base_verts[(i + verts_wide + 1) as usize]));
-
-            // TODO: I'm unsure about the normal calculations. I think they were originally done in
-            // the source or opengl coordinates rather than bevys and not sure I corrected them
-            // right.
-            if i % 2 != 0 {
-                let normal = find_normal(v2, v3, v1);
-                // let color = Color::rgb(tex_r1, tex_g1, tex_b1);
-
-                tris.push(v2);
-                normals.push(normal);
-                tris.push(v3);
-                normals.push(normal);
-                tris.push(v1);
-                normals.push(normal);
-
-                let normal = find_normal(v4, v3, v2);
-
-                tris.push(v4);
-                normals.push(normal);
-                tris.push(v3);
-                normals.push(normal);
-                tris.push(v2);
-                normals.push(normal);
-            } else {
-                let normal = find_normal(v4, v3, v1);
-
-                tris.push(v4);
-                normals.push(normal);
-                tris.push(v3);
-                normals.push(normal);
-                tris.push(v1);
-                normals.push(normal);
-
-                let normal = find_normal(v4, v1, v2);
-
-                tris.push(v4);
-                normals.push(normal);
-                tris.push(v1);
-                normals.push(normal);
-                tris.push(v2);
-                normals.push(normal);
-            }
-        }
+    let vertex_count = grid.triangles.len() * 3;
+    let mut tris = Vec::with_capacity(vertex_count);
+    let mut normals = Vec::with_capacity(vertex_count);
+    let mut uvs = Vec::with_capacity(vertex_count);
+    let mut lightmap_uvs = Vec::with_capacity(vertex_count);
+    let mut colors = Vec::with_capacity(vertex_count);
+    // The triangles are already wound counter-clockwise, and rotating to bevy's coordinates keeps
+    // that winding
+    for &i in grid.triangles.iter().flatten() {
+        tris.push(scale(rotate(grid.positions[i])));
+        normals.push(rotate(smooth_normals[i]));
+        // Texture coordinates come from the position on the flat face, before it is displaced
+        let flat = grid.flat[i];
+        uvs.push(calc_uv(
+            &texture_info,
+            rotate(scale(flat)),
+            tex_width,
+            tex_height,
+        ));
+        lightmap_uvs.push(lightmaps.uv(face_i, flat));
+        // The alpha is how much of a `WorldVertexTransition` material's second texture is used
+        colors.push([1.0, 1.0, 1.0, grid.alphas[i] / 255.0]);
     }
 
     let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
     mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, tris);
     mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_1, lightmap_uvs);
+    let bump_offsets = vec![lightmaps.bump_offset(face_i); vertex_count];
+    mesh.insert_attribute(ATTRIBUTE_BUMP_LIGHTMAP_OFFSET, bump_offsets);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
 
-    FaceInfo {
+    Ok(FaceInfo {
         mesh,
         transform: Transform::from_translation(offset),
         material_name: face.texture().name(),
         // TODO: do something better than letting the caller set this?
         face_i: 0,
-    }
+    })
 }
 
-fn find_normal(a: [f32; 3], b: [f32; 3], c: [f32; 3]) -> [f32; 3] {
-    let u = [b[0] - c[0], b[1] - c[1], b[2] - c[2]];
-    let v = [a[0] - c[0], a[1] - c[1], a[2] - c[2]];
+/// Build the grid of the displacement that was made from the face.
+fn displacement_grid(
+    bsp: &Bsp,
+    face: &vbsp::Face,
+    start_position: [f32; 3],
+    vertex_start: usize,
+    power: u32,
+) -> eyre::Result<DispGrid> {
+    if face.num_edges != 4 {
+        eyre::bail!(
+            "Displacement face has {} edges rather than 4",
+            face.num_edges
+        );
+    }
+
+    let mut corners = [[0.0; 3]; 4];
+    for (i, corner) in corners.iter_mut().enumerate() {
+        let surface_edge = bsp
+            .surface_edges
+            .get((face.first_edge + i as i32) as usize)
+            .ok_or_else(|| eyre::eyre!("Displacement face has an invalid surface edge"))?;
+        let edge = bsp
+            .edges
+            .get(surface_edge.edge_index() as usize)
+            .ok_or_else(|| eyre::eyre!("Displacement face has an invalid edge"))?;
+        let vertex_index = match surface_edge.direction() {
+            vbsp::EdgeDirection::FirstToLast => edge.start_index,
+            vbsp::EdgeDirection::LastToFirst => edge.end_index,
+        };
+        let vertex = bsp
+            .vertices
+            .get(vertex_index as usize)
+            .ok_or_else(|| eyre::eyre!("Displacement face has an invalid vertex"))?;
 
-    let norm = [
-        u[1] * v[2] - u[2] * v[1],
-        u[2] * v[0] - u[0] * v[2],
-        u[0] * v[1] - u[1] * v[0],
-    ];
+        *corner = <[f32; 3]>::from(vertex.position);
+    }
 
-    let len = (norm[0] * norm[0] + norm[1] * norm[1] + norm[2] * norm[2]).sqrt();
+    let verts_wide = (1usize << power.min(MAX_DISPLACEMENT_POWER)) + 1;
+    let vertices = bsp
+        .displacement_vertices
+        .get(vertex_start..vertex_start + verts_wide * verts_wide)
+        .ok_or_else(|| eyre::eyre!("Displacement vertices are out of bounds"))?
+        .iter()
+        .map(|vertex| DispVertex {
+            vector: <[f32; 3]>::from(vertex.vector),
+            distance: vertex.distance,
+            alpha: vertex.alpha,
+        })
+        .collect::<Vec<_>>();
 
-    [norm[0] / len, norm[1] / len, norm[2] / len]
+    DispGrid::new(corners, start_position, power, &vertices)
 }
 
 // fn pick_color(name: &str, x: f32) -> u32 {
@@ -0,0 +1,187 @@
+//! The material used for models (props).
+//! Like the game's `VertexLitGeneric`, models are lit by the [`AmbientCube`] where they are, with
+//! the normal picking between the light from each of its six directions.
+//! Materials with an `$envmap` add the reflection of the cubemap nearest to the model.
+use bevy::{
+    app::{App, Plugin},
+    asset::{embedded_asset, Asset, Handle},
+    math::Vec4,
+    pbr::{AlphaMode, Material, MaterialPipeline, MaterialPipelineKey, MaterialPlugin},
+    prelude::{Color, Image, Mesh},
+    reflect::TypePath,
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_asset::RenderAssets,
+        render_resource::{
+            AsBindGroup, AsBindGroupShaderType, RenderPipelineDescriptor, ShaderRef, ShaderType,
+            SpecializedMeshPipelineError,
+        },
+    },
+};
+
+use crate::{ambient::AmbientCube, material::EnvMapParams};
+
+const MODEL_SHADER: &str = "embedded://quell/model.wgsl";
+
+// Keep in sync with the flags in `model.wgsl`
+const FLAG_UNLIT: u32 = 1;
+const FLAG_BASE_ALPHA_ENVMAP_MASK: u32 = 2;
+
+/// Adds the [`ModelMaterial`]
+pub struct ModelMaterialPlugin;
+impl Plugin for ModelMaterialPlugin {
+    fn build(&self, app: &mut App) {
+        embedded_asset!(app, "model.wgsl");
+
+        app.add_plugins(MaterialPlugin::<ModelMaterial> {
+            // The shader has no prepass
+            prepass_enabled: false,
+            ..Default::default()
+        });
+    }
+}
+
+#[derive(Debug, Clone, Asset, TypePath, AsBindGroup)]
+#[bind_group_data(ModelMaterialKey)]
+#[uniform(0, ModelMaterialUniform)]
+pub struct ModelMaterial {
+    /// Multiplied with the base texture, ex: the `rendercolor` of a prop
+    pub base_color: Color,
+    #[texture(1)]
+    #[sampler(2)]
+    pub base_texture: Handle<Image>,
+    pub alpha_mode: AlphaMode,
+    /// The light the model is lit by, `None` ignores lighting, ex: detail props which are tinted
+    /// by their lighting instead
+    pub ambient: Option<AmbientCube>,
+    /// The cubemap reflected by the model
+    #[texture(3, dimension = "cube")]
+    #[sampler(4)]
+    pub envmap: Option<Handle<Image>>,
+    /// Masks the reflection by its color
+    #[texture(5)]
+    #[sampler(6)]
+    pub envmap_mask: Option<Handle<Image>>,
+    /// How the material reflects `envmap`
+    pub envmap_params: Option<EnvMapParams>,
+}
+impl Default for ModelMaterial {
+    fn default() -> Self {
+        ModelMaterial {
+            base_color: Color::WHITE,
+            base_texture: Handle::default(),
+            alpha_mode: AlphaMode::Opaque,
+            ambient: None,
+            envmap: None,
+            envmap_mask: None,
+            envmap_params: None,
+        }
+    }
+}
+impl Material for ModelMaterial {
+    fn vertex_shader() -> ShaderRef {
+        MODEL_SHADER.into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        MODEL_SHADER.into()
+    }
+
+    fn alpha_mode(&self) -> AlphaMode {
+        self.alpha_mode
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout,
+        key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        let vertex_layout = layout.get_layout(&[
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_NORMAL.at_shader_location(1),
+            Mesh::ATTRIBUTE_UV_0.at_shader_location(2),
+        ])?;
+        descriptor.vertex.buffers = vec![vertex_layout];
+        if key.bind_group_data.envmap {
+            descriptor.vertex.shader_defs.push("ENVMAP".into());
+        }
+
+        if let Some(fragment) = descriptor.fragment.as_mut() {
+            if key.bind_group_data.alpha_mask {
+                fragment.shader_defs.push("ALPHA_MASK".into());
+            }
+            if key.bind_group_data.additive {
+                fragment.shader_defs.push("ADDITIVE".into());
+            }
+            if key.bind_group_data.envmap {
+                fragment.shader_defs.push("ENVMAP".into());
+            }
+            if key.bind_group_data.envmap_mask {
+                fragment.shader_defs.push("ENVMAP_MASK".into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The parts of the material which need a different pipeline
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ModelMaterialKey {
+    alpha_mask: bool,
+    additive: bool,
+    envmap: bool,
+    envmap_mask: bool,
+}
+impl From<&ModelMaterial> for ModelMaterialKey {
+    fn from(material: &ModelMaterial) -> Self {
+        ModelMaterialKey {
+            alpha_mask: matches!(material.alpha_mode, AlphaMode::Mask(_)),
+            additive: material.alpha_mode == AlphaMode::Add,
+            envmap: material.envmap.is_some(),
+            envmap_mask: material.envmap.is_some() && material.envmap_mask.is_some(),
+        }
+    }
+}
+
+/// The uniform data of a [`ModelMaterial`], matching the struct in `model.wgsl`
+#[derive(Debug, Clone, Default, ShaderType)]
+pub struct ModelMaterialUniform {
+    pub base_color: Vec4,
+    /// The colors of the [`AmbientCube`], from the same directions
+    pub ambient: [Vec4; 6],
+    /// `$envmaptint`
+    pub envmap_tint: Vec4,
+    pub alpha_cutoff: f32,
+    pub flags: u32,
+}
+impl AsBindGroupShaderType<ModelMaterialUniform> for ModelMaterial {
+    fn as_bind_group_shader_type(&self, _images: &RenderAssets<Image>) -> ModelMaterialUniform {
+        let alpha_cutoff = match self.alpha_mode {
+            AlphaMode::Mask(cutoff) => cutoff,
+            _ => 0.5,
+        };
+
+        let mut uniform = ModelMaterialUniform {
+            base_color: self.base_color.as_linear_rgba_f32().into(),
+            alpha_cutoff,
+            ..Default::default()
+        };
+        match &self.ambient {
+            Some(ambient) => {
+                uniform.ambient = ambient.colors.map(|[r, g, b]| Vec4::new(r, g, b, 1.0));
+            }
+            None => uniform.flags |= FLAG_UNLIT,
+        }
+        if let Some(envmap) = &self.envmap_params {
+            let [r, g, b] = envmap.tint;
+            uniform.envmap_tint = Color::rgb(r, g, b).as_linear_rgba_f32().into();
+            if envmap.base_alpha_mask {
+                uniform.flags |= FLAG_BASE_ALPHA_ENVMAP_MASK;
+            }
+        }
+
+        uniform
+    }
+}
@@ -0,0 +1,108 @@
+// Shader for models, which are lit by the ambient cube of where they are rather than by dynamic
+// lights.
+#import bevy_pbr::mesh_functions::{
+    get_model_matrix, mesh_normal_local_to_world, mesh_position_local_to_clip,
+    mesh_position_local_to_world
+}
+#import bevy_pbr::mesh_view_bindings::view
+
+struct ModelMaterial {
+    base_color: vec4<f32>,
+    // The light arriving from +X, -X, +Y, -Y, +Z and -Z
+    ambient: array<vec4<f32>, 6>,
+    envmap_tint: vec4<f32>,
+    alpha_cutoff: f32,
+    flags: u32,
+};
+
+// Keep in sync with the flags in `model.rs`
+const FLAG_UNLIT: u32 = 1u;
+const FLAG_BASE_ALPHA_ENVMAP_MASK: u32 = 2u;
+
+@group(1) @binding(0) var<uniform> material: ModelMaterial;
+@group(1) @binding(1) var base_texture: texture_2d<f32>;
+@group(1) @binding(2) var base_sampler: sampler;
+@group(1) @binding(3) var envmap_texture: texture_cube<f32>;
+@group(1) @binding(4) var envmap_sampler: sampler;
+@group(1) @binding(5) var envmap_mask_texture: texture_2d<f32>;
+@group(1) @binding(6) var envmap_mask_sampler: sampler;
+
+struct Vertex {
+    @builtin(instance_index) instance_index: u32,
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) uv: vec2<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) world_normal: vec3<f32>,
+#ifdef ENVMAP
+    @location(2) world_position: vec3<f32>,
+#endif
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+    let model = get_model_matrix(vertex.instance_index);
+    out.clip_position = mesh_position_local_to_clip(model, vec4<f32>(vertex.position, 1.0));
+    out.world_normal = mesh_normal_local_to_world(vertex.normal, vertex.instance_index);
+    out.uv = vertex.uv;
+#ifdef ENVMAP
+    out.world_position = mesh_position_local_to_world(model, vec4<f32>(vertex.position, 1.0)).xyz;
+#endif
+    return out;
+}
+
+// The light from the directions the normal faces, weighted by the square of the normal like the
+// game's `AmbientLight`
+fn ambient_light(normal: vec3<f32>) -> vec3<f32> {
+    let n2 = normal * normal;
+    let x = select(material.ambient[0], material.ambient[1], normal.x < 0.0);
+    let y = select(material.ambient[2], material.ambient[3], normal.y < 0.0);
+    let z = select(material.ambient[4], material.ambient[5], normal.z < 0.0);
+    return n2.x * x.rgb + n2.y * y.rgb + n2.z * z.rgb;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    let base = textureSample(base_texture, base_sampler, in.uv);
+    let albedo = material.base_color * base;
+
+#ifdef ENVMAP
+    // The cubemap reflected off of the model, tinted by `$envmaptint`
+    let view_dir = normalize(view.world_position - in.world_position);
+    let dir = reflect(-view_dir, normalize(in.world_normal));
+    var envmap = textureSample(envmap_texture, envmap_sampler, dir).rgb * material.envmap_tint.rgb;
+#ifdef ENVMAP_MASK
+    envmap *= textureSample(envmap_mask_texture, envmap_mask_sampler, in.uv).rgb;
+#endif
+    if (material.flags & FLAG_BASE_ALPHA_ENVMAP_MASK) != 0u {
+        envmap *= base.a;
+    }
+#endif
+
+#ifdef ALPHA_MASK
+    if albedo.a < material.alpha_cutoff {
+        discard;
+    }
+#endif
+
+    var light = vec3<f32>(1.0);
+    if (material.flags & FLAG_UNLIT) == 0u {
+        light = ambient_light(normalize(in.world_normal));
+    }
+    var color = albedo.rgb * light;
+#ifdef ENVMAP
+    color += envmap;
+#endif
+
+#ifdef ADDITIVE
+    // Blended with premultiplied alpha, so no alpha means it is purely added
+    return vec4<f32>(color * albedo.a, 0.0);
+#else
+    return vec4<f32>(color, albedo.a);
+#endif
+}
@@ -0,0 +1,669 @@
+//! Overlays and decals, which are materials projected onto the faces of the map, ex: signs, grime
+//! and graffiti.
+//! Overlays (`info_overlay`) are compiled into the overlay lump, with the faces they cover already
+//! found by vbsp. Decals (`infodecal`) are entities which the game projects onto whatever faces are
+//! near them when the map loads, which we do the same way.
+//! Both are clipped to each face they're on, and drawn with the lightmap of that face.
+
+use std::sync::Arc;
+
+use bevy::{
+    prelude::{Mesh, Vec3},
+    render::{mesh::Indices, render_resource::PrimitiveTopology},
+};
+use vbsp::Bsp;
+
+use crate::{
+    brush::ATTRIBUTE_BUMP_LIGHTMAP_OFFSET,
+    lump::{read_records, LumpType, Lumps},
+    map::GameMap,
+    mesh::{rotate, scale},
+    util::parse_vector,
+};
+
+/// Size of a `doverlay_t`
+const OVERLAY_SIZE: usize = 352;
+/// The number of faces that each overlay has room for
+const OVERLAY_FACE_COUNT: usize = 64;
+/// The low bits of an overlay's face count field, the rest are its render order
+const OVERLAY_FACE_COUNT_MASK: u16 = 0x3FFF;
+/// How far away from a face an `infodecal` can be and still be projected onto it
+const DECAL_DISTANCE: f32 = 4.0;
+/// How closely a face has to face the same way as an `infodecal` to be covered by it
+const DECAL_MIN_FACING: f32 = 0.7;
+
+/// A material projected onto faces of the map.
+/// The overlay is a quad on the plane through `origin` with the normal `basis[2]`, which is
+/// projected along that normal onto the faces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Overlay {
+    pub material: Arc<str>,
+    /// The faces that the overlay is projected onto
+    pub faces: Vec<usize>,
+    /// In source coordinates
+    pub origin: [f32; 3],
+    /// The directions of the quad's x and y, and its normal
+    pub basis: [[f32; 3]; 3],
+    /// The corners of the quad in the plane of the basis, relative to the origin
+    pub points: [[f32; 2]; 4],
+    /// The texture coordinates at each of the corners
+    pub uvs: [[f32; 2]; 4],
+    /// Overlays with a higher render order are drawn on top of lower ones
+    pub render_order: u8,
+}
+impl Overlay {
+    /// Create the overlay for an `infodecal`, which covers the faces near its origin.
+    /// `size` is the size of the decal's texture, in source units.
+    pub fn decal(
+        bsp: &Bsp,
+        material: Arc<str>,
+        origin: [f32; 3],
+        size: [f32; 2],
+    ) -> Option<Overlay> {
+        let origin = Vec3::from(origin);
+
+        // The decal only goes onto the world, so the faces of the first model
+        let world = bsp.models.first()?;
+        let start = world.first_face as usize;
+        let end = start + world.face_count as usize;
+
+        // Checking the plane first is much cheaper than finding the polygon of every face
+        let near_planes = (start..end).filter(|&face_i| {
+            let face = vbsp::Handle::new(bsp, &bsp.faces[face_i]);
+            if face.displacement().is_some() {
+                return false;
+            }
+            let Some(plane) = bsp.planes.get(face.plane_num as usize) else {
+                return false;
+            };
+            let plane_normal = Vec3::new(plane.normal.x, plane.normal.y, plane.normal.z);
+            (plane_normal.dot(origin) - plane.dist).abs() <= DECAL_DISTANCE
+        });
+        let polygons = near_planes.map(|face_i| (face_i, face_vertices(bsp, face_i)));
+
+        let [w, h] = size.map(|v| v / 2.0);
+        let (normal, faces) = decal_faces(polygons, origin, w.hypot(h))?;
+
+        // Decals are upright on walls, and aligned with the world on floors and ceilings
+        let up = if normal.z.abs() > 0.7 {
+            Vec3::X
+        } else {
+            Vec3::Z
+        };
+        let x = up.cross(normal).try_normalize()?;
+        let y = normal.cross(x);
+
+        Some(Overlay {
+            material,
+            faces,
+            origin: origin.to_array(),
+            basis: [x, y, normal].map(Vec3::to_array),
+            points: [[-w, h], [-w, -h], [w, -h], [w, h]],
+            uvs: [[0.0, 0.0], [0.0, 1.0], [1.0, 1.0], [1.0, 0.0]],
+            render_order: 0,
+        })
+    }
+}
+
+/// Pick the faces that a decal at `origin` goes onto, out of the faces whose planes are near it,
+/// along with the normal of the decal. `reach` is how far the decal extends from its origin.
+fn decal_faces(
+    polygons: impl IntoIterator<Item = (usize, Vec<Vec3>)>,
+    origin: Vec3,
+    reach: f32,
+) -> Option<(Vec3, Vec<usize>)> {
+    let mut candidates = Vec::new();
+    for (face_i, vertices) in polygons {
+        let Some(normal) = polygon_normal(&vertices) else {
+            continue;
+        };
+        // Faces anywhere in the map can share the plane, so the face itself has to be near
+        let along_plane = distance_to_polygon(&vertices, normal, origin);
+        if along_plane > reach.max(DECAL_DISTANCE) {
+            continue;
+        }
+        let distance = normal.dot(origin - vertices[0]).abs();
+        candidates.push((face_i, normal, distance, along_plane));
+    }
+
+    // The decal faces the same way as the closest face under it, and only goes onto faces which
+    // face roughly the same way, rather than the back of a thin wall
+    let (_, normal, _, _) = candidates
+        .iter()
+        .filter(|(_, _, _, along_plane)| *along_plane <= DECAL_DISTANCE)
+        .min_by(|(_, _, a, _), (_, _, b, _)| a.total_cmp(b))
+        .copied()?;
+    let faces = candidates
+        .into_iter()
+        .filter(|(_, face_normal, _, _)| face_normal.dot(normal) > DECAL_MIN_FACING)
+        .map(|(face_i, _, _, _)| face_i)
+        .collect();
+
+    Some((normal, faces))
+}
+
+/// Read the overlays of the map, getting the name of the material of each from its texinfo.
+pub fn read_overlays(
+    data: &[u8],
+    texinfo_material: impl Fn(usize) -> Option<Arc<str>>,
+) -> eyre::Result<Vec<Overlay>> {
+    let lumps = Lumps::new(data)?;
+    parse_overlays(&lumps.get(LumpType::Overlays)?, texinfo_material)
+}
+
+fn parse_overlays(
+    lump: &[u8],
+    texinfo_material: impl Fn(usize) -> Option<Arc<str>>,
+) -> eyre::Result<Vec<Overlay>> {
+    read_records(lump, OVERLAY_SIZE, |r| {
+        let _id = r.i32()?;
+        let texinfo = r.i16()?;
+        let face_count = r.u16()?;
+        let faces = (0..OVERLAY_FACE_COUNT)
+            .map(|_| r.i32())
+            .collect::<eyre::Result<Vec<_>>>()?;
+        let [u0, u1] = [r.f32()?, r.f32()?];
+        let [v0, v1] = [r.f32()?, r.f32()?];
+        let points = [r.vec3()?, r.vec3()?, r.vec3()?, r.vec3()?];
+        let origin = r.vec3()?;
+        let normal = r.vec3()?;
+
+        let count = ((face_count & OVERLAY_FACE_COUNT_MASK) as usize).min(OVERLAY_FACE_COUNT);
+        let faces = faces[..count]
+            .iter()
+            .filter_map(|face| usize::try_from(*face).ok())
+            .collect();
+
+        // The overlay's x direction is stored in the unused z of its points, and its y is
+        // perpendicular to that and the normal
+        let x = [points[0][2], points[1][2], points[2][2]];
+        let y = Vec3::from(normal).cross(Vec3::from(x)).to_array();
+
+        let material = usize::try_from(texinfo)
+            .ok()
+            .and_then(&texinfo_material)
+            .ok_or_else(|| eyre::eyre!("Overlay has invalid texinfo {texinfo}"))?;
+
+        Ok(Overlay {
+            material,
+            faces,
+            origin,
+            basis: [x, y, normal],
+            points: points.map(|[x, y, _]| [x, y]),
+            uvs: [[u0, v0], [u0, v1], [u1, v1], [u1, v0]],
+            render_order: (face_count >> 14) as u8,
+        })
+    })
+}
+
+/// An `infodecal` entity
+#[derive(Debug, Clone, PartialEq)]
+pub struct InfoDecal {
+    pub material: Arc<str>,
+    /// In source coordinates
+    pub origin: [f32; 3],
+}
+impl InfoDecal {
+    /// Read the decal from the keyvalues of an entity, if it is an `infodecal`.
+    pub fn from_properties<'a>(
+        properties: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Option<InfoDecal> {
+        let mut class_name = "";
+        let mut material = None;
+        let mut origin = None;
+        for (key, value) in properties {
+            match key {
+                "classname" => class_name = value,
+                "texture" => material = Some(value),
+                "origin" => origin = parse_vector(value),
+                _ => {}
+            }
+        }
+
+        if class_name != "infodecal" {
+            return None;
+        }
+
+        Some(InfoDecal {
+            material: Arc::from(material?.to_lowercase()),
+            origin: origin?,
+        })
+    }
+}
+
+/// The materials used by the overlays and decals of the map, which aren't used by any faces.
+pub fn overlay_material_names(map: &GameMap) -> Vec<Arc<str>> {
+    let decals = map
+        .bsp
+        .entities
+        .iter()
+        .filter_map(|raw_ent| InfoDecal::from_properties(raw_ent.properties()))
+        .map(|decal| decal.material);
+
+    let mut names: Vec<Arc<str>> = Vec::new();
+    for name in map
+        .overlays
+        .iter()
+        .map(|o| o.material.clone())
+        .chain(decals)
+    {
+        if !names.iter().any(|other| other.eq_ignore_ascii_case(&name)) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// Build the mesh of the overlay, clipped to each of the faces it is on.
+/// The mesh has the same attributes as the faces, so that it can use the brush material along with
+/// their lightmaps.
+pub fn overlay_mesh(map: &GameMap, overlay: &Overlay) -> Option<Mesh> {
+    let [x, y, normal] = overlay.basis.map(Vec3::from);
+    let origin = Vec3::from(overlay.origin);
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut lightmap_uvs = Vec::new();
+    let mut bump_offsets = Vec::new();
+    let mut indices = Vec::new();
+    for &face_i in &overlay.faces {
+        let Some(face) = map.bsp.faces.get(face_i) else {
+            continue;
+        };
+        // TODO: project overlays onto displacements, which would need their triangles
+        if vbsp::Handle::new(&map.bsp, face).displacement().is_some() {
+            continue;
+        }
+
+        let vertices = face_vertices(&map.bsp, face_i);
+        let Some(face_normal) = polygon_normal(&vertices) else {
+            continue;
+        };
+        let face_dist = face_normal.dot(vertices[0]);
+        // Projecting along the overlay's normal can't reach faces perpendicular to it
+        let facing = face_normal.dot(normal);
+        if facing.abs() < 0.01 {
+            continue;
+        }
+
+        let face_points = vertices
+            .iter()
+            .map(|v| {
+                let d = *v - origin;
+                [d.dot(x), d.dot(y)]
+            })
+            .collect::<Vec<_>>();
+        let clipped = clip_polygon(&overlay.points, &face_points);
+        if clipped.len() < 3 {
+            continue;
+        }
+
+        let start = positions.len() as u32;
+        for point in &clipped {
+            let on_plane = origin + x * point[0] + y * point[1];
+            // Move the point along the normal onto the face
+            let height = (face_dist - face_normal.dot(on_plane)) / facing;
+            let vertex = (on_plane + normal * height).to_array();
+
+            positions.push(rotate(scale(vertex)));
+            normals.push(rotate(face_normal.to_array()));
+            uvs.push(quad_uv(&overlay.points, &overlay.uvs, *point));
+            lightmap_uvs.push(map.lightmaps.uv(face_i, vertex));
+            bump_offsets.push(map.lightmaps.bump_offset(face_i));
+        }
+
+        // Bevy's front faces are counter-clockwise, so the triangles have to wind that way when
+        // looking at the face from the side its normal points to
+        let clockwise = polygon_normal_2d(&clipped, x, y).dot(face_normal) < 0.0;
+        for i in 1..clipped.len() as u32 - 1 {
+            if clockwise {
+                indices.extend([start, start + i + 1, start + i]);
+            } else {
+                indices.extend([start, start + i, start + i + 1]);
+            }
+        }
+    }
+
+    if indices.is_empty() {
+        return None;
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_1, lightmap_uvs);
+    mesh.insert_attribute(ATTRIBUTE_BUMP_LIGHTMAP_OFFSET, bump_offsets);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    Some(mesh)
+}
+
+/// The vertices of a face's polygon, in source coordinates
+fn face_vertices(bsp: &Bsp, face_i: usize) -> Vec<Vec3> {
+    let face = &bsp.faces[face_i];
+    (0..face.num_edges)
+        .filter_map(|i| {
+            let surface_edge = bsp
+                .surface_edges
+                .get((face.first_edge + i as i32) as usize)?;
+            let edge = bsp.edges.get(surface_edge.edge_index() as usize)?;
+            let vertex_index = match surface_edge.direction() {
+                vbsp::EdgeDirection::FirstToLast => edge.start_index,
+                vbsp::EdgeDirection::LastToFirst => edge.end_index,
+            };
+            let vertex = bsp.vertices.get(vertex_index as usize)?;
+            let position = &vertex.position;
+            Some(Vec3::new(position.x, position.y, position.z))
+        })
+        .collect()
+}
+
+/// The normal of the side of the polygon that it winds clockwise around, which is the front of a
+/// face in source.
+fn polygon_normal(vertices: &[Vec3]) -> Option<Vec3> {
+    // Newell's method, which also works for polygons with collinear vertices
+    let mut normal = Vec3::ZERO;
+    for (i, a) in vertices.iter().enumerate() {
+        let b = vertices[(i + 1) % vertices.len()];
+        normal.x += (a.y - b.y) * (a.z + b.z);
+        normal.y += (a.z - b.z) * (a.x + b.x);
+        normal.z += (a.x - b.x) * (a.y + b.y);
+    }
+    // Newell's method gives the counter-clockwise normal
+    (-normal).try_normalize()
+}
+
+/// The distance from the point, projected onto the plane of the convex polygon, to the polygon.
+/// Zero if the point is over the polygon.
+fn distance_to_polygon(vertices: &[Vec3], normal: Vec3, point: Vec3) -> f32 {
+    let point = point - normal * normal.dot(point - vertices[0]);
+    let edges = || vertices.iter().zip(vertices.iter().cycle().skip(1));
+
+    // Inside if the point is on the same side of every edge, whichever way the polygon winds
+    let sides = edges().map(|(&a, &b)| (b - a).cross(point - a).dot(normal));
+    if sides.clone().all(|side| side >= 0.0) || sides.into_iter().all(|side| side <= 0.0) {
+        return 0.0;
+    }
+
+    edges()
+        .map(|(&a, &b)| {
+            let edge = b - a;
+            let t = (point - a).dot(edge) / edge.length_squared().max(f32::EPSILON);
+            point.distance(a + edge * t.clamp(0.0, 1.0))
+        })
+        .fold(f32::INFINITY, f32::min)
+}
+
+/// The normal (in source coordinates) of a 2D polygon in the plane of `x` and `y`, pointing to
+/// the side it winds counter-clockwise around.
+fn polygon_normal_2d(points: &[[f32; 2]], x: Vec3, y: Vec3) -> Vec3 {
+    x.cross(y) * signed_area(points)
+}
+
+/// Twice the signed area of the polygon, which is positive if it is counter-clockwise
+fn signed_area(points: &[[f32; 2]]) -> f32 {
+    points
+        .iter()
+        .zip(points.iter().cycle().skip(1))
+        .map(|(a, b)| a[0] * b[1] - b[0] * a[1])
+        .sum()
+}
+
+/// Clip the polygon to the inside of the convex polygon `clip`, which can wind either way.
+fn clip_polygon(polygon: &[[f32; 2]], clip: &[[f32; 2]]) -> Vec<[f32; 2]> {
+    let winding = signed_area(clip).signum();
+    let mut output = polygon.to_vec();
+    for (a, b) in clip.iter().zip(clip.iter().cycle().skip(1)) {
+        if output.is_empty() {
+            break;
+        }
+
+        // Positive on the inside of the edge
+        let side =
+            |p: [f32; 2]| winding * ((b[0] - a[0]) * (p[1] - a[1]) - (b[1] - a[1]) * (p[0] - a[0]));
+        let input = std::mem::take(&mut output);
+        for (i, &current) in input.iter().enumerate() {
+            let previous = input[(i + input.len() - 1) % input.len()];
+            let (current_side, previous_side) = (side(current), side(previous));
+            if (current_side >= 0.0) != (previous_side >= 0.0) {
+                let t = previous_side / (previous_side - current_side);
+                output.push([
+                    previous[0] + (current[0] - previous[0]) * t,
+                    previous[1] + (current[1] - previous[1]) * t,
+                ]);
+            }
+            if current_side >= 0.0 {
+                output.push(current);
+            }
+        }
+    }
+
+    output
+}
+
+/// Interpolate the texture coordinates of the quad's corners at the point, by splitting the quad
+/// into two triangles.
+fn quad_uv(points: &[[f32; 2]; 4], uvs: &[[f32; 2]; 4], point: [f32; 2]) -> [f32; 2] {
+    let barycentric = |[a, b, c]: [usize; 3]| {
+        let (pa, pb, pc) = (points[a], points[b], points[c]);
+        let det = (pb[1] - pc[1]) * (pa[0] - pc[0]) + (pc[0] - pb[0]) * (pa[1] - pc[1]);
+        if det.abs() < f32::EPSILON {
+            return None;
+        }
+        let wa =
+            ((pb[1] - pc[1]) * (point[0] - pc[0]) + (pc[0] - pb[0]) * (point[1] - pc[1])) / det;
+        let wb =
+            ((pc[1] - pa[1]) * (point[0] - pc[0]) + (pa[0] - pc[0]) * (point[1] - pc[1])) / det;
+        Some(([a, b, c], [wa, wb, 1.0 - wa - wb]))
+    };
+
+    // Use the triangle the point is most inside of, since it may be slightly outside both
+    let Some((corners, weights)) = [[0, 1, 2], [0, 2, 3]]
+        .into_iter()
+        .filter_map(barycentric)
+        .max_by(|(_, a), (_, b)| {
+            let min = |w: &[f32; 3]| w.iter().copied().fold(f32::INFINITY, f32::min);
+            min(a).total_cmp(&min(b))
+        })
+    else {
+        return uvs[0];
+    };
+
+    let mut uv = [0.0; 2];
+    for (corner, weight) in corners.into_iter().zip(weights) {
+        uv[0] += uvs[corner][0] * weight;
+        uv[1] += uvs[corner][1] * weight;
+    }
+    uv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Twice the area of the polygon
+    fn area(points: &[[f32; 2]]) -> f32 {
+        signed_area(points).abs()
+    }
+
+    #[test]
+    fn test_clip_polygon() {
+        let quad = [[-1.0, 1.0], [-1.0, -1.0], [1.0, -1.0], [1.0, 1.0]];
+
+        // A face covering the right half of the quad and past it, in either winding
+        let face = [[0.0, -4.0], [4.0, -4.0], [4.0, 4.0], [0.0, 4.0]];
+        let clipped = clip_polygon(&quad, &face);
+        assert_eq!(area(&clipped), 4.0);
+        assert!(clipped.iter().all(|p| p[0] >= 0.0));
+
+        let mut reversed = face;
+        reversed.reverse();
+        assert_eq!(area(&clip_polygon(&quad, &reversed)), 4.0);
+
+        // A face entirely inside of the quad is left as is
+        let small = [[0.0, 0.0], [0.5, 0.0], [0.5, 0.5]];
+        assert_eq!(area(&clip_polygon(&quad, &small)), 0.25);
+
+        // A face that doesn't touch the quad
+        let outside = [[2.0, 2.0], [3.0, 2.0], [3.0, 3.0]];
+        assert!(clip_polygon(&quad, &outside).len() < 3);
+    }
+
+    #[test]
+    fn test_quad_uv() {
+        let points = [[-2.0, 1.0], [-2.0, -1.0], [2.0, -1.0], [2.0, 1.0]];
+        let uvs = [[0.0, 0.0], [0.0, 1.0], [1.0, 1.0], [1.0, 0.0]];
+        for (point, uv) in points.iter().zip(&uvs) {
+            assert_eq!(quad_uv(&points, &uvs, *point), *uv);
+        }
+        assert_eq!(quad_uv(&points, &uvs, [0.0, 0.0]), [0.5, 0.5]);
+        assert_eq!(quad_uv(&points, &uvs, [1.0, 0.5]), [0.75, 0.25]);
+    }
+
+    #[test]
+    fn test_polygon_normal() {
+        // Clockwise when looking down from above, so the front faces up
+        let floor = [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(1.0, 1.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+        ];
+        assert_eq!(polygon_normal(&floor), Some(Vec3::Z));
+        assert_eq!(polygon_normal(&[Vec3::ZERO; 3]), None);
+
+        // The counter-clockwise side of a polygon in the overlay's plane
+        let ccw = [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]];
+        assert_eq!(
+            polygon_normal_2d(&ccw, Vec3::Y, Vec3::Z).try_normalize(),
+            Some(Vec3::X)
+        );
+    }
+
+    #[test]
+    fn test_decal_faces() {
+        // A square on the floor facing up, wound clockwise when seen from above
+        let square = |x: f32, y: f32| {
+            vec![
+                Vec3::new(x, y, 0.0),
+                Vec3::new(x, y + 64.0, 0.0),
+                Vec3::new(x + 64.0, y + 64.0, 0.0),
+                Vec3::new(x + 64.0, y, 0.0),
+            ]
+        };
+        let mut ceiling = square(1024.0, 0.0);
+        ceiling.reverse();
+
+        // A face on the same plane, far away and facing the other way, is just as close to the
+        // plane as the floor under the decal
+        let origin = Vec3::new(32.0, 32.0, 1.0);
+        let faces = [(0, ceiling), (1, square(0.0, 0.0)), (2, square(128.0, 0.0))];
+        let (normal, faces) = decal_faces(faces, origin, 16.0).unwrap();
+        assert_eq!(normal, Vec3::Z);
+        assert_eq!(faces, vec![1]);
+
+        // Near the edge, the decal also goes onto the neighboring face
+        let faces = [(1, square(0.0, 0.0)), (2, square(64.0, 0.0))];
+        let (_, faces) = decal_faces(faces, Vec3::new(56.0, 32.0, 1.0), 16.0).unwrap();
+        assert_eq!(faces, vec![1, 2]);
+
+        // Nothing under the decal
+        assert_eq!(decal_faces([(2, square(128.0, 0.0))], origin, 16.0), None);
+    }
+
+    #[test]
+    fn test_distance_to_polygon() {
+        let square = [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 64.0, 0.0),
+            Vec3::new(64.0, 64.0, 0.0),
+            Vec3::new(64.0, 0.0, 0.0),
+        ];
+        let mut reversed = square;
+        reversed.reverse();
+        for (vertices, normal) in [(square, Vec3::Z), (reversed, -Vec3::Z)] {
+            let distance = |point| distance_to_polygon(&vertices, normal, point);
+            assert_eq!(distance(Vec3::new(32.0, 32.0, 8.0)), 0.0);
+            assert_eq!(distance(Vec3::new(70.0, 32.0, -8.0)), 6.0);
+            assert_eq!(distance(Vec3::new(67.0, 68.0, 0.0)), 5.0);
+        }
+    }
+
+    #[test]
+    fn test_overlays() {
+        let mut data = Vec::new();
+        data.extend(7i32.to_le_bytes());
+        data.extend(3i16.to_le_bytes());
+        // Two faces, render order 1
+        data.extend((2u16 | (1 << 14)).to_le_bytes());
+        let mut faces = [0i32; OVERLAY_FACE_COUNT];
+        faces[..2].copy_from_slice(&[12, 40]);
+        for face in faces {
+            data.extend(face.to_le_bytes());
+        }
+        for v in [0.0f32, 1.0, 0.25, 0.75] {
+            data.extend(v.to_le_bytes());
+        }
+        // The points, with the x direction in their z
+        let points: [[f32; 3]; 4] = [
+            [-8.0, 4.0, 0.0],
+            [-8.0, -4.0, 1.0],
+            [8.0, -4.0, 0.0],
+            [8.0, 4.0, 0.0],
+        ];
+        let origin = [64.0f32, 0.0, 32.0];
+        let normal = [1.0f32, 0.0, 0.0];
+        for v in points.iter().flatten().chain(&origin).chain(&normal) {
+            data.extend(v.to_le_bytes());
+        }
+        assert_eq!(data.len(), OVERLAY_SIZE);
+
+        let names = |texinfo| (texinfo == 3).then(|| Arc::from("overlays/sign"));
+        let overlays = parse_overlays(&data, names).unwrap();
+        assert_eq!(
+            overlays,
+            vec![Overlay {
+                material: Arc::from("overlays/sign"),
+                faces: vec![12, 40],
+                origin,
+                basis: [[0.0, 1.0, 0.0], [0.0, 0.0, 1.0], normal],
+                points: [[-8.0, 4.0], [-8.0, -4.0], [8.0, -4.0], [8.0, 4.0]],
+                uvs: [[0.0, 0.25], [0.0, 0.75], [1.0, 0.75], [1.0, 0.25]],
+                render_order: 1,
+            }]
+        );
+
+        assert!(parse_overlays(&data, |_| None).is_err());
+        assert!(parse_overlays(&data[..100], names).is_err());
+    }
+
+    #[test]
+    fn test_info_decal() {
+        let decal = InfoDecal::from_properties([
+            ("classname", "infodecal"),
+            ("texture", "Decals/Stain01"),
+            ("origin", "16 -32 8"),
+        ]);
+        assert_eq!(
+            decal,
+            Some(InfoDecal {
+                material: Arc::from("decals/stain01"),
+                origin: [16.0, -32.0, 8.0],
+            })
+        );
+
+        assert_eq!(
+            InfoDecal::from_properties([("classname", "infodecal"), ("origin", "0 0 0")]),
+            None
+        );
+        assert_eq!(
+            InfoDecal::from_properties([
+                ("classname", "info_target"),
+                ("texture", "decals/stain01"),
+                ("origin", "0 0 0"),
+            ]),
+            None
+        );
+    }
+}
@@ -0,0 +1,751 @@
+//! Props, which are models placed in the map.
+//! Static props never move, and are stored in the `sprp` game lump, which references the models by
+//! name. Dynamic and physics props (and items like health packs) are entities instead. Detail
+//! props are loaded along with them, see [`crate::detail`].
+//! The models themselves (`.mdl`, `.vvd` and `.vtx`) are loaded from the vpks or the map's pakfile.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use bevy::{
+    prelude::{AlphaMode, Component, EulerRot, Image, Mesh, Quat, Resource},
+    render::{mesh::Indices, render_resource::PrimitiveTopology},
+    tasks::{AsyncComputeTaskPool, Task},
+};
+use vmt::VMT;
+
+use crate::{
+    cache::{TextureCache, TextureRole},
+    cubemap::load_cubemap,
+    data::{construct_image, find_vmt, LSrc, LoadedNames, TextureError, TextureName, VpkState},
+    detail::{detail_material, DetailProps},
+    lump::{find_game_lump, read_records, LumpReader},
+    map::GameMap,
+    material::EnvMapParams,
+    mesh::{degrees_to_radians, rotate, scale},
+    util::parse_vector,
+};
+
+/// `sprp`, the id of the static prop game lump
+const STATIC_PROP_LUMP_ID: i32 = i32::from_be_bytes(*b"sprp");
+/// Length of the model names in the static prop dictionary
+const STATIC_PROP_NAME_LENGTH: usize = 128;
+/// Size of the fields which every version of `StaticPropLump_t` starts with
+const STATIC_PROP_MIN_SIZE: usize = 56;
+/// `STATIC_PROP_USE_LIGHTING_ORIGIN`, the flag for props which are lit from their lighting origin
+/// rather than their center
+const STATIC_PROP_USE_LIGHTING_ORIGIN: u8 = 2;
+
+/// A static prop, as stored in the `sprp` game lump
+#[derive(Debug, Clone, PartialEq)]
+pub struct StaticProp {
+    /// Ex: `models/props_2fort/roof_metal001.mdl`
+    pub model: Arc<str>,
+    /// In source coordinates
+    pub origin: [f32; 3],
+    /// Pitch, yaw and roll in degrees
+    pub angles: [f32; 3],
+    pub skin: i32,
+    /// Distance at which the prop starts to fade out, in source units
+    pub fade_min: f32,
+    /// Distance at which the prop is no longer drawn, in source units.
+    /// Not positive if the prop never fades.
+    pub fade_max: f32,
+    /// Uniform scale of the model, which only newer versions of the lump have
+    pub scale: f32,
+    /// Where the prop's lighting is sampled, if not at the center of the model
+    pub lighting_origin: Option<[f32; 3]>,
+}
+
+/// Read the static props of the map. Maps without the lump have no props.
+pub fn read_static_props(data: &[u8]) -> eyre::Result<Vec<StaticProp>> {
+    match find_game_lump(data, STATIC_PROP_LUMP_ID)? {
+        Some((lump, version)) => parse_static_props(&lump, version),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Parse the `sprp` game lump: the dictionary of model names, the leaves of each prop, and then
+/// the props themselves.
+fn parse_static_props(lump: &[u8], version: u16) -> eyre::Result<Vec<StaticProp>> {
+    if !(4..=11).contains(&version) {
+        eyre::bail!("Unsupported static prop lump version {version}");
+    }
+
+    let mut r = LumpReader::new(lump);
+    let name_count = r.i32()?;
+    let names = (0..name_count)
+        .map(|_| {
+            let name = r.bytes::<STATIC_PROP_NAME_LENGTH>()?;
+            let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+            Ok(Arc::from(
+                String::from_utf8_lossy(&name[..end]).to_lowercase(),
+            ))
+        })
+        .collect::<eyre::Result<Vec<Arc<str>>>>()?;
+
+    // We find the leaves of the props ourselves if we ever need them
+    let leaf_count = r.i32()?;
+    r.skip(leaf_count.max(0) as usize * 2);
+
+    let prop_count = r.i32()?;
+    if prop_count <= 0 {
+        return Ok(Vec::new());
+    }
+    let props = r.remaining();
+
+    // The size of each prop varies between versions, and between games using the same version,
+    // so it is found from the length of the lump
+    let size = props.len() / prop_count as usize;
+    // Version 11 has the scale after the other fields
+    let min_size = if version >= 11 {
+        STATIC_PROP_MIN_SIZE + 4
+    } else {
+        STATIC_PROP_MIN_SIZE
+    };
+    if size < min_size {
+        eyre::bail!("Static props of version {version} are too small ({size} bytes)");
+    }
+
+    read_records(props, size, |r| {
+        let origin = r.vec3()?;
+        let angles = r.vec3()?;
+        let prop_type = r.u16()? as usize;
+        // First leaf, leaf count and solidity
+        r.skip(2 + 2 + 1);
+        let flags = r.u8()?;
+        let skin = r.i32()?;
+        let fade_min = r.f32()?;
+        let fade_max = r.f32()?;
+        let lighting_origin = r.vec3()?;
+
+        // The uniform scale is the last field of version 11, and we've read 56 bytes so far
+        let scale = if version >= 11 {
+            r.skip(size - STATIC_PROP_MIN_SIZE - 4);
+            r.f32()?
+        } else {
+            1.0
+        };
+
+        let model = names
+            .get(prop_type)
+            .cloned()
+            .ok_or_else(|| eyre::eyre!("Static prop has invalid model index {prop_type}"))?;
+
+        Ok(StaticProp {
+            model,
+            origin,
+            angles,
+            skin,
+            fade_min,
+            fade_max,
+            scale,
+            lighting_origin: (flags & STATIC_PROP_USE_LIGHTING_ORIGIN != 0)
+                .then_some(lighting_origin),
+        })
+    })
+}
+
+/// Convert source's pitch, yaw and roll (in degrees) into a rotation in bevy coordinates.
+/// Source applies roll around X, then pitch around Y, then yaw around Z, where positive pitch
+/// looks down.
+pub fn source_rotation([pitch, yaw, roll]: [f32; 3]) -> Quat {
+    // Source's Z is bevy's Y, source's Y is bevy's -X and source's X is bevy's -Z
+    Quat::from_euler(
+        EulerRot::YXZ,
+        degrees_to_radians(yaw),
+        -degrees_to_radians(pitch),
+        -degrees_to_radians(roll),
+    )
+}
+
+/// The keyvalues of the entity that something was spawned from, so that they can be inspected
+#[derive(Debug, Clone, Default, PartialEq, Component)]
+pub struct EntityKeyValues(pub Vec<(String, String)>);
+impl EntityKeyValues {
+    pub fn from_properties<'a>(
+        properties: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> EntityKeyValues {
+        EntityKeyValues(
+            properties
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    /// Get the value of the first keyvalue with the key, ignoring case like the game does.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// How an entity is drawn, from its `rendermode`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum RenderMode {
+    #[default]
+    Normal,
+    /// Blended with what is behind it by its `renderamt`
+    Transparent,
+    /// Added onto what is behind it
+    Additive,
+    /// Not drawn at all
+    None,
+}
+impl RenderMode {
+    pub fn from_value(value: u8) -> RenderMode {
+        match value {
+            // kRenderTransColor, kRenderTransTexture, kRenderTransAlpha
+            1 | 2 | 4 => RenderMode::Transparent,
+            // kRenderGlow, kRenderTransAdd, kRenderTransAddFrameBlend, kRenderTransAlphaAdd,
+            // kRenderWorldGlow
+            3 | 5 | 7 | 8 | 9 => RenderMode::Additive,
+            // kRenderEnvironmental, kRenderNone
+            6 | 10 => RenderMode::None,
+            _ => RenderMode::Normal,
+        }
+    }
+
+    pub fn alpha_mode(self) -> AlphaMode {
+        match self {
+            RenderMode::Normal | RenderMode::None => AlphaMode::Opaque,
+            RenderMode::Transparent => AlphaMode::Blend,
+            RenderMode::Additive => AlphaMode::Add,
+        }
+    }
+}
+
+/// A prop entity, such as a `prop_dynamic`, or an item which is drawn with a model
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityProp {
+    pub model: Arc<str>,
+    /// In source coordinates
+    pub origin: [f32; 3],
+    /// Pitch, yaw and roll in degrees
+    pub angles: [f32; 3],
+    pub skin: i32,
+    /// `modelscale`
+    pub scale: f32,
+    /// `rendercolor` and `renderamt`
+    pub color: [u8; 4],
+    pub render_mode: RenderMode,
+    /// Whether `disableshadows` isn't set
+    pub shadows: bool,
+    pub keyvalues: EntityKeyValues,
+}
+impl EntityProp {
+    /// Get the prop from the keyvalues of an entity, if it is one that is drawn with a model.
+    pub fn from_properties<'a>(
+        properties: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Option<EntityProp> {
+        let keyvalues = EntityKeyValues::from_properties(properties);
+        let class_name = keyvalues.get("classname")?;
+        let model = match class_name {
+            "prop_dynamic"
+            | "prop_dynamic_override"
+            | "prop_physics"
+            | "prop_physics_override"
+            | "prop_physics_multiplayer" => keyvalues.get("model")?,
+            // Items have a default model, which maps can replace
+            _ => {
+                let default = item_model(class_name)?;
+                keyvalues
+                    .get("powerup_model")
+                    .filter(|model| !model.is_empty())
+                    .unwrap_or(default)
+            }
+        };
+        let model = Arc::from(model.replace('\\', "/").to_lowercase());
+
+        let parse_f32 = |key| keyvalues.get(key)?.trim().parse::<f32>().ok();
+        let parse_u8 = |key| keyvalues.get(key)?.trim().parse::<u8>().ok();
+
+        let [r, g, b] = keyvalues
+            .get("rendercolor")
+            .and_then(parse_vector)
+            .map_or([255; 3], |color| color.map(|c| c.clamp(0.0, 255.0) as u8));
+
+        Some(EntityProp {
+            model,
+            origin: keyvalues
+                .get("origin")
+                .and_then(parse_vector)
+                .unwrap_or_default(),
+            angles: keyvalues
+                .get("angles")
+                .and_then(parse_vector)
+                .unwrap_or_default(),
+            skin: keyvalues
+                .get("skin")
+                .and_then(|skin| skin.trim().parse().ok())
+                .unwrap_or(0),
+            scale: parse_f32("modelscale")
+                .filter(|scale| *scale > 0.0)
+                .unwrap_or(1.0),
+            color: [r, g, b, parse_u8("renderamt").unwrap_or(255)],
+            render_mode: RenderMode::from_value(parse_u8("rendermode").unwrap_or(0)),
+            shadows: parse_u8("disableshadows").unwrap_or(0) == 0,
+            keyvalues,
+        })
+    }
+}
+
+/// The model that an item entity is drawn with, if it is one
+fn item_model(class_name: &str) -> Option<&'static str> {
+    Some(match class_name {
+        "item_ammopack_small" => "models/items/ammopack_small.mdl",
+        "item_ammopack_medium" => "models/items/ammopack_medium.mdl",
+        "item_ammopack_full" => "models/items/ammopack_large.mdl",
+        "item_healthkit_small" => "models/items/medkit_small.mdl",
+        "item_healthkit_medium" => "models/items/medkit_medium.mdl",
+        "item_healthkit_full" => "models/items/medkit_large.mdl",
+        _ => return None,
+    })
+}
+
+/// Fade distances of a static prop, in source units
+#[derive(Debug, Clone, Copy, PartialEq, Component)]
+pub struct PropFade {
+    pub min: f32,
+    pub max: f32,
+}
+
+/// A mesh of a model, which has one material per skin
+pub struct PropMesh {
+    pub mesh: Mesh,
+    /// The material for each of the model's skins, if it could be found
+    pub materials: Vec<Option<String>>,
+}
+
+/// The parts of a prop's material that we use
+#[derive(Debug, Clone)]
+pub struct PropMaterial {
+    pub base_texture: TextureName,
+    pub envmap: Option<EnvMapParams>,
+}
+
+/// The loaded props of a map, along with everything needed to spawn them.
+pub struct LoadedProps {
+    pub props: Vec<StaticProp>,
+    pub entity_props: Vec<EntityProp>,
+    pub detail_props: Arc<DetailProps>,
+    /// The material that the detail sprites are cut out of
+    pub detail_material: String,
+    /// The meshes of each model that could be loaded
+    pub models: HashMap<Arc<str>, Vec<PropMesh>>,
+    /// Each of the materials used by the props
+    pub materials: HashMap<String, PropMaterial>,
+    pub images: Vec<(
+        TextureName,
+        TextureRole,
+        Result<(Image, LSrc), TextureError>,
+    )>,
+    /// The cubemaps named by the materials, other than the map's own which are already loaded
+    pub cubemaps: Vec<(TextureName, Image)>,
+}
+
+/// The task loading the props of the current map, which are spawned once it finishes.
+#[derive(Resource)]
+pub struct PropLoadTask(pub Task<LoadedProps>);
+
+/// Load the models used by the map's props, and the textures of their materials.
+/// Textures in `loaded` are skipped, since they're already loaded.
+/// Static props are skipped if `static_props` is false, and likewise for `detail_props`.
+pub fn spawn_prop_load(
+    vpk: VpkState,
+    map: GameMap,
+    cache: Option<Arc<TextureCache>>,
+    loaded: LoadedNames,
+    static_props: bool,
+    detail_props: bool,
+) -> Task<LoadedProps> {
+    AsyncComputeTaskPool::get().spawn(async move {
+        let start_time = std::time::Instant::now();
+
+        let props = if static_props {
+            map.static_props.to_vec()
+        } else {
+            Vec::new()
+        };
+        let entity_props = map
+            .bsp
+            .entities
+            .iter()
+            .filter_map(|raw_ent| EntityProp::from_properties(raw_ent.properties()))
+            .collect::<Vec<_>>();
+        let detail_props = if detail_props {
+            map.detail_props.clone()
+        } else {
+            Arc::default()
+        };
+        let detail_material = detail_material(&map);
+
+        let models = props
+            .iter()
+            .map(|prop| prop.model.clone())
+            .chain(entity_props.iter().map(|prop| prop.model.clone()))
+            .chain(detail_props.models().cloned())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .filter_map(|model| match load_model(&vpk, &map, &model) {
+                Ok(meshes) => Some((model, meshes)),
+                Err(err) => {
+                    eprintln!("Failed to load model {model:?}: {err:?}");
+                    None
+                }
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut materials = models
+            .values()
+            .flatten()
+            .flat_map(|mesh| mesh.materials.iter().flatten())
+            .collect::<HashSet<_>>();
+        if !detail_props.sprites.is_empty() {
+            materials.insert(&detail_material);
+        }
+        let materials = materials
+            .into_iter()
+            .filter_map(|material| {
+                let prop_material = prop_material(&vpk, &map, material)?;
+                Some((material.clone(), prop_material))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let textures = materials
+            .values()
+            .flat_map(|material| {
+                let mask = material
+                    .envmap
+                    .as_ref()
+                    .and_then(|envmap| envmap.mask.clone());
+                std::iter::once((material.base_texture.clone(), TextureRole::Base))
+                    .chain(mask.map(|mask| (mask, TextureRole::EnvMapMask)))
+            })
+            .filter(|(texture, role)| !loaded.has_texture(texture, *role))
+            .collect::<HashSet<_>>();
+        let images = textures
+            .into_iter()
+            .map(|(texture, role)| {
+                let res = construct_image(&vpk, Some(&map), cache.as_deref(), None, &texture, role);
+                (texture, role, res)
+            })
+            .collect();
+
+        let map_cubemaps = map.cubemaps.texture_names().collect::<HashSet<_>>();
+        let cubemaps = materials
+            .values()
+            .filter_map(|material| material.envmap.as_ref()?.cubemap.clone())
+            .filter(|name| !map_cubemaps.contains(name))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .filter_map(|name| match load_cubemap(&vpk, &map, &name) {
+                Ok(image) => Some((name, image)),
+                Err(err) => {
+                    eprintln!("Failed to load cubemap {name:?}: {err:?}");
+                    None
+                }
+            })
+            .collect();
+
+        let end_time = std::time::Instant::now();
+        println!(
+            "Loaded {} models for {} static props, {} prop entities and {} detail props in {:?}",
+            models.len(),
+            props.len(),
+            entity_props.len(),
+            detail_props.props.len(),
+            end_time - start_time
+        );
+
+        LoadedProps {
+            props,
+            entity_props,
+            detail_props,
+            detail_material,
+            models,
+            materials,
+            images,
+            cubemaps,
+        }
+    })
+}
+
+/// Read the parts of a prop's material that we use.
+// TODO: use the rest of the material's parameters, like $translucent
+fn prop_material(vpk: &VpkState, map: &GameMap, material: &str) -> Option<PropMaterial> {
+    let (vmt, _) = find_vmt(vpk, Some(map), material).ok()?;
+    let vmt = VMT::from_bytes(&vmt).ok()?;
+    let envmap = EnvMapParams::from_vmt(&vmt);
+    let texture = vmt.base_texture?;
+    Some(PropMaterial {
+        base_texture: Arc::from(texture.to_lowercase()),
+        envmap,
+    })
+}
+
+/// Read a file of the game, such as `models/props/crate.mdl`.
+/// Loose files take precedence over the vpks, and the map's pakfile is searched last.
+fn read_game_file(vpk: &VpkState, map: &GameMap, path: &str) -> eyre::Result<Vec<u8>> {
+    if let Some(path) = vpk.find_loose(path) {
+        return Ok(std::fs::read(path)?);
+    }
+
+    if let Some((entry, _)) = vpk.find_file(path) {
+        return Ok(entry.get()?.into_owned());
+    }
+
+    if let Ok(Some(data)) = map.bsp.pack.get(path) {
+        return Ok(data);
+    }
+
+    Err(eyre::eyre!("Failed to find {path:?}"))
+}
+
+/// Load the meshes of a model along with the material each of them uses for each skin.
+fn load_model(vpk: &VpkState, map: &GameMap, path: &str) -> eyre::Result<Vec<PropMesh>> {
+    let base = path.strip_suffix(".mdl").unwrap_or(path);
+    let mdl = vmdl::Mdl::read(&read_game_file(vpk, map, &format!("{base}.mdl"))?)?;
+    let vvd = vmdl::Vvd::read(&read_game_file(vpk, map, &format!("{base}.vvd"))?)?;
+    let vtx = vmdl::Vtx::read(&read_game_file(vpk, map, &format!("{base}.dx90.vtx"))?)?;
+    let model = vmdl::Model::from_parts(mdl, vtx, vvd);
+
+    // The model only has the names of its textures, which are found in one of its directories
+    let skins = model
+        .skin_tables()
+        .map(|skin| {
+            (0..model.textures().len())
+                .map(|i| {
+                    let info = skin.texture_info(i)?;
+                    info.search_paths
+                        .iter()
+                        .map(|dir| {
+                            format!("{dir}{}", info.name)
+                                .replace('\\', "/")
+                                .to_lowercase()
+                        })
+                        .find(|material| find_vmt(vpk, Some(map), material).is_ok())
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let meshes = model
+        .meshes()
+        .map(|mesh| {
+            let positions = mesh
+                .vertices()
+                .map(|v| rotate(scale(v.position.into())))
+                .collect::<Vec<_>>();
+            let normals = mesh
+                .vertices()
+                .map(|v| rotate(v.normal.into()))
+                .collect::<Vec<_>>();
+            let uvs = mesh
+                .vertices()
+                .map(|v| v.texture_coordinates)
+                .collect::<Vec<_>>();
+            let mut indices = mesh
+                .vertex_strip_indices()
+                .flat_map(|strip| strip.map(|index| index as u32))
+                .collect::<Vec<_>>();
+            // Source's triangles are clockwise, while bevy's front faces are counter-clockwise
+            for triangle in indices.chunks_exact_mut(3) {
+                triangle.swap(1, 2);
+            }
+
+            let mut bevy_mesh = Mesh::new(PrimitiveTopology::TriangleList);
+            bevy_mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+            bevy_mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+            bevy_mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+            bevy_mesh.set_indices(Some(Indices::U32(indices)));
+
+            let material_index = mesh.material_index() as usize;
+            let materials = skins
+                .iter()
+                .map(|skin| skin.get(material_index).cloned().flatten())
+                .collect();
+
+            PropMesh {
+                mesh: bevy_mesh,
+                materials,
+            }
+        })
+        .collect();
+
+    Ok(meshes)
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::Vec3;
+
+    use crate::lump::{make_game_lump_bsp, write_names, write_records};
+
+    use super::*;
+
+    fn write_prop(data: &mut Vec<u8>, origin: [f32; 3], model: u16, skin: i32, size: usize) {
+        let start = data.len();
+        for v in origin.into_iter().chain([0.0, 90.0, 0.0]) {
+            data.extend(v.to_le_bytes());
+        }
+        data.extend(model.to_le_bytes());
+        data.extend([0; 5]);
+        // Props with a skin are lit from above their origin
+        let flags = if skin != 0 {
+            STATIC_PROP_USE_LIGHTING_ORIGIN
+        } else {
+            0
+        };
+        data.push(flags);
+        data.extend(skin.to_le_bytes());
+        data.extend(512.0f32.to_le_bytes());
+        data.extend(1024.0f32.to_le_bytes());
+        for v in [origin[0], origin[1], origin[2] + 16.0] {
+            data.extend(v.to_le_bytes());
+        }
+        data.resize(start + size - 4, 0);
+        data.extend(2.0f32.to_le_bytes());
+    }
+
+    fn make_lump(size: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        let names = ["models/Props/Crate.mdl", "models/props/barrel.mdl"];
+        write_names(&mut data, &names, STATIC_PROP_NAME_LENGTH);
+        write_records(&mut data, &[0u16; 3], |data, leaf| {
+            data.extend(leaf.to_le_bytes())
+        });
+        let props = [([1.0, 2.0, 3.0], 1, 0), ([-64.0, 0.0, 32.0], 0, 2)];
+        write_records(&mut data, &props, |data, &(origin, model, skin)| {
+            write_prop(data, origin, model, skin, size)
+        });
+        data
+    }
+
+    #[test]
+    fn test_static_props() {
+        // Version 10 as in tf2
+        let bsp = make_game_lump_bsp(&[(STATIC_PROP_LUMP_ID, 10, make_lump(76))]);
+        let props = read_static_props(&bsp).unwrap();
+        assert_eq!(
+            props,
+            vec![
+                StaticProp {
+                    model: Arc::from("models/props/barrel.mdl"),
+                    origin: [1.0, 2.0, 3.0],
+                    angles: [0.0, 90.0, 0.0],
+                    skin: 0,
+                    fade_min: 512.0,
+                    fade_max: 1024.0,
+                    scale: 1.0,
+                    lighting_origin: None,
+                },
+                StaticProp {
+                    model: Arc::from("models/props/crate.mdl"),
+                    origin: [-64.0, 0.0, 32.0],
+                    angles: [0.0, 90.0, 0.0],
+                    skin: 2,
+                    fade_min: 512.0,
+                    fade_max: 1024.0,
+                    scale: 1.0,
+                    lighting_origin: Some([-64.0, 0.0, 48.0]),
+                },
+            ]
+        );
+
+        // Version 11 has a scale at the end
+        let props = parse_static_props(&make_lump(80), 11).unwrap();
+        assert_eq!(props[1].scale, 2.0);
+        assert_eq!(props[1].skin, 2);
+        assert_eq!(props[1].lighting_origin, Some([-64.0, 0.0, 48.0]));
+
+        assert!(parse_static_props(&make_lump(76), 3).is_err());
+        assert!(parse_static_props(&make_lump(40), 10).is_err());
+        // Room for the fields of version 10, but not for the scale
+        for size in 56..60 {
+            assert!(parse_static_props(&make_lump(size), 11).is_err());
+        }
+        assert!(parse_static_props(&make_lump(56), 10).is_ok());
+    }
+
+    #[test]
+    fn test_entity_prop() {
+        let prop = EntityProp::from_properties([
+            ("classname", "prop_dynamic"),
+            ("model", "Models\\Props_Gameplay\\Cap_Point_Base.mdl"),
+            ("origin", "128 -64 0"),
+            ("angles", "0 270 0"),
+            ("skin", "1"),
+            ("modelscale", "1.5"),
+            ("rendercolor", "255 128 0"),
+            ("renderamt", "200"),
+            ("rendermode", "1"),
+            ("DisableShadows", "1"),
+        ])
+        .unwrap();
+        assert_eq!(&*prop.model, "models/props_gameplay/cap_point_base.mdl");
+        assert_eq!(prop.origin, [128.0, -64.0, 0.0]);
+        assert_eq!(prop.angles, [0.0, 270.0, 0.0]);
+        assert_eq!(prop.skin, 1);
+        assert_eq!(prop.scale, 1.5);
+        assert_eq!(prop.color, [255, 128, 0, 200]);
+        assert_eq!(prop.render_mode, RenderMode::Transparent);
+        assert!(!prop.shadows);
+        assert_eq!(prop.keyvalues.get("disableshadows"), Some("1"));
+        assert_eq!(prop.keyvalues.0.len(), 10);
+
+        // Items have a default model
+        let item = EntityProp::from_properties([
+            ("classname", "item_healthkit_full"),
+            ("origin", "0 0 16"),
+        ])
+        .unwrap();
+        assert_eq!(&*item.model, "models/items/medkit_large.mdl");
+        assert_eq!(item.scale, 1.0);
+        assert_eq!(item.color, [255; 4]);
+        assert_eq!(item.render_mode, RenderMode::Normal);
+        assert!(item.shadows);
+
+        // Props without a model and other entities aren't drawn with one
+        assert_eq!(
+            EntityProp::from_properties([("classname", "prop_physics")]),
+            None
+        );
+        assert_eq!(
+            EntityProp::from_properties([("classname", "info_target"), ("model", "a.mdl")]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_source_rotation() {
+        let turn = |angles, dir| source_rotation(angles) * Vec3::from(rotate(dir));
+        let close = |a: Vec3, b: [f32; 3]| a.abs_diff_eq(Vec3::from(rotate(b)), 1e-5);
+
+        // Yaw turns forward towards left
+        assert!(close(
+            turn([0.0, 90.0, 0.0], [1.0, 0.0, 0.0]),
+            [0.0, 1.0, 0.0]
+        ));
+        // Positive pitch looks down
+        assert!(close(
+            turn([90.0, 0.0, 0.0], [1.0, 0.0, 0.0]),
+            [0.0, 0.0, -1.0]
+        ));
+        // Roll turns left towards up
+        assert!(close(
+            turn([0.0, 0.0, 90.0], [0.0, 1.0, 0.0]),
+            [0.0, 0.0, 1.0]
+        ));
+        // Yaw applies after pitch
+        assert!(close(
+            turn([90.0, 90.0, 0.0], [0.0, 1.0, 0.0]),
+            [-1.0, 0.0, 0.0]
+        ));
+    }
+}
@@ -0,0 +1,150 @@
+//! Hot reloading of materials and textures from the loose file directories.
+//! We poll the modification times (and sizes) rather than using OS file watching, since this works
+//! the same everywhere. The loose directories can be large (ex: `tf/custom`), so they are scanned
+//! on the [`AsyncComputeTaskPool`] and only the changes are applied on the main thread.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use bevy::{
+    prelude::Resource,
+    tasks::{AsyncComputeTaskPool, Task},
+    time::{Timer, TimerMode},
+};
+use futures_lite::future;
+
+use crate::cache::SourceStamp;
+
+/// How often the loose directories are checked for changes
+pub const LOOSE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LooseKind {
+    Vmt,
+    Vtf,
+}
+
+/// A loose material file that was added, modified or removed.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LooseChange {
+    pub kind: LooseKind,
+    /// The lowercase name, relative to `materials/` and without the extension.
+    /// Ex: `concrete/wall`
+    pub name: String,
+}
+
+/// The stamps of the material files in the loose directories
+type LooseFiles = HashMap<PathBuf, SourceStamp>;
+
+/// Keeps track of the files in the loose directories, so that we can tell when they change.
+#[derive(Resource)]
+pub struct LooseWatcher {
+    dirs: Vec<PathBuf>,
+    /// The files as of the last finished scan, or `None` before the first one finishes
+    files: Option<LooseFiles>,
+    scan: Option<Task<LooseFiles>>,
+    timer: Timer,
+}
+impl LooseWatcher {
+    pub fn new(dirs: Vec<PathBuf>) -> LooseWatcher {
+        LooseWatcher {
+            dirs,
+            files: None,
+            scan: None,
+            timer: Timer::new(LOOSE_POLL_INTERVAL, TimerMode::Repeating),
+        }
+    }
+
+    /// Advance the watcher by `delta`, starting a scan of the loose directories when it is time
+    /// to, and return the changes found by a scan that finished since the last update.
+    pub fn update(&mut self, delta: Duration) -> Vec<LooseChange> {
+        let poll_due = self.timer.tick(delta).just_finished() || self.files.is_none();
+        if poll_due && self.scan.is_none() {
+            let dirs = self.dirs.clone();
+            let task = AsyncComputeTaskPool::get().spawn(async move { scan(&dirs) });
+            self.scan = Some(task);
+        }
+
+        let Some(task) = &mut self.scan else {
+            return Vec::new();
+        };
+        let Some(files) = future::block_on(future::poll_once(task)) else {
+            return Vec::new();
+        };
+        self.scan = None;
+
+        // Everything that exists at the start is loaded normally, so isn't a change
+        let Some(old_files) = self.files.replace(files) else {
+            return Vec::new();
+        };
+        let files = self.files.as_ref().unwrap();
+
+        let mut changes = Vec::new();
+        for (path, stamp) in files.iter() {
+            if old_files.get(path) != Some(stamp) {
+                changes.extend(self.change_of(path));
+            }
+        }
+        // A removed file means that the vpk's version (if any) should be used again
+        for path in old_files.keys() {
+            if !files.contains_key(path) {
+                changes.extend(self.change_of(path));
+            }
+        }
+
+        changes.sort_unstable();
+        changes.dedup();
+        changes
+    }
+
+    fn change_of(&self, path: &Path) -> Option<LooseChange> {
+        let kind = match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "vmt" => LooseKind::Vmt,
+            "vtf" => LooseKind::Vtf,
+            _ => return None,
+        };
+
+        let materials = self
+            .dirs
+            .iter()
+            .find_map(|dir| path.strip_prefix(dir.join("materials")).ok())?;
+        let name = materials
+            .with_extension("")
+            .to_str()?
+            .replace('\\', "/")
+            .to_lowercase();
+
+        Some(LooseChange { kind, name })
+    }
+}
+
+/// Get the stamps of all the material files in the loose directories
+fn scan(dirs: &[PathBuf]) -> LooseFiles {
+    let mut files = HashMap::new();
+    for dir in dirs {
+        scan_dir(&dir.join("materials"), &mut files);
+    }
+
+    files
+}
+
+fn scan_dir(dir: &Path, files: &mut LooseFiles) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+
+        let path = entry.path();
+        if meta.is_dir() {
+            scan_dir(&path, files);
+        } else if let Some(stamp) = SourceStamp::from_metadata(&meta) {
+            files.insert(path, stamp);
+        }
+    }
+}
@@ -0,0 +1,330 @@
+//! The skybox of the map.
+//! The 2D skybox is six textures named after the worldspawn's `skyname`, which are turned into a
+//! cubemap drawn behind everything.
+//! The 3D skybox is a small part of the map around the `sky_camera`, which is drawn scaled up
+//! behind the rest of the map by a second camera.
+//! Faces with the sky flag aren't drawn at all, so that the skyboxes show through them.
+
+use bevy::{
+    prelude::{default, Component, Image},
+    render::render_resource::{
+        Extent3d, TextureDimension, TextureViewDescriptor, TextureViewDimension,
+    },
+};
+use vbsp::Bsp;
+use vmt::VMT;
+
+use crate::{
+    cache::{TextureCache, TextureRole},
+    data::{construct_image, find_vmt, TextureError, VpkState},
+    map::GameMap,
+    mesh::unrotate,
+    util::parse_vector,
+};
+
+/// The render layer of the 3D skybox's faces, which only the skybox camera sees
+pub const SKYBOX_LAYER: u8 = 1;
+
+/// The suffixes of the skybox's materials, in the order of the sides in [`sky_side`]
+const SIDE_SUFFIXES: [&str; 6] = ["rt", "lf", "bk", "ft", "up", "dn"];
+
+/// Marker for faces which are part of the 3D skybox
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Component)]
+pub struct SkyboxFace;
+
+/// The `sky_camera`, which is the center of the 3D skybox
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SkyCamera {
+    /// In source coordinates
+    pub origin: [f32; 3],
+    /// How many times smaller the 3D skybox is than the map
+    pub scale: f32,
+}
+
+/// The sky of the map, from its entities
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MapSky {
+    /// The `skyname` of the worldspawn, ex: `sky_tf2_04`
+    pub name: Option<String>,
+    pub camera: Option<SkyCamera>,
+    /// The area that the 3D skybox is in, which is where the `sky_camera` is
+    pub area: Option<u16>,
+}
+impl MapSky {
+    pub fn from_bsp(bsp: &Bsp) -> MapSky {
+        let mut sky = MapSky::default();
+        for raw_ent in bsp.entities.iter() {
+            sky.add_entity(raw_ent.properties());
+        }
+        sky
+    }
+
+    /// Take the sky information out of the keyvalues of an entity, if it has any.
+    pub fn add_entity<'a>(&mut self, properties: impl IntoIterator<Item = (&'a str, &'a str)>) {
+        let mut class_name = "";
+        let mut sky_name = None;
+        let mut origin = None;
+        let mut scale = None;
+        for (key, value) in properties {
+            match key {
+                "classname" => class_name = value,
+                "skyname" => sky_name = Some(value),
+                "origin" => origin = parse_vector(value),
+                "scale" => scale = value.trim().parse::<f32>().ok(),
+                _ => {}
+            }
+        }
+
+        match class_name {
+            "worldspawn" => self.name = sky_name.map(str::to_lowercase),
+            "sky_camera" => {
+                self.camera = origin.map(|origin| SkyCamera {
+                    origin,
+                    // Same as the game, which treats a missing scale as no scaling
+                    scale: scale.filter(|scale| *scale > 0.0).unwrap_or(1.0),
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+/// One side of the 2D skybox, as rgba8
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkySide {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+impl SkySide {
+    /// Get the color at the uv, which is in `[0, 1]` with `v` going down the image.
+    pub(crate) fn sample(&self, [u, v]: [f32; 2]) -> [u8; 4] {
+        let x = ((u * self.width as f32) as u32).min(self.width.saturating_sub(1));
+        let y = ((v * self.height as f32) as u32).min(self.height.saturating_sub(1));
+        let i = (y * self.width + x) as usize * 4;
+        self.data
+            .get(i..i + 4)
+            .map_or([0, 0, 0, 255], |px| px.try_into().unwrap())
+    }
+}
+
+/// Load the six sides of the map's 2D skybox into a cubemap.
+/// Missing sides are left black, since the others are still worth drawing.
+pub fn load_skybox(vpk: &VpkState, map: &GameMap, cache: Option<&TextureCache>) -> Option<Image> {
+    let name = map.sky.name.as_deref()?;
+    let sides = SIDE_SUFFIXES.map(|suffix| {
+        let material = format!("skybox/{name}{suffix}");
+        match load_side(vpk, map, cache, &material) {
+            Ok(side) => Some(side),
+            Err(err) => {
+                eprintln!("Failed to load skybox side {material:?}: {err:?}");
+                None
+            }
+        }
+    });
+
+    skybox_image(&sides)
+}
+
+fn load_side(
+    vpk: &VpkState,
+    map: &GameMap,
+    cache: Option<&TextureCache>,
+    material: &str,
+) -> Result<SkySide, TextureError> {
+    // The sides are typically `UnlitGeneric` materials whose base texture has the same name, so
+    // that is used if the vmt can't be read
+    let texture = find_vmt(vpk, Some(map), material)
+        .ok()
+        .and_then(|(vmt, _)| {
+            let vmt = VMT::from_bytes(&vmt).ok()?;
+            vmt.base_texture.map(|texture| texture.to_lowercase())
+        })
+        .unwrap_or_else(|| material.to_string());
+
+    let (image, _) = construct_image(vpk, Some(map), cache, None, &texture, TextureRole::Base)?;
+    Ok(SkySide {
+        width: image.texture_descriptor.size.width,
+        height: image.texture_descriptor.size.height,
+        data: image.data,
+    })
+}
+
+/// Create the cubemap image for the skybox, which has the size of its largest side.
+pub fn skybox_image(sides: &[Option<SkySide>; 6]) -> Option<Image> {
+    let (size, data) = build_cubemap(sides)?;
+    Some(cube_image(size, data))
+}
+
+/// Create a cube image out of its six faces, which are `size` by `size` and stacked vertically.
+pub(crate) fn cube_image(size: u32, data: Vec<u8>) -> Image {
+    let mut image = Image::new(
+        Extent3d {
+            width: size,
+            height: size * 6,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureRole::Base.format(),
+    );
+    image.reinterpret_stacked_2d_as_array(6);
+    image.texture_view_descriptor = Some(TextureViewDescriptor {
+        dimension: Some(TextureViewDimension::Cube),
+        ..default()
+    });
+
+    image
+}
+
+/// Resample the sides of the source skybox into the six faces of a cubemap, stacked vertically.
+fn build_cubemap(sides: &[Option<SkySide>; 6]) -> Option<(u32, Vec<u8>)> {
+    let size = sides.iter().flatten().map(|side| side.width).max()?;
+
+    let data = resample_cube(size, |dir| {
+        let (side, uv) = sky_side(dir);
+        sides[side]
+            .as_ref()
+            .map_or([0, 0, 0, 255], |side| side.sample(uv))
+    });
+
+    Some((size, data))
+}
+
+/// Fill the six faces of a cubemap, stacked vertically, with the color in each direction (in
+/// source coordinates) from the center of each of their texels.
+pub(crate) fn resample_cube(size: u32, sample: impl Fn([f32; 3]) -> [u8; 4]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(size as usize * size as usize * 6 * 4);
+    for face in 0..6 {
+        for y in 0..size {
+            for x in 0..size {
+                // Sample the center of the texel
+                let u = (x as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                let v = (y as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                data.extend(sample(unrotate(cube_direction(face, u, v))));
+            }
+        }
+    }
+
+    data
+}
+
+/// The direction (in bevy coordinates) through a texel of a cubemap face, where `u` and `v` are in
+/// `[-1, 1]` and `v` goes down the face.
+/// The faces are in the order `+X, -X, +Y, -Y, +Z, -Z`, as wgpu expects.
+pub(crate) fn cube_direction(face: usize, u: f32, v: f32) -> [f32; 3] {
+    match face {
+        0 => [1.0, -v, -u],
+        1 => [-1.0, -v, u],
+        2 => [u, 1.0, v],
+        3 => [u, -1.0, -v],
+        4 => [u, -v, 1.0],
+        _ => [-u, -v, -1.0],
+    }
+}
+
+/// Find which side of the source skybox the direction (in source coordinates) goes through, and
+/// where on it, with the uv in `[0, 1]` and `v` going down the image.
+/// The sides are `+X, -X, +Y, -Y, +Z, -Z`, which are named like in [`SIDE_SUFFIXES`].
+/// This is the layout of quake 2's skybox, which source inherited.
+fn sky_side([x, y, z]: [f32; 3]) -> (usize, [f32; 2]) {
+    let (ax, ay, az) = (x.abs(), y.abs(), z.abs());
+    // The side, and the position on it going right and up
+    let (side, s, t, major) = if ax >= ay && ax >= az {
+        if x > 0.0 {
+            (0, -y, z, ax)
+        } else {
+            (1, y, z, ax)
+        }
+    } else if ay >= az {
+        if y > 0.0 {
+            (2, x, z, ay)
+        } else {
+            (3, -x, z, ay)
+        }
+    } else if z > 0.0 {
+        (4, -y, -x, az)
+    } else {
+        (5, -y, x, az)
+    };
+
+    (side, [(s / major + 1.0) / 2.0, (1.0 - t / major) / 2.0])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mesh::rotate;
+
+    use super::*;
+
+    #[test]
+    fn test_sky_side() {
+        assert_eq!(sky_side([1.0, 0.0, 0.0]), (0, [0.5, 0.5]));
+        assert_eq!(sky_side([0.0, -1.0, 0.0]), (3, [0.5, 0.5]));
+        assert_eq!(sky_side([0.0, 0.0, -1.0]), (5, [0.5, 0.5]));
+
+        // The top of the horizontal sides is up
+        assert_eq!(sky_side([1.0, 0.0, 0.5]).1, [0.5, 0.25]);
+        // Right of `rt` is towards `ft`, and right of `ft` is towards `lf`
+        assert_eq!(sky_side([1.0, -0.5, 0.0]).1, [0.75, 0.5]);
+        assert_eq!(sky_side([-0.5, -1.0, 0.0]).1, [0.75, 0.5]);
+        // The top of `up` is towards `lf`, the bottom of `dn` is towards `lf`
+        assert_eq!(sky_side([-0.5, 0.0, 1.0]).1, [0.5, 0.25]);
+        assert_eq!(sky_side([-0.5, 0.0, -1.0]).1, [0.5, 0.75]);
+    }
+
+    #[test]
+    fn test_cubemap() {
+        // Each side is a single color, which is its index
+        let sides = std::array::from_fn(|i| {
+            Some(SkySide {
+                width: 2,
+                height: 2,
+                data: [i as u8, 0, 0, 255].repeat(4),
+            })
+        });
+        let (size, data) = build_cubemap(&sides).unwrap();
+        assert_eq!(size, 2);
+        assert_eq!(data.len(), 2 * 2 * 6 * 4);
+
+        // The cubemap face the source direction goes through should have that side's color
+        for (side, dir) in [
+            [1.0, 0.0, 0.0],
+            [-1.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [0.0, -1.0, 0.0],
+            [0.0, 0.0, 1.0],
+            [0.0, 0.0, -1.0],
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let bevy = rotate(dir);
+            let face = (0..6)
+                .find(|face| cube_direction(*face, 0.0, 0.0) == bevy)
+                .unwrap();
+            assert_eq!(data[face * 2 * 2 * 4], side as u8, "side {side}");
+        }
+    }
+
+    #[test]
+    fn test_map_sky() {
+        let mut sky = MapSky::default();
+        sky.add_entity([("classname", "worldspawn"), ("skyname", "Sky_TF2_04")]);
+        sky.add_entity([
+            ("origin", "-128 256.5 -1024"),
+            ("classname", "sky_camera"),
+            ("scale", "16"),
+        ]);
+        sky.add_entity([("classname", "info_player_teamspawn"), ("origin", "0 0 0")]);
+
+        assert_eq!(sky.name.as_deref(), Some("sky_tf2_04"));
+        assert_eq!(
+            sky.camera,
+            Some(SkyCamera {
+                origin: [-128.0, 256.5, -1024.0],
+                scale: 16.0,
+            })
+        );
+    }
+}
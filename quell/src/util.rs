@@ -97,9 +97,15 @@ pub fn vec_to_csv(data: &[f32], file_path: impl AsRef<Path>) -> Result<(), Box<d
     Ok(())
 }
 
+/// Parse a vector keyvalue of an entity, ex: `-128 256.5 -1024`
+pub fn parse_vector(value: &str) -> Option<[f32; 3]> {
+    let mut parts = value.split_whitespace().map(|v| v.parse::<f32>().ok());
+    Some([parts.next()??, parts.next()??, parts.next()??])
+}
+
 pub fn transform_to_vbsp(transform: Transform) -> vbsp::Vector {
     let p = transform.translation.to_array();
-    // let p = unscale(p);
+    let p = unscale(p);
     let p = unrotate(p);
     vbsp::Vector {
         x: p[0],
@@ -0,0 +1,773 @@
+//! The potentially visible set (PVS), which is which clusters of the map vvis found to be visible
+//! from each other.
+//! Every point of the map is in exactly one leaf of the bsp tree, and the leaves that can be
+//! entered are grouped into clusters. Faces are culled by whether any of their clusters are in the
+//! visible set of the cluster the camera is in.
+//! The map is also split into areas, which are connected by areaportals (typically placed in
+//! doorways). Clusters in areas that can't be reached through open portals are culled as well.
+//! The tree and the visibility data come from vbsp. The leaves and areas are read from the lumps
+//! directly, since vbsp sorts the leaves by cluster (so the nodes' leaf indices don't point into
+//! them), can't read the older leaf lump, and doesn't read the areas at all.
+
+use std::ops::Range;
+
+use vbsp::{Bsp, Node, Plane, VisData};
+
+use crate::lump::{read_records, LumpReader, LumpType, Lumps};
+
+/// Size of a `dleaf_t` in version 0 of the leaf lump, which stores the ambient lighting inline
+const LEAF_V0_SIZE: usize = 56;
+/// Size of a `dleaf_t` in version 1 of the leaf lump
+const LEAF_V1_SIZE: usize = 32;
+/// Size of a `darea_t`
+const AREA_SIZE: usize = 8;
+/// Size of a `dareaportal_t`
+const AREA_PORTAL_SIZE: usize = 12;
+/// Only the low 9 bits of a leaf's area field are the area, the rest are flags
+const LEAF_AREA_MASK: u16 = 0x1FF;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Leaf {
+    /// `-1` for leaves that are outside the map (ex: solid)
+    cluster: i16,
+    area: u16,
+    bounds: Bounds,
+}
+
+/// An axis aligned bounding box, in source coordinates
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Bounds {
+    pub mins: [f32; 3],
+    pub maxs: [f32; 3],
+}
+impl Bounds {
+    fn new(mins: [i16; 3], maxs: [i16; 3]) -> Bounds {
+        Bounds {
+            mins: mins.map(f32::from),
+            maxs: maxs.map(f32::from),
+        }
+    }
+
+    fn read(r: &mut LumpReader<'_>) -> eyre::Result<Bounds> {
+        let mins = [r.i16()?, r.i16()?, r.i16()?];
+        let maxs = [r.i16()?, r.i16()?, r.i16()?];
+        Ok(Bounds::new(mins, maxs))
+    }
+
+    /// Grow the bounds to also contain the other bounds
+    pub fn union(self, other: Bounds) -> Bounds {
+        let mut out = self;
+        for i in 0..3 {
+            out.mins[i] = out.mins[i].min(other.mins[i]);
+            out.maxs[i] = out.maxs[i].max(other.maxs[i]);
+        }
+        out
+    }
+}
+
+/// A plane that splits a node of the bsp tree
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Split {
+    pub normal: [f32; 3],
+    pub dist: f32,
+    /// The bounds of the node being split
+    pub bounds: Bounds,
+}
+
+/// One side of an areaportal, from the area it is listed in to `other_area`
+#[derive(Debug, Clone, PartialEq)]
+struct Portal {
+    /// Shared by both sides of the portal, and is the `portalnumber` of the `func_areaportal`
+    key: u16,
+    other_area: u16,
+    /// Range of the portal's polygon in the clip portal vertices
+    verts: Range<usize>,
+}
+
+/// The bsp tree of the world and the visibility between its clusters.
+#[derive(Debug, Clone, Default)]
+pub struct Vis {
+    planes: Vec<Plane>,
+    /// Child indices are node indices if positive, and `-1 - leaf index` if negative
+    nodes: Vec<Node>,
+    /// The node the world's tree starts at
+    head_node: usize,
+    leaves: Vec<Leaf>,
+    /// The area of each cluster, taken from its first leaf.
+    /// vbsp splits the map into areas before clustering, so clusters don't cross areaportals.
+    cluster_areas: Vec<u16>,
+    /// The range of each area's portals in `portals`
+    areas: Vec<Range<usize>>,
+    portals: Vec<Portal>,
+    portal_verts: Vec<[f32; 3]>,
+    /// Offset of each cluster's compressed PVS from the start of the visibility lump
+    pvs_offsets: Vec<usize>,
+    /// The visibility lump after its header, which is the cluster count and the offsets
+    data: Vec<u8>,
+}
+impl Vis {
+    /// Read the vis of the bsp. `data` is the bsp file that it was read from.
+    pub fn from_bsp(bsp: &Bsp, data: &[u8]) -> eyre::Result<Vis> {
+        let head_node = bsp
+            .models
+            .first()
+            .map_or(0, |world| world.head_node.max(0) as usize);
+        Vis::new(
+            bsp.planes.clone(),
+            bsp.nodes.clone(),
+            head_node,
+            &bsp.vis_data,
+            data,
+        )
+    }
+
+    fn new(
+        planes: Vec<Plane>,
+        nodes: Vec<Node>,
+        head_node: usize,
+        vis_data: &VisData,
+        data: &[u8],
+    ) -> eyre::Result<Vis> {
+        let lumps = Lumps::new(data)?;
+
+        let leaf_size = if lumps.lump_version(LumpType::Leafs) == 0 {
+            LEAF_V0_SIZE
+        } else {
+            LEAF_V1_SIZE
+        };
+        let leaves = read_records(&lumps.get(LumpType::Leafs)?, leaf_size, |r| {
+            // contents
+            r.skip(4);
+            Ok(Leaf {
+                cluster: r.i16()?,
+                area: r.u16()? & LEAF_AREA_MASK,
+                bounds: Bounds::read(r)?,
+            })
+        })?;
+
+        // Empty if the map was never ran through vvis, so everything is visible
+        let pvs_offsets = vis_data
+            .pvs_offsets
+            .iter()
+            .map(|&offset| offset as usize)
+            .collect::<Vec<_>>();
+
+        let mut cluster_areas = vec![0; pvs_offsets.len()];
+        let mut has_area = vec![false; pvs_offsets.len()];
+        for leaf in &leaves {
+            let Ok(cluster) = usize::try_from(leaf.cluster) else {
+                continue;
+            };
+            if cluster < cluster_areas.len() && !has_area[cluster] {
+                cluster_areas[cluster] = leaf.area;
+                has_area[cluster] = true;
+            }
+        }
+
+        let areas = read_records(&lumps.get(LumpType::Areas)?, AREA_SIZE, |r| {
+            let count = r.i32()? as usize;
+            let first = r.i32()? as usize;
+            Ok(first..first + count)
+        })?;
+        let portals = read_records(&lumps.get(LumpType::AreaPortals)?, AREA_PORTAL_SIZE, |r| {
+            let key = r.u16()?;
+            let other_area = r.u16()?;
+            let first_vert = r.u16()? as usize;
+            let vert_count = r.u16()? as usize;
+            // The portal's plane isn't needed
+            Ok(Portal {
+                key,
+                other_area,
+                verts: first_vert..first_vert + vert_count,
+            })
+        })?;
+        let portal_verts = read_records(&lumps.get(LumpType::ClipPortalVerts)?, 12, |r| r.vec3())?;
+
+        Ok(Vis {
+            planes,
+            nodes,
+            head_node,
+            leaves,
+            cluster_areas,
+            areas,
+            portals,
+            portal_verts,
+            pvs_offsets,
+            data: vis_data.data.clone(),
+        })
+    }
+
+    /// The number of clusters that have visibility data
+    pub fn cluster_count(&self) -> usize {
+        self.pvs_offsets.len()
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.leaves.len()
+    }
+
+    /// The cluster of the leaf, `-1` if it is outside the map.
+    pub fn leaf_cluster(&self, leaf: usize) -> i16 {
+        self.leaves.get(leaf).map_or(-1, |leaf| leaf.cluster)
+    }
+
+    pub fn leaf_bounds(&self, leaf: usize) -> Option<Bounds> {
+        self.leaves.get(leaf).map(|leaf| leaf.bounds)
+    }
+
+    /// The leaves which are part of the cluster
+    pub fn cluster_leaves(&self, cluster: i16) -> impl Iterator<Item = usize> + '_ {
+        self.leaves
+            .iter()
+            .enumerate()
+            .filter(move |(_, leaf)| cluster != -1 && leaf.cluster == cluster)
+            .map(|(i, _)| i)
+    }
+
+    /// The bounds of all the leaves in the cluster
+    pub fn cluster_bounds(&self, cluster: i16) -> Option<Bounds> {
+        self.cluster_leaves(cluster)
+            .map(|leaf| self.leaves[leaf].bounds)
+            .reduce(Bounds::union)
+    }
+
+    /// Find the leaf which contains the point, which is in source coordinates.
+    pub fn leaf_at(&self, point: [f32; 3]) -> Option<usize> {
+        self.walk(point, |_| {})
+    }
+
+    /// The planes that split the nodes on the way from the root of the tree to the leaf that
+    /// contains the point.
+    pub fn splits_at(&self, point: [f32; 3]) -> Vec<Split> {
+        let mut splits = Vec::new();
+        self.walk(point, |split| splits.push(split));
+        splits
+    }
+
+    /// Walk the tree to the leaf containing the point, calling `visit` for each node on the way.
+    fn walk(&self, point: [f32; 3], mut visit: impl FnMut(Split)) -> Option<usize> {
+        if self.nodes.is_empty() {
+            return (!self.leaves.is_empty()).then_some(0);
+        }
+
+        let mut index = self.head_node as i32;
+        // Bounded by the node count so that a malformed tree can't loop forever
+        for _ in 0..=self.nodes.len() {
+            if index < 0 {
+                let leaf = (-1 - index) as usize;
+                return (leaf < self.leaves.len()).then_some(leaf);
+            }
+
+            let node = self.nodes.get(index as usize)?;
+            let plane = self.planes.get(usize::try_from(node.plane_index).ok()?)?;
+            let normal = [plane.normal.x, plane.normal.y, plane.normal.z];
+            visit(Split {
+                normal,
+                dist: plane.dist,
+                bounds: Bounds::new(node.mins, node.maxs),
+            });
+            let dist = dot(normal, point) - plane.dist;
+            index = if dist >= 0.0 {
+                node.children[0]
+            } else {
+                node.children[1]
+            };
+        }
+
+        None
+    }
+
+    /// Find the cluster which contains the point, which is in source coordinates.
+    /// Returns `None` if the point is outside of the map or the map has no visibility data.
+    pub fn cluster_at(&self, point: [f32; 3]) -> Option<i16> {
+        let leaf = self.leaf_at(point)?;
+        let cluster = self.leaves[leaf].cluster;
+        (cluster >= 0 && (cluster as usize) < self.cluster_count()).then_some(cluster)
+    }
+
+    /// Find the area which contains the point, which is in source coordinates.
+    pub fn area_at(&self, point: [f32; 3]) -> Option<u16> {
+        let leaf = self.leaf_at(point)?;
+        Some(self.leaves[leaf].area)
+    }
+
+    /// Decompress the set of clusters that are potentially visible from the cluster.
+    pub fn visible_clusters(&self, cluster: i16) -> eyre::Result<ClusterSet> {
+        let offset = usize::try_from(cluster)
+            .ok()
+            .and_then(|cluster| self.pvs_offsets.get(cluster))
+            .ok_or_else(|| eyre::eyre!("Cluster {cluster} has no visibility data"))?;
+
+        // The offsets count the header, which vbsp doesn't keep
+        let header_len = 4 + self.cluster_count() * 8;
+        let mut set = ClusterSet::new(self.cluster_count());
+        let mut data = offset
+            .checked_sub(header_len)
+            .and_then(|offset| self.data.get(offset..))
+            .ok_or_else(|| eyre::eyre!("PVS of cluster {cluster} is past the end of the lump"))?
+            .iter();
+
+        // Each byte is 8 bits of the set, except that a zero byte is followed by the number of
+        // zero bytes to skip.
+        let mut i = 0;
+        while i < set.bits.len() {
+            let truncated = || eyre::eyre!("PVS of cluster {cluster} is truncated");
+            let byte = *data.next().ok_or_else(truncated)?;
+            if byte == 0 {
+                i += *data.next().ok_or_else(truncated)? as usize;
+            } else {
+                set.bits[i] = byte;
+                i += 1;
+            }
+        }
+
+        // vvis always makes clusters visible from themselves, but we don't rely on that
+        set.insert(cluster);
+
+        Ok(set)
+    }
+
+    /// The area that the cluster is in
+    pub fn cluster_area(&self, cluster: i16) -> Option<u16> {
+        usize::try_from(cluster)
+            .ok()
+            .and_then(|cluster| self.cluster_areas.get(cluster))
+            .copied()
+    }
+
+    /// Find which areas can be reached from the starting areas by going through open portals.
+    /// Indexed by area.
+    pub fn reachable_areas(
+        &self,
+        start: impl IntoIterator<Item = u16>,
+        is_open: impl Fn(u16) -> bool,
+    ) -> Vec<bool> {
+        let mut reachable = vec![false; self.areas.len()];
+        let mut stack = Vec::new();
+        for area in start {
+            if let Some(reached) = reachable.get_mut(area as usize) {
+                if !*reached {
+                    *reached = true;
+                    stack.push(area as usize);
+                }
+            }
+        }
+
+        while let Some(area) = stack.pop() {
+            let portals = self
+                .portals
+                .get(self.areas[area].clone())
+                .unwrap_or_default();
+            for portal in portals {
+                if !is_open(portal.key) {
+                    continue;
+                }
+
+                let other = portal.other_area as usize;
+                if let Some(reached) = reachable.get_mut(other) {
+                    if !*reached {
+                        *reached = true;
+                        stack.push(other);
+                    }
+                }
+            }
+        }
+
+        reachable
+    }
+
+    /// Remove the clusters in areas which can't be reached from any of the `from` clusters through
+    /// open portals.
+    pub fn cull_areas(
+        &self,
+        visible: &mut ClusterSet,
+        from: &[i16],
+        is_open: impl Fn(u16) -> bool,
+    ) {
+        let start = from
+            .iter()
+            .map(|cluster| self.cluster_area(*cluster))
+            .collect::<Option<Vec<_>>>();
+        // Area 0 is the area of the solid leaves, so being in it means we don't know where we are
+        let Some(start) = start.filter(|start| !start.contains(&0)) else {
+            return;
+        };
+        if start.iter().any(|area| *area as usize >= self.areas.len()) {
+            return;
+        }
+
+        let reachable = self.reachable_areas(start, is_open);
+        visible.retain(|cluster| {
+            self.cluster_area(cluster)
+                .and_then(|area| reachable.get(area as usize))
+                .is_none_or(|reached| *reached)
+        });
+    }
+
+    /// The center of the areaportal's polygon, in source coordinates
+    pub fn portal_center(&self, key: u16) -> Option<[f32; 3]> {
+        let portal = self.portals.iter().find(|portal| portal.key == key)?;
+        let verts = self.portal_verts.get(portal.verts.clone())?;
+        if verts.is_empty() {
+            return None;
+        }
+
+        let mut center = [0.0; 3];
+        for vert in verts {
+            for (c, v) in center.iter_mut().zip(vert) {
+                *c += v;
+            }
+        }
+        Some(center.map(|c| c / verts.len() as f32))
+    }
+}
+
+/// A set of clusters, ex: the ones that are visible from the camera.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ClusterSet {
+    bits: Vec<u8>,
+}
+impl ClusterSet {
+    pub fn new(cluster_count: usize) -> ClusterSet {
+        ClusterSet {
+            bits: vec![0; cluster_count.div_ceil(8)],
+        }
+    }
+
+    pub fn contains(&self, cluster: i16) -> bool {
+        let Ok(cluster) = usize::try_from(cluster) else {
+            return false;
+        };
+        self.bits
+            .get(cluster / 8)
+            .is_some_and(|byte| byte & (1 << (cluster % 8)) != 0)
+    }
+
+    pub fn insert(&mut self, cluster: i16) {
+        let Ok(cluster) = usize::try_from(cluster) else {
+            return;
+        };
+        if let Some(byte) = self.bits.get_mut(cluster / 8) {
+            *byte |= 1 << (cluster % 8);
+        }
+    }
+
+    pub fn remove(&mut self, cluster: i16) {
+        let Ok(cluster) = usize::try_from(cluster) else {
+            return;
+        };
+        if let Some(byte) = self.bits.get_mut(cluster / 8) {
+            *byte &= !(1 << (cluster % 8));
+        }
+    }
+
+    /// Only keep the clusters for which `keep` returns true.
+    pub fn retain(&mut self, mut keep: impl FnMut(i16) -> bool) {
+        for cluster in 0..self.bits.len() * 8 {
+            let cluster = cluster as i16;
+            if self.contains(cluster) && !keep(cluster) {
+                self.remove(cluster);
+            }
+        }
+    }
+
+    /// Add all the clusters of the other set to this one.
+    pub fn union_with(&mut self, other: &ClusterSet) {
+        if self.bits.len() < other.bits.len() {
+            self.bits.resize(other.bits.len(), 0);
+        }
+        for (byte, other) in self.bits.iter_mut().zip(&other.bits) {
+            *byte |= other;
+        }
+    }
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::{Transform, Vec3};
+
+    use crate::{
+        lump::make_bsp,
+        mesh::{rotate, scale},
+        util::transform_to_vbsp,
+    };
+
+    use super::*;
+
+    fn plane([x, y, z]: [f32; 3], dist: f32) -> Plane {
+        Plane {
+            normal: vbsp::Vector { x, y, z },
+            dist,
+            ty: 0,
+        }
+    }
+
+    fn bounds(mins: [i16; 3], maxs: [i16; 3]) -> Vec<u8> {
+        mins.iter()
+            .chain(&maxs)
+            .flat_map(|v| v.to_le_bytes())
+            .collect()
+    }
+
+    fn node(plane_index: i32, children: [i32; 2], mins: [i16; 3], maxs: [i16; 3]) -> Node {
+        Node {
+            plane_index,
+            children,
+            mins,
+            maxs,
+            first_face: 0,
+            face_cound: 0,
+            area: 0,
+            padding: 0,
+        }
+    }
+
+    fn leaf(cluster: i16, area: u16, mins: [i16; 3], maxs: [i16; 3]) -> Vec<u8> {
+        let mut out = Vec::new();
+        // contents
+        out.extend(1i32.to_le_bytes());
+        out.extend(cluster.to_le_bytes());
+        // With some flags set, which aren't part of the area
+        out.extend((area | 0x400).to_le_bytes());
+        out.extend(bounds(mins, maxs));
+        out.resize(LEAF_V1_SIZE, 0);
+        out
+    }
+
+    fn area(first_portal: i32, portal_count: i32) -> Vec<u8> {
+        [portal_count.to_le_bytes(), first_portal.to_le_bytes()].concat()
+    }
+
+    fn area_portal(key: u16, other_area: u16, first_vert: u16, vert_count: u16) -> Vec<u8> {
+        let mut out = Vec::new();
+        for v in [key, other_area, first_vert, vert_count] {
+            out.extend(v.to_le_bytes());
+        }
+        // plane
+        out.extend(0i32.to_le_bytes());
+        out
+    }
+
+    /// A map split by the planes `x = 64` and `y = -32`:
+    /// - leaf 0 is the solid leaf, which is in no cluster
+    /// - leaf 1 (`x >= 64`, `y >= -32`) is cluster 0
+    /// - leaf 2 (`x < 64`) is cluster 1
+    /// - leaf 3 (`x >= 64`, `y < -32`) is cluster 2
+    ///
+    /// Clusters 0 and 1 see each other, and cluster 2 sees cluster 19, which only exists in the
+    /// visibility data so that the PVS has runs of zeroes.
+    ///
+    /// Clusters 0 and 2 are in area 1 and cluster 1 is in area 2, which are connected by the
+    /// areaportal with key 7 on the `x = 64` plane.
+    fn test_map() -> Vis {
+        let planes = vec![plane([1.0, 0.0, 0.0], 64.0), plane([0.0, 1.0, 0.0], -32.0)];
+        let (mins, maxs) = ([-512, -512, -512], [512, 512, 512]);
+        let nodes = vec![
+            node(0, [1, -3], mins, maxs),
+            node(1, [-2, -4], [64, -512, -512], maxs),
+        ];
+        let leaves = [
+            leaf(-1, 0, [0; 3], [0; 3]),
+            leaf(0, 1, [64, -32, -512], maxs),
+            leaf(1, 2, mins, [64, 512, 512]),
+            leaf(2, 1, [64, -512, -512], [512, -32, 512]),
+        ]
+        .concat();
+        let areas = [area(0, 0), area(0, 1), area(1, 1)].concat();
+        let portals = [area_portal(7, 2, 0, 4), area_portal(7, 1, 0, 4)].concat();
+        let portal_verts = [
+            [64.0f32, 90.0, -10.0],
+            [64.0, 110.0, -10.0],
+            [64.0, 110.0, 10.0],
+            [64.0, 90.0, 10.0],
+        ]
+        .iter()
+        .flatten()
+        .flat_map(|v| v.to_le_bytes())
+        .collect::<Vec<_>>();
+        let cluster_count = 20;
+        let pvs: [&[u8]; 3] = [
+            &[0b011, 0, 2],
+            &[0b011, 0, 2],
+            // Cluster 19, which is bit 3 of the third byte
+            &[0b100, 0, 1, 0b1000],
+        ];
+        // Like in the file, the offsets are from the start of the lump
+        let header_size = 4 + cluster_count * 8;
+        let mut visibility = VisData {
+            cluster_count: cluster_count as u32,
+            ..Default::default()
+        };
+        let mut offset = header_size;
+        for cluster in 0..cluster_count {
+            // Clusters without their own data share the empty set at the end
+            let pvs_offset = if cluster < pvs.len() {
+                let o = offset;
+                offset += pvs[cluster].len();
+                o
+            } else {
+                header_size + pvs.iter().map(|pvs| pvs.len()).sum::<usize>()
+            };
+            visibility.pvs_offsets.push(pvs_offset as i32);
+            visibility.pas_offsets.push(pvs_offset as i32);
+        }
+        visibility.data.extend(pvs.concat());
+        visibility.data.extend([0, 3]);
+
+        let data = make_bsp(&[
+            (LumpType::Leafs, 1, leaves),
+            (LumpType::Areas, 0, areas),
+            (LumpType::AreaPortals, 0, portals),
+            (LumpType::ClipPortalVerts, 0, portal_verts),
+        ]);
+        Vis::new(planes, nodes, 0, &visibility, &data).unwrap()
+    }
+
+    #[test]
+    fn test_leaf_at() {
+        let vis = test_map();
+        assert_eq!(vis.cluster_count(), 20);
+
+        assert_eq!(vis.leaf_at([100.0, 0.0, 0.0]), Some(1));
+        assert_eq!(vis.leaf_at([0.0, 500.0, 10.0]), Some(2));
+        assert_eq!(vis.leaf_at([100.0, -100.0, -50.0]), Some(3));
+        // Points on a plane are in front of it
+        assert_eq!(vis.leaf_at([64.0, -32.0, 0.0]), Some(1));
+
+        assert_eq!(vis.cluster_at([100.0, 0.0, 0.0]), Some(0));
+        assert_eq!(vis.cluster_at([0.0, 500.0, 10.0]), Some(1));
+        assert_eq!(vis.cluster_at([100.0, -100.0, -50.0]), Some(2));
+    }
+
+    #[test]
+    fn test_visible_clusters() {
+        let vis = test_map();
+
+        let set = vis.visible_clusters(0).unwrap();
+        assert!(set.contains(0));
+        assert!(set.contains(1));
+        assert!(!set.contains(2));
+        assert!(!set.contains(19));
+
+        let set = vis.visible_clusters(2).unwrap();
+        assert!(set.contains(2));
+        assert!(set.contains(19));
+        assert!((0..19).filter(|c| *c != 2).all(|c| !set.contains(c)));
+
+        // Clusters only see themselves, even if vvis said otherwise
+        let set = vis.visible_clusters(5).unwrap();
+        assert!((0..20).all(|c| set.contains(c) == (c == 5)));
+
+        let mut set = vis.visible_clusters(0).unwrap();
+        set.union_with(&vis.visible_clusters(2).unwrap());
+        assert!([0, 1, 2, 19].iter().all(|c| set.contains(*c)));
+        assert!(!set.contains(-1));
+
+        assert!(vis.visible_clusters(-1).is_err());
+        assert!(vis.visible_clusters(20).is_err());
+    }
+
+    #[test]
+    fn test_camera_cluster() {
+        let vis = test_map();
+
+        // Bevy positions are scaled down from source units, which the camera's position must be
+        // scaled back up from to land in the right leaf
+        for (point, cluster) in [
+            ([100.0, 0.0, 0.0], 0),
+            ([0.0, 500.0, 10.0], 1),
+            ([100.0, -100.0, -50.0], 2),
+        ] {
+            let transform = Transform::from_translation(Vec3::from(rotate(scale(point))));
+            let p = transform_to_vbsp(transform);
+            assert_eq!(vis.cluster_at([p.x, p.y, p.z]), Some(cluster));
+        }
+    }
+
+    #[test]
+    fn test_no_vis_data() {
+        let data = make_bsp(&[(LumpType::Leafs, 1, leaf(0, 0, [0; 3], [0; 3]))]);
+        let vis = Vis::new(Vec::new(), Vec::new(), 0, &VisData::default(), &data).unwrap();
+
+        assert_eq!(vis.leaf_at([0.0, 0.0, 0.0]), Some(0));
+        assert_eq!(vis.cluster_at([0.0, 0.0, 0.0]), None);
+    }
+
+    #[test]
+    fn test_areaportals() {
+        let vis = test_map();
+
+        assert_eq!(vis.cluster_area(0), Some(1));
+        assert_eq!(vis.cluster_area(1), Some(2));
+        assert_eq!(vis.cluster_area(2), Some(1));
+        assert_eq!(vis.reachable_areas([1], |_| true), [false, true, true]);
+        assert_eq!(vis.reachable_areas([1], |_| false), [false, true, false]);
+        assert_eq!(vis.reachable_areas([2], |_| false), [false, false, true]);
+
+        let pvs = vis.visible_clusters(0).unwrap();
+
+        let mut visible = pvs.clone();
+        vis.cull_areas(&mut visible, &[0], |key| key == 7);
+        assert_eq!(visible, pvs);
+
+        // Closing the portal hides the other side of it
+        let mut visible = pvs.clone();
+        vis.cull_areas(&mut visible, &[0], |_| false);
+        assert!(visible.contains(0));
+        assert!(!visible.contains(1));
+
+        // Unless a camera is on that side
+        let mut visible = pvs.clone();
+        vis.cull_areas(&mut visible, &[0, 1], |_| false);
+        assert_eq!(visible, pvs);
+
+        assert_eq!(vis.portal_center(7), Some([64.0, 100.0, 0.0]));
+        assert_eq!(vis.portal_center(8), None);
+    }
+
+    #[test]
+    fn test_leaf_bounds() {
+        let vis = test_map();
+
+        assert_eq!(vis.leaf_count(), 4);
+        assert_eq!(vis.leaf_cluster(3), 2);
+        assert_eq!(vis.leaf_cluster(4), -1);
+        assert_eq!(vis.cluster_leaves(1).collect::<Vec<_>>(), [2]);
+        assert_eq!(vis.cluster_leaves(-1).count(), 0);
+        assert_eq!(
+            vis.leaf_bounds(1),
+            Some(Bounds {
+                mins: [64.0, -32.0, -512.0],
+                maxs: [512.0; 3],
+            })
+        );
+        let a = Bounds {
+            mins: [0.0, 0.0, 0.0],
+            maxs: [1.0, 2.0, 3.0],
+        };
+        let b = Bounds {
+            mins: [-1.0, 1.0, 0.0],
+            maxs: [0.0, 5.0, 1.0],
+        };
+        assert_eq!(
+            a.union(b),
+            Bounds {
+                mins: [-1.0, 0.0, 0.0],
+                maxs: [1.0, 5.0, 3.0],
+            }
+        );
+
+        let splits = vis.splits_at([100.0, 0.0, 0.0]);
+        assert_eq!(splits.len(), 2);
+        assert_eq!(splits[0].normal, [1.0, 0.0, 0.0]);
+        assert_eq!(splits[0].dist, 64.0);
+        assert_eq!(splits[1].normal, [0.0, 1.0, 0.0]);
+        assert_eq!(splits[1].bounds.mins, [64.0, -512.0, -512.0]);
+        // The other side of the first plane is a leaf
+        assert_eq!(vis.splits_at([0.0, 0.0, 0.0]).len(), 1);
+    }
+}
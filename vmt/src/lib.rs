@@ -1,9 +1,11 @@
 use std::{borrow::Cow, collections::HashMap};
 
-use util::{apply, StopOnErr};
+use util::{apply, lenient, StopOnErr};
 
 use crate::{
-    parse::{expect_char, take_text, take_vec3, take_whitespace},
+    parse::{
+        expect_char, parse_bool, parse_f32, take_color, take_text, take_vec3, take_whitespace,
+    },
     util::to_lowercase_cow,
 };
 
@@ -91,6 +93,8 @@ pub enum ShaderName<'a> {
     LightmappedGeneric,
     UnlitGeneric,
     VertexLitGeneric,
+    /// Blends between two textures by the displacement's vertex alpha
+    WorldVertexTransition,
     // ?
     Water,
     Patch,
@@ -102,6 +106,7 @@ impl<'a> ShaderName<'a> {
             ShaderName::LightmappedGeneric => b"LightmappedGeneric",
             ShaderName::UnlitGeneric => b"UnlitGeneric",
             ShaderName::VertexLitGeneric => b"VertexLitGeneric",
+            ShaderName::WorldVertexTransition => b"WorldVertexTransition",
             ShaderName::Water => b"Water",
             ShaderName::Patch => b"Patch",
         }
@@ -115,6 +120,8 @@ impl<'a> From<&'a [u8]> for ShaderName<'a> {
             ShaderName::UnlitGeneric
         } else if s.eq_ignore_ascii_case(b"VertexLitGeneric") {
             ShaderName::VertexLitGeneric
+        } else if s.eq_ignore_ascii_case(b"WorldVertexTransition") {
+            ShaderName::WorldVertexTransition
         } else if s.eq_ignore_ascii_case(b"Water") {
             ShaderName::Water
         } else if s.eq_ignore_ascii_case(b"Patch") {
@@ -133,6 +140,7 @@ impl<'a> PartialEq for ShaderName<'a> {
             (ShaderName::LightmappedGeneric, ShaderName::LightmappedGeneric) => true,
             (ShaderName::UnlitGeneric, ShaderName::UnlitGeneric) => true,
             (ShaderName::VertexLitGeneric, ShaderName::VertexLitGeneric) => true,
+            (ShaderName::WorldVertexTransition, ShaderName::WorldVertexTransition) => true,
             (ShaderName::Water, ShaderName::Water) => true,
             (ShaderName::Patch, ShaderName::Patch) => true,
             (ShaderName::String(a), b) => a.eq_ignore_ascii_case(b.as_bytes()),
@@ -153,6 +161,7 @@ impl std::fmt::Debug for ShaderName<'_> {
             ShaderName::LightmappedGeneric => write!(f, "LightmappedGeneric"),
             ShaderName::UnlitGeneric => write!(f, "UnlitGeneric"),
             ShaderName::VertexLitGeneric => write!(f, "VertexLitGeneric"),
+            ShaderName::WorldVertexTransition => write!(f, "WorldVertexTransition"),
             ShaderName::Water => write!(f, "Water"),
             ShaderName::Patch => write!(f, "Patch"),
         }
@@ -229,6 +238,11 @@ pub struct VMT<'a> {
     // TODO: some parameters might only be supported with certain shaders?
     /// Defines the albedo texture
     pub base_texture: Option<TextureStr<'a>>,
+    /// The second albedo texture of `WorldVertexTransition`, which is blended towards by the
+    /// vertex alpha
+    pub base_texture2: Option<TextureStr<'a>>,
+    /// Controls the sharpness of the blend between the two base textures (`$blendmodulatetexture`)
+    pub blend_modulate_texture: Option<TextureStr<'a>>,
     /// Whether this material is a decal
     pub decal: Option<bool>,
     /// Links the surface to a set of physical properties
@@ -236,7 +250,50 @@ pub struct VMT<'a> {
     pub detail: VMTDetail<'a>,
     pub detail2: VMTDetail2<'a>,
     pub base_texture_transform: Option<Cow<'a, str>>,
+    /// Tint of the material, in `0.0..=1.0`
     pub color: Option<RGB>,
+    /// Tint of models, in `0.0..=1.0`, which is multiplied with `color`
+    pub color2: Option<RGB>,
+
+    /// Normal map, for brushes (`$bumpmap`)
+    pub bump_map: Option<TextureStr<'a>>,
+    /// Normal map, for water and some model shaders (`$normalmap`)
+    pub normal_map: Option<TextureStr<'a>>,
+
+    /// Whether to discard pixels whose alpha is below `alpha_test_reference`
+    pub alpha_test: Option<bool>,
+    pub alpha_test_reference: Option<f32>,
+    /// Whether to blend using the base texture's alpha
+    pub translucent: Option<bool>,
+    /// Whether to add the material's color to what is behind it
+    pub additive: Option<bool>,
+    /// Whether both sides of the faces are drawn
+    pub no_cull: Option<bool>,
+    /// Whether the material glows, masked by the base texture's alpha
+    pub self_illum: Option<bool>,
+
+    /// Color of the fog under water
+    pub fog_color: Option<RGB>,
+    /// Distance under water, in source units, at which the fog starts
+    pub fog_start: Option<f32>,
+    /// Distance under water, in source units, at which the fog is opaque
+    pub fog_end: Option<f32>,
+    /// Tint of the reflection on water
+    pub reflect_tint: Option<RGB>,
+    /// Tint of what is seen through water
+    pub refract_tint: Option<RGB>,
+    /// How much the water's normal map distorts its reflection
+    pub reflect_amount: Option<f32>,
+    /// How much the water's normal map distorts what is seen through it
+    pub refract_amount: Option<f32>,
+
+    /// Cubemap texture that the material reflects, or `env_cubemap` for the map's cubemap nearest
+    /// to the surface
+    pub env_map: Option<TextureStr<'a>>,
+    /// Tint of the reflected cubemap (`$envmap`)
+    pub env_map_tint: Option<RGB>,
+    /// Texture whose color masks the reflected cubemap
+    pub env_map_mask: Option<TextureStr<'a>>,
 
     // TODO: detail texture transform
     pub phong: Option<f32>,
@@ -250,6 +307,10 @@ pub struct VMT<'a> {
 
     pub include: Option<Cow<'a, str>>,
 
+    /// Parameters which we recognize but whose values failed to parse, as `(key, value)`.  
+    /// Like the game, these are left unset rather than failing the whole vmt.
+    pub invalid: Vec<(Cow<'a, [u8]>, Cow<'a, str>)>,
+
     // TODO: is this some sort of enum?
     pub other: VMTOther<'a>,
     pub sub: VMTSubs<'a>,
@@ -264,12 +325,33 @@ impl<'a> VMT<'a> {
         VMT {
             shader_name: self.shader_name,
             base_texture: apply(self.base_texture, &o.base_texture),
+            base_texture2: apply(self.base_texture2, &o.base_texture2),
+            blend_modulate_texture: apply(self.blend_modulate_texture, &o.blend_modulate_texture),
             decal: o.decal.or(self.decal),
             surface_prop: apply(self.surface_prop, &o.surface_prop),
             detail: self.detail.apply(&o.detail),
             detail2: self.detail2.apply(&o.detail2),
             base_texture_transform: apply(self.base_texture_transform, &o.base_texture_transform),
             color: apply(self.color, &o.color),
+            color2: apply(self.color2, &o.color2),
+            bump_map: apply(self.bump_map, &o.bump_map),
+            normal_map: apply(self.normal_map, &o.normal_map),
+            alpha_test: o.alpha_test.or(self.alpha_test),
+            alpha_test_reference: o.alpha_test_reference.or(self.alpha_test_reference),
+            translucent: o.translucent.or(self.translucent),
+            additive: o.additive.or(self.additive),
+            no_cull: o.no_cull.or(self.no_cull),
+            self_illum: o.self_illum.or(self.self_illum),
+            fog_color: apply(self.fog_color, &o.fog_color),
+            fog_start: o.fog_start.or(self.fog_start),
+            fog_end: o.fog_end.or(self.fog_end),
+            reflect_tint: apply(self.reflect_tint, &o.reflect_tint),
+            refract_tint: apply(self.refract_tint, &o.refract_tint),
+            reflect_amount: o.reflect_amount.or(self.reflect_amount),
+            refract_amount: o.refract_amount.or(self.refract_amount),
+            env_map: apply(self.env_map, &o.env_map),
+            env_map_tint: apply(self.env_map_tint, &o.env_map_tint),
+            env_map_mask: apply(self.env_map_mask, &o.env_map_mask),
             phong: o.phong.or(self.phong),
             phong_boost: o.phong_boost.or(self.phong_boost),
             phong_exponent: o.phong_exponent.or(self.phong_exponent),
@@ -277,6 +359,11 @@ impl<'a> VMT<'a> {
             lightwarp_texture: apply(self.lightwarp_texture, &o.lightwarp_texture),
             keywords: apply(self.keywords, &o.keywords),
             include: apply(self.include, &o.include),
+            invalid: {
+                let mut invalid = self.invalid;
+                invalid.extend(o.invalid.iter().cloned());
+                invalid
+            },
             other: {
                 let mut other = self.other;
                 other
@@ -379,6 +466,10 @@ impl<'a> VMT<'a> {
                     // Root shader names that we recognize
                     if k.eq_ignore_ascii_case(b"$basetexture") {
                         vmt.base_texture = Some(Cow::Borrowed(val));
+                    } else if k.eq_ignore_ascii_case(b"$basetexture2") {
+                        vmt.base_texture2 = Some(Cow::Borrowed(val));
+                    } else if k.eq_ignore_ascii_case(b"$blendmodulatetexture") {
+                        vmt.blend_modulate_texture = Some(Cow::Borrowed(val));
                     } else if k.eq_ignore_ascii_case(b"%keywords") {
                         vmt.keywords = Some(Cow::Borrowed(val));
                     } else if k.eq_ignore_ascii_case(b"$detail") {
@@ -395,12 +486,60 @@ impl<'a> VMT<'a> {
                     } else if k.eq_ignore_ascii_case(b"$surfaceprop") {
                         vmt.surface_prop = Some(Cow::Borrowed(val));
                     } else if k.eq_ignore_ascii_case(b"$decal") {
-                        vmt.decal = Some(val.parse()?);
+                        vmt.decal = Some(parse_bool(val)?);
                     } else if k.eq_ignore_ascii_case(b"$basetexturetransform") {
                         vmt.base_texture_transform = Some(Cow::Borrowed(val));
                     } else if k.eq_ignore_ascii_case(b"$color") {
-                        let (_, val) = take_vec3(val.as_bytes())?;
+                        let (_, val) = take_color(val.as_bytes())?;
                         vmt.color = Some(val);
+                    } else if k.eq_ignore_ascii_case(b"$color2") {
+                        vmt.color2 = lenient(&mut vmt.invalid, k, val, |val| {
+                            Ok(take_color(val.as_bytes())?.1)
+                        });
+                    } else if k.eq_ignore_ascii_case(b"$bumpmap") {
+                        vmt.bump_map = Some(Cow::Borrowed(val));
+                    } else if k.eq_ignore_ascii_case(b"$normalmap") {
+                        vmt.normal_map = Some(Cow::Borrowed(val));
+                    } else if k.eq_ignore_ascii_case(b"$alphatest") {
+                        vmt.alpha_test = lenient(&mut vmt.invalid, k, val, parse_bool);
+                    } else if k.eq_ignore_ascii_case(b"$alphatestreference") {
+                        vmt.alpha_test_reference = lenient(&mut vmt.invalid, k, val, parse_f32);
+                    } else if k.eq_ignore_ascii_case(b"$translucent") {
+                        vmt.translucent = lenient(&mut vmt.invalid, k, val, parse_bool);
+                    } else if k.eq_ignore_ascii_case(b"$additive") {
+                        vmt.additive = lenient(&mut vmt.invalid, k, val, parse_bool);
+                    } else if k.eq_ignore_ascii_case(b"$nocull") {
+                        vmt.no_cull = lenient(&mut vmt.invalid, k, val, parse_bool);
+                    } else if k.eq_ignore_ascii_case(b"$selfillum") {
+                        vmt.self_illum = lenient(&mut vmt.invalid, k, val, parse_bool);
+                    } else if k.eq_ignore_ascii_case(b"$fogcolor") {
+                        vmt.fog_color = lenient(&mut vmt.invalid, k, val, |val| {
+                            Ok(take_color(val.as_bytes())?.1)
+                        });
+                    } else if k.eq_ignore_ascii_case(b"$fogstart") {
+                        vmt.fog_start = lenient(&mut vmt.invalid, k, val, parse_f32);
+                    } else if k.eq_ignore_ascii_case(b"$fogend") {
+                        vmt.fog_end = lenient(&mut vmt.invalid, k, val, parse_f32);
+                    } else if k.eq_ignore_ascii_case(b"$reflecttint") {
+                        vmt.reflect_tint = lenient(&mut vmt.invalid, k, val, |val| {
+                            Ok(take_color(val.as_bytes())?.1)
+                        });
+                    } else if k.eq_ignore_ascii_case(b"$refracttint") {
+                        vmt.refract_tint = lenient(&mut vmt.invalid, k, val, |val| {
+                            Ok(take_color(val.as_bytes())?.1)
+                        });
+                    } else if k.eq_ignore_ascii_case(b"$reflectamount") {
+                        vmt.reflect_amount = lenient(&mut vmt.invalid, k, val, parse_f32);
+                    } else if k.eq_ignore_ascii_case(b"$refractamount") {
+                        vmt.refract_amount = lenient(&mut vmt.invalid, k, val, parse_f32);
+                    } else if k.eq_ignore_ascii_case(b"$envmap") {
+                        vmt.env_map = Some(Cow::Borrowed(val));
+                    } else if k.eq_ignore_ascii_case(b"$envmaptint") {
+                        vmt.env_map_tint = lenient(&mut vmt.invalid, k, val, |val| {
+                            Ok(take_color(val.as_bytes())?.1)
+                        });
+                    } else if k.eq_ignore_ascii_case(b"$envmapmask") {
+                        vmt.env_map_mask = Some(Cow::Borrowed(val));
                     } else if k.eq_ignore_ascii_case(b"$detailtint") {
                         let (_, val) = take_vec3(val.as_bytes())?;
                         vmt.detail.tint = Some(val);
@@ -474,6 +613,8 @@ impl<'a> Default for VMT<'a> {
         VMT {
             shader_name: ShaderName::LightmappedGeneric,
             base_texture: None,
+            base_texture2: None,
+            blend_modulate_texture: None,
             keywords: None,
             detail: VMTDetail::default(),
             detail2: VMTDetail2::default(),
@@ -481,12 +622,32 @@ impl<'a> Default for VMT<'a> {
             decal: None,
             base_texture_transform: None,
             color: None,
+            color2: None,
+            bump_map: None,
+            normal_map: None,
+            alpha_test: None,
+            alpha_test_reference: None,
+            translucent: None,
+            additive: None,
+            no_cull: None,
+            self_illum: None,
+            fog_color: None,
+            fog_start: None,
+            fog_end: None,
+            reflect_tint: None,
+            refract_tint: None,
+            reflect_amount: None,
+            refract_amount: None,
+            env_map: None,
+            env_map_tint: None,
+            env_map_mask: None,
             phong: None,
             phong_boost: None,
             phong_exponent: None,
             phong_fresnel_ranges: None,
             lightwarp_texture: None,
             include: None,
+            invalid: Vec::new(),
             other: VMTOther::default(),
             sub: VMTSubs::default(),
         }
@@ -848,7 +1009,8 @@ mod test {
         assert_eq!(vmt.shader_name, ShaderName::LightmappedGeneric);
         assert_eq!(vmt.base_texture, Some("Thing/thingy001".into()));
         assert_eq!(vmt.keywords, Some("test".into()));
-        assert_eq!(vmt.other.get(b"$envmap" as &[u8]), Some("env_cubemap"));
+        assert_eq!(vmt.env_map, Some("env_cubemap".into()));
+        assert_eq!(vmt.other.get(b"$envmap" as &[u8]), None);
         assert_eq!(vmt.other.get(b"$basealphaenvmapmask" as &[u8]), Some("1"));
         assert_eq!(vmt.surface_prop, Some("metal".into()));
 
@@ -871,6 +1033,136 @@ mod test {
         assert_eq!(vmt.surface_prop, Some("metal".into()));
     }
 
+    #[test]
+    fn test_material_params() {
+        let text = r#""VertexLitGeneric"
+        {
+            "$basetexture" "models/thing/thing"
+            "$bumpmap" "models/thing/thing_normal"
+            "$alphatest" 1
+            "$alphatestreference" ".6"
+            "$nocull" "1"
+            "$selfillum" 0
+            "$color" "[1 0.5 0.25]"
+            "$color2" "{255 0 255}"
+        }
+        "#;
+
+        let vmt = VMT::from_bytes(text.as_bytes()).unwrap();
+        assert_eq!(vmt.shader_name, ShaderName::VertexLitGeneric);
+        assert_eq!(vmt.bump_map, Some("models/thing/thing_normal".into()));
+        assert_eq!(vmt.normal_map, None);
+        assert_eq!(vmt.alpha_test, Some(true));
+        assert_eq!(vmt.alpha_test_reference, Some(0.6));
+        assert_eq!(vmt.no_cull, Some(true));
+        assert_eq!(vmt.self_illum, Some(false));
+        assert_eq!(vmt.translucent, None);
+        assert_eq!(vmt.color, Some([1.0, 0.5, 0.25]));
+        assert_eq!(vmt.color2, Some([1.0, 0.0, 1.0]));
+
+        let text = r#""Water"
+        {
+            "$normalmap" "water/water_normal"
+            "$translucent" 1
+            "$additive" 0
+            "$fogcolor" "{30 40 25}"
+            "$fogstart" 0
+            "$fogend" "192"
+            "$reflecttint" "[0.5 0.6 0.7]"
+            "$refracttint" "{255 255 255}"
+            "$refractamount" ".1"
+        }
+        "#;
+
+        let vmt = VMT::from_bytes(text.as_bytes()).unwrap();
+        assert_eq!(vmt.normal_map, Some("water/water_normal".into()));
+        assert_eq!(vmt.translucent, Some(true));
+        assert_eq!(vmt.additive, Some(false));
+        assert_eq!(
+            vmt.fog_color,
+            Some([30.0 / 255.0, 40.0 / 255.0, 25.0 / 255.0])
+        );
+        assert_eq!(vmt.fog_start, Some(0.0));
+        assert_eq!(vmt.fog_end, Some(192.0));
+        assert_eq!(vmt.reflect_tint, Some([0.5, 0.6, 0.7]));
+        assert_eq!(vmt.refract_tint, Some([1.0, 1.0, 1.0]));
+        assert_eq!(vmt.reflect_amount, None);
+        assert_eq!(vmt.refract_amount, Some(0.1));
+
+        let text = r#""LightmappedGeneric"
+        {
+            "$basetexture" "metal/metalwall001"
+            "$envmap" "env_cubemap"
+            "$envmaptint" "[.25 .5 .5]"
+            "$envmapmask" "metal/metalwall001_mask"
+        }
+        "#;
+
+        let vmt = VMT::from_bytes(text.as_bytes()).unwrap();
+        assert_eq!(vmt.env_map_tint, Some([0.25, 0.5, 0.5]));
+        assert_eq!(vmt.env_map, Some("env_cubemap".into()));
+        assert_eq!(vmt.env_map_mask, Some("metal/metalwall001_mask".into()));
+
+        let text = r#""WorldVertexTransition"
+        {
+            "$basetexture" "nature/grass"
+            "$basetexture2" "nature/dirt"
+            "$blendmodulatetexture" "nature/grass_dirt_modulate"
+        }
+        "#;
+
+        let vmt = VMT::from_bytes(text.as_bytes()).unwrap();
+        assert_eq!(vmt.shader_name, ShaderName::WorldVertexTransition);
+        assert_eq!(vmt.base_texture, Some("nature/grass".into()));
+        assert_eq!(vmt.base_texture2, Some("nature/dirt".into()));
+        assert_eq!(
+            vmt.blend_modulate_texture,
+            Some("nature/grass_dirt_modulate".into())
+        );
+
+        // Included values are overridden by the including material
+        let base = VMT::from_bytes(
+            br#""LightmappedGeneric" { "$translucent" 1 "$nocull" 1 "$color" "[0 0 1]" }"#,
+        )
+        .unwrap();
+        let over = VMT::from_bytes(br#""patch" { "include" "base" "$translucent" 0 }"#).unwrap();
+        let vmt = over.resolve(|_| Ok::<_, ()>(base)).unwrap();
+        assert_eq!(vmt.translucent, Some(false));
+        assert_eq!(vmt.no_cull, Some(true));
+        assert_eq!(vmt.color, Some([0.0, 0.0, 1.0]));
+
+        // Invalid values don't fail the whole vmt, they're left unset
+        let text = r#""LightmappedGeneric"
+        {
+            "$basetexture" "metal/metalwall001"
+            "$alphatest" "yes"
+            "$alphatestreference" ".5.5"
+            "$envmaptint" "[1 1]"
+            "$nocull" 1
+        }
+        "#;
+
+        let vmt = VMT::from_bytes(text.as_bytes()).unwrap();
+        assert_eq!(vmt.base_texture, Some("metal/metalwall001".into()));
+        assert_eq!(vmt.alpha_test, None);
+        assert_eq!(vmt.alpha_test_reference, None);
+        assert_eq!(vmt.env_map_tint, None);
+        assert_eq!(vmt.no_cull, Some(true));
+        let invalid = vmt
+            .invalid
+            .iter()
+            .map(|(k, v)| (&**k, &**v))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            invalid,
+            [
+                (b"$alphatest" as &[u8], "yes"),
+                (b"$alphatestreference", ".5.5"),
+                (b"$envmaptint", "[1 1]"),
+            ]
+        );
+    }
+
     #[test]
     fn test_sub_vmt() {
         let text = r#""Water"
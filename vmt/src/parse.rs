@@ -56,12 +56,24 @@ pub(crate) fn take_str(bytes: &[u8]) -> Result<(&[u8], &[u8]), VMTError> {
     Ok((&bytes[1..], name))
 }
 
+/// Parse a number inside of a vector, which ends at whitespace or the closing bracket
+fn take_number(bytes: &[u8]) -> Result<(&[u8], &[u8]), VMTError> {
+    let end = bytes
+        .iter()
+        .position(|&b| b.is_ascii_whitespace() || b == b']' || b == b'}')
+        .unwrap_or(bytes.len());
+
+    let (num, bytes) = bytes.split_at(end);
+
+    Ok((bytes, num))
+}
+
 pub(crate) fn take_vec2(bytes: &[u8]) -> Result<(&[u8], [f32; 2]), VMTError> {
     let b = expect_char(bytes, b'[')?;
     let b = take_whitespace(b)?;
-    let (b, x) = take_text(b)?;
+    let (b, x) = take_number(b)?;
     let b = take_whitespace(b)?;
-    let (b, y) = take_text(b)?;
+    let (b, y) = take_number(b)?;
     let b = take_whitespace(b)?;
     let b = expect_char(b, b']')?;
 
@@ -75,11 +87,11 @@ pub(crate) fn take_vec2(bytes: &[u8]) -> Result<(&[u8], [f32; 2]), VMTError> {
 pub(crate) fn take_vec3(bytes: &[u8]) -> Result<(&[u8], [f32; 3]), VMTError> {
     let b = expect_char(bytes, b'[')?;
     let b = take_whitespace(b)?;
-    let (b, x) = take_text(b)?;
+    let (b, x) = take_number(b)?;
     let b = take_whitespace(b)?;
-    let (b, y) = take_text(b)?;
+    let (b, y) = take_number(b)?;
     let b = take_whitespace(b)?;
-    let (b, z) = take_text(b)?;
+    let (b, z) = take_number(b)?;
     let b = take_whitespace(b)?;
     let b = expect_char(b, b']')?;
 
@@ -90,9 +102,52 @@ pub(crate) fn take_vec3(bytes: &[u8]) -> Result<(&[u8], [f32; 3]), VMTError> {
     Ok((b, [x, y, z]))
 }
 
+/// Parse a color, either like `[ 1.0 0.5 0.25 ]` or in the `0..=255` form like `{ 255 128 64 }`.
+/// The result is always in `0.0..=1.0`.
+pub(crate) fn take_color(bytes: &[u8]) -> Result<(&[u8], [f32; 3]), VMTError> {
+    if !bytes.starts_with(b"{") {
+        return take_vec3(bytes);
+    }
+
+    let b = expect_char(bytes, b'{')?;
+    let b = take_whitespace(b)?;
+    let (b, r) = take_number(b)?;
+    let b = take_whitespace(b)?;
+    let (b, g) = take_number(b)?;
+    let b = take_whitespace(b)?;
+    let (b, bl) = take_number(b)?;
+    let b = take_whitespace(b)?;
+    let b = expect_char(b, b'}')?;
+
+    let r: f32 = std::str::from_utf8(r)?.parse()?;
+    let g: f32 = std::str::from_utf8(g)?.parse()?;
+    let bl: f32 = std::str::from_utf8(bl)?.parse()?;
+
+    Ok((b, [r / 255.0, g / 255.0, bl / 255.0]))
+}
+
+/// Parse a boolean, which in vmts is typically written as `0` or `1`
+pub(crate) fn parse_bool(text: &str) -> Result<bool, VMTError> {
+    let text = text.trim();
+    if text.eq_ignore_ascii_case("true") {
+        Ok(true)
+    } else if text.eq_ignore_ascii_case("false") {
+        Ok(false)
+    } else {
+        // Any non-zero number is true
+        let val: f32 = text.parse()?;
+        Ok(val != 0.0)
+    }
+}
+
+/// Parse a number, ignoring surrounding whitespace
+pub(crate) fn parse_f32(text: &str) -> Result<f32, VMTError> {
+    Ok(text.trim().parse()?)
+}
+
 #[cfg(test)]
 mod test {
-    use crate::take_text;
+    use crate::{parse::parse_bool, parse::take_color, take_text};
 
     use super::take_str;
 
@@ -143,4 +198,28 @@ mod test {
         assert_eq!(bytes, b"");
         assert_eq!(name, b"VertexLitGeneric");
     }
+
+    #[test]
+    fn test_take_color() {
+        let (bytes, color) = take_color(b"[1 0.5 0]").unwrap();
+        assert_eq!(bytes, b"");
+        assert_eq!(color, [1.0, 0.5, 0.0]);
+
+        let (bytes, color) = take_color(b"{ 255 0 255 } ").unwrap();
+        assert_eq!(bytes, b" ");
+        assert_eq!(color, [1.0, 0.0, 1.0]);
+
+        assert!(take_color(b"{255 0}").is_err());
+    }
+
+    #[test]
+    fn test_parse_bool() {
+        assert!(parse_bool("1").unwrap());
+        assert!(!parse_bool("0").unwrap());
+        assert!(parse_bool(" 1 ").unwrap());
+        assert!(parse_bool("TRUE").unwrap());
+        assert!(!parse_bool("false").unwrap());
+        assert!(!parse_bool("0.0").unwrap());
+        assert!(parse_bool("yes").is_err());
+    }
 }
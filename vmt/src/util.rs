@@ -1,5 +1,7 @@
 use std::borrow::Cow;
 
+use crate::VMTError;
+
 pub(crate) fn apply<T: Clone>(a: Option<T>, b: &Option<T>) -> Option<T> {
     if let Some(b) = b {
         Some(b.clone())
@@ -21,6 +23,23 @@ pub(crate) fn to_lowercase_cow(text: &[u8]) -> Cow<'_, [u8]> {
     }
 }
 
+/// Parse the value of an optional parameter. If it is invalid then it is recorded in `invalid`
+/// and left unset, rather than failing the whole vmt.
+pub(crate) fn lenient<'a, T>(
+    invalid: &mut Vec<(Cow<'a, [u8]>, Cow<'a, str>)>,
+    key: &'a [u8],
+    val: &'a str,
+    parse: impl FnOnce(&'a str) -> Result<T, VMTError>,
+) -> Option<T> {
+    match parse(val) {
+        Ok(val) => Some(val),
+        Err(_) => {
+            invalid.push((Cow::Borrowed(key), Cow::Borrowed(val)));
+            None
+        }
+    }
+}
+
 pub(crate) struct StopOnErr<I, T, E>
 where
     I: Iterator<Item = Result<T, E>>,